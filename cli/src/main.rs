@@ -0,0 +1,144 @@
+//! Every scraper behind one command, eg: `scraper truelayer -c scraper.toml
+//! sync …` or `scraper gocardless sync -c scraper.toml …`. Each reads its
+//! own table (`[truelayer]` or `[gocardless]`) from a shared config file,
+//! and `scraper schedule` runs the scheduled providers from both.
+
+use std::{path::PathBuf, pin::Pin, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use futures::{Future, FutureExt};
+use scraper_sdk::{ScheduleConfig, Scheduler};
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+#[derive(Debug, Parser)]
+#[clap(name = "scraper")]
+enum Cli {
+    /// Sync from TrueLayer.
+    Truelayer(tl_scraper::cli::Options),
+    /// Sync from GoCardless Bank Account Data.
+    #[clap(subcommand)]
+    Gocardless(gc_scraper::Command),
+    /// Keep running, syncing each provider of either backend with a
+    /// `schedule` as it falls due.
+    Schedule {
+        #[clap(short = 'c', long = "config")]
+        config: PathBuf,
+    },
+}
+
+/// The tables of a shared config file; each backend parses its own.
+#[derive(Debug, Deserialize)]
+struct SharedConfig {
+    /// Only `jitter_s` applies across backends; each backend's own
+    /// `schedule` table sets how its runs go.
+    #[serde(default)]
+    schedule: ScheduleConfig,
+    truelayer: Option<toml::Table>,
+    gocardless: Option<toml::Table>,
+}
+
+type ScheduledRun = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Cli::parse() {
+        Cli::Truelayer(opts) => {
+            let program = std::env::args()
+                .next()
+                .unwrap_or_else(|| "scraper".to_owned());
+            let opts = opts.with_invoked_as(format!("{} truelayer", program));
+            tl_scraper::cli::main(opts).await
+        }
+        Cli::Gocardless(cmd) => {
+            let run = async {
+                gc_scraper::setup_logging()?;
+                color_eyre::install()?;
+                cmd.run().await
+            };
+            if let Err(report) = run.await {
+                if let Some(status) = gc_scraper::exit_status(&report) {
+                    eprintln!("Error: {:?}", report);
+                    std::process::exit(status);
+                }
+                return Err(anyhow!("{:?}", report));
+            }
+            Ok(())
+        }
+        Cli::Schedule { config } => {
+            gc_scraper::setup_logging().map_err(|report| anyhow!("{:?}", report))?;
+            schedule(config).await
+        }
+    }
+}
+
+/// Schedules every provider under one [`Scheduler`], by `backend/provider`.
+async fn schedule(config_path: PathBuf) -> Result<()> {
+    let content = tokio::fs::read_to_string(&config_path)
+        .await
+        .with_context(|| format!("Reading config file: {:?}", config_path))?;
+    let config: SharedConfig = toml::from_str(&content).context("Parse config")?;
+    if config.truelayer.is_none() && config.gocardless.is_none() {
+        bail!("Config has neither a [truelayer] nor a [gocardless] table");
+    }
+
+    let cnx = CancellationToken::new();
+    let truelayer = match config.truelayer {
+        Some(_) => Some(tl_scraper::cli::ScheduledSyncs::load(&config_path, cnx.clone()).await?),
+        None => None,
+    };
+    let gocardless = match config.gocardless {
+        Some(_) => Some(
+            gc_scraper::ScheduledSyncs::load(&config_path)
+                .await
+                .map_err(|report| anyhow!("{:?}", report))?,
+        ),
+        None => None,
+    };
+
+    let mut schedules = Vec::new();
+    for (name, schedule) in truelayer.iter().flat_map(|syncs| syncs.schedules()) {
+        schedules.push((format!("truelayer/{}", name), schedule.clone()));
+    }
+    for (name, schedule) in gocardless.iter().flat_map(|syncs| syncs.schedules()) {
+        schedules.push((format!("gocardless/{}", name), schedule.clone()));
+    }
+    let scheduler = Scheduler::new(
+        schedules
+            .iter()
+            .map(|(name, schedule)| (name.as_str(), schedule)),
+        config.schedule.jitter_s.map(Duration::from_secs),
+    )?;
+
+    let servers = truelayer.clone().map(|syncs| tokio::spawn(syncs.serve()));
+    tokio::spawn({
+        let cnx = cnx.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                info!("Interrupted; stopping syncs in progress");
+                cnx.cancel();
+            }
+        }
+    });
+
+    let sync = |name: String| -> ScheduledRun {
+        let (backend, provider) = name.split_once('/').expect("backend/provider");
+        match (backend, &truelayer, &gocardless) {
+            ("truelayer", Some(syncs), _) => syncs.sync(provider.to_owned()).boxed(),
+            ("gocardless", _, Some(syncs)) => syncs
+                .sync(provider.to_owned())
+                .map(|res| res.map_err(|report| anyhow!("{:?}", report)))
+                .boxed(),
+            _ => unreachable!("Scheduled {} without its config", name),
+        }
+    };
+    scheduler.run(sync, cnx.clone()).await?;
+
+    cnx.cancel();
+    if let Some(servers) = servers {
+        servers.await??;
+    }
+    Ok(())
+}
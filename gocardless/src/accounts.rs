@@ -16,6 +16,26 @@ pub(crate) struct Account {
     pub(crate) other: serde_json::Value,
 }
 
+/// The response from `/api/v2/accounts/{id}/details/`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AccountDetails {
+    pub(crate) account: AccountDetail,
+    #[serde(flatten)]
+    pub(crate) other: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AccountDetail {
+    #[serde(rename = "ownerName", default)]
+    pub(crate) owner_name: Option<String>,
+    #[serde(default)]
+    pub(crate) product: Option<String>,
+    #[serde(rename = "cashAccountType", default)]
+    pub(crate) cash_account_type: Option<String>,
+    #[serde(flatten)]
+    pub(crate) other: serde_json::Value,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Balances {
     pub(crate) balances: Vec<Balance>,
@@ -1,13 +1,15 @@
 use std::{
-    io,
+    io::{self, Write},
     path::{Path, PathBuf},
 };
 
 use chrono::{DateTime, Duration, Utc};
 use clap::Args;
 use color_eyre::Result;
-use serde::{Deserialize, Serialize};
-use tracing::{debug, info, instrument, warn};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize, Serializer};
+use tempfile::NamedTempFile;
+use tracing::{debug, info, instrument, warn, Span};
 
 use crate::client::BankDataClient;
 
@@ -24,36 +26,53 @@ pub struct AuthArgs {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Secrets {
     secret_id: String,
-    secret_key: String,
+    #[serde(serialize_with = "serialize_secret")]
+    secret_key: SecretString,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub(crate) struct TokenRefreshReq {
-    refresh: String,
+    #[serde(serialize_with = "serialize_secret")]
+    refresh: SecretString,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct TokenRefreshResp {
-    access: String,
+    access: SecretString,
     access_expires: i64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub(crate) struct TokenPair {
-    access: String,
+    access: SecretString,
     access_expires: i64,
-    refresh: String,
+    refresh: SecretString,
     refresh_expires: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Token {
-    pub(crate) access: String,
+    #[serde(serialize_with = "serialize_secret")]
+    pub(crate) access: SecretString,
     access_expires: DateTime<Utc>,
-    refresh: String,
+    #[serde(serialize_with = "serialize_secret")]
+    refresh: SecretString,
     refresh_expires: DateTime<Utc>,
 }
 
+impl AuthArgs {
+    pub(crate) fn new(secrets: PathBuf, token: PathBuf) -> Self {
+        AuthArgs { secrets, token }
+    }
+}
+
+fn serialize_secret<S: Serializer>(
+    secret: &SecretString,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    secret.expose_secret().serialize(serializer)
+}
+
 impl Token {
     pub(crate) fn from_token_pair(authed_at: DateTime<Utc>, gctoken: &TokenPair) -> Token {
         Token {
@@ -74,11 +93,54 @@ impl Token {
     }
 }
 
+/// Replaces `path` atomically, with a file only we can read.
 #[instrument(skip_all, fields(?path))]
 async fn store_token(path: &Path, tok: &Token) -> Result<()> {
     let buf = serde_json::to_vec(&tok)?;
-    tokio::fs::write(&path, buf).await?;
-    debug!(?path, "Stored token");
+    let path = path.to_owned();
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let _entered = span.enter();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut tmpf = NamedTempFile::new_in(dir)?;
+        restrict_permissions(tmpf.as_file())?;
+        tmpf.write_all(&buf)?;
+        tmpf.as_file_mut().flush()?;
+        tmpf.persist(&path).map_err(|e| e.error)?;
+        debug!(?path, "Stored token");
+        Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn restrict_permissions(file: &std::fs::File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_: &std::fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Warns if anyone else could read `path`.
+#[cfg(unix)]
+async fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = tokio::fs::metadata(path).await?.permissions().mode();
+    if mode & 0o077 != 0 {
+        warn!(?path, mode=%format!("{:o}", mode & 0o777), "Readable by others; run `chmod 600` on it");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn check_permissions(_: &Path) -> Result<()> {
     Ok(())
 }
 
@@ -93,6 +155,7 @@ async fn load_token(path: &Path) -> Result<Option<Token>> {
         Err(err) => return Err(err.into()),
     };
 
+    check_permissions(path).await?;
     let mut token = serde_json::from_slice::<Token>(&buf)?;
 
     let now = Utc::now();
@@ -132,6 +195,7 @@ async fn refresh_token(token: &Token) -> Result<Token> {
 #[instrument(skip_all, fields(?path))]
 async fn load_secrets(path: &Path) -> Result<Secrets> {
     let buf = tokio::fs::read(&path).await?;
+    check_permissions(path).await?;
     let secrets = serde_json::from_slice(&buf)?;
 
     debug!(?path, "Loaded secrets");
@@ -1,30 +1,71 @@
-use std::fmt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use axum::http::{uri::Scheme, Uri};
+use again::RetryPolicy;
+use axum::http::{uri::PathAndQuery, Uri};
 use chrono::{DateTime, Duration, Utc};
 use color_eyre::{
     eyre::{eyre, Context},
-    Result,
+    Report, Result,
 };
-use reqwest::{header::CONTENT_TYPE, Client};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
+use secrecy::ExposeSecret;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tracing::{debug, trace, warn};
+use tracing::{debug, info, trace, warn};
 
 use crate::auth::Token;
 
-const BANK_DATA_HOST: &str = "bankaccountdata.gocardless.com";
+const BANK_DATA_URL: &str = "https://bankaccountdata.gocardless.com";
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RETRIES: usize = 3;
 
 #[derive(Clone)]
 pub(crate) struct UnauthenticatedBankDataClient {
     http: Client,
+    base_url: Uri,
 }
 #[derive(Clone)]
 pub(crate) struct BankDataClient {
     http: Client,
+    base_url: Uri,
     token: Token,
+    /// The last reported per-account success quota, by endpoint path.
+    quotas: Arc<Mutex<HashMap<String, AccountQuota>>>,
+    usage: Arc<ApiUsage>,
+    max_quota_wait: Duration,
+    retry_policy: RetryPolicy,
+}
+
+/// What we've asked of the API, for the run's summary.
+#[derive(Debug, Default)]
+struct ApiUsage {
+    calls: AtomicUsize,
+    /// The general rate limit remaining, as of the last response.
+    rate_limit_remaining: Mutex<Option<i64>>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+struct AccountQuota {
+    remaining: i64,
+    reset_at: DateTime<Utc>,
+}
+
+/// Returned rather than calling an endpoint whose per-account quota is used
+/// up until further than we're prepared to wait.
+#[derive(Debug)]
+pub(crate) struct QuotaExhausted {
+    path: String,
+    reset_at: DateTime<Utc>,
 }
 #[derive(Debug, Deserialize)]
-struct ErrorResponse {
+pub(crate) struct ErrorResponse {
     #[serde(default)]
     summary: String,
     #[serde(default)]
@@ -43,7 +84,8 @@ pub(crate) trait ResponseExt: Sized {
 impl UnauthenticatedBankDataClient {
     fn new() -> Self {
         let http = Client::new();
-        UnauthenticatedBankDataClient { http }
+        let base_url = Uri::from_static(BANK_DATA_URL);
+        UnauthenticatedBankDataClient { http, base_url }
     }
 
     pub(crate) async fn post<Response: DeserializeOwned>(
@@ -52,13 +94,7 @@ impl UnauthenticatedBankDataClient {
         body: &impl Serialize,
     ) -> Result<Response> {
         // "https://bankaccountdata.gocardless.com/api/v2/token/new/"
-        let url = Uri::builder()
-            .scheme(Scheme::HTTPS)
-            .authority(BANK_DATA_HOST)
-            .path_and_query(path)
-            .build()
-            .wrap_err("Build base URI")?
-            .to_string();
+        let url = url(&self.base_url, path)?;
 
         debug!(%url, "POST");
 
@@ -85,7 +121,101 @@ impl UnauthenticatedBankDataClient {
 impl BankDataClient {
     pub(crate) fn new(token: Token) -> Self {
         let http = Client::new();
-        Self { http, token }
+        Self {
+            http,
+            base_url: Uri::from_static(BANK_DATA_URL),
+            token,
+            quotas: Default::default(),
+            usage: Default::default(),
+            max_quota_wait: Duration::zero(),
+            retry_policy: RetryPolicy::exponential(RETRY_BASE_DELAY)
+                .with_jitter(true)
+                .with_max_retries(MAX_RETRIES),
+        }
+    }
+
+    /// When an account's quota for an endpoint is exhausted, wait up to
+    /// `max_wait` for it to reset, rather than failing straight away.
+    pub(crate) fn with_max_quota_wait(mut self, max_wait: Duration) -> Self {
+        self.max_quota_wait = max_wait;
+        self
+    }
+
+    /// A client for `base_url`, eg: a mock server, with a made-up token.
+    #[cfg(test)]
+    pub(crate) fn for_tests(base_url: &str) -> Self {
+        let token: Token = serde_json::from_value(serde_json::json!({
+            "access": "access-token",
+            "access_expires": "2099-01-01T00:00:00Z",
+            "refresh": "refresh-token",
+            "refresh_expires": "2099-01-01T00:00:00Z",
+        }))
+        .expect("token");
+        Self {
+            base_url: base_url.parse().expect("base url"),
+            ..Self::new(token)
+        }
+    }
+
+    /// How many calls got a response.
+    pub(crate) fn api_calls(&self) -> usize {
+        self.usage.calls.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn rate_limit_remaining(&self) -> Option<i64> {
+        *self.usage.rate_limit_remaining.lock().expect("lock")
+    }
+
+    /// The last reported per-account quota remaining, by endpoint path.
+    pub(crate) fn account_quotas_remaining(&self) -> BTreeMap<String, i64> {
+        self.quotas
+            .lock()
+            .expect("lock")
+            .iter()
+            .map(|(path, quota)| (path.clone(), quota.remaining))
+            .collect()
+    }
+
+    /// Picks up the quotas an earlier run saw from `path`, so that we don't
+    /// spend a call finding out that one is still exhausted.
+    pub(crate) async fn load_quotas(&self, path: &Path) -> Result<()> {
+        let saved: HashMap<String, AccountQuota> = scraper_sdk::load_state(path)
+            .await
+            .wrap_err_with(|| format!("Reading {:?}", path))?
+            .unwrap_or_default();
+        let now = Utc::now();
+        let mut quotas = self.quotas.lock().expect("lock");
+        for (path, quota) in saved {
+            if quota.reset_at > now {
+                quotas.entry(path).or_insert(quota);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the quotas that have yet to reset in `path`, for the next run.
+    pub(crate) async fn save_quotas(&self, path: &Path) -> Result<()> {
+        let now = Utc::now();
+        let quotas = self
+            .quotas
+            .lock()
+            .expect("lock")
+            .iter()
+            .filter(|(_, quota)| quota.reset_at > now)
+            .map(|(path, quota)| (path.clone(), *quota))
+            .collect::<BTreeMap<_, _>>();
+        scraper_sdk::write_json_atomically(path, quotas)
+            .await
+            .wrap_err_with(|| format!("Writing {:?}", path))?;
+        Ok(())
+    }
+
+    fn record_call(&self, resp: &reqwest::Response) -> Result<()> {
+        self.usage.calls.fetch_add(1, Ordering::Relaxed);
+        if let Some(remaining) = maybe_parse_header(resp, HTTP_X_RATELIMIT_REMAINING)? {
+            *self.usage.rate_limit_remaining.lock().expect("lock") = Some(remaining);
+        }
+        Ok(())
     }
 
     pub(crate) fn unauthenticated() -> UnauthenticatedBankDataClient {
@@ -94,13 +224,19 @@ impl BankDataClient {
 
     pub(crate) async fn get<Response: DeserializeOwned>(&self, path: &str) -> Result<Response> {
         // "https://bankaccountdata.gocardless.com/api/v2/token/new/"
-        let url = Uri::builder()
-            .scheme(Scheme::HTTPS)
-            .authority(BANK_DATA_HOST)
-            .path_and_query(path)
-            .build()
-            .wrap_err("Build base URI")?
-            .to_string();
+        let url = url(&self.base_url, path)?;
+
+        self.retry_policy
+            .retry_if(|| self.get_once(path, &url), is_transient)
+            .await
+    }
+
+    async fn get_once<Response: DeserializeOwned>(
+        &self,
+        path: &str,
+        url: &str,
+    ) -> Result<Response> {
+        self.wait_for_quota(path).await?;
 
         let started_at = Utc::now();
 
@@ -108,50 +244,110 @@ impl BankDataClient {
         let resp = self
             .http
             .get(url)
-            .bearer_auth(&self.token.access)
+            .bearer_auth(self.token.access.expose_secret())
             .send()
-            .await?
-            .parse_error()
             .await?;
+        self.record_call(&resp)?;
 
-        log_rate_limits(&resp, started_at)?;
+        if let Some(quota) = log_rate_limits(&resp, started_at)? {
+            self.quotas
+                .lock()
+                .expect("lock")
+                .insert(quota_key(path).to_owned(), quota);
+        }
+
+        let resp = resp.parse_error().await?;
 
         let data = resp.json().await?;
 
         Ok(data)
     }
 
+    /// Holds off calling `path` while the account's quota for it is used up.
+    async fn wait_for_quota(&self, path: &str) -> Result<()> {
+        let Some(quota) = self
+            .quotas
+            .lock()
+            .expect("lock")
+            .get(quota_key(path))
+            .copied()
+        else {
+            return Ok(());
+        };
+        let wait = quota.reset_at - Utc::now();
+        if quota.remaining > 0 || wait <= Duration::zero() {
+            return Ok(());
+        }
+        if wait > self.max_quota_wait {
+            return Err(QuotaExhausted {
+                path: quota_key(path).to_owned(),
+                reset_at: quota.reset_at,
+            }
+            .into());
+        }
+        info!(%path, reset_at=%quota.reset_at, "Account quota exhausted; waiting for reset");
+        tokio::time::sleep(wait.to_std()?).await;
+        Ok(())
+    }
+
     pub(crate) async fn post<Response: DeserializeOwned>(
         &self,
         path: &str,
         body: &impl Serialize,
     ) -> Result<Response> {
         // "https://bankaccountdata.gocardless.com/api/v2/token/new/"
-        let url = Uri::builder()
-            .scheme(Scheme::HTTPS)
-            .authority(BANK_DATA_HOST)
-            .path_and_query(path)
-            .build()
-            .wrap_err("Build base URI")?
-            .to_string();
-
-        let started_at = Utc::now();
-
-        debug!(%url, "POST");
-        let resp = self
-            .http
-            .post(url)
-            .json(body)
-            .bearer_auth(&self.token.access)
-            .send()
-            .await?
-            .log_rate_limits(started_at)?
-            .parse_error()
-            .await?;
-
-        let data = resp.json().await?;
+        let url = url(&self.base_url, path)?;
+
+        // Creating things isn't idempotent, so only retry when the request
+        // never got as far as the server.
+        self.retry_policy
+            .retry_if(
+                || async {
+                    let started_at = Utc::now();
+
+                    debug!(%url, "POST");
+                    let resp = self
+                        .http
+                        .post(&url)
+                        .json(body)
+                        .bearer_auth(self.token.access.expose_secret())
+                        .send()
+                        .await?;
+                    self.record_call(&resp)?;
+                    let resp = resp.log_rate_limits(started_at)?.parse_error().await?;
+
+                    let data = resp.json().await?;
+
+                    Ok(data)
+                },
+                is_connect_error,
+            )
+            .await
+    }
 
-        Ok(data)
+    pub(crate) async fn delete(&self, path: &str) -> Result<()> {
+        let url = url(&self.base_url, path)?;
+
+        self.retry_policy
+            .retry_if(
+                || async {
+                    let started_at = Utc::now();
+
+                    debug!(%url, "DELETE");
+                    let resp = self
+                        .http
+                        .delete(&url)
+                        .bearer_auth(self.token.access.expose_secret())
+                        .send()
+                        .await?;
+                    self.record_call(&resp)?;
+                    resp.log_rate_limits(started_at)?.parse_error().await?;
+
+                    Ok(())
+                },
+                is_transient,
+            )
+            .await
     }
 }
 
@@ -163,20 +359,102 @@ const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING: &str =
     "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING";
 const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET: &str = "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET";
 
-fn log_rate_limits(resp: &reqwest::Response, started_at: DateTime<Utc>) -> Result<()> {
+/// Connection failures, timeouts and server errors are worth another go;
+/// anything the API rejected, or that we chose not to send, is not.
+fn is_transient(err: &Report) -> bool {
+    let Some(err) = err.downcast_ref::<reqwest::Error>() else {
+        return false;
+    };
+    let transient = is_transient_http(err);
+    if transient {
+        warn!(error=%err, "Transient error; retrying");
+    }
+    transient
+}
+
+fn is_transient_http(err: &reqwest::Error) -> bool {
+    err.is_connect()
+        || err.is_timeout()
+        || err.is_request()
+        || err.status().map_or(false, |s| s.is_server_error())
+}
+
+/// Whether `err` should clear up by itself given time, eg: an outage, or
+/// an exhausted rate limit, rather than needing someone to step in.
+pub(crate) fn is_temporary(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        is_transient_http(err) || err.status() == Some(StatusCode::TOO_MANY_REQUESTS)
+    } else if let Some(err) = err.downcast_ref::<ErrorResponse>() {
+        err.status_code == StatusCode::TOO_MANY_REQUESTS.as_u16() || err.status_code >= 500
+    } else {
+        err.is::<QuotaExhausted>()
+    }
+}
+
+/// Whether the API refused `err`'s request for asking after transactions
+/// older than the institution will give us, eg: "Date range exceeds
+/// max_historical_days", or a complaint about `date_from` itself.
+pub(crate) fn is_date_out_of_range(err: &Report) -> bool {
+    err.chain().any(|cause| {
+        let Some(err) = cause.downcast_ref::<ErrorResponse>() else {
+            return false;
+        };
+        if err.status_code != StatusCode::BAD_REQUEST.as_u16() {
+            return false;
+        }
+        let mentions_dates = |text: &str| {
+            let text = text.to_lowercase();
+            text.contains("date range") || text.contains("historical")
+        };
+        mentions_dates(&err.summary)
+            || mentions_dates(&err.detail)
+            || err.other.get("date_from").is_some()
+    })
+}
+
+fn is_connect_error(err: &Report) -> bool {
+    let connect = err
+        .downcast_ref::<reqwest::Error>()
+        .map_or(false, |err| err.is_connect());
+    if connect {
+        warn!(error=%err, "Could not connect; retrying");
+    }
+    connect
+}
+
+/// Resolves the API `path` against `base_url`.
+fn url(base_url: &Uri, path: &str) -> Result<String> {
+    let mut parts = base_url.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path).wrap_err("Build base URI")?);
+    let url = Uri::from_parts(parts).wrap_err("Build base URI")?;
+    Ok(url.to_string())
+}
+
+/// Per-account quotas apply to each endpoint under an account, regardless
+/// of the query.
+fn quota_key(path: &str) -> &str {
+    path.split('?').next().unwrap_or(path)
+}
+
+/// Logs the rate limits reported with `resp`, returning the per-account
+/// quota if there is one.
+fn log_rate_limits(
+    resp: &reqwest::Response,
+    started_at: DateTime<Utc>,
+) -> Result<Option<AccountQuota>> {
     let Some(limit) = maybe_parse_header(resp, HTTP_X_RATELIMIT_LIMIT)? else {
         warn!(header=%HTTP_X_RATELIMIT_LIMIT, "rate limit header missing");
-        return Ok(());
+        return Ok(None);
     };
 
     let Some(remaining) = maybe_parse_header(resp, HTTP_X_RATELIMIT_REMAINING)? else {
         warn!(header=%HTTP_X_RATELIMIT_REMAINING, "rate limit header missing");
-        return Ok(());
+        return Ok(None);
     };
 
     let Some(reset) = maybe_parse_header(resp, HTTP_X_RATELIMIT_RESET)? else {
         warn!(header=%HTTP_X_RATELIMIT_RESET, "rate limit header missing");
-        return Ok(());
+        return Ok(None);
     };
 
     let reset_at = started_at + Duration::seconds(reset);
@@ -184,23 +462,26 @@ fn log_rate_limits(resp: &reqwest::Response, started_at: DateTime<Utc>) -> Resul
     debug!(%limit, %remaining, %reset_at, "Rate limit status");
 
     let Some(limit) = maybe_parse_header(resp, HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT)? else {
-        return Ok(());
+        return Ok(None);
     };
 
     let Some(remaining) = maybe_parse_header(resp, HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING)?
     else {
-        return Ok(());
+        return Ok(None);
     };
 
     let Some(reset) = maybe_parse_header(resp, HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET)? else {
-        return Ok(());
+        return Ok(None);
     };
 
     let reset_at = started_at + Duration::seconds(reset);
 
     debug!(%limit, %remaining, %reset_at, "Account rate limit status");
 
-    Ok(())
+    Ok(Some(AccountQuota {
+        remaining,
+        reset_at,
+    }))
 }
 
 fn maybe_parse_header(resp: &reqwest::Response, header: &str) -> Result<Option<i64>> {
@@ -245,6 +526,18 @@ impl ResponseExt for reqwest::Response {
     }
 }
 
+impl std::error::Error for QuotaExhausted {}
+
+impl fmt::Display for QuotaExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Account quota for {} exhausted until {}",
+            self.path, self.reset_at
+        )
+    }
+}
+
 impl std::error::Error for ErrorResponse {
     fn description(&self) -> &str {
         &self.detail
@@ -267,3 +560,143 @@ impl fmt::Display for ErrorResponse {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    const BALANCES: &str = "/api/v2/accounts/acc/balances/";
+
+    fn client(server: &MockServer) -> BankDataClient {
+        BankDataClient::for_tests(&server.uri())
+    }
+
+    fn with_quota(response: ResponseTemplate, remaining: i64, reset_s: i64) -> ResponseTemplate {
+        response
+            .insert_header(HTTP_X_RATELIMIT_LIMIT, "100")
+            .insert_header(HTTP_X_RATELIMIT_REMAINING, "99")
+            .insert_header(HTTP_X_RATELIMIT_RESET, "60")
+            .insert_header(HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT, "4")
+            .insert_header(
+                HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING,
+                remaining.to_string(),
+            )
+            .insert_header(HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET, reset_s.to_string())
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_on_get() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(BALANCES))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(BALANCES))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"ok": true})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let resp: serde_json::Value = client(&server).get(BALANCES).await.expect("get");
+
+        assert_eq!(resp, json!({"ok": true}));
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_server_errors_on_post() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2/requisitions/"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client(&server)
+            .post::<serde_json::Value>("/api/v2/requisitions/", &json!({}))
+            .await;
+
+        let err = res.expect_err("post should fail");
+        let status = err
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|err| err.status());
+        assert_eq!(status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[tokio::test]
+    async fn persists_account_quotas_between_runs() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(BALANCES))
+            .respond_with(with_quota(
+                ResponseTemplate::new(200).set_body_json(json!({})),
+                0,
+                3600,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().expect("tempdir");
+        let quotas_path = dir.path().join(".quotas.json");
+
+        let first = client(&server);
+        first.get::<serde_json::Value>(BALANCES).await.expect("get");
+        first.save_quotas(&quotas_path).await.expect("save");
+
+        let second = client(&server);
+        second.load_quotas(&quotas_path).await.expect("load");
+        assert_eq!(
+            second.account_quotas_remaining(),
+            BTreeMap::from([(BALANCES.to_owned(), 0)])
+        );
+        let err = second
+            .get::<serde_json::Value>(BALANCES)
+            .await
+            .expect_err("quota should be exhausted");
+        assert!(err.is::<QuotaExhausted>(), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn waits_for_exhausted_quota_to_reset() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(BALANCES))
+            .respond_with(with_quota(
+                ResponseTemplate::new(200).set_body_json(json!({})),
+                0,
+                1,
+            ))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let client = client(&server).with_max_quota_wait(Duration::seconds(5));
+
+        client
+            .get::<serde_json::Value>(BALANCES)
+            .await
+            .expect("first get");
+        let started = Instant::now();
+        client
+            .get::<serde_json::Value>(BALANCES)
+            .await
+            .expect("second get");
+
+        assert!(
+            started.elapsed() >= std::time::Duration::from_millis(500),
+            "waited {:?}",
+            started.elapsed()
+        );
+    }
+}
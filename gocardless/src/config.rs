@@ -1,15 +1,21 @@
-use std::{collections::HashMap, fs, io::Write, path::PathBuf};
+use std::{collections::HashMap, io, path::PathBuf};
 
 use chrono::Days;
 use clap::Args;
-use color_eyre::{eyre::Context, Result};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use scraper_sdk::{ScheduleConfig, SyncSchedule};
 use serde::{Deserialize, Serialize};
-use tokio::task::spawn_blocking;
-use tracing::{instrument, Span};
+use tracing::instrument;
 use uuid::Uuid;
 
 use crate::connect::Requisition;
 
+/// Our table in a config file shared with other scrapers.
+const SHARED_CONFIG_SECTION: &str = "gocardless";
+
 #[derive(Debug, Clone, Args)]
 pub(crate) struct ConfigArg {
     #[clap(short = 'c', long = "config", help = "Configuration file")]
@@ -22,22 +28,45 @@ pub(crate) struct ProviderConfig {
     pub(crate) output: PathBuf,
     pub(crate) history_days: Option<u64>,
     pub(crate) state: PathBuf,
+    /// When the combined `scraper schedule` syncs it, if at all.
+    pub(crate) schedule: Option<SyncSchedule>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct ScraperConfig {
     pub(crate) provider: HashMap<String, ProviderConfig>,
+    /// The secrets and token files for scheduled syncs, which can't take
+    /// them from the command line.
+    pub(crate) secrets: Option<PathBuf>,
+    pub(crate) token: Option<PathBuf>,
+    /// How scheduled syncs go, as `sync`'s options would otherwise say.
+    #[serde(default)]
+    pub(crate) schedule: ScheduleConfig,
+    /// Seconds a scheduled sync waits for an exhausted account quota to
+    /// reset, as with `--max-quota-wait`.
+    pub(crate) max_quota_wait_s: Option<i64>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct ProviderState {
     pub(crate) requisition_id: Uuid,
 }
 impl ConfigArg {
+    pub(crate) fn new(config: PathBuf) -> Self {
+        ConfigArg { config }
+    }
+
+    /// Either a config of its own, or a config shared with other scrapers,
+    /// with ours in a `gocardless` table.
     pub(crate) async fn load(&self) -> Result<ScraperConfig> {
         let content = tokio::fs::read_to_string(&self.config)
             .await
             .wrap_err_with(|| format!("Reading config file: {:?}", self.config))?;
-        let config = toml::from_str(&content).context("Parse toml")?;
+        let mut table: toml::Table = toml::from_str(&content).context("Parse toml")?;
+        let config = match table.remove(SHARED_CONFIG_SECTION) {
+            Some(section) => section.try_into(),
+            None => table.try_into(),
+        }
+        .context("Parse config")?;
 
         Ok(config)
     }
@@ -50,36 +79,26 @@ impl ProviderConfig {
 
     #[instrument(skip_all, fields(path=?self.state))]
     pub(crate) async fn write_state(&self, state: &ProviderState) -> Result<()> {
-        let span = Span::current();
-        let path = self.state.to_owned();
-        let state = state.clone();
-        spawn_blocking(move || -> Result<()> {
-            let _entered = span.enter();
-            let parent = path.parent().unwrap_or(".".as_ref());
-            let mut f = tempfile::NamedTempFile::new_in(parent)?;
-            serde_json::to_writer_pretty(&mut f, &state)?;
-            f.flush()?;
-            f.persist(&path)?;
-
-            Ok(())
-        })
-        .await??;
+        scraper_sdk::store_state(&self.state, state.clone()).await?;
 
         Ok(())
     }
 
     #[instrument(skip_all, fields(path=?self.state))]
     pub(crate) async fn load_state(&self) -> Result<ProviderState> {
-        let span = Span::current();
-        let path = self.state.to_owned();
-        spawn_blocking(move || -> Result<_> {
-            let _entered = span.enter();
-            let f = fs::File::open(&path).wrap_err_with(|| format!("Open state file: {path:?}"))?;
-            let state = serde_json::from_reader(f)?;
+        scraper_sdk::load_state(&self.state)
+            .await
+            .wrap_err_with(|| format!("Open state file: {:?}", self.state))?
+            .ok_or_else(|| eyre!("State file not found: {:?}", self.state))
+    }
 
-            Ok(state)
-        })
-        .await?
+    #[instrument(skip_all, fields(path=?self.state))]
+    pub(crate) async fn remove_state(&self) -> Result<()> {
+        match tokio::fs::remove_file(&self.state).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).wrap_err_with(|| format!("Remove state file: {:?}", self.state)),
+        }
     }
 }
 
@@ -48,11 +48,14 @@ pub(crate) struct Requisition {
     pub(crate) link: String,
     pub(crate) status: RequisitionStatus,
     pub(crate) accounts: Vec<Uuid>,
+    /// The end user agreement the requisition was created under.
+    #[serde(default)]
+    pub(crate) agreement: Option<String>,
     #[serde(flatten)]
     pub(crate) other: serde_json::Value,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub(crate) enum RequisitionStatus {
     // Requisition has been successfully created
     #[serde(rename = "CR")]
@@ -78,10 +81,13 @@ pub(crate) enum RequisitionStatus {
     // Access to accounts has expired as set in End User Agreement
     #[serde(rename = "EX")]
     Expired,
+    // Requisition was suspended, e.g. after repeated failed access attempts
+    #[serde(rename = "SU")]
+    Suspended,
 }
 
 impl Cmd {
-    #[instrument("auth", skip_all, fields(provider = %self.provider, institution_id, requisition_id))]
+    #[instrument("auth", skip_all, fields(provider = %self.provider, institution_id))]
     pub(crate) async fn run(&self) -> Result<()> {
         let config = self.config.load().await?;
         let token = self.auth.load_token().await?;
@@ -94,61 +100,89 @@ impl Cmd {
 
         let client = BankDataClient::new(token);
 
-        let cnx = CancellationToken::new();
-        let ip_addr = IpAddr::from([127, 0, 0, 1]);
-        let listener = TcpListener::bind((ip_addr, self.port))
-            .await
-            .with_context(|| format!("Bind to address: {}:{}", ip_addr, self.port))?;
-
-        let listen_address = listener.local_addr().context("listen address")?;
-        let base_url = Uri::builder()
-            .scheme(Scheme::HTTP)
-            .authority(listen_address.to_string())
-            .path_and_query("")
-            .build()
-            .context("Build base URI")?;
-
-        let req = RequisitionReq {
-            institution_id: provider_config.institution_id.clone(),
-            redirect: base_url.to_string(),
-        };
+        let requisition =
+            create_linked_requisition(&client, &provider_config.institution_id, self.port).await?;
 
-        let requisition = client
-            .post::<Requisition>("/api/v2/requisitions/", &req)
-            .await?;
+        let state = ProviderState::from_requisition(&requisition);
 
-        Span::current().record("requisition_id", field::display(&requisition.id));
+        provider_config.write_state(&state).await?;
 
-        debug!(?requisition, "Got requisition");
+        Ok(())
+    }
+}
 
-        let app = Router::new().merge(routes(cnx.clone(), requisition.id));
+/// Creates a requisition for `institution_id`, and waits for the end-user to
+/// follow the link and return to our listener on `port`.
+#[instrument(skip(client), fields(requisition_id))]
+pub(crate) async fn create_linked_requisition(
+    client: &BankDataClient,
+    institution_id: &str,
+    port: u16,
+) -> Result<Requisition> {
+    let cnx = CancellationToken::new();
+    let ip_addr = IpAddr::from([127, 0, 0, 1]);
+    let listener = TcpListener::bind((ip_addr, port))
+        .await
+        .with_context(|| format!("Bind to address: {}:{}", ip_addr, port))?;
 
-        println!("Go to link: {}", requisition.link);
-        info!("Awaiting response");
+    let listen_address = listener.local_addr().context("listen address")?;
+    let base_url = Uri::builder()
+        .scheme(Scheme::HTTP)
+        .authority(listen_address.to_string())
+        .path_and_query("")
+        .build()
+        .context("Build base URI")?;
 
-        axum::serve(listener, app)
-            .with_graceful_shutdown(cnx.clone().cancelled_owned())
-            .await
-            .context("Running server")?;
+    let req = RequisitionReq {
+        institution_id: institution_id.to_owned(),
+        redirect: base_url.to_string(),
+    };
 
-        let requisition = client
-            .get::<Requisition>(&format!("/api/v2/requisitions/{}/", requisition.id))
-            .await?;
+    let requisition = client
+        .post::<Requisition>("/api/v2/requisitions/", &req)
+        .await?;
 
-        debug!(?requisition, "Got requisition",);
+    Span::current().record("requisition_id", field::display(&requisition.id));
 
-        let state = ProviderState::from_requisition(&requisition);
+    debug!(?requisition, "Got requisition");
 
-        provider_config.write_state(&state).await?;
+    let app = Router::new().merge(routes(cnx.clone(), requisition.id));
 
-        Ok(())
-    }
+    println!("Go to link: {}", requisition.link);
+    info!("Awaiting response");
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(cnx.clone().cancelled_owned())
+        .await
+        .context("Running server")?;
+
+    let requisition = client
+        .get::<Requisition>(&format!("/api/v2/requisitions/{}/", requisition.id))
+        .await?;
+
+    debug!(?requisition, "Got requisition",);
+
+    Ok(requisition)
 }
 
 impl Requisition {
     pub(crate) fn is_linked(&self) -> bool {
         self.status == RequisitionStatus::Linked
     }
+
+    /// Whether the end-user needs to grant access again via a new requisition.
+    pub(crate) fn needs_renewal(&self) -> bool {
+        self.status.needs_renewal()
+    }
+}
+
+impl RequisitionStatus {
+    pub(crate) fn needs_renewal(&self) -> bool {
+        matches!(
+            self,
+            RequisitionStatus::Expired | RequisitionStatus::Suspended
+        )
+    }
 }
 
 #[derive(Clone)]
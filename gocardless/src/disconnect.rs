@@ -0,0 +1,60 @@
+use clap::Parser;
+use color_eyre::{eyre::eyre, Result};
+use tracing::{debug, field, info, instrument, warn, Span};
+
+use crate::{auth::AuthArgs, client::BankDataClient, config::ConfigArg, connect::Requisition};
+
+#[derive(Debug, Parser)]
+pub struct Cmd {
+    #[clap(flatten)]
+    auth: AuthArgs,
+    #[clap(flatten)]
+    config: ConfigArg,
+    #[clap(short = 'p', long = "provider", help = "Provider name")]
+    provider: String,
+}
+
+impl Cmd {
+    #[instrument("disconnect", skip_all, fields(provider = %self.provider, requisition_id))]
+    pub(crate) async fn run(&self) -> Result<()> {
+        let config = self.config.load().await?;
+        let token = self.auth.load_token().await?;
+
+        let Some(provider_config) = config.provider.get(&self.provider) else {
+            return Err(eyre!("Unrecognised provider: {}", self.provider));
+        };
+
+        let client = BankDataClient::new(token);
+
+        let state = provider_config.load_state().await?;
+        Span::current().record("requisition_id", field::display(&state.requisition_id));
+
+        let requisition = client
+            .get::<Requisition>(&format!("/api/v2/requisitions/{}/", state.requisition_id))
+            .await?;
+
+        debug!(?requisition, "Got requisition");
+
+        client
+            .delete(&format!("/api/v2/requisitions/{}/", requisition.id))
+            .await?;
+        info!("Deleted requisition");
+
+        // Deleting the requisition may take the agreement with it, so this
+        // is only worth a warning.
+        if let Some(agreement) = requisition.agreement.filter(|id| !id.is_empty()) {
+            match client
+                .delete(&format!("/api/v2/agreements/enduser/{}/", agreement))
+                .await
+            {
+                Ok(()) => info!(%agreement, "Deleted end user agreement"),
+                Err(error) => warn!(%agreement, ?error, "Could not delete end user agreement"),
+            }
+        }
+
+        provider_config.remove_state().await?;
+        info!("Removed state");
+
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
-use clap::Parser;
-use color_eyre::Result;
+use chrono::Days;
+use clap::{Parser, ValueEnum};
+use color_eyre::{eyre::Context, Result};
 use serde::{Deserialize, Serialize};
-use tracing::{info, instrument};
+use tracing::{debug, instrument};
 
 use crate::{auth::AuthArgs, client::BankDataClient};
 
@@ -9,10 +10,31 @@ use crate::{auth::AuthArgs, client::BankDataClient};
 pub struct Cmd {
     #[clap(flatten)]
     auth: AuthArgs,
+    #[clap(
+        short = 'C',
+        long = "country",
+        default_value = "gb",
+        help = "ISO 3166 country code to list institutions for"
+    )]
+    country: String,
+    #[clap(
+        short = 'q',
+        long = "search",
+        help = "Only show institutions whose name or id contains this"
+    )]
+    search: Option<String>,
+    #[clap(short = 'o', long = "output", value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Institution {
+pub(crate) struct Institution {
     id: String,
     name: String,
     bic: String,
@@ -23,18 +45,58 @@ struct Institution {
 }
 
 impl Cmd {
-    #[instrument("institutions", skip_all)]
+    #[instrument("institutions", skip_all, fields(country = %self.country))]
     pub(crate) async fn run(&self) -> Result<()> {
         let token = self.auth.load_token().await?;
 
         let client = BankDataClient::new(token);
 
-        let data = client
-            .get::<Vec<Institution>>("/api/v2/institutions/?country=gb")
+        let mut data = client
+            .get::<Vec<Institution>>(&format!(
+                "/api/v2/institutions/?country={}",
+                self.country.to_lowercase()
+            ))
             .await?;
 
-        info!("Institutions: {}", serde_json::to_string_pretty(&data)?);
+        if let Some(search) = self.search.as_deref() {
+            let search = search.to_lowercase();
+            data.retain(|inst| inst.matches(&search));
+        }
+
+        debug!(count = data.len(), "Found institutions");
+
+        match self.output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&data)?),
+            OutputFormat::Table => {
+                for inst in data.iter() {
+                    println!(
+                        "{:<40} {:<11} {:>4} {:>4} {}",
+                        inst.id,
+                        inst.bic,
+                        inst.transaction_total_days,
+                        inst.max_access_valid_for_days,
+                        inst.name
+                    );
+                }
+            }
+        }
 
         Ok(())
     }
 }
+
+impl Institution {
+    /// How far back the institution holds transactions for.
+    pub(crate) fn transaction_total_days(&self) -> Result<Days> {
+        let days = self.transaction_total_days.parse().wrap_err_with(|| {
+            format!("transaction_total_days: {:?}", self.transaction_total_days)
+        })?;
+        Ok(Days::new(days))
+    }
+
+    /// Case-insensitive substring match against the name or id; `search`
+    /// must already be lower case.
+    fn matches(&self, search: &str) -> bool {
+        self.name.to_lowercase().contains(search) || self.id.to_lowercase().contains(search)
+    }
+}
@@ -3,28 +3,55 @@ mod auth;
 mod client;
 mod config;
 mod connect;
+mod disconnect;
 mod institutions;
+mod reconnect;
 mod sync;
 mod transactions;
 
 use clap::Parser;
 use color_eyre::Result;
 
+pub use sync::{exit_status, ScheduledSyncs};
+
 #[derive(Debug, Parser)]
 pub enum Command {
     Institutions(institutions::Cmd),
     Connect(connect::Cmd),
+    Reconnect(reconnect::Cmd),
+    Disconnect(disconnect::Cmd),
     Sync(sync::Cmd),
 }
 
 impl Command {
+    /// Runs the command; see [`exit_status`] for how the process should
+    /// exit if it fails.
     pub async fn run(&self) -> Result<()> {
         match self {
             Command::Institutions(cmd) => cmd.run().await?,
             Command::Connect(cmd) => cmd.run().await?,
+            Command::Reconnect(cmd) => cmd.run().await?,
+            Command::Disconnect(cmd) => cmd.run().await?,
             Command::Sync(cmd) => cmd.run().await?,
         }
 
         Ok(())
     }
 }
+
+/// Logs to stdout, filtered by `RUST_LOG`, defaulting to `info`.
+pub fn setup_logging() -> Result<()> {
+    use tracing_error::ErrorLayer;
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::{fmt, EnvFilter};
+
+    let filter_layer = EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info"))?;
+
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(fmt::layer())
+        .with(ErrorLayer::default())
+        .init();
+
+    Ok(())
+}
@@ -5,28 +5,18 @@ use gc_scraper::Command;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    setup_logging()?;
+    gc_scraper::setup_logging()?;
     color_eyre::install()?;
 
     let cmd = Command::parse();
 
-    cmd.run().await?;
-
-    Ok(())
-}
-
-fn setup_logging() -> Result<()> {
-    use tracing_error::ErrorLayer;
-    use tracing_subscriber::prelude::*;
-    use tracing_subscriber::{fmt, EnvFilter};
-
-    let filter_layer = EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info"))?;
-
-    tracing_subscriber::registry()
-        .with(filter_layer)
-        .with(fmt::layer())
-        .with(ErrorLayer::default())
-        .init();
+    if let Err(err) = cmd.run().await {
+        if let Some(status) = gc_scraper::exit_status(&err) {
+            eprintln!("Error: {:?}", err);
+            std::process::exit(status);
+        }
+        return Err(err);
+    }
 
     Ok(())
 }
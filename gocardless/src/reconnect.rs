@@ -0,0 +1,80 @@
+use clap::Parser;
+use color_eyre::{eyre::eyre, Result};
+use tracing::{debug, field, info, instrument, Span};
+
+use crate::{
+    auth::AuthArgs,
+    client::BankDataClient,
+    config::{ConfigArg, ProviderState},
+    connect::{create_linked_requisition, Requisition},
+};
+
+#[derive(Debug, Parser)]
+pub struct Cmd {
+    #[clap(flatten)]
+    auth: AuthArgs,
+    #[clap(flatten)]
+    config: ConfigArg,
+    #[clap(short = 'p', long = "provider", help = "Provider name")]
+    provider: String,
+    #[clap(short = 'l', long = "port", help = "HTTP Listener port")]
+    port: u16,
+    #[clap(
+        short = 'f',
+        long = "force",
+        help = "Renew even if the current requisition is still usable"
+    )]
+    force: bool,
+}
+
+impl Cmd {
+    #[instrument("reconnect", skip_all, fields(provider = %self.provider, institution_id, old_requisition_id))]
+    pub(crate) async fn run(&self) -> Result<()> {
+        let config = self.config.load().await?;
+        let token = self.auth.load_token().await?;
+
+        let Some(provider_config) = config.provider.get(&self.provider) else {
+            return Err(eyre!("Unrecognised provider: {}", self.provider));
+        };
+
+        Span::current().record("institution_id", &provider_config.institution_id);
+
+        let client = BankDataClient::new(token);
+
+        let state = provider_config.load_state().await?;
+        Span::current().record("old_requisition_id", field::display(&state.requisition_id));
+
+        let current = client
+            .get::<Requisition>(&format!("/api/v2/requisitions/{}/", state.requisition_id))
+            .await?;
+
+        debug!(?current, "Got current requisition");
+
+        if !current.needs_renewal() && !self.force {
+            info!(status = ?current.status, "Requisition does not need renewal");
+            return Ok(());
+        }
+
+        info!(status = ?current.status, "Renewing requisition");
+
+        let requisition =
+            create_linked_requisition(&client, &provider_config.institution_id, self.port).await?;
+
+        // Keep the old requisition until we have one that works.
+        if !requisition.is_linked() {
+            return Err(eyre!(
+                "New requisition {} not linked: {:?}",
+                requisition.id,
+                requisition.status
+            ));
+        }
+
+        let state = ProviderState::from_requisition(&requisition);
+
+        provider_config.write_state(&state).await?;
+
+        info!(requisition_id = %requisition.id, "Switched to new requisition");
+
+        Ok(())
+    }
+}
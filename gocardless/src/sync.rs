@@ -1,22 +1,46 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    cmp::max,
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use chrono::{Datelike, Days, Local, Months, NaiveDate};
-use clap::Parser;
-use color_eyre::{eyre::eyre, Result};
+use chrono::{Datelike, Days, Duration, Local, Months, NaiveDate};
+use clap::{Parser, ValueEnum};
+use color_eyre::{eyre::eyre, Report, Result};
+use scraper_sdk::{
+    check_target_dir, ByMonth, ErrorPolicy, JobPool, JobsFailed, Provider, ProviderAggregator,
+    SyncSchedule,
+};
 use serde::Serialize;
-use tokio::io::AsyncWriteExt;
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
 use uuid::Uuid;
 
 use crate::{
-    accounts::{Account, Balances},
+    accounts::{Account, AccountDetails, Balances},
     auth::AuthArgs,
-    client::BankDataClient,
+    client::{is_date_out_of_range, is_temporary, BankDataClient},
     config::{ConfigArg, ProviderConfig, ScraperConfig},
-    connect::Requisition,
-    transactions::{Transactions, TransactionsQuery},
+    connect::{Requisition, RequisitionStatus},
+    institutions::Institution,
+    transactions::{Transaction, Transactions, TransactionsQuery},
 };
 
+/// The requisition needs the user to `connect` or `reconnect`.
+const EXIT_NOT_LINKED: i32 = 3;
+/// Worth trying again later, as with `EX_TEMPFAIL` from `sysexits.h`.
+const EXIT_TEMPORARY: i32 = 75;
+/// Per-account quotas yet to reset, relative to the provider's output.
+const QUOTAS_FILE: &str = ".quotas.json";
+/// Seconds to wait for an account quota to reset, unless told otherwise.
+const DEFAULT_MAX_QUOTA_WAIT_S: i64 = 60;
+
 #[derive(Debug, Parser)]
 pub struct Cmd {
     #[clap(flatten)]
@@ -25,20 +49,120 @@ pub struct Cmd {
     config: ConfigArg,
     #[clap(short = 'p', long = "provider", help = "Provider name")]
     provider: String,
+    #[clap(
+        short = 'j',
+        long = "concurrent-tasks",
+        default_value_t = 1,
+        help = "How many accounts to fetch at once"
+    )]
+    concurrency: usize,
+    #[clap(
+        long = "max-quota-wait",
+        default_value_t = DEFAULT_MAX_QUOTA_WAIT_S,
+        help = "Seconds to wait for an exhausted account quota to reset, rather than skip calling"
+    )]
+    max_quota_wait_s: i64,
+    #[clap(
+        long = "from",
+        help = "First date to fetch, rather than `history_days` ago"
+    )]
+    from: Option<NaiveDate>,
+    #[clap(long = "to", help = "Last date to fetch, rather than today")]
+    to: Option<NaiveDate>,
+    #[clap(
+        long = "backfill",
+        conflicts_with = "from",
+        help = "Fetch as much history as the institution holds, a month at a time"
+    )]
+    backfill: bool,
+    #[clap(
+        long = "summary",
+        value_enum,
+        help = "Print what the sync did once it's finished"
+    )]
+    summary: Option<SummaryFormat>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SummaryFormat {
+    Json,
+}
+
+/// What a sync did, whether or not it succeeded.
+#[derive(Debug, Serialize)]
+struct SyncSummary {
+    accounts: usize,
+    /// Months of transactions, across all accounts.
+    months_written: usize,
+    /// Calls to the API that got a response, including any retries.
+    api_calls: usize,
+    /// The general rate limit remaining, as of the last call.
+    rate_limit_remaining: Option<i64>,
+    /// Each endpoint's per-account quota remaining, as of the last call.
+    account_quotas_remaining: BTreeMap<String, i64>,
+    error: Option<String>,
+}
+
+/// Counts what's been synced, as the accounts' jobs go.
+#[derive(Debug, Default)]
+struct Progress {
+    accounts: AtomicUsize,
+    months: AtomicUsize,
+}
+
+/// Returned when the requisition isn't linked, so can't be synced.
+#[derive(Debug)]
+pub(crate) struct NotLinked {
+    requisition_id: Uuid,
+    status: RequisitionStatus,
 }
 
 impl Cmd {
     #[instrument("sync", skip_all, fields(provider = %self.provider))]
     pub(crate) async fn run(&self) -> Result<()> {
         let config: ScraperConfig = self.config.load().await?;
-        let token = self.auth.load_token().await?;
 
         let Some(provider_config) = config.provider.get(&self.provider) else {
             return Err(eyre!("Unrecognised provider: {}", self.provider));
         };
 
-        let client = BankDataClient::new(token);
+        check_target_dir(&provider_config.output).await?;
+
+        let token = self.auth.load_token().await?;
+        let client = BankDataClient::new(token)
+            .with_max_quota_wait(Duration::seconds(self.max_quota_wait_s));
+        let quotas_path = provider_config.output.join(QUOTAS_FILE);
+        client.load_quotas(&quotas_path).await?;
+
+        let progress = Arc::new(Progress::default());
+        let result = self.sync(provider_config, &client, &progress).await;
+        if let Err(error) = client.save_quotas(&quotas_path).await {
+            warn!(?error, "Could not save account quotas");
+        }
+
+        if let Some(SummaryFormat::Json) = self.summary {
+            let summary = SyncSummary {
+                accounts: progress.accounts.load(Ordering::Relaxed),
+                months_written: progress.months.load(Ordering::Relaxed),
+                api_calls: client.api_calls(),
+                rate_limit_remaining: client.rate_limit_remaining(),
+                account_quotas_remaining: client.account_quotas_remaining(),
+                error: result.as_ref().err().map(|err| format!("{:#}", err)),
+            };
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        }
 
+        result
+    }
+
+    /// Everything after setting up the client, so that the summary covers
+    /// failures such as the requisition not being linked.
+    async fn sync(
+        &self,
+        provider_config: &ProviderConfig,
+        client: &BankDataClient,
+        progress: &Arc<Progress>,
+    ) -> Result<()> {
         let state = provider_config.load_state().await?;
 
         let requisition = client
@@ -48,109 +172,251 @@ impl Cmd {
         debug!(?requisition, "Got requisition",);
 
         if !requisition.is_linked() {
-            return Err(eyre!("Requisition not linked"));
+            return Err(NotLinked {
+                requisition_id: requisition.id,
+                status: requisition.status,
+            }
+            .into());
         }
 
-        let end_date = Local::now().date_naive();
-        let mut start_date = end_date - provider_config.history_days();
-        if start_date.day() > 1 {
-            start_date = start_date + Months::new(1);
-            start_date = start_date - Days::new(start_date.day0().into());
+        let end_date = self.to.unwrap_or_else(|| Local::now().date_naive());
+        let start_date = if let Some(from) = self.from {
+            from
+        } else if self.backfill {
+            let institution = client
+                .get::<Institution>(&format!(
+                    "/api/v2/institutions/{}/",
+                    provider_config.institution_id
+                ))
+                .await?;
+            end_date - institution.transaction_total_days()?
+        } else {
+            let mut start_date = end_date - provider_config.history_days();
+            if start_date.day() > 1 {
+                start_date = start_date + Months::new(1);
+                start_date = start_date - Days::new(start_date.day0().into());
+            }
+            start_date
+        };
+        if start_date > end_date {
+            return Err(eyre!(
+                "Start date {} is after end date {}",
+                start_date,
+                end_date
+            ));
         }
-        debug!(%start_date, %end_date, "Scanning date range");
+        debug!(%start_date, %end_date, backfill=%self.backfill, "Scanning date range");
 
-        for acc in requisition.accounts.iter().cloned() {
-            self.list_account(provider_config, &client, acc, start_date, end_date)
-                .await?;
+        let sync = Arc::new(ProviderAggregator::new(ProviderSync {
+            name: self.provider.clone(),
+            provider_config: provider_config.clone(),
+            client: client.clone(),
+            accounts: requisition.accounts.clone(),
+            backfill: self.backfill,
+            progress: progress.clone(),
+        }));
+
+        // Carry on with the other accounts should one's quota be exhausted.
+        let (pool, handle) = JobPool::new(self.concurrency);
+        let pool = pool.with_error_policy(ErrorPolicy::KeepGoing);
+        scraper_sdk::sync_all(vec![sync], start_date..=end_date, (pool, handle)).await
+    }
+}
+
+/// Syncs the providers with a `schedule` as a [`scraper_sdk::Scheduler`]
+/// calls for them, for the combined `scraper schedule`.
+pub struct ScheduledSyncs {
+    config_path: PathBuf,
+    config: ScraperConfig,
+    auth: Option<AuthArgs>,
+}
+
+impl ScheduledSyncs {
+    pub async fn load(config_path: &Path) -> Result<Self> {
+        let config = ConfigArg::new(config_path.to_owned()).load().await?;
+        let auth = match (&config.secrets, &config.token) {
+            (Some(secrets), Some(token)) => Some(AuthArgs::new(secrets.clone(), token.clone())),
+            _ => None,
+        };
+        let scheduled = config.provider.values().any(|p| p.schedule.is_some());
+        if scheduled && auth.is_none() {
+            return Err(eyre!(
+                "Both `secrets` and `token` need configuring to sync on a schedule"
+            ));
         }
-        Ok(())
+        Ok(ScheduledSyncs {
+            config_path: config_path.to_owned(),
+            config,
+            auth,
+        })
     }
 
-    #[instrument(skip_all,fields(%account_id))]
-    async fn list_account(
-        &self,
-        provider_config: &ProviderConfig,
-        client: &BankDataClient,
-        account_id: Uuid,
-        start_date: NaiveDate,
-        end_date: NaiveDate,
-    ) -> Result<()> {
-        let details = fetch_account(client, account_id).await?;
+    /// Each provider with a `schedule`, by name.
+    pub fn schedules(&self) -> impl Iterator<Item = (&str, &SyncSchedule)> {
+        self.config
+            .provider
+            .iter()
+            .filter_map(|(name, p)| Some((name.as_str(), p.schedule.as_ref()?)))
+    }
 
-        let account_base = provider_config.output.join(&details.iban);
+    /// Syncs `provider` as `sync` does by default, re-reading the config,
+    /// but with the concurrency and quota wait the schedule was loaded with.
+    pub fn sync(&self, provider: String) -> impl Future<Output = Result<()>> + Send + 'static {
+        let cmd = self.auth.clone().map(|auth| Cmd {
+            auth,
+            config: ConfigArg::new(self.config_path.clone()),
+            provider,
+            concurrency: self.config.schedule.concurrency.unwrap_or(1),
+            max_quota_wait_s: self
+                .config
+                .max_quota_wait_s
+                .unwrap_or(DEFAULT_MAX_QUOTA_WAIT_S),
+            from: None,
+            to: None,
+            backfill: false,
+            summary: None,
+        });
+        async move {
+            let cmd = cmd.ok_or_else(|| eyre!("No `secrets` or `token` configured"))?;
+            cmd.run().await
+        }
+    }
+}
 
-        self.write_file(&account_base.join("account-details.json"), &details)
-            .await?;
+/// How the process should exit after `err`, if not with the usual failure
+/// status: distinguishing failures that need the user to reconnect from
+/// those that should clear up by themselves, so a scheduler can tell.
+pub fn exit_status(err: &Report) -> Option<i32> {
+    if err.chain().any(|cause| cause.is::<NotLinked>()) {
+        Some(EXIT_NOT_LINKED)
+    } else if is_temporary_failure(err) {
+        Some(EXIT_TEMPORARY)
+    } else {
+        None
+    }
+}
 
-        let balances = fetch_balances(client, account_id).await?;
+/// Whether every cause of `err`, including every failed job, is temporary.
+fn is_temporary_failure(err: &Report) -> bool {
+    err.chain().any(|cause| {
+        if let Some(failed) = cause.downcast_ref::<JobsFailed<Report>>() {
+            !failed.errors.is_empty() && failed.errors.iter().all(is_temporary_failure)
+        } else {
+            is_temporary(cause)
+        }
+    })
+}
 
-        self.write_file(&account_base.join("balances.json"), &balances)
-            .await?;
+impl std::error::Error for NotLinked {}
 
-        let transactions = fetch_transactions(client, account_id, start_date, end_date).await?;
+impl fmt::Display for NotLinked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.status.needs_renewal() {
+            write!(
+                f,
+                "Requisition {} is {:?}; run `reconnect` to renew access",
+                self.requisition_id, self.status
+            )
+        } else {
+            write!(
+                f,
+                "Requisition {} not linked: {:?}",
+                self.requisition_id, self.status
+            )
+        }
+    }
+}
 
-        let mut by_month = HashMap::<_, Transactions>::new();
+/// Syncs the accounts linked to a single provider's requisition.
+struct ProviderSync {
+    name: String,
+    provider_config: ProviderConfig,
+    client: BankDataClient,
+    accounts: Vec<Uuid>,
+    /// Fetch a month at a time, newest first, keeping what we have when the
+    /// institution turns out to hold less history than it claims.
+    backfill: bool,
+    progress: Arc<Progress>,
+}
 
-        for booked in transactions.transactions.booked {
-            let date = booked
-                .booking_date
-                .or(booked.booking_date_time.map(|dt| dt.date_naive()))
-                .or(booked.value_date);
-            let start_of_month = date.map(|d| d.with_day(1).expect("valid date"));
+impl Provider for ProviderSync {
+    type AccountId = Uuid;
+    type Account = Account;
+    type Details = AccountDetails;
+    type Balances = Balances;
+    type Transactions = Transactions;
+    type Error = Report;
 
-            by_month
-                .entry(start_of_month)
-                .or_default()
-                .transactions
-                .booked
-                .push(booked)
-        }
-        for pending in transactions.transactions.pending {
-            let date = pending
-                .booking_date
-                .or(pending.booking_date_time.map(|dt| dt.date_naive()))
-                .or(pending.value_date);
-            let start_of_month = date.map(|d| d.with_day(1).expect("valid date"));
-
-            by_month
-                .entry(start_of_month)
-                .or_default()
-                .transactions
-                .pending
-                .push(pending)
-        }
+    fn name(&self) -> &str {
+        &self.name
+    }
 
-        for (month, transactions) in by_month {
-            let fname = month
-                .map(|month| month.format("%Y-%m.json").to_string())
-                .unwrap_or_else(|| "undated.json".to_owned());
-            let path = account_base.join(fname);
-            self.write_file(&path, &transactions).await?;
+    fn target_dir(&self) -> &Path {
+        &self.provider_config.output
+    }
+
+    async fn list_accounts(&self) -> Result<Vec<Uuid>> {
+        Ok(self.accounts.clone())
+    }
+
+    async fn account(&self, account_id: Uuid) -> Result<Account> {
+        fetch_account(&self.client, account_id).await
+    }
+
+    fn account_dir(&self, account: &Account) -> PathBuf {
+        PathBuf::from(&account.iban)
+    }
+
+    /// Only used to label accounts, so the sync carries on without them.
+    async fn details(&self, account: &Account) -> Result<Option<AccountDetails>> {
+        match fetch_details(&self.client, account.id).await {
+            Ok(details) => Ok(Some(details)),
+            Err(error) => {
+                warn!(account_id=%account.id, ?error, "Fetching account details; skipping them");
+                Ok(None)
+            }
         }
+    }
 
-        Ok(())
+    async fn balances(&self, account: &Account) -> Result<Balances> {
+        fetch_balances(&self.client, account.id).await
     }
 
-    #[instrument(skip_all, fields(?path))]
-    async fn write_file(
+    async fn transactions(
         &self,
-        path: &Path,
-        data: impl Serialize,
-    ) -> Result<(), color_eyre::eyre::Error> {
-        if let Some(parent) = path.parent() {
-            tokio::fs::create_dir_all(parent).await?;
+        account: &Account,
+        period: RangeInclusive<NaiveDate>,
+    ) -> Result<ByMonth<Transactions>> {
+        let transactions = if self.backfill {
+            backfill_transactions(&self.client, account.id, period).await?
+        } else {
+            fetch_transactions(&self.client, account.id, *period.start(), *period.end()).await?
+        };
+
+        let mut by_month = ByMonth::<Transactions>::new();
+        for (month, booked) in scraper_sdk::by_month(transactions.transactions.booked, date) {
+            by_month.entry(month).or_default().transactions.booked = booked;
+        }
+        for (month, pending) in scraper_sdk::by_month(transactions.transactions.pending, date) {
+            by_month.entry(month).or_default().transactions.pending = pending;
         }
-        let mut of = tokio::fs::File::create(&path).await?;
-        let buf = serde_json::to_string_pretty(&data)?;
-        of.write_all(buf.as_bytes()).await?;
-        of.flush().await?;
 
-        debug!(size=%buf.len(), ?path, "Wrote data to file");
+        self.progress.accounts.fetch_add(1, Ordering::Relaxed);
+        Ok(by_month)
+    }
 
-        Ok(())
+    fn month_written(&self, _account: &Account, _month: Option<NaiveDate>) {
+        self.progress.months.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+fn date(transaction: &Transaction) -> Option<NaiveDate> {
+    transaction
+        .booking_date
+        .or(transaction.booking_date_time.map(|dt| dt.date_naive()))
+        .or(transaction.value_date)
+}
+
 #[instrument(skip_all)]
 async fn fetch_account(
     client: &BankDataClient,
@@ -162,6 +428,14 @@ async fn fetch_account(
     Ok(details)
 }
 
+#[instrument(skip_all)]
+async fn fetch_details(client: &BankDataClient, account_id: Uuid) -> Result<AccountDetails> {
+    let details = client
+        .get::<AccountDetails>(&format!("/api/v2/accounts/{}/details/", account_id))
+        .await?;
+    Ok(details)
+}
+
 #[instrument(skip_all)]
 async fn fetch_balances(client: &BankDataClient, account_id: Uuid) -> Result<Balances> {
     let balances = client
@@ -170,6 +444,39 @@ async fn fetch_balances(client: &BankDataClient, account_id: Uuid) -> Result<Bal
     Ok(balances)
 }
 
+/// Walks backwards through `period` a month at a time, stopping at the first
+/// month the institution says is out of range; if that's the latest month,
+/// there's nothing to backfill. Any other failure fails the account, rather
+/// than leaving it short of history.
+#[instrument(skip_all)]
+async fn backfill_transactions(
+    client: &BankDataClient,
+    account_id: Uuid,
+    period: RangeInclusive<NaiveDate>,
+) -> Result<Transactions> {
+    let mut all = Transactions::default();
+    let windows = scraper_sdk::months(period.clone()).collect::<Vec<_>>();
+    for month in windows.into_iter().rev() {
+        let start = max(*month.start(), *period.start());
+        match fetch_transactions(client, account_id, start, *month.end()).await {
+            Ok(transactions) => {
+                all.transactions
+                    .booked
+                    .extend(transactions.transactions.booked);
+                all.transactions
+                    .pending
+                    .extend(transactions.transactions.pending);
+            }
+            Err(error) if is_date_out_of_range(&error) => {
+                warn!(%start, ?error, "Could not fetch further back; stopping");
+                break;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(all)
+}
+
 #[instrument(skip_all)]
 async fn fetch_transactions(
     client: &BankDataClient,
@@ -190,3 +497,144 @@ async fn fetch_transactions(
         .await?;
     Ok(transactions)
 }
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::WrapErr;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::client::ErrorResponse;
+
+    fn api_error(status_code: u16) -> Report {
+        let response: ErrorResponse = serde_json::from_value(json!({
+            "summary": "Failed",
+            "detail": "Failed",
+            "status_code": status_code,
+        }))
+        .expect("error response");
+        Report::new(response)
+    }
+
+    fn jobs_failed(errors: Vec<Report>) -> Report {
+        Report::new(JobsFailed { errors })
+    }
+
+    #[test]
+    fn exits_not_linked_for_unlinked_requisition() {
+        let err = Report::new(NotLinked {
+            requisition_id: Uuid::nil(),
+            status: RequisitionStatus::Expired,
+        })
+        .wrap_err("Syncing");
+        assert_eq!(exit_status(&err), Some(EXIT_NOT_LINKED));
+    }
+
+    #[test]
+    fn exits_temporary_for_outages_and_rate_limits() {
+        assert_eq!(exit_status(&api_error(503)), Some(EXIT_TEMPORARY));
+        assert_eq!(exit_status(&api_error(429)), Some(EXIT_TEMPORARY));
+        assert_eq!(exit_status(&api_error(400)), None);
+        assert_eq!(exit_status(&eyre!("boom")), None);
+    }
+
+    #[test]
+    fn temporary_only_if_every_job_failed_temporarily() {
+        assert!(is_temporary_failure(&jobs_failed(vec![
+            api_error(503),
+            api_error(429),
+        ])));
+        assert!(!is_temporary_failure(&jobs_failed(vec![
+            api_error(503),
+            api_error(400),
+        ])));
+        assert!(!is_temporary_failure(&jobs_failed(Vec::new())));
+    }
+
+    fn out_of_range() -> ResponseTemplate {
+        ResponseTemplate::new(400).set_body_json(json!({
+            "summary": "Invalid date range",
+            "detail": "Date range exceeds max_historical_days",
+            "status_code": 400,
+        }))
+    }
+
+    fn booked_on(date: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "transactions": {
+                "booked": [{"bookingDate": date}],
+                "pending": [],
+            },
+        }))
+    }
+
+    fn transactions_path() -> String {
+        format!("/api/v2/accounts/{}/transactions/", Uuid::nil())
+    }
+
+    #[tokio::test]
+    async fn backfills_nothing_when_latest_month_is_out_of_range() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(transactions_path()))
+            .respond_with(out_of_range())
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = BankDataClient::for_tests(&server.uri());
+        let period = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()
+            ..=NaiveDate::from_ymd_opt(2024, 3, 20).unwrap();
+
+        let transactions = backfill_transactions(&client, Uuid::nil(), period)
+            .await
+            .expect("backfill");
+
+        assert!(transactions.transactions.booked.is_empty());
+        assert!(transactions.transactions.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backfill_stops_at_first_month_out_of_range() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(transactions_path()))
+            .and(query_param("date_from", "2024-03-01"))
+            .respond_with(booked_on("2024-03-05"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(transactions_path()))
+            .and(query_param("date_from", "2024-02-01"))
+            .respond_with(out_of_range())
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(transactions_path()))
+            .and(query_param("date_from", "2024-01-15"))
+            .respond_with(booked_on("2024-01-20"))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let client = BankDataClient::for_tests(&server.uri());
+        let period = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()
+            ..=NaiveDate::from_ymd_opt(2024, 3, 20).unwrap();
+
+        let transactions = backfill_transactions(&client, Uuid::nil(), period)
+            .await
+            .expect("backfill");
+
+        let dates = transactions
+            .transactions
+            .booked
+            .iter()
+            .map(date)
+            .collect::<Vec<_>>();
+        assert_eq!(dates, vec![NaiveDate::from_ymd_opt(2024, 3, 5)]);
+    }
+}
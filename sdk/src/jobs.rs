@@ -0,0 +1,900 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, Future, FutureExt};
+use tokio::{
+    sync::mpsc,
+    task::{AbortHandle, JoinError, JoinSet},
+    time::{sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{instrument, trace, warn};
+
+#[derive(Clone, Debug, Default)]
+pub struct PoolStats {
+    pub jobs_submitted: usize,
+    pub jobs_started: usize,
+    pub jobs_completed: usize,
+    /// Jobs that returned an error or panicked.
+    pub jobs_failed: usize,
+    /// When a job last started or finished.
+    pub last_progress: Option<std::time::Instant>,
+    /// Attempts at jobs that were cancelled for running too long; these
+    /// may have been retried.
+    pub jobs_timed_out: usize,
+    /// The names of the jobs that failed.
+    pub failed_jobs: Vec<String>,
+    /// The names of the jobs running now.
+    pub running_jobs: Vec<String>,
+}
+
+pub struct JobPool<E> {
+    rx: mpsc::UnboundedReceiver<Job<E>>,
+    stats: Arc<Mutex<PoolStats>>,
+    has_terminated: bool,
+    concurrency: usize,
+    deadline: Option<Deadline>,
+    cancellation: Option<Cancellation>,
+    job_timeout: Option<JobTimeout>,
+    error_policy: ErrorPolicy,
+}
+
+/// What [`JobPool::run`] does when a job fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Return the first error, dropping any jobs still running. Under
+    /// [`JobPool::run_tagged`], only the failed job's tag is stopped.
+    #[default]
+    FailFast,
+    /// Run the remaining jobs regardless, and return every error as a
+    /// [`JobsFailed`] once they're done.
+    KeepGoing,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    stop_at: Instant,
+    grace: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Cancellation {
+    token: CancellationToken,
+    grace: Duration,
+}
+
+/// Queued jobs start highest priority first, then in the order submitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+struct Job<E> {
+    name: String,
+    priority: Priority,
+    tag: Option<String>,
+    work: Work<E>,
+}
+
+type JobFuture<E> = BoxFuture<'static, Result<(), E>>;
+
+enum Work<E> {
+    Once(Option<JobFuture<E>>),
+    /// Can be started afresh if it times out.
+    Repeatable(Box<dyn Fn() -> JobFuture<E> + Send>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct JobTimeout {
+    timeout: Duration,
+    retries: usize,
+}
+
+struct Queued<E> {
+    job: Job<E>,
+    seq: u64,
+}
+
+pub struct JobHandle<E> {
+    tx: mpsc::UnboundedSender<Job<E>>,
+    stats: Arc<Mutex<PoolStats>>,
+    tag: Option<String>,
+}
+
+/// Read-only view of a pool's progress; unlike a [`JobHandle`], holding one
+/// does not keep the pool running.
+#[derive(Clone, Debug)]
+pub struct PoolMonitor {
+    stats: Arc<Mutex<PoolStats>>,
+}
+
+/// Returned from [`JobPool::run`] when the pool's deadline passed before all
+/// jobs had been run.
+#[derive(Debug)]
+pub struct RunDeadlineExceeded {
+    pub skipped: usize,
+    pub aborted: usize,
+}
+
+/// Returned from [`JobPool::run`] when the pool was cancelled before all
+/// jobs had been run.
+#[derive(Debug)]
+pub struct RunCancelled {
+    pub skipped: usize,
+    pub aborted: usize,
+}
+
+/// The error from a job that ran for longer than the pool's job timeout.
+#[derive(Debug)]
+pub struct JobTimedOut {
+    pub name: String,
+    pub timeout: Duration,
+}
+
+/// Returned from [`JobPool::run`] under [`ErrorPolicy::KeepGoing`] when any
+/// jobs failed.
+#[derive(Debug)]
+pub struct JobsFailed<E> {
+    pub errors: Vec<E>,
+}
+
+/// Returned when submitting a job to a pool that has already shut down.
+#[derive(Debug)]
+pub struct PoolClosed;
+
+impl<E> JobPool<E>
+where
+    E: From<JoinError>
+        + From<RunDeadlineExceeded>
+        + From<RunCancelled>
+        + From<JobTimedOut>
+        + From<JobsFailed<E>>
+        + fmt::Debug
+        + Send
+        + 'static,
+{
+    pub fn new(concurrency: usize) -> (Self, JobHandle<E>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stats = Arc::<Mutex<PoolStats>>::default();
+        let pool = JobPool {
+            rx,
+            concurrency,
+            stats: stats.clone(),
+            has_terminated: false,
+            deadline: None,
+            cancellation: None,
+            job_timeout: None,
+            error_policy: ErrorPolicy::default(),
+        };
+        let handle = JobHandle {
+            tx,
+            stats,
+            tag: None,
+        };
+        (pool, handle)
+    }
+
+    /// Stop starting new jobs after `max_duration`, and abort any still
+    /// running `grace` after that.
+    pub fn with_deadline(mut self, max_duration: Duration, grace: Duration) -> Self {
+        self.deadline = Some(Deadline {
+            stop_at: Instant::now() + max_duration,
+            grace,
+        });
+        self
+    }
+
+    /// Once `token` is cancelled, stop starting new jobs, and abort any
+    /// still running `grace` after that.
+    pub fn with_cancellation(mut self, token: CancellationToken, grace: Duration) -> Self {
+        self.cancellation = Some(Cancellation { token, grace });
+        self
+    }
+
+    /// Cancel any job attempt that runs for longer than `timeout`. Jobs
+    /// submitted with [`JobHandle::spawn_retryable`] are then retried up to
+    /// `retries` times; otherwise, the job fails with [`JobTimedOut`].
+    pub fn with_job_timeout(mut self, timeout: Duration, retries: usize) -> Self {
+        self.job_timeout = Some(JobTimeout { timeout, retries });
+        self
+    }
+
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    #[instrument(skip_all)]
+    pub async fn run(self) -> Result<(), E> {
+        let errors = self.drive(false).await?;
+        if !errors.is_empty() {
+            let errors = errors.into_iter().map(|(_, error)| error).collect();
+            return Err(JobsFailed { errors }.into());
+        }
+        Ok(())
+    }
+
+    /// Like [`JobPool::run`], but a failing job only fails its own tag; see
+    /// [`JobHandle::with_tag`]. Returns the error for each tag that failed,
+    /// as a pool of its own would have; the run as a whole only fails if an
+    /// untagged job does, or if it stops short, eg: at its deadline.
+    #[instrument(skip_all)]
+    pub async fn run_tagged(self) -> Result<BTreeMap<String, E>, E> {
+        let error_policy = self.error_policy;
+        let mut by_tag = BTreeMap::<_, Vec<E>>::new();
+        let mut untagged = Vec::new();
+        for (tag, error) in self.drive(true).await? {
+            match tag {
+                Some(tag) => by_tag.entry(tag).or_default().push(error),
+                None => untagged.push(error),
+            }
+        }
+        if !untagged.is_empty() {
+            return Err(JobsFailed { errors: untagged }.into());
+        }
+        Ok(by_tag
+            .into_iter()
+            .map(|(tag, mut errors)| {
+                let error = match error_policy {
+                    ErrorPolicy::FailFast => errors.remove(0),
+                    ErrorPolicy::KeepGoing => JobsFailed { errors }.into(),
+                };
+                (tag, error)
+            })
+            .collect())
+    }
+
+    /// Runs jobs until every handle has been dropped, returning the errors
+    /// collected along the way, with their job's tag. With `by_tag`, a
+    /// failing tagged job stops the rest of its tag, rather than the run.
+    async fn drive(mut self, by_tag: bool) -> Result<Vec<(Option<String>, E)>, E> {
+        let mut tasks = JoinSet::new();
+        let mut names = HashMap::new();
+        let mut tagged = HashMap::<_, (String, AbortHandle)>::new();
+        let mut failed_tags = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        let mut seq = 0;
+        // Far enough away to never fire when we have no deadline.
+        let never = Instant::now() + Duration::from_secs(86400 * 365);
+        let stop_at = self.deadline.map_or(never, |d| d.stop_at);
+        let mut abort_at = self.deadline.map_or(never, |d| d.stop_at + d.grace);
+        let cancellation = self.cancellation.clone();
+        let cancel_token = cancellation
+            .as_ref()
+            .map(|c| c.token.clone())
+            .unwrap_or_default();
+        let mut cancelled = false;
+        let mut stopping = false;
+        let mut skipped = 0;
+        let mut aborted = 0;
+        let mut errors = Vec::new();
+        loop {
+            while !stopping && tasks.len() < self.concurrency {
+                let Some(Queued { job, .. }) = queue.pop() else {
+                    break;
+                };
+                trace!(name=%job.name, priority=?job.priority, "Spawning job");
+                let task = tasks.spawn(self.attempt(job.name.clone(), job.work));
+                names.insert(task.id(), job.name);
+                if let Some(tag) = job.tag.filter(|_| by_tag) {
+                    tagged.insert(task.id(), (tag, task));
+                }
+                let mut stats = self.stats.lock().expect("lock");
+                stats.jobs_started += 1;
+                stats.last_progress = Some(std::time::Instant::now());
+                stats.running_jobs = names.values().cloned().collect();
+                drop(stats);
+            }
+
+            let stats = self.stats.lock().expect("lock").clone();
+            trace!(
+                incoming=?!self.has_terminated(),
+                queued=?queue.len(),
+                tasks=?tasks.len(),
+                ?stats.jobs_submitted,
+                ?stats.jobs_started,
+                ?stats.jobs_completed,
+                "Loop"
+            );
+            if self.has_terminated() && tasks.is_empty() && queue.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                _ = sleep_until(stop_at), if self.deadline.is_some() && !stopping => {
+                    warn!(in_flight=%tasks.len(), queued=%queue.len(), "Run deadline reached; no longer starting jobs");
+                    stopping = true;
+                    skipped += queue.len();
+                    queue.clear();
+                },
+                _ = cancel_token.cancelled(), if cancellation.is_some() && !cancelled => {
+                    warn!(in_flight=%tasks.len(), queued=%queue.len(), "Cancelled; no longer starting jobs");
+                    cancelled = true;
+                    let grace = cancellation.as_ref().map_or(Duration::ZERO, |c| c.grace);
+                    abort_at = if stopping { abort_at.min(Instant::now() + grace) } else { Instant::now() + grace };
+                    stopping = true;
+                    skipped += queue.len();
+                    queue.clear();
+                },
+                _ = sleep_until(abort_at), if stopping && aborted == 0 && !tasks.is_empty() => {
+                    warn!(in_flight=%tasks.len(), "Grace period expired; aborting jobs");
+                    aborted = tasks.len();
+                    tasks.abort_all();
+                },
+                item = self.next_job(), if !self.has_terminated() => {
+                    match item {
+                        Some(job) if stopping => {
+                            trace!(name=%job.name, "Skipping job after deadline");
+                            skipped += 1;
+                        }
+                        Some(job) if job.tag.as_ref().map_or(false, |tag| failed_tags.contains(tag)) => {
+                            trace!(name=%job.name, "Skipping job of failed tag");
+                        }
+                        Some(job) => {
+                            queue.push(Queued { job, seq });
+                            seq += 1;
+                        }
+                        None => trace!("Channel closed"),
+                    }
+                },
+                result = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                    if let Some(result) = result {
+                        let (id, result) = match result {
+                            Ok((id, result)) => (id, Ok(result)),
+                            Err(err) => (err.id(), Err(err)),
+                        };
+                        let name = names.remove(&id).unwrap_or_default();
+                        let tag = tagged.remove(&id).map(|(tag, _)| tag);
+                        let failed = match &result {
+                            Ok(Ok(())) => false,
+                            Ok(Err(_)) => true,
+                            Err(err) => !err.is_cancelled(),
+                        };
+                        let mut stats = self.stats.lock().expect("lock");
+                        stats.jobs_completed += 1;
+                        if failed {
+                            stats.jobs_failed += 1;
+                            stats.failed_jobs.push(name.clone());
+                        }
+                        stats.last_progress = Some(std::time::Instant::now());
+                        stats.running_jobs = names.values().cloned().collect();
+                        drop(stats);
+                        trace!(%name, "Task exited with: {:?}", result);
+                        let error = match result {
+                            Ok(Ok(())) => continue,
+                            Err(err) if err.is_cancelled() => continue,
+                            Err(err) => {
+                                warn!(job=%name, error=?err, "Job panicked");
+                                E::from(err)
+                            }
+                            Ok(Err(err)) => {
+                                warn!(job=%name, error=?err, "Job failed");
+                                err
+                            }
+                        };
+                        match tag {
+                            _ if self.error_policy == ErrorPolicy::KeepGoing => {
+                                errors.push((tag, error));
+                            }
+                            Some(tag) if failed_tags.contains(&tag) => {
+                                trace!(job=%name, %tag, "Tag already failed");
+                            }
+                            Some(tag) => {
+                                warn!(%tag, "Stopping the rest of the tag's jobs");
+                                for (other, abort) in tagged.values() {
+                                    if *other == tag {
+                                        abort.abort();
+                                    }
+                                }
+                                queue.retain(|queued| queued.job.tag.as_ref() != Some(&tag));
+                                failed_tags.insert(tag.clone());
+                                errors.push((Some(tag), error));
+                            }
+                            None => return Err(error),
+                        }
+                    }
+                }
+            }
+        }
+        trace!("Done");
+        if stopping {
+            if !errors.is_empty() {
+                warn!(failed=%errors.len(), "Jobs also failed before stopping");
+            }
+            if cancelled {
+                return Err(RunCancelled { skipped, aborted }.into());
+            }
+            return Err(RunDeadlineExceeded { skipped, aborted }.into());
+        }
+        Ok(errors)
+    }
+
+    /// Runs `work`, subject to the job timeout, if any.
+    fn attempt(&self, name: String, mut work: Work<E>) -> JobFuture<E> {
+        let Some(JobTimeout { timeout, retries }) = self.job_timeout else {
+            return work.start();
+        };
+        let stats = self.stats.clone();
+        async move {
+            let mut attempts = 0;
+            loop {
+                match tokio::time::timeout(timeout, work.start()).await {
+                    Ok(result) => return result,
+                    Err(_) => {
+                        stats.lock().expect("lock").jobs_timed_out += 1;
+                        attempts += 1;
+                        if attempts > retries || !work.is_repeatable() {
+                            return Err(JobTimedOut { name, timeout }.into());
+                        }
+                        warn!(job=%name, ?timeout, %attempts, "Job timed out; retrying");
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    async fn next_job(&mut self) -> Option<Job<E>> {
+        let job = self.rx.recv().await;
+        if job.is_none() {
+            self.has_terminated = true;
+        }
+        job
+    }
+
+    fn has_terminated(&self) -> bool {
+        self.has_terminated
+    }
+}
+
+impl<E: Send + 'static> JobHandle<E> {
+    /// Tags the jobs submitted through this handle, eg: with the provider
+    /// they're for, so that [`JobPool::run_tagged`] can fail them apart
+    /// from the others.
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Submits an anonymous job at [`Priority::Normal`].
+    pub fn spawn(
+        &self,
+        fut: impl Future<Output = Result<(), E>> + Send + 'static,
+    ) -> Result<(), PoolClosed> {
+        self.spawn_named("job", Priority::Normal, fut)
+    }
+
+    /// Submits a job; `name` identifies it in logs and in
+    /// [`PoolStats::failed_jobs`].
+    pub fn spawn_named(
+        &self,
+        name: impl Into<String>,
+        priority: Priority,
+        fut: impl Future<Output = Result<(), E>> + Send + 'static,
+    ) -> Result<(), PoolClosed> {
+        self.submit(Job {
+            name: name.into(),
+            priority,
+            tag: self.tag.clone(),
+            work: Work::Once(Some(fut.boxed())),
+        })
+    }
+
+    /// Like [`JobHandle::spawn_named`], but `make` is called again to retry
+    /// the job should it time out.
+    pub fn spawn_retryable<F>(
+        &self,
+        name: impl Into<String>,
+        priority: Priority,
+        make: impl Fn() -> F + Send + 'static,
+    ) -> Result<(), PoolClosed>
+    where
+        F: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        self.submit(Job {
+            name: name.into(),
+            priority,
+            tag: self.tag.clone(),
+            work: Work::Repeatable(Box::new(move || make().boxed())),
+        })
+    }
+
+    fn submit(&self, job: Job<E>) -> Result<(), PoolClosed> {
+        self.tx.send(job).map_err(|_| PoolClosed)?;
+        self.stats.lock().expect("lock").jobs_submitted += 1;
+
+        Ok(())
+    }
+
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        self.stats.lock().expect("lock").clone()
+    }
+}
+
+impl<E> Clone for JobHandle<E> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            stats: self.stats.clone(),
+            tag: self.tag.clone(),
+        }
+    }
+}
+
+impl<E> Work<E> {
+    fn start(&mut self) -> JobFuture<E> {
+        match self {
+            Work::Once(fut) => fut.take().expect("job only started once"),
+            Work::Repeatable(make) => make(),
+        }
+    }
+
+    fn is_repeatable(&self) -> bool {
+        matches!(self, Work::Repeatable(_))
+    }
+}
+
+impl<E> Queued<E> {
+    fn key(&self) -> (Priority, Reverse<u64>) {
+        (self.job.priority, Reverse(self.seq))
+    }
+}
+
+impl<E> PartialEq for Queued<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<E> Eq for Queued<E> {}
+
+impl<E> PartialOrd for Queued<E> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E> Ord for Queued<E> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl fmt::Display for PoolClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pool dropped?")
+    }
+}
+
+impl std::error::Error for PoolClosed {}
+
+impl fmt::Display for RunDeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Run deadline exceeded; {} jobs skipped, {} aborted",
+            self.skipped, self.aborted
+        )
+    }
+}
+
+impl std::error::Error for RunDeadlineExceeded {}
+
+impl fmt::Display for RunCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Run cancelled; {} jobs skipped, {} aborted",
+            self.skipped, self.aborted
+        )
+    }
+}
+
+impl std::error::Error for RunCancelled {}
+
+impl fmt::Display for JobTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Job {} timed out after {:?}", self.name, self.timeout)
+    }
+}
+
+impl std::error::Error for JobTimedOut {}
+
+impl<E: fmt::Display> fmt::Display for JobsFailed<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} jobs failed", self.errors.len())?;
+        for error in self.errors.iter() {
+            write!(f, "; {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for JobsFailed<E> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    enum TestError {
+        Failed(&'static str),
+        Panicked,
+        DeadlineExceeded(RunDeadlineExceeded),
+        Cancelled(RunCancelled),
+        TimedOut(JobTimedOut),
+        JobsFailed(Vec<TestError>),
+    }
+
+    impl From<JoinError> for TestError {
+        fn from(_: JoinError) -> Self {
+            TestError::Panicked
+        }
+    }
+
+    impl From<RunDeadlineExceeded> for TestError {
+        fn from(e: RunDeadlineExceeded) -> Self {
+            TestError::DeadlineExceeded(e)
+        }
+    }
+
+    impl From<RunCancelled> for TestError {
+        fn from(e: RunCancelled) -> Self {
+            TestError::Cancelled(e)
+        }
+    }
+
+    impl From<JobTimedOut> for TestError {
+        fn from(e: JobTimedOut) -> Self {
+            TestError::TimedOut(e)
+        }
+    }
+
+    impl From<JobsFailed<TestError>> for TestError {
+        fn from(e: JobsFailed<TestError>) -> Self {
+            TestError::JobsFailed(e.errors)
+        }
+    }
+
+    type Ran = Arc<Mutex<Vec<&'static str>>>;
+
+    /// A job that notes it ran, then fails if `fail` is set.
+    fn job(
+        ran: &Ran,
+        name: &'static str,
+        fail: bool,
+    ) -> impl Future<Output = Result<(), TestError>> + Send + 'static {
+        let ran = ran.clone();
+        async move {
+            ran.lock().expect("lock").push(name);
+            if fail {
+                return Err(TestError::Failed(name));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_job_stops_only_its_own_tag() {
+        let ran = Ran::default();
+        let (pool, handle) = JobPool::<TestError>::new(1);
+        let a = handle.clone().with_tag("a");
+        let b = handle.with_tag("b");
+        a.spawn_named("a1", Priority::Normal, job(&ran, "a1", true))
+            .expect("spawn");
+        a.spawn_named("a2", Priority::Normal, job(&ran, "a2", false))
+            .expect("spawn");
+        b.spawn_named("b1", Priority::Normal, job(&ran, "b1", false))
+            .expect("spawn");
+        drop(a);
+        drop(b);
+
+        let failed = pool.run_tagged().await.expect("run");
+
+        assert_eq!(failed.keys().collect::<Vec<_>>(), ["a"]);
+        assert!(
+            matches!(failed["a"], TestError::Failed("a1")),
+            "{:?}",
+            failed
+        );
+        assert_eq!(*ran.lock().expect("lock"), ["a1", "b1"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_going_collects_each_tags_errors() {
+        let ran = Ran::default();
+        let (pool, handle) = JobPool::<TestError>::new(1);
+        let pool = pool.with_error_policy(ErrorPolicy::KeepGoing);
+        let a = handle.clone().with_tag("a");
+        let b = handle.with_tag("b");
+        a.spawn_named("a1", Priority::Normal, job(&ran, "a1", true))
+            .expect("spawn");
+        a.spawn_named("a2", Priority::Normal, job(&ran, "a2", true))
+            .expect("spawn");
+        b.spawn_named("b1", Priority::Normal, job(&ran, "b1", false))
+            .expect("spawn");
+        drop(a);
+        drop(b);
+
+        let failed = pool.run_tagged().await.expect("run");
+
+        assert_eq!(failed.keys().collect::<Vec<_>>(), ["a"]);
+        let TestError::JobsFailed(errors) = &failed["a"] else {
+            panic!("{:?}", failed);
+        };
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_eq!(*ran.lock().expect("lock"), ["a1", "a2", "b1"]);
+    }
+
+    /// A job that never finishes by itself.
+    async fn stuck() -> Result<(), TestError> {
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn starts_high_priority_jobs_first() {
+        let ran = Ran::default();
+        let (pool, handle) = JobPool::<TestError>::new(1);
+        // Holds the only slot until the rest have been queued.
+        let first = job(&ran, "first", false);
+        handle
+            .spawn_named("first", Priority::Normal, async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                first.await
+            })
+            .expect("spawn");
+        handle
+            .spawn_named("low", Priority::Low, job(&ran, "low", false))
+            .expect("spawn");
+        handle
+            .spawn_named("normal", Priority::Normal, job(&ran, "normal", false))
+            .expect("spawn");
+        handle
+            .spawn_named("high", Priority::High, job(&ran, "high", false))
+            .expect("spawn");
+        drop(handle);
+
+        pool.run().await.expect("run");
+
+        assert_eq!(
+            *ran.lock().expect("lock"),
+            ["first", "high", "normal", "low"]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_going_past_failed_jobs() {
+        let ran = Ran::default();
+        let (pool, handle) = JobPool::<TestError>::new(1);
+        let pool = pool.with_error_policy(ErrorPolicy::KeepGoing);
+        handle
+            .spawn_named("a", Priority::Normal, job(&ran, "a", true))
+            .expect("spawn");
+        handle
+            .spawn_named("b", Priority::Normal, job(&ran, "b", false))
+            .expect("spawn");
+        handle
+            .spawn_named("c", Priority::Normal, job(&ran, "c", true))
+            .expect("spawn");
+        let monitor = handle.monitor();
+        drop(handle);
+
+        let err = pool.run().await.expect_err("jobs failed");
+
+        let TestError::JobsFailed(errors) = err else {
+            panic!("{:?}", err);
+        };
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [TestError::Failed("a"), TestError::Failed("c")]
+            ),
+            "{:?}",
+            errors
+        );
+        assert_eq!(*ran.lock().expect("lock"), ["a", "b", "c"]);
+        assert_eq!(monitor.stats().failed_jobs, ["a", "c"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_at_deadline_and_aborts_after_grace() {
+        let (pool, handle) = JobPool::<TestError>::new(1);
+        let pool = pool.with_deadline(Duration::from_secs(10), Duration::from_secs(5));
+        handle
+            .spawn_named("stuck", Priority::Normal, stuck())
+            .expect("spawn");
+        handle
+            .spawn_named("queued", Priority::Normal, stuck())
+            .expect("spawn");
+        drop(handle);
+        let started = Instant::now();
+
+        let err = pool.run().await.expect_err("deadline");
+
+        let TestError::DeadlineExceeded(exceeded) = err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!((exceeded.skipped, exceeded.aborted), (1, 1));
+        assert!(
+            started.elapsed() >= Duration::from_secs(15),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_once_cancelled() {
+        let token = CancellationToken::new();
+        let (pool, handle) = JobPool::<TestError>::new(1);
+        let pool = pool.with_cancellation(token.clone(), Duration::from_secs(5));
+        handle
+            .spawn_named("stuck", Priority::Normal, stuck())
+            .expect("spawn");
+        handle
+            .spawn_named("queued", Priority::Normal, stuck())
+            .expect("spawn");
+        drop(handle);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            token.cancel();
+        });
+        let started = Instant::now();
+
+        let err = pool.run().await.expect_err("cancelled");
+
+        let TestError::Cancelled(cancelled) = err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!((cancelled.skipped, cancelled.aborted), (1, 1));
+        assert!(
+            started.elapsed() >= Duration::from_secs(6),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_timed_out_jobs_then_gives_up() {
+        let attempts = Arc::new(Mutex::new(0));
+        let (pool, handle) = JobPool::<TestError>::new(1);
+        let pool = pool.with_job_timeout(Duration::from_secs(10), 2);
+        handle
+            .spawn_retryable("slow", Priority::Normal, {
+                let attempts = attempts.clone();
+                move || {
+                    *attempts.lock().expect("lock") += 1;
+                    stuck()
+                }
+            })
+            .expect("spawn");
+        let monitor = handle.monitor();
+        drop(handle);
+
+        let err = pool.run().await.expect_err("timed out");
+
+        let TestError::TimedOut(timed_out) = err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(timed_out.name, "slow");
+        assert_eq!(timed_out.timeout, Duration::from_secs(10));
+        assert_eq!(*attempts.lock().expect("lock"), 3);
+        assert_eq!(monitor.stats().jobs_timed_out, 3);
+    }
+}
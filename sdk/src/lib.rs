@@ -0,0 +1,160 @@
+//! Aggregator-agnostic plumbing shared by the individual scrapers.
+//!
+//! A backend implements [`Aggregator`] to schedule its fetches onto a
+//! [`JobPool`]; the month bucketing, output and state helpers take care of
+//! laying out what gets written.
+
+use std::{collections::BTreeMap, ops::RangeInclusive, sync::Arc};
+
+use chrono::NaiveDate;
+use futures::Future;
+use tokio::task::JoinError;
+use tracing::{debug, instrument, warn};
+
+mod jobs;
+mod lock;
+mod months;
+mod output;
+mod preflight;
+mod provider;
+mod schedule;
+mod seen;
+mod state;
+
+pub use jobs::{
+    ErrorPolicy, JobHandle, JobPool, JobTimedOut, JobsFailed, PoolClosed, PoolMonitor, PoolStats,
+    Priority, RunCancelled, RunDeadlineExceeded,
+};
+pub use lock::{TargetLock, LOCK_FILE};
+pub use months::{month_file_name, month_start, months};
+pub use output::{write_encoded_jsons_atomically, write_json_atomically, write_jsons_atomically};
+pub use preflight::check_target_dir;
+pub use provider::{by_month, ByMonth, Provider, ProviderAggregator};
+pub use schedule::{ScheduleConfig, ScheduleError, Scheduler, SyncSchedule};
+pub use seen::{SeenIndex, SeenIndexes};
+pub use state::{load_state, store_state};
+
+pub trait Aggregator: Send + Sync + 'static {
+    type Error: From<JoinError>
+        + From<PoolClosed>
+        + From<RunDeadlineExceeded>
+        + From<RunCancelled>
+        + From<JobTimedOut>
+        + From<JobsFailed<Self::Error>>
+        + std::fmt::Debug
+        + Send
+        + 'static;
+
+    /// The name of the configured provider, used for logging.
+    fn name(&self) -> &str;
+
+    /// Submit the jobs needed to sync `period` onto the pool.
+    fn schedule(
+        self: Arc<Self>,
+        period: RangeInclusive<NaiveDate>,
+        jobs: JobHandle<Self::Error>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Called once every scheduled job has completed successfully.
+    fn finish(self: Arc<Self>) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        futures::future::ready(Ok(()))
+    }
+
+    /// Called instead of [`Aggregator::finish`] when the run stops short,
+    /// eg: at its deadline, to keep whatever progress was made.
+    fn checkpoint(self: Arc<Self>) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        futures::future::ready(Ok(()))
+    }
+}
+
+/// Schedules every aggregator onto the given pool, and waits for all of the
+/// resulting jobs to finish.
+#[instrument(skip_all)]
+pub async fn sync_all<A: Aggregator>(
+    aggregators: Vec<Arc<A>>,
+    period: RangeInclusive<NaiveDate>,
+    (pool, handle): (JobPool<A::Error>, JobHandle<A::Error>),
+) -> Result<(), A::Error> {
+    let schedule = async {
+        for aggregator in aggregators.iter().cloned() {
+            debug!(name=%aggregator.name(), "Scheduling");
+            aggregator.schedule(period.clone(), handle.clone()).await?;
+        }
+        drop(handle);
+        Ok::<_, A::Error>(())
+    };
+
+    if let Err(error) = futures::try_join!(pool.run(), schedule) {
+        for aggregator in aggregators {
+            checkpoint(aggregator).await;
+        }
+        return Err(error);
+    }
+
+    for aggregator in aggregators {
+        debug!(name=%aggregator.name(), "Finishing");
+        aggregator.finish().await?;
+    }
+    Ok(())
+}
+
+/// Like [`sync_all`], but each aggregator's jobs are tagged with its name, so
+/// that one failing doesn't stop the others; see [`JobPool::run_tagged`].
+/// Returns how each aggregator's sync went, by name, unless the run as a
+/// whole failed, eg: at its deadline.
+#[instrument(skip_all)]
+pub async fn sync_each<A: Aggregator>(
+    aggregators: Vec<Arc<A>>,
+    period: RangeInclusive<NaiveDate>,
+    (pool, handle): (JobPool<A::Error>, JobHandle<A::Error>),
+) -> Result<BTreeMap<String, Result<(), A::Error>>, A::Error> {
+    let schedule = futures::future::join_all(aggregators.iter().map(|aggregator| {
+        let aggregator = aggregator.clone();
+        let jobs = handle.clone().with_tag(aggregator.name());
+        let period = period.clone();
+        async move {
+            debug!(name=%aggregator.name(), "Scheduling");
+            if let Err(error) = aggregator.schedule(period, jobs.clone()).await {
+                // Fail the aggregator's tag, as though one of its jobs had. The
+                // pool only closes once the run as a whole has failed.
+                let _ = jobs.spawn_named("schedule", Priority::High, async move { Err(error) });
+            }
+        }
+    }));
+    drop(handle);
+
+    let (run, _) = futures::join!(pool.run_tagged(), schedule);
+    let mut failed = match run {
+        Ok(failed) => failed,
+        Err(error) => {
+            for aggregator in aggregators {
+                checkpoint(aggregator).await;
+            }
+            return Err(error);
+        }
+    };
+
+    let mut results = BTreeMap::new();
+    for aggregator in aggregators {
+        let name = aggregator.name().to_owned();
+        let result = match failed.remove(&name) {
+            Some(error) => {
+                checkpoint(aggregator).await;
+                Err(error)
+            }
+            None => {
+                debug!(%name, "Finishing");
+                aggregator.finish().await
+            }
+        };
+        results.insert(name, result);
+    }
+    Ok(results)
+}
+
+async fn checkpoint<A: Aggregator>(aggregator: Arc<A>) {
+    debug!(name=%aggregator.name(), "Checkpointing");
+    if let Err(error) = aggregator.clone().checkpoint().await {
+        warn!(name=%aggregator.name(), ?error, "Failed to checkpoint");
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+use tokio::task::spawn_blocking;
+use tracing::{debug, info, Span};
+
+/// Kept in the locked directory, so callers may want to leave it out of
+/// backups and the like.
+pub const LOCK_FILE: &str = ".sync.lock";
+
+/// An advisory lock on a target directory, held for the duration of a sync so
+/// that concurrent runs don't interleave their writes. Released on drop.
+#[derive(Debug)]
+pub struct TargetLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl TargetLock {
+    /// Locks `dir`, creating it if needed. If another process holds the
+    /// lock, either waits for it to be released, or fails with
+    /// [`io::ErrorKind::WouldBlock`].
+    pub async fn acquire(dir: &Path, wait: bool) -> io::Result<Self> {
+        let dir = dir.to_owned();
+        let span = Span::current();
+        spawn_blocking(move || {
+            let _entered = span.enter();
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(LOCK_FILE);
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            match file.try_lock_exclusive() {
+                Ok(()) => {}
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    if !wait {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            format!(
+                                "Another sync is running against {:?}; lock held on {:?}",
+                                dir, path
+                            ),
+                        ));
+                    }
+                    info!(?path, "Waiting for another sync to release its lock");
+                    file.lock_exclusive()?;
+                }
+                Err(e) => return Err(e),
+            }
+            debug!(?path, "Locked target directory");
+            Ok(Self { file, path })
+        })
+        .await?
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        if let Err(error) = self.file.unlock() {
+            debug!(path=?self.path, %error, "Failed to unlock target directory");
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::{cmp::min, ops::RangeInclusive};
+
+use chrono::{Datelike, NaiveDate};
+
+/// Splits a period into calendar months, clamped to the end of the period.
+pub fn months(
+    period: RangeInclusive<NaiveDate>,
+) -> impl Iterator<Item = RangeInclusive<NaiveDate>> {
+    let month_start_date = month_start(*period.start());
+
+    let month_starts = month_start_date.iter_days().filter(|d| d.day() == 1);
+    let month_ends = month_starts.clone().skip(1).map({
+        let period = period.clone();
+        move |d| min(d.pred_opt().unwrap(), *period.end())
+    });
+    month_starts
+        .take_while(move |d| d <= period.end())
+        .zip(month_ends)
+        .map(|(a, b)| a..=b)
+}
+
+pub fn month_start(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).expect("day one")
+}
+
+/// File name for a month's worth of data, eg: `2024-06.jsons`.
+pub fn month_file_name(month: NaiveDate, extension: &str) -> String {
+    format!("{}.{}", month.format("%Y-%m"), extension)
+}
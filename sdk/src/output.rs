@@ -0,0 +1,171 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+use tempfile::NamedTempFile;
+use tokio::task::spawn_blocking;
+use tracing::{debug, Span};
+
+/// Writes one JSON document per line, replacing `path` atomically. Returns
+/// false, leaving the file untouched, if it already held the same content.
+/// Keys are written in sorted order; see [`canonical`].
+pub async fn write_jsons_atomically<T: Serialize + Send + 'static>(
+    path: &Path,
+    data: Vec<T>,
+) -> io::Result<bool> {
+    write_atomically(path, move |out| write_lines(out, data)).await
+}
+
+/// As [`write_jsons_atomically`], but passing the content through `encode`
+/// before it's written, eg: to compress it. `encode` should always produce
+/// the same output for the same input, so unchanged content can be spotted.
+pub async fn write_encoded_jsons_atomically<T, E>(
+    path: &Path,
+    data: Vec<T>,
+    encode: E,
+) -> io::Result<bool>
+where
+    T: Serialize + Send + 'static,
+    E: FnOnce(Vec<u8>) -> io::Result<Vec<u8>> + Send + 'static,
+{
+    write_atomically(path, move |out| {
+        let mut plain = Vec::new();
+        write_lines(&mut plain, data)?;
+        *out = encode(plain)?;
+        Ok(())
+    })
+    .await
+}
+
+fn write_lines<T: Serialize>(out: &mut Vec<u8>, data: Vec<T>) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for item in data {
+        serde_json::to_writer(&mut buf, &canonical(&item)?)?;
+        assert!(!buf.contains(&b'\n'));
+        out.write_all(&buf)?;
+        out.write_all(b"\n")?;
+        buf.clear();
+    }
+    Ok(())
+}
+
+/// Writes a single pretty-printed JSON document, replacing `path` atomically.
+/// Returns false, leaving the file untouched, if it already held the same
+/// content. Keys are written in sorted order, as with
+/// [`write_jsons_atomically`].
+pub async fn write_json_atomically<T: Serialize + Send + 'static>(
+    path: &Path,
+    data: T,
+) -> io::Result<bool> {
+    write_atomically(path, move |out| {
+        serde_json::to_writer_pretty(out, &canonical(&data)?)?;
+        Ok(())
+    })
+    .await
+}
+
+/// `item` with every object's keys sorted, rather than in the order fields
+/// are declared or were received from an API; so that the same data is
+/// always written as the same bytes. Sorted explicitly, as any crate in the
+/// build enabling `serde_json`'s `preserve_order` feature would otherwise
+/// change what we write.
+fn canonical<T: Serialize>(item: &T) -> serde_json::Result<Value> {
+    Ok(sort_keys(serde_json::to_value(item)?))
+}
+
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries = map
+                .into_iter()
+                .map(|(key, value)| (key, sort_keys(value)))
+                .collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(entries.into_iter().collect())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+async fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut Vec<u8>) -> io::Result<()> + Send + 'static,
+) -> io::Result<bool> {
+    let path = path.to_owned();
+    let span = Span::current();
+    spawn_blocking(move || -> io::Result<bool> {
+        let _guard = span.enter();
+        let mut content = Vec::new();
+        write(&mut content)?;
+        // Avoid churning mtimes (and anything downstream that watches them)
+        // when re-fetching data that hasn't changed.
+        match std::fs::read(&path) {
+            Ok(existing) if existing == content => {
+                debug!(?path, "Unchanged");
+                return Ok(false);
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        let mut tmpf = NamedTempFile::new_in(dir)?;
+        tmpf.write_all(&content)?;
+        tmpf.as_file_mut().flush()?;
+        tmpf.persist(&path)?;
+        debug!(?path, "Stored data");
+        Ok(true)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Outer {
+        zebra: u32,
+        mango: Vec<Inner>,
+        apple: Inner,
+    }
+
+    #[derive(Serialize)]
+    struct Inner {
+        second: &'static str,
+        first: &'static str,
+    }
+
+    #[tokio::test]
+    async fn writes_keys_in_sorted_order() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("out.jsons");
+        let inner = || Inner {
+            second: "b",
+            first: "a",
+        };
+        let item = Outer {
+            zebra: 1,
+            mango: vec![inner()],
+            apple: inner(),
+        };
+
+        write_jsons_atomically(&path, vec![item])
+            .await
+            .expect("write");
+
+        assert_eq!(
+            std::fs::read_to_string(&path).expect("read"),
+            concat!(
+                r#"{"apple":{"first":"a","second":"b"},"#,
+                r#""mango":[{"first":"a","second":"b"}],"zebra":1}"#,
+                "\n"
+            )
+        );
+    }
+}
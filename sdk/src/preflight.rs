@@ -0,0 +1,68 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use tokio::task::spawn_blocking;
+use tracing::{debug, Span};
+
+/// Always insist on at least this much headroom, even for an empty target.
+const MIN_FREE_BYTES: u64 = 16 * 1024 * 1024;
+/// Touched by each check, so the next can tell what's been written since.
+const PREFLIGHT_FILE: &str = ".preflight";
+
+/// Checks that `dir` can be written to, and that it has at least as much free
+/// space as the last run wrote there, so we fail before spending API quota
+/// rather than with `ENOSPC` midway.
+pub async fn check_target_dir(dir: &Path) -> io::Result<()> {
+    let dir = dir.to_owned();
+    let span = Span::current();
+    spawn_blocking(move || {
+        let _entered = span.enter();
+        fs::create_dir_all(&dir).map_err(|e| annotate(e, &dir, "Creating target directory"))?;
+        tempfile::NamedTempFile::new_in(&dir)
+            .map_err(|e| annotate(e, &dir, "Target directory is not writable"))?;
+
+        let marker = dir.join(PREFLIGHT_FILE);
+        let estimate = match fs::metadata(&marker) {
+            Ok(meta) => written_since(&dir, meta.modified()?)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let required = estimate.max(MIN_FREE_BYTES);
+        let available = fs2::available_space(&dir)?;
+        debug!(?dir, %estimate, %available, "Pre-flight space check");
+        if available < required {
+            return Err(io::Error::other(format!(
+                "Insufficient space in {:?}: {} bytes free, need ~{} bytes based on the last run",
+                dir, available, required
+            )));
+        }
+        fs::write(&marker, b"")?;
+        Ok(())
+    })
+    .await?
+}
+
+/// The total size of the files under `dir` modified after `since`.
+fn written_since(dir: &Path, since: SystemTime) -> io::Result<u64> {
+    let mut total = 0;
+    let mut pending = vec![PathBuf::from(dir)];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                pending.push(entry.path());
+            } else if meta.modified()? > since {
+                total += meta.len();
+            }
+        }
+    }
+    Ok(total)
+}
+
+fn annotate(err: io::Error, dir: &Path, what: &str) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {:?}: {}", what, dir, err))
+}
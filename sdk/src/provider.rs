@@ -0,0 +1,193 @@
+//! A backend-neutral description of what gets synced, so that a new
+//! backend only has to say how to fetch accounts, balances and
+//! transactions; [`ProviderAggregator`] takes care of scheduling the fetches
+//! and laying out what they return.
+//!
+//! Each account gets a directory under the provider's target directory,
+//! holding:
+//!
+//! * `account-details.json`: the account itself.
+//! * `details.json`: any further details the provider has for the account.
+//! * `balances.json`: the latest balances.
+//! * `YYYY-MM.json`: the transactions dated in that month, or
+//!   `undated.json` for any without a date.
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::NaiveDate;
+use futures::Future;
+use serde::Serialize;
+use tokio::task::JoinError;
+use tracing::{debug, instrument, Instrument, Span};
+
+use crate::{
+    month_file_name, month_start, write_json_atomically, Aggregator, JobHandle, JobTimedOut,
+    JobsFailed, PoolClosed, Priority, RunCancelled, RunDeadlineExceeded,
+};
+
+const ACCOUNT_FILE: &str = "account-details.json";
+const DETAILS_FILE: &str = "details.json";
+const BALANCES_FILE: &str = "balances.json";
+const UNDATED_FILE: &str = "undated.json";
+
+/// Transactions, keyed by the start of the month they fall in, if known.
+pub type ByMonth<T> = BTreeMap<Option<NaiveDate>, T>;
+
+pub trait Provider: Send + Sync + 'static {
+    /// Just enough to find an account by, eg: its id; see
+    /// [`Provider::account`].
+    type AccountId: fmt::Display + Send + 'static;
+    type Account: Serialize + Send + Sync + 'static;
+    /// Extra account metadata fetched separately; see [`Provider::details`].
+    type Details: Serialize + Send + 'static;
+    type Balances: Serialize + Send + 'static;
+    /// A month's worth of transactions, as written to its file.
+    type Transactions: Serialize + Send + 'static;
+    type Error: From<JoinError>
+        + From<PoolClosed>
+        + From<RunDeadlineExceeded>
+        + From<RunCancelled>
+        + From<JobTimedOut>
+        + From<JobsFailed<Self::Error>>
+        + From<io::Error>
+        + fmt::Debug
+        + Send
+        + 'static;
+
+    /// The name of the configured provider, used for logging.
+    fn name(&self) -> &str;
+
+    /// Where this provider's accounts are written.
+    fn target_dir(&self) -> &Path;
+
+    fn list_accounts(
+        &self,
+    ) -> impl Future<Output = Result<Vec<Self::AccountId>, Self::Error>> + Send;
+
+    /// Fetches the account itself, from within its own job, so that each
+    /// account's lookup runs alongside the others' fetches.
+    fn account(
+        &self,
+        id: Self::AccountId,
+    ) -> impl Future<Output = Result<Self::Account, Self::Error>> + Send;
+
+    /// Where the account's files go, relative to [`Provider::target_dir`].
+    fn account_dir(&self, account: &Self::Account) -> PathBuf;
+
+    /// Metadata (such as product names) that the provider does not return
+    /// with the account itself. Providers without any return `None`.
+    fn details(
+        &self,
+        _account: &Self::Account,
+    ) -> impl Future<Output = Result<Option<Self::Details>, Self::Error>> + Send {
+        async { Ok(None) }
+    }
+
+    fn balances(
+        &self,
+        account: &Self::Account,
+    ) -> impl Future<Output = Result<Self::Balances, Self::Error>> + Send;
+
+    /// The account's transactions in `period`, split by month; see
+    /// [`by_month`].
+    fn transactions(
+        &self,
+        account: &Self::Account,
+        period: RangeInclusive<NaiveDate>,
+    ) -> impl Future<Output = Result<ByMonth<Self::Transactions>, Self::Error>> + Send;
+
+    /// Called as each of the account's months is written, eg: to count
+    /// them; `None` for the undated transactions.
+    fn month_written(&self, _account: &Self::Account, _month: Option<NaiveDate>) {}
+}
+
+/// Syncs a [`Provider`] into the common layout.
+pub struct ProviderAggregator<P> {
+    provider: Arc<P>,
+}
+
+impl<P: Provider> ProviderAggregator<P> {
+    pub fn new(provider: P) -> Self {
+        Self {
+            provider: Arc::new(provider),
+        }
+    }
+}
+
+impl<P: Provider> Aggregator for ProviderAggregator<P> {
+    type Error = P::Error;
+
+    fn name(&self) -> &str {
+        self.provider.name()
+    }
+
+    async fn schedule(
+        self: Arc<Self>,
+        period: RangeInclusive<NaiveDate>,
+        jobs: JobHandle<P::Error>,
+    ) -> Result<(), P::Error> {
+        let accounts = self.provider.list_accounts().await?;
+        debug!(accounts=%accounts.len(), "Listed accounts");
+        for id in accounts {
+            let provider = self.provider.clone();
+            let period = period.clone();
+            jobs.spawn_named(
+                format!("{}: {}", provider.name(), id),
+                Priority::Normal,
+                async move { sync_account(&*provider, id, period).await }
+                    .instrument(Span::current()),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[instrument(skip_all, fields(%id))]
+async fn sync_account<P: Provider>(
+    provider: &P,
+    id: P::AccountId,
+    period: RangeInclusive<NaiveDate>,
+) -> Result<(), P::Error> {
+    let account = provider.account(id).await?;
+    let base = provider.target_dir().join(provider.account_dir(&account));
+
+    let details = provider.details(&account).await?;
+    let balances = provider.balances(&account).await?;
+    let transactions = provider.transactions(&account, period).await?;
+
+    write_json_atomically(&base.join(ACCOUNT_FILE), account).await?;
+    if let Some(details) = details {
+        write_json_atomically(&base.join(DETAILS_FILE), details).await?;
+    }
+    write_json_atomically(&base.join(BALANCES_FILE), balances).await?;
+    for (month, transactions) in transactions {
+        let file = month
+            .map(|month| month_file_name(month, "json"))
+            .unwrap_or_else(|| UNDATED_FILE.to_owned());
+        write_json_atomically(&base.join(file), transactions).await?;
+        provider.month_written(&account, month);
+    }
+
+    Ok(())
+}
+
+/// Groups `items` by the start of the month `date` puts them in.
+pub fn by_month<T>(
+    items: impl IntoIterator<Item = T>,
+    date: impl Fn(&T) -> Option<NaiveDate>,
+) -> ByMonth<Vec<T>> {
+    let mut months = ByMonth::<Vec<T>>::new();
+    for item in items {
+        months
+            .entry(date(&item).map(month_start))
+            .or_default()
+            .push(item);
+    }
+    months
+}
@@ -0,0 +1,211 @@
+//! Running syncs on a per-provider cadence within a single process.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    future::Future,
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::task::{JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// When to sync a provider under `schedule`; the same for every backend.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncSchedule {
+    /// A cron expression, with a leading seconds field, eg: `0 30 4 * * *`.
+    Cron(String),
+    /// Every so many seconds, starting when the scheduler does.
+    IntervalS(u64),
+}
+
+/// Settings for the `schedule` command.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ScheduleConfig {
+    /// Delay each scheduled run by a random amount up to this long.
+    pub jitter_s: Option<u64>,
+    /// How far back each scheduled run syncs; defaults to 31 days.
+    pub lookback_days: Option<u32>,
+    /// Concurrent jobs per run; defaults to 1.
+    pub concurrency: Option<usize>,
+}
+
+/// Runs each scheduled provider's sync when it falls due, skipping a run if
+/// the previous one for that provider is still going.
+pub struct Scheduler {
+    providers: BTreeMap<String, Cadence>,
+    jitter: Duration,
+}
+
+enum Cadence {
+    Cron(Box<cron::Schedule>),
+    Interval(chrono::Duration),
+}
+
+/// Returned when the schedules can't be run.
+#[derive(Debug)]
+pub enum ScheduleError {
+    /// No providers have a schedule.
+    Empty,
+    /// The named provider's schedule is malformed, or has no further runs.
+    Invalid { provider: String, reason: String },
+    /// A scheduled run panicked.
+    Panicked(JoinError),
+}
+
+impl Scheduler {
+    pub fn new<'a>(
+        schedules: impl IntoIterator<Item = (&'a str, &'a SyncSchedule)>,
+        jitter: Option<Duration>,
+    ) -> Result<Self, ScheduleError> {
+        let providers = schedules
+            .into_iter()
+            .map(|(name, schedule)| {
+                let cadence = Cadence::new(schedule).map_err(|reason| ScheduleError::Invalid {
+                    provider: name.to_owned(),
+                    reason,
+                })?;
+                Ok((name.to_owned(), cadence))
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        if providers.is_empty() {
+            return Err(ScheduleError::Empty);
+        }
+        Ok(Self {
+            providers,
+            jitter: jitter.unwrap_or_default(),
+        })
+    }
+
+    /// Calls `sync` with each provider's name as it falls due, until `cnx`
+    /// is cancelled; then waits for any runs in progress.
+    pub async fn run<F, Fut, E>(&self, sync: F, cnx: CancellationToken) -> Result<(), ScheduleError>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: fmt::Debug + Send + 'static,
+    {
+        let start = Utc::now();
+        let mut due = self
+            .providers
+            .keys()
+            .map(|name| Ok((self.next_run(name, start, true)?, name.clone())))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        let mut running = BTreeSet::new();
+        let mut runs = JoinSet::new();
+
+        loop {
+            let Some((at, _)) = due.first().cloned() else {
+                break;
+            };
+            let wait = (at - Utc::now()).to_std().unwrap_or_default();
+            info!(%at, "Waiting for next scheduled run");
+            tokio::select! {
+                _ = cnx.cancelled() => break,
+                Some(res) = runs.join_next(), if !runs.is_empty() => {
+                    let (name, res): (String, Result<(), E>) =
+                        res.map_err(ScheduleError::Panicked)?;
+                    match res {
+                        Ok(()) => info!(provider=%name, "Scheduled sync complete"),
+                        Err(error) => error!(provider=%name, ?error, "Scheduled sync failed"),
+                    }
+                    running.remove(&name);
+                    continue;
+                }
+                _ = tokio::time::sleep(wait) => {}
+            }
+
+            let now = Utc::now();
+            while let Some((at, name)) = due.first().cloned() {
+                if at > now {
+                    break;
+                }
+                due.remove(&(at, name.clone()));
+                due.insert((self.next_run(&name, now, false)?, name.clone()));
+
+                if running.contains(&name) {
+                    warn!(provider=%name, "Previous sync still running; skipping");
+                    continue;
+                }
+                info!(provider=%name, "Starting scheduled sync");
+                running.insert(name.clone());
+                let fut = sync(name.clone());
+                runs.spawn(async move { (name, fut.await) });
+            }
+        }
+
+        if !running.is_empty() {
+            info!(?running, "Waiting for syncs in progress");
+        }
+        while let Some(res) = runs.join_next().await {
+            let (name, res) = res.map_err(ScheduleError::Panicked)?;
+            if let Err(error) = res {
+                error!(provider=%name, ?error, "Scheduled sync failed");
+            }
+        }
+        Ok(())
+    }
+
+    fn next_run(
+        &self,
+        name: &str,
+        after: DateTime<Utc>,
+        first: bool,
+    ) -> Result<DateTime<Utc>, ScheduleError> {
+        let invalid = |reason: &str| ScheduleError::Invalid {
+            provider: name.to_owned(),
+            reason: reason.to_owned(),
+        };
+        let at = match &self.providers[name] {
+            Cadence::Cron(schedule) => schedule
+                .after(&after)
+                .next()
+                .ok_or_else(|| invalid("Cron schedule has no further runs"))?,
+            Cadence::Interval(_) if first => after,
+            Cadence::Interval(interval) => after + *interval,
+        };
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..self.jitter)
+        };
+        let jitter = chrono::Duration::from_std(jitter).map_err(|_| invalid("Jitter too long"))?;
+        Ok(at + jitter)
+    }
+}
+
+impl Cadence {
+    fn new(schedule: &SyncSchedule) -> Result<Self, String> {
+        match schedule {
+            SyncSchedule::Cron(expr) => {
+                let schedule = cron::Schedule::from_str(expr)
+                    .map_err(|e| format!("Parse cron expression {:?}: {}", expr, e))?;
+                Ok(Cadence::Cron(Box::new(schedule)))
+            }
+            SyncSchedule::IntervalS(0) => Err("Schedule interval must be non-zero".to_owned()),
+            SyncSchedule::IntervalS(secs) => chrono::Duration::from_std(Duration::from_secs(*secs))
+                .map(Cadence::Interval)
+                .map_err(|_| "Schedule interval too long".to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::Empty => write!(f, "No providers have a schedule"),
+            ScheduleError::Invalid { provider, reason } => {
+                write!(f, "Schedule for {}: {}", provider, reason)
+            }
+            ScheduleError::Panicked(err) => write!(f, "Scheduled sync panicked: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tempfile::NamedTempFile;
+use tokio::task::spawn_blocking;
+use tracing::{debug, Span};
+
+const MAGIC: &[u8; 4] = b"SEEN";
+/// 128KiB per index; good for ~100k ids at a 1% false-positive rate.
+const DEFAULT_BITS: usize = 1 << 20;
+const DEFAULT_HASHES: u32 = 7;
+
+/// A bloom filter over transaction ids. Answers "definitely new" or
+/// "probably seen" without having to load previously stored data.
+#[derive(Debug, Clone)]
+pub struct SeenIndex {
+    bits: Vec<u64>,
+    hashes: u32,
+    inserted: u64,
+}
+
+/// Lazily loaded [`SeenIndex`]es keyed by the file they're stored in, so that
+/// concurrent jobs for the same account share (and serialise on) one index.
+#[derive(Debug, Default)]
+pub struct SeenIndexes {
+    indexes: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<Option<SeenIndex>>>>>,
+}
+
+impl SeenIndex {
+    pub fn new() -> Self {
+        Self {
+            bits: vec![0; DEFAULT_BITS / 64],
+            hashes: DEFAULT_HASHES,
+            inserted: 0,
+        }
+    }
+
+    pub fn might_contain(&self, id: &str) -> bool {
+        self.positions(id)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Adds `id` to the index, returning true if it was definitely not present.
+    pub fn insert(&mut self, id: &str) -> bool {
+        let mut new = false;
+        for bit in self.positions(id).collect::<Vec<_>>() {
+            let mask = 1 << (bit % 64);
+            new |= self.bits[bit / 64] & mask == 0;
+            self.bits[bit / 64] |= mask;
+        }
+        if new {
+            self.inserted += 1;
+        }
+        new
+    }
+
+    /// Number of distinct ids inserted (approximately; collisions undercount).
+    pub fn len(&self) -> u64 {
+        self.inserted
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inserted == 0
+    }
+
+    fn positions(&self, id: &str) -> impl Iterator<Item = usize> {
+        // Kirsch-Mitzenmacher double hashing, over a hash that is stable
+        // across builds (unlike `DefaultHasher`), as we persist the result.
+        let h1 = fnv1a(0xcbf29ce484222325, id.as_bytes());
+        let h2 = fnv1a(0x84222325cbf29ce4, id.as_bytes()) | 1;
+        let nbits = (self.bits.len() * 64) as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    fn load(path: &Path) -> io::Result<Option<Self>> {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || io::Error::new(ErrorKind::InvalidData, format!("Bad index: {:?}", path));
+        const HEADER: usize = 16;
+        if buf.len() <= HEADER || (buf.len() - HEADER) % 8 != 0 || &buf[..4] != MAGIC {
+            return Err(invalid());
+        }
+        Ok(Some(Self {
+            hashes: u32::from_le_bytes(buf[4..8].try_into().expect("4 bytes")),
+            inserted: u64::from_le_bytes(buf[8..16].try_into().expect("8 bytes")),
+            bits: buf[HEADER..]
+                .chunks_exact(8)
+                .map(|c| u64::from_le_bytes(c.try_into().expect("8 bytes")))
+                .collect(),
+        }))
+    }
+
+    fn store(&self, path: &Path) -> io::Result<()> {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir)?;
+        let mut tmpf = NamedTempFile::new_in(dir)?;
+        tmpf.write_all(MAGIC)?;
+        tmpf.write_all(&self.hashes.to_le_bytes())?;
+        tmpf.write_all(&self.inserted.to_le_bytes())?;
+        for word in self.bits.iter() {
+            tmpf.write_all(&word.to_le_bytes())?;
+        }
+        tmpf.as_file_mut().flush()?;
+        tmpf.persist(path)?;
+        Ok(())
+    }
+}
+
+impl Default for SeenIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SeenIndexes {
+    /// Records `ids` in the index stored at `path`, returning how many of
+    /// them were new.
+    pub async fn record<I>(&self, path: &Path, ids: I) -> io::Result<usize>
+    where
+        I: IntoIterator<Item = String> + Send + 'static,
+    {
+        self.with_index(path, move |path, seen| {
+            let new = ids.into_iter().filter(|id| seen.insert(id)).count();
+            if new > 0 {
+                seen.store(path)?;
+            }
+            debug!(?path, %new, total=%seen.len(), "Updated seen index");
+            Ok(new)
+        })
+        .await
+    }
+
+    /// Those of `ids` that are definitely not in the index stored at `path`,
+    /// or `None` if nothing's been recorded there yet, as then we can't
+    /// tell.
+    pub async fn unseen(&self, path: &Path, ids: Vec<String>) -> io::Result<Option<Vec<String>>> {
+        self.with_index(path, move |_, seen| {
+            if seen.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(
+                ids.into_iter()
+                    .filter(|id| !seen.might_contain(id))
+                    .collect(),
+            ))
+        })
+        .await
+    }
+
+    /// Runs `f` on the index stored at `path`, loading it if need be.
+    async fn with_index<T, F>(&self, path: &Path, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Path, &mut SeenIndex) -> io::Result<T> + Send + 'static,
+    {
+        let slot = self
+            .indexes
+            .lock()
+            .expect("lock")
+            .entry(path.to_owned())
+            .or_default()
+            .clone();
+        let mut index = slot.lock_owned().await;
+        let path = path.to_owned();
+        let span = Span::current();
+        spawn_blocking(move || {
+            let _entered = span.enter();
+            if index.is_none() {
+                *index = Some(SeenIndex::load(&path)?.unwrap_or_default());
+            }
+            f(&path, index.as_mut().expect("loaded"))
+        })
+        .await?
+    }
+}
+
+fn fnv1a(seed: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(seed, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,27 @@
+use std::{fs::File, io, path::Path};
+
+use serde::de::DeserializeOwned;
+use tokio::task::spawn_blocking;
+use tracing::{debug, Span};
+
+pub use crate::output::write_json_atomically as store_state;
+
+/// Reads a JSON state file, returning `None` when it does not exist yet.
+pub async fn load_state<T: DeserializeOwned + Send + 'static>(
+    path: &Path,
+) -> io::Result<Option<T>> {
+    let path = path.to_owned();
+    let span = Span::current();
+    spawn_blocking(move || {
+        let _entered = span.enter();
+        let f = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let state = serde_json::from_reader(f)?;
+        debug!(?path, "Loaded state");
+        Ok(Some(state))
+    })
+    .await?
+}
@@ -0,0 +1,58 @@
+use std::io::{self, BufRead, Write};
+
+use axum::http::Uri;
+use secrecy::SecretString;
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+use crate::{Error, Result, TlClient};
+
+use super::{check_registered, start::auth_url, AuthServerOptions};
+
+/// TrueLayer's own page for displaying the authorization code, for when we
+/// have no redirect target of our own.
+const CONSOLE_REDIRECT_URI: &str = "https://console.truelayer.com/redirect-page";
+
+/// Authenticates without a local listener: the user visits the auth URL in
+/// any browser, and pastes the resulting code (or redirect URL) back here.
+pub async fn authenticate_manually(tl: &TlClient, options: &AuthServerOptions) -> Result<()> {
+    let redirect_uri = options
+        .redirect_uri
+        .as_deref()
+        .unwrap_or(CONSOLE_REDIRECT_URI)
+        .parse::<Uri>()
+        .map_err(|e| Error::Config(format!("Redirect URI: {}", e)))?;
+    check_registered(&redirect_uri, &options.registered_redirect_uris)?;
+
+    eprintln!("Please visit:\n\n{}\n", auth_url(tl, &redirect_uri, None)?);
+    let input = spawn_blocking(|| -> io::Result<String> {
+        eprint!("Paste the authorization code, or the URL you were redirected to: ");
+        io::stderr().flush()?;
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        Ok(line)
+    })
+    .await??;
+
+    let code = extract_code(input.trim())?;
+    tl.authenticate(code, &redirect_uri.to_string()).await?;
+    info!("Authenticated!");
+    Ok(())
+}
+
+fn extract_code(input: &str) -> Result<SecretString> {
+    if input.is_empty() {
+        return Err(Error::Auth("No authorization code given".to_owned()));
+    }
+    let Some((_, query)) = input.split_once('?') else {
+        return Ok(SecretString::new(input.to_owned()));
+    };
+    let query = query.split('#').next().unwrap_or_default();
+    let code = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .map_err(|e| Error::Auth(format!("Invalid redirect URL: {}", e)))?
+        .into_iter()
+        .find(|(k, _)| k == "code")
+        .map(|(_, v)| v)
+        .ok_or_else(|| Error::Auth("No code found in redirect URL".to_owned()))?;
+    Ok(SecretString::new(code))
+}
@@ -1,8 +1,10 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc};
 
-use anyhow::{Context, Result};
 use axum::{
-    http::uri::{Scheme, Uri},
+    http::{
+        uri::{Scheme, Uri},
+        StatusCode,
+    },
     response::{IntoResponse, Response},
     Router,
 };
@@ -10,62 +12,116 @@ use tokio::net::TcpListener;
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
-use crate::{ClientCreds, Environment, ProviderConfig, TlClient};
+use crate::{error::Context, Error, Result, TlClient, TlsConfig};
 
+mod manual;
 mod start;
+mod tls;
 
-struct WebError(anyhow::Error);
+pub use manual::authenticate_manually;
+pub use start::check_scopes;
+
+struct WebError(Error);
 
 type WebResult<T> = std::result::Result<T, WebError>;
 
+/// How to run the local server that receives the OAuth redirect.
+#[derive(Debug, Clone)]
+pub struct AuthServerOptions {
+    pub listen: SocketAddr,
+    /// Publicly visible redirect URI, if not the listen address.
+    pub redirect_uri: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// When non-empty, the redirect URI must be one of these.
+    pub registered_redirect_uris: Vec<String>,
+    /// The config file in use, for suggesting what to run next.
+    pub config_path: Option<PathBuf>,
+}
+
+/// Runs the auth flow for each of `clients`, keyed by provider name, from a
+/// single server.
 pub async fn authenticate(
-    client: &reqwest::Client,
-    environment: Environment,
-    provider: &ProviderConfig,
-    client_creds: &ClientCreds,
-    listen_port: u16,
+    clients: BTreeMap<String, Arc<TlClient>>,
+    options: &AuthServerOptions,
 ) -> Result<()> {
     let cnx = CancellationToken::new();
-    let tl = Arc::new(TlClient::new(
-        client.clone(),
-        environment,
-        &provider.user_token,
-        client_creds,
-    ));
+    let acceptor = options.tls.as_ref().map(tls::acceptor).transpose()?;
 
-    let ip_addr = IpAddr::from([127, 0, 0, 1]);
-    let listener = TcpListener::bind((ip_addr, listen_port))
+    let listener = TcpListener::bind(options.listen)
         .await
-        .with_context(|| format!("Bind to address: {}:{}", ip_addr, listen_port))?;
+        .with_context(|| format!("Bind to address: {}", options.listen))?;
 
     let listen_address = listener.local_addr().context("listen address")?;
     let base_url = Uri::builder()
-        .scheme(Scheme::HTTP)
+        .scheme(if acceptor.is_some() {
+            Scheme::HTTPS
+        } else {
+            Scheme::HTTP
+        })
         .authority(listen_address.to_string())
-        .path_and_query("")
-        .build()
-        .context("Build base URI")?;
-    let app = Router::new().merge(start::routes(cnx.clone(), tl.clone(), base_url));
+        .path_and_query("/")
+        .build()?;
+    let redirect_uri = match options.redirect_uri.as_deref() {
+        Some(uri) => uri
+            .parse::<Uri>()
+            .map_err(|e| Error::Config(format!("Redirect URI {:?}: {}", uri, e)))?,
+        None => start::default_redirect_uri(&base_url)?,
+    };
+    check_registered(&redirect_uri, &options.registered_redirect_uris)?;
+    let app = Router::new().merge(start::routes(
+        cnx.clone(),
+        clients,
+        redirect_uri,
+        options.config_path.clone(),
+    )?);
 
-    eprintln!("Please visit http://{}/", listen_address,);
+    eprintln!("Please visit {}", base_url);
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(cnx.clone().cancelled_owned())
-        .await
-        .context("Running server")?;
+    if let Some(acceptor) = acceptor {
+        tls::serve(listener, acceptor, app, cnx.clone()).await?;
+    } else {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(cnx.clone().cancelled_owned())
+            .await
+            .context("Running server")?;
+    }
     info!("Done!");
     Ok(())
 }
 
+/// TrueLayer rejects redirect URIs that aren't registered for the client, so
+/// catch mismatches before sending the user off.
+fn check_registered(redirect_uri: &Uri, registered: &[String]) -> Result<()> {
+    if registered.is_empty() {
+        return Ok(());
+    }
+    let redirect_uri = redirect_uri.to_string();
+    if !registered.iter().any(|r| *r == redirect_uri) {
+        return Err(Error::Config(format!(
+            "Redirect URI {} is not one of the registered redirect URIs: {:?}",
+            redirect_uri, registered
+        )));
+    }
+    Ok(())
+}
+
 impl IntoResponse for WebError {
     fn into_response(self) -> Response {
         error!(error=?self.0, "Error handling request");
-        "Error handling response".into_response()
+        let page = start::ErrorTemplate {
+            provider: None,
+            error: format!("{:#}", self.0),
+        };
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            start::AskamaTemplate(page),
+        )
+            .into_response()
     }
 }
 
-impl From<anyhow::Error> for WebError {
-    fn from(value: anyhow::Error) -> Self {
+impl From<Error> for WebError {
+    fn from(value: Error) -> Self {
         Self(value)
     }
 }
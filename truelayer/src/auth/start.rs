@@ -1,28 +1,41 @@
-use std::{borrow::Cow, collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-use anyhow::{anyhow, Context, Result};
 use askama::Template;
 use axum::{
     extract::{Query, State},
-    http::uri,
+    http::StatusCode,
     response::{Html, IntoResponse, Response},
     routing::get,
     Router,
 };
+use chrono::{DateTime, Duration, Utc};
 use hyper::Uri;
 use secrecy::SecretString;
 use serde::Deserialize;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
+use tracing::{debug, error, info};
 
-use crate::{auth::WebResult, Environment, TlClient};
+use crate::{auth::WebResult, consent, Environment, Error, Result, TlClient};
 
 use super::WebError;
 
+/// Serves the auth flow for one or more providers. With a single provider,
+/// the server shuts down once it's authenticated; otherwise, once the user
+/// says they're done.
 #[derive(Clone)]
 pub(crate) struct Start {
-    client: Arc<TlClient>,
-    base_url: Uri,
+    clients: Arc<BTreeMap<String, Arc<TlClient>>>,
+    authenticated: Arc<Mutex<BTreeSet<String>>>,
+    /// The `state` sent with each provider's auth link: a random nonce, so
+    /// that a redirect we didn't ask for is refused.
+    states: Arc<BTreeMap<String, String>>,
+    redirect_uri: Uri,
+    config_path: Option<PathBuf>,
     cnx: CancellationToken,
 }
 
@@ -32,112 +45,332 @@ struct StartTemplate {
     url: hyper::Uri,
 }
 
+#[derive(Template)]
+#[template(path = "auth_select.html")]
+struct SelectTemplate {
+    providers: Vec<ProviderLink>,
+}
+
+struct ProviderLink {
+    name: String,
+    url: hyper::Uri,
+    authenticated: bool,
+}
+
+#[derive(Template)]
+#[template(path = "auth_success.html")]
+struct SuccessTemplate {
+    provider: String,
+    scopes: Vec<String>,
+    token_expires_at: String,
+    consent_expires_at: String,
+    sync_command: String,
+    /// Other providers are waiting to be authenticated.
+    more: bool,
+}
+
+#[derive(Template)]
+#[template(path = "auth_error.html")]
+pub(super) struct ErrorTemplate {
+    pub(super) provider: Option<String>,
+    pub(super) error: String,
+}
+
+/// Either an authorization code, or why we didn't get one.
 #[derive(Debug, Deserialize)]
 struct RedirectToken {
-    code: SecretString,
-    // Also state, scope
+    code: Option<SecretString>,
+    /// The nonce we sent with the provider's auth link.
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+    // Also scope
 }
 
 #[derive(Debug)]
-struct AskamaTemplate<T>(T);
+pub(super) struct AskamaTemplate<T>(pub(super) T);
+
+/// How far back the suggested first sync goes.
+const SUGGESTED_SYNC_DAYS: i64 = 90;
+
+/// What we ask for unless configured otherwise.
+const DEFAULT_SCOPES: &[&str] = &[
+    "info",
+    "accounts",
+    "balance",
+    "cards",
+    "transactions",
+    "direct_debits",
+    "standing_orders",
+    "offline_access",
+];
+/// Needed for a refresh token, so always requested.
+const OFFLINE_ACCESS: &str = "offline_access";
 
-pub(crate) fn routes(cnx: CancellationToken, client: Arc<TlClient>, base_url: Uri) -> Router {
-    Router::new()
+const REDIRECT_PATH: &str = "/start-redirect";
+const DONE_PATH: &str = "/done";
+
+pub(crate) fn routes(
+    cnx: CancellationToken,
+    clients: BTreeMap<String, Arc<TlClient>>,
+    redirect_uri: Uri,
+    config_path: Option<PathBuf>,
+) -> Result<Router> {
+    if clients.is_empty() {
+        return Err(Error::Config("No providers to authenticate".to_owned()));
+    }
+    let mut router = Router::new()
         .route("/", get(Start::index))
-        .route("/start-redirect", get(Start::redirect))
-        .with_state(Start {
-            client,
-            base_url,
-            cnx,
-        })
+        .route(DONE_PATH, get(Start::done))
+        .route(REDIRECT_PATH, get(Start::redirect));
+    match redirect_uri.path() {
+        "/" | DONE_PATH => {
+            return Err(Error::Config(format!(
+                "Redirect URI must not use the path {:?}: {:?}",
+                redirect_uri.path(),
+                redirect_uri
+            )))
+        }
+        REDIRECT_PATH => {}
+        path => router = router.route(path, get(Start::redirect)),
+    }
+    let states = clients
+        .keys()
+        .map(|name| (name.clone(), format!("{:032x}", rand::random::<u128>())))
+        .collect();
+    Ok(router.with_state(Start {
+        clients: Arc::new(clients),
+        authenticated: Default::default(),
+        states: Arc::new(states),
+        redirect_uri,
+        config_path,
+        cnx,
+    }))
 }
 
-// #[debug_handler]
-impl Start {
-    async fn index(State(state): State<Start>) -> WebResult<impl IntoResponse> {
-        Ok(state.handle_index()?)
-    }
+/// The redirect URI for a server listening at `base_url`.
+pub(crate) fn default_redirect_uri(base_url: &Uri) -> Result<Uri> {
+    let scheme = base_url
+        .scheme()
+        .cloned()
+        .ok_or_else(|| Error::Config(format!("Base URL missing scheme: {}", base_url)))?;
+    let authority = base_url
+        .authority()
+        .cloned()
+        .ok_or_else(|| Error::Config(format!("Base URL missing authority: {}", base_url)))?;
+    let uri = Uri::builder()
+        .scheme(scheme)
+        .authority(authority)
+        .path_and_query(REDIRECT_PATH)
+        .build()?;
+    Ok(uri)
+}
 
-    fn handle_index(&self) -> Result<impl IntoResponse> {
-        let host = match self.client.env() {
-            Environment::Sandbox => "auth.truelayer-sandbox.com",
-            Environment::Live => "auth.truelayer.com",
-        };
+/// Fails on scopes TrueLayer's data API doesn't know about.
+pub fn check_scopes(scopes: &[String]) -> Result<()> {
+    let unknown = scopes
+        .iter()
+        .filter(|scope| !DEFAULT_SCOPES.contains(&scope.as_str()))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(Error::Config(format!(
+            "Unknown scopes {:?}; expected some of: {}",
+            unknown,
+            DEFAULT_SCOPES.join(" ")
+        )));
+    }
+    Ok(())
+}
 
-        let providers = match self.client.env() {
-            Environment::Sandbox => "uk-cs-mock uk-ob-all uk-oauth-all",
-            Environment::Live => "uk-ob-all uk-oauth-all",
-        };
-        let redirect_url = self.redirect_uri()?;
+/// The `scope` parameter for `client`'s auth link.
+fn scope(client: &TlClient) -> Result<String> {
+    let Some(scopes) = client.scopes() else {
+        return Ok(DEFAULT_SCOPES.join(" "));
+    };
+    check_scopes(scopes)?;
+    let mut scopes = scopes.iter().map(String::as_str).collect::<Vec<_>>();
+    if !scopes.contains(&OFFLINE_ACCESS) {
+        scopes.push(OFFLINE_ACCESS);
+    }
+    Ok(scopes.join(" "))
+}
 
-        info!(%redirect_url);
+/// Where to send the user to grant us access; `state` is passed back to the
+/// redirect URI.
+pub(crate) fn auth_url(client: &TlClient, redirect_url: &Uri, state: Option<&str>) -> Result<Uri> {
+    let providers = match (client.provider_filter(), client.env()) {
+        (Some(filter), _) => filter,
+        (None, Environment::Sandbox | Environment::Custom { .. }) => {
+            "uk-cs-mock uk-ob-all uk-oauth-all"
+        }
+        (None, Environment::Live) => "uk-ob-all uk-oauth-all",
+    };
 
-        let query = HashMap::<&str, Cow<'_, str>>::from([
+    let mut query = HashMap::<&str, Cow<'_, str>>::from([
         ("response_type", "code".into()),
-        ("client_id", self.client.client_id().into()),
-        ("redirect_uri", redirect_url.to_string().into() ),
-        (
-            "scope",
-            "info accounts balance cards transactions direct_debits standing_orders offline_access".into(),
-        ),
-        ("providers", providers.into(),),
+        ("client_id", client.client_id().into()),
+        ("redirect_uri", redirect_url.to_string().into()),
+        ("scope", scope(client)?.into()),
+        ("providers", providers.into()),
     ]);
-        let qs = serde_urlencoded::to_string(query).context("encode query")?;
-        let u = uri::Builder::new()
-            .scheme("https")
-            .authority(host)
-            .path_and_query(format!("/?{}", qs))
-            .build()
-            .map_err(anyhow::Error::from)?;
-        let template = StartTemplate { url: u };
-        Ok(AskamaTemplate(template))
+    if let Some(state) = state {
+        query.insert("state", state.into());
     }
+    let qs = serde_urlencoded::to_string(query)
+        .map_err(|e| Error::Config(format!("Auth link query: {}", e)))?;
+    let u = client
+        .env()
+        .auth_url_builder()
+        .path_and_query(format!("/?{}", qs))
+        .build()?;
+    Ok(u)
+}
 
-    fn redirect_uri(&self) -> Result<Uri, anyhow::Error> {
-        let uri = Uri::builder()
-            .scheme(
-                self.base_url
-                    .scheme()
-                    .cloned()
-                    .ok_or(anyhow!("Base URL missing scheme: {}", self.base_url))?,
-            )
-            .authority(
-                self.base_url
-                    .authority()
-                    .cloned()
-                    .ok_or(anyhow!("Base URL missing authority: {}", self.base_url))?,
-            )
-            .path_and_query("/start-redirect")
-            .build()
-            .context("Build redirect URI")?;
-        Ok(uri)
+// #[debug_handler]
+impl Start {
+    async fn index(State(state): State<Start>) -> WebResult<impl IntoResponse> {
+        Ok(state.handle_index()?)
+    }
+
+    fn handle_index(&self) -> Result<Response> {
+        info!(redirect_url=%self.redirect_uri);
+        if let Some((name, client)) = self.single() {
+            let template = StartTemplate {
+                url: auth_url(client, &self.redirect_uri, Some(&self.states[name]))?,
+            };
+            return Ok(AskamaTemplate(template).into_response());
+        }
+        let authenticated = self.authenticated.lock().expect("lock").clone();
+        let providers = self
+            .clients
+            .iter()
+            .map(|(name, client)| -> Result<_> {
+                Ok(ProviderLink {
+                    name: name.clone(),
+                    url: auth_url(client, &self.redirect_uri, Some(&self.states[name]))?,
+                    authenticated: authenticated.contains(name),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(AskamaTemplate(SelectTemplate { providers }).into_response())
     }
 
     async fn redirect(
         State(state): State<Start>,
-        Query(RedirectToken { code }): Query<RedirectToken>,
+        Query(token): Query<RedirectToken>,
     ) -> WebResult<impl IntoResponse> {
-        Ok(state.handle_redirect(code).await?)
+        Ok(state.handle_redirect(token).await?)
     }
 
-    async fn handle_redirect(&self, code: SecretString) -> Result<impl IntoResponse> {
-        let redirect_uri = self.redirect_uri()?;
-        debug!("Got code; authenticating…");
-        self.client
-            .authenticate(code, &redirect_uri.to_string())
+    async fn handle_redirect(&self, token: RedirectToken) -> Result<Response> {
+        let provider = token.state.as_deref().and_then(|state| {
+            self.states
+                .iter()
+                .find(|(_, nonce)| nonce.as_str() == state)
+                .map(|(name, _)| name)
+        });
+        let Some((name, client)) = provider.and_then(|name| self.clients.get_key_value(name))
+        else {
+            error!(state=?token.state, "Redirect state doesn't match any auth link");
+            return Ok(error_page(
+                None,
+                "Redirected without a matching state; start again from the auth page".to_owned(),
+            ));
+        };
+        let more = self.single().is_none();
+        let code = match (token.code, token.error) {
+            (Some(code), None) => code,
+            (_, error) => {
+                let mut error = error.unwrap_or_else(|| "no authorization code".to_owned());
+                if let Some(description) = token.error_description {
+                    error = format!("{} ({})", error, description);
+                }
+                error!(provider=%name, %error, "Authorization refused");
+                return Ok(error_page(Some(name), error));
+            }
+        };
+        debug!(provider=%name, "Got code; authenticating…");
+        if let Err(err) = client
+            .authenticate(code, &self.redirect_uri.to_string())
             .await
-            .context("Authenticate to Truelayer")?;
-        info!("Authenticated! Shutting down server");
-        self.cnx.cancel();
-        Ok("Done!")
+        {
+            error!(provider=%name, error=?err, "Authentication failed");
+            let error = err
+                .api_error()
+                .map_or_else(|| err.to_string(), |api_error| api_error.to_string());
+            return Ok(error_page(Some(name), error));
+        }
+
+        let page = SuccessTemplate {
+            provider: name.clone(),
+            scopes: client.granted_scopes().await?,
+            token_expires_at: format_time(Some(client.token_expires_at().await?)),
+            consent_expires_at: format_time(consent::expires_at(client.authed_at().await?, None)),
+            sync_command: self.sync_command(name),
+            more,
+        };
+        if more {
+            info!(provider=%name, "Authenticated!");
+            self.authenticated
+                .lock()
+                .expect("lock")
+                .insert(name.clone());
+        } else {
+            info!(provider=%name, "Authenticated! Shutting down server");
+            self.cnx.cancel();
+        }
+        Ok(AskamaTemplate(page).into_response())
+    }
+
+    /// What to run to fetch the last few months from `provider`.
+    fn sync_command(&self, provider: &str) -> String {
+        let to = Utc::now().date_naive();
+        let from = to - Duration::days(SUGGESTED_SYNC_DAYS);
+        let mut command = env!("CARGO_PKG_NAME").to_owned();
+        if let Some(path) = self.config_path.as_ref() {
+            command.push_str(&format!(" -c {}", path.display()));
+        }
+        command.push_str(&format!(" sync -p {} {} {}", provider, from, to));
+        command
+    }
+
+    async fn done(State(state): State<Start>) -> impl IntoResponse {
+        let authenticated = state.authenticated.lock().expect("lock").clone();
+        info!(?authenticated, "Done; shutting down server");
+        state.cnx.cancel();
+        "Done!"
     }
+
+    /// The provider being authenticated, when there's only the one.
+    fn single(&self) -> Option<(&String, &Arc<TlClient>)> {
+        if self.clients.len() == 1 {
+            self.clients.iter().next()
+        } else {
+            None
+        }
+    }
+}
+
+fn error_page(provider: Option<&String>, error: String) -> Response {
+    let page = ErrorTemplate {
+        provider: provider.cloned(),
+        error,
+    };
+    (StatusCode::BAD_REQUEST, AskamaTemplate(page)).into_response()
+}
+
+fn format_time(at: Option<DateTime<Utc>>) -> String {
+    at.map_or_else(
+        || "unknown".to_owned(),
+        |at| at.format("%Y-%m-%d %H:%M UTC").to_string(),
+    )
 }
 
 impl<T: Template> IntoResponse for AskamaTemplate<T> {
     fn into_response(self) -> Response {
         match self.0.render() {
             Ok(html) => Html(html).into_response(),
-            Err(err) => WebError::from(anyhow::Error::from(err)).into_response(),
+            Err(err) => WebError::from(Error::from(err)).into_response(),
         }
     }
 }
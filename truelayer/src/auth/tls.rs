@@ -0,0 +1,103 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc, time::Duration};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::{net::TcpListener, task::JoinSet, time::timeout};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{error::Context, Error, Result, TlsConfig};
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(super) fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let (certs, key) = match (config.cert.as_deref(), config.key.as_deref()) {
+        (Some(cert), Some(key)) => (read_certs(cert)?, read_key(key)?),
+        (None, None) => self_signed()?,
+        _ => {
+            return Err(Error::Config(
+                "TLS needs both a certificate and key, or neither".to_owned(),
+            ))
+        }
+    };
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::Config(format!("TLS certificate and key: {}", e)))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut rdr =
+        BufReader::new(File::open(path).with_context(|| format!("Open certificate: {:?}", path))?);
+    let certs = rustls_pemfile::certs(&mut rdr)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Read certificate: {:?}", path))?;
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut rdr =
+        BufReader::new(File::open(path).with_context(|| format!("Open key: {:?}", path))?);
+    rustls_pemfile::private_key(&mut rdr)
+        .with_context(|| format!("Read key: {:?}", path))?
+        .ok_or_else(|| Error::Config(format!("No private key found in {:?}", path)))
+}
+
+fn self_signed() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let names = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| Error::Config(format!("Generating certificate: {}", e)))?;
+    info!("Using a self-signed certificate; your browser will ask you to accept it");
+    let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+    Ok((vec![cert.der().clone()], key.into()))
+}
+
+/// Like `axum::serve`, but over TLS.
+pub(super) async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    app: Router,
+    cnx: CancellationToken,
+) -> Result<()> {
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = cnx.cancelled() => break,
+            res = listener.accept() => res.context("Accept connection")?,
+        };
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone());
+        connections.spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    warn!(%error, %peer, "TLS handshake failed");
+                    return;
+                }
+            };
+            if let Err(error) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(%error, %peer, "Connection failed");
+            }
+        });
+    }
+    // Give the final response a chance to make it out, without waiting on
+    // idle keep-alive connections.
+    let drain = async { while connections.join_next().await.is_some() {} };
+    let _ = timeout(DRAIN_TIMEOUT, drain).await;
+    Ok(())
+}
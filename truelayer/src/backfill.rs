@@ -0,0 +1,177 @@
+//! First-time imports of years of history: a month at a time, newest first,
+//! pausing between months, until the provider has nothing older to give.
+
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc, time::Duration};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use scraper_sdk::{month_start, months};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument, warn};
+
+use crate::{error::Context, Error, ProviderConfig, Result, SyncEngine, SyncOptions, TlClient};
+
+const BACKFILL_FILE: &str = ".backfill.json";
+
+/// What earlier backfills of a target directory got done.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// The start of each month synced.
+    #[serde(default)]
+    done: BTreeSet<NaiveDate>,
+    /// The earliest month the provider refused, if any.
+    refused: Option<NaiveDate>,
+    /// When it refused; authenticating since may have changed its mind.
+    #[serde(default)]
+    refused_at: Option<DateTime<Utc>>,
+}
+
+/// Syncs a provider's history back to the start of a year, one month per
+/// run, so that an interrupted import can carry on where it left off.
+pub struct Backfill {
+    name: String,
+    client: Arc<TlClient>,
+    config: ProviderConfig,
+    from_year: i32,
+    delay: Duration,
+    concurrency: usize,
+    cancel: CancellationToken,
+}
+
+/// What a [`Backfill`] did.
+#[derive(Debug, Default, Serialize)]
+pub struct BackfillReport {
+    pub months_synced: usize,
+    /// Months synced by an earlier backfill.
+    pub months_skipped: usize,
+    /// The earliest month synced, by this run or earlier ones.
+    pub earliest: Option<NaiveDate>,
+    /// The month the provider refused to go back to, if it did.
+    pub refused: Option<NaiveDate>,
+    /// Whether we were interrupted before getting to the start.
+    pub cancelled: bool,
+}
+
+impl Backfill {
+    pub fn new(name: &str, client: Arc<TlClient>, config: &ProviderConfig, from_year: i32) -> Self {
+        Self {
+            name: name.to_owned(),
+            client,
+            config: config.clone(),
+            from_year,
+            delay: Duration::ZERO,
+            concurrency: 1,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Wait this long between months, to go easy on the provider.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// How many fetches to run at once within each month; defaults to one.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Stop once `cancel` is cancelled, after the month in progress.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    #[instrument(skip_all, fields(provider=%self.name, from_year=%self.from_year))]
+    pub async fn run(self) -> Result<BackfillReport> {
+        let from = NaiveDate::from_ymd_opt(self.from_year, 1, 1)
+            .ok_or_else(|| Error::Config(format!("Invalid year: {}", self.from_year)))?;
+        let today = Utc::now().date_naive();
+        let path = self.checkpoint_path();
+        let mut checkpoint: Checkpoint = scraper_sdk::load_state(&path)
+            .await
+            .with_context(|| format!("Loading backfill checkpoint: {:?}", path))?
+            .unwrap_or_default();
+        if let Some(refused) = checkpoint.refused {
+            let authed_at = self.client.authed_at().await?;
+            let reauthed = match (authed_at, checkpoint.refused_at) {
+                (Some(authed_at), Some(refused_at)) => authed_at > refused_at,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if reauthed {
+                info!(%refused, ?authed_at, "Authenticated since the provider refused; trying again");
+                checkpoint.refused = None;
+                checkpoint.refused_at = None;
+            }
+        }
+
+        let mut report = BackfillReport::default();
+        let mut newest_first = months(from..=today).collect::<Vec<_>>();
+        newest_first.reverse();
+        let mut synced_any = false;
+        for month in newest_first {
+            let start = *month.start();
+            if checkpoint.refused.map_or(false, |refused| start <= refused) {
+                info!(month=%start, "Provider refused this far back before; stopping");
+                report.refused = checkpoint.refused;
+                break;
+            }
+            if checkpoint.done.contains(&start) && start != month_start(today) {
+                report.months_skipped += 1;
+                continue;
+            }
+            if self.cancel.is_cancelled() {
+                report.cancelled = true;
+                break;
+            }
+            if synced_any && !self.delay.is_zero() {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.delay) => {}
+                    _ = self.cancel.cancelled() => {
+                        report.cancelled = true;
+                        break;
+                    }
+                }
+            }
+            synced_any = true;
+
+            info!(month=%start, "Backfilling");
+            let engine = SyncEngine::builder(&self.name, self.client.clone(), &self.config)
+                .period(month)
+                .options(SyncOptions::default())
+                .concurrency(self.concurrency)
+                .build()?;
+            match engine.run().await.into_result() {
+                Ok(_) => {}
+                Err(e) if e.is_history_unavailable() => {
+                    warn!(month=%start, error=%e, "Provider refused older data; stopping");
+                    checkpoint.refused = Some(start);
+                    checkpoint.refused_at = Some(Utc::now());
+                    report.refused = Some(start);
+                    self.store_checkpoint(&checkpoint).await?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+            checkpoint.done.insert(start);
+            self.store_checkpoint(&checkpoint).await?;
+            report.months_synced += 1;
+        }
+        report.earliest = checkpoint.done.iter().next().copied();
+        Ok(report)
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.config.target_dir.join(BACKFILL_FILE)
+    }
+
+    async fn store_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let path = self.checkpoint_path();
+        scraper_sdk::store_state(&path, checkpoint.clone())
+            .await
+            .with_context(|| format!("Storing backfill checkpoint: {:?}", path))?;
+        Ok(())
+    }
+}
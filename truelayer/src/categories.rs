@@ -0,0 +1,139 @@
+//! Bucketing transactions by rules of our own, as providers disagree on
+//! what goes in `transaction_category` and `transaction_classification`.
+
+use std::path::Path;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::TransactionsResult, error::Context, Error, Result};
+
+/// Rules read from a TOML file, eg:
+///
+/// ```toml
+/// [[rule]]
+/// description = "(?i)^tesco"
+/// category = "Groceries"
+/// tags = ["food"]
+///
+/// [[rule]]
+/// merchant = "^Trainline$"
+/// category = "Travel"
+/// ```
+///
+/// A rule matches when each of its patterns does, and the first matching
+/// rule wins.
+#[derive(Debug, Clone)]
+pub struct CategoryRules {
+    rules: Vec<Rule>,
+}
+
+/// What a rule assigns to the transactions it matches.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Category {
+    pub category: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleConfig {
+    /// Matched against the transaction's description.
+    description: Option<String>,
+    /// Matched against the merchant name; never matches transactions
+    /// without one.
+    merchant: Option<String>,
+    #[serde(flatten)]
+    category: Category,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    description: Option<Regex>,
+    merchant: Option<Regex>,
+    category: Category,
+}
+
+impl CategoryRules {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Reading category rules: {:?}", path))?;
+        Self::parse(&content)
+            .map_err(|e| Error::Config(format!("Category rules {:?}: {}", path, e)))
+    }
+
+    fn parse(content: &str) -> std::result::Result<Self, String> {
+        let file: RulesFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                if rule.description.is_none() && rule.merchant.is_none() {
+                    return Err(format!(
+                        "Rule {} for {:?} has no patterns",
+                        i + 1,
+                        rule.category.category
+                    ));
+                }
+                let compile = |pattern: Option<String>| {
+                    pattern
+                        .map(|p| {
+                            Regex::new(&p)
+                                .map_err(|e| format!("Rule {} pattern {:?}: {}", i + 1, p, e))
+                        })
+                        .transpose()
+                };
+                Ok(Rule {
+                    description: compile(rule.description)?,
+                    merchant: compile(rule.merchant)?,
+                    category: rule.category,
+                })
+            })
+            .collect::<std::result::Result<_, String>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The category from the first rule that matches `tx`, if any.
+    pub fn categorise(&self, tx: &TransactionsResult) -> Option<&Category> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(tx))
+            .map(|rule| &rule.category)
+    }
+
+    /// Adds `category` and `tags` fields to `tx`, should a rule match.
+    pub(crate) fn annotate(&self, tx: &mut TransactionsResult) {
+        let Some(category) = self.categorise(tx).cloned() else {
+            return;
+        };
+        if let Some(fields) = tx.other.as_object_mut() {
+            fields.insert("category".to_owned(), category.category.into());
+            if !category.tags.is_empty() {
+                fields.insert("tags".to_owned(), category.tags.into());
+            }
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, tx: &TransactionsResult) -> bool {
+        let description = self
+            .description
+            .as_ref()
+            .map_or(true, |re| re.is_match(&tx.description));
+        let merchant = self.merchant.as_ref().map_or(true, |re| {
+            tx.merchant_name
+                .as_deref()
+                .map_or(false, |name| re.is_match(name))
+        });
+        description && merchant
+    }
+}
@@ -0,0 +1,1283 @@
+//! The `tl-scraper` command line; also embedded in the combined `scraper`
+//! binary.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::IsTerminal,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::{NaiveDate, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::Future;
+use scraper_sdk::{ErrorPolicy, PoolStats, Scheduler};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{
+    consent::{self, ConsentStatus},
+    metrics,
+    progress::Progress,
+    redact,
+    telemetry::LogFormat,
+    AuthServerOptions, Cassette, ClientCreds, Health, JobHandle, JobPool, PingConfig,
+    ProviderConfig, ProviderStatus, ProviderSync, ScraperConfig, SyncOptions, SyncSchedule,
+    SyncSummary, TlClient, TokenRefresher, EXIT_CANCELLED, EXIT_DEADLINE_EXCEEDED,
+};
+
+const DEFAULT_ASYNC_MAX_WAIT_S: u64 = 300;
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    #[clap(short = 'c', long = "config")]
+    config: PathBuf,
+    /// Defaults to `log_format` from the config.
+    #[clap(long = "log-format", value_enum, global = true)]
+    log_format: Option<LogFormat>,
+    /// Save each API response into this directory.
+    #[clap(long = "record", global = true, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Answer API requests from responses saved with `--record`, rather
+    /// than calling the API.
+    #[clap(long = "replay", global = true)]
+    replay: Option<PathBuf>,
+    /// How we were run, for suggesting commands to run next.
+    #[clap(skip)]
+    invoked_as: Option<String>,
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    Auth(Auth),
+    /// Disconnect a provider and delete its stored token.
+    Revoke {
+        #[clap(short = 'p', long = "provider")]
+        provider: String,
+    },
+    Refresh(Refresh),
+    /// Copy the client credentials and the tokens of providers configured
+    /// with `token_store = "keyring"` from their files into the keyring.
+    #[cfg(feature = "keyring")]
+    KeyringImport,
+    Sync(Sync),
+    Backfill(Backfill),
+    /// Keep running, syncing each provider with a `schedule` as it falls due.
+    Schedule,
+    Report(Report),
+    /// List the provider's accounts.
+    Accounts(Show),
+    /// List the provider's cards.
+    Cards(Show),
+    /// Show the account holder's details.
+    Info(Show),
+    CheckConsent(CheckConsent),
+    Status(Status),
+    Verify(Verify),
+    Reconcile(Reconcile),
+}
+
+#[derive(Debug, Parser)]
+struct Show {
+    #[clap(short = 'p', long = "provider")]
+    provider: String,
+    #[clap(short = 'o', long = "output", value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+    /// Mask account and card numbers, IBANs and names.
+    #[clap(long = "redact")]
+    redact: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Parser)]
+struct Auth {
+    /// With more than one, the server lists them all to authenticate in
+    /// turn.
+    #[clap(
+        short = 'p',
+        long = "provider",
+        required_unless_present = "all_providers"
+    )]
+    provider: Vec<String>,
+    /// Authenticate every configured provider.
+    #[clap(long = "all-providers", conflicts_with = "provider")]
+    all_providers: bool,
+    #[clap(short = 'l', long = "listen-port")]
+    port: Option<u16>,
+    /// Address to listen on; defaults to `auth_listen` from the config, or
+    /// 127.0.0.1.
+    #[clap(long = "listen-address")]
+    address: Option<IpAddr>,
+    /// Request only these scopes, rather than the providers' configured
+    /// `scopes`; repeat for each scope.
+    #[clap(long = "scope")]
+    scopes: Vec<String>,
+    /// Externally visible redirect URI, eg: when behind a reverse proxy or
+    /// SSH tunnel. Overrides the provider's configured `redirect_uri`.
+    #[clap(long = "redirect-uri")]
+    redirect_uri: Option<String>,
+    /// Paste the authorization code into the terminal, rather than
+    /// running a local server for the redirect.
+    #[clap(long = "manual", conflicts_with_all = ["port", "address"])]
+    manual: bool,
+    /// Serve over HTTPS, with a self-signed certificate unless one is
+    /// configured in `auth_tls`.
+    #[clap(long = "tls", conflicts_with = "manual")]
+    tls: bool,
+}
+
+/// Refresh providers' access tokens, so their refresh tokens stay valid.
+#[derive(Debug, Parser)]
+struct Refresh {
+    /// Defaults to every configured provider.
+    #[clap(short = 'p', long = "provider")]
+    provider: Vec<String>,
+    /// Keep running, refreshing each token ahead of its expiry.
+    #[clap(long = "keep-alive")]
+    keep_alive: bool,
+}
+
+/// Check when providers' consent expires, exiting with a distinct status if
+/// any need re-authenticating.
+#[derive(Debug, Parser)]
+struct CheckConsent {
+    /// Defaults to every configured provider.
+    #[clap(short = 'p', long = "provider")]
+    provider: Vec<String>,
+    /// Treat consent expiring within this many days as needing renewal.
+    #[clap(long = "within-days", default_value_t = consent::CONSENT_WARNING.num_days())]
+    within_days: i64,
+    #[clap(short = 'o', long = "output", value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+}
+
+/// Show each provider's token expiry, refresh token age, and how far its
+/// syncs have got, without calling the API.
+#[derive(Debug, Parser)]
+struct Status {
+    /// Defaults to every configured provider.
+    #[clap(short = 'p', long = "provider")]
+    provider: Vec<String>,
+    #[clap(short = 'o', long = "output", value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+}
+
+/// Check the provider's stored files against the hashes recorded when they
+/// were written, failing if any were modified or removed since.
+#[derive(Debug, Parser)]
+struct Verify {
+    #[clap(short = 'p', long = "provider")]
+    provider: String,
+    #[clap(short = 'o', long = "output", value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+}
+
+/// Summarise stored transactions by classification, or by category where
+/// the provider's `category_rules` assign one.
+#[derive(Debug, Parser)]
+struct Report {
+    #[clap(short = 'p', long = "provider")]
+    provider: String,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+    #[clap(long = "json")]
+    json: bool,
+}
+
+/// Check stored transactions against their running balances and the
+/// current balance, failing if any months or transactions look to be
+/// missing.
+#[derive(Debug, Parser)]
+struct Reconcile {
+    #[clap(short = 'p', long = "provider")]
+    provider: String,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+    #[clap(short = 'o', long = "output", value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+}
+
+#[derive(Debug, Parser)]
+struct Sync {
+    #[clap(
+        short = 'p',
+        long = "provider",
+        required_unless_present = "all_providers"
+    )]
+    provider: Vec<String>,
+    /// Sync every configured provider.
+    #[clap(long = "all-providers", conflicts_with = "provider")]
+    all_providers: bool,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+    /// Fetches to run at once, shared between all the providers being
+    /// synced; defaults to 1.
+    #[clap(short = 't', long = "concurrent-tasks")]
+    concurrency: Option<usize>,
+    /// Only fetch months since the last successful sync.
+    #[clap(long = "incremental")]
+    incremental: bool,
+    /// List what would be fetched and written, without doing so.
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+    /// Wait for any other sync of the same target directory to finish,
+    /// rather than failing.
+    #[clap(long = "wait")]
+    wait: bool,
+    /// Carry on with the remaining fetches when one fails, reporting every
+    /// failure at the end.
+    #[clap(long = "keep-going")]
+    keep_going: bool,
+    /// Skip months already fetched by an interrupted sync of the same date
+    /// range.
+    #[clap(long = "resume")]
+    resume: bool,
+    /// Show progress on stderr, when it's a terminal.
+    #[clap(long = "progress")]
+    progress: bool,
+    /// How to print the end-of-run summary.
+    #[clap(long = "summary-format", value_enum, default_value_t = SummaryFormat::Text)]
+    summary_format: SummaryFormat,
+}
+
+/// Import a provider's history for the first time, a month at a time from
+/// the newest, until reaching the start of `from_year` or the provider
+/// refuses to go further back. Re-run to carry on after an interruption.
+#[derive(Debug, Parser)]
+struct Backfill {
+    #[clap(short = 'p', long = "provider")]
+    provider: String,
+    from_year: i32,
+    /// Wait this long between months.
+    #[clap(long = "delay-s", default_value_t = 10)]
+    delay_s: u64,
+    #[clap(short = 't', long = "concurrent-tasks")]
+    concurrency: Option<usize>,
+}
+
+impl Sync {
+    fn provider_names(&self, config: &ScraperConfig) -> Vec<String> {
+        if self.all_providers {
+            let mut names = config.providers.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            names
+        } else {
+            self.provider.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SummaryFormat {
+    Text,
+    Json,
+}
+
+impl Options {
+    /// The command that runs these options, less any arguments, eg: when
+    /// embedded as `scraper truelayer`; defaults to how the process was run.
+    pub fn with_invoked_as(mut self, invoked_as: String) -> Self {
+        self.invoked_as = Some(invoked_as);
+        self
+    }
+}
+
+/// Runs the command given by `opts`, exiting the process with a distinct
+/// status if a sync overran or was interrupted.
+pub async fn main(opts: Options) -> Result<()> {
+    let mut config = ScraperConfig::load(&opts.config)?;
+    if let Some(dir) = opts.record.clone() {
+        config.main.cassette = Some(Cassette::Record(dir));
+    } else if let Some(dir) = opts.replay.clone() {
+        config.main.cassette = Some(Cassette::Replay(dir));
+    }
+
+    let telemetry = crate::telemetry::init(
+        &config.main,
+        opts.log_format.unwrap_or(config.main.log_format),
+    )?;
+    let config_path = opts.config.clone();
+    let invoked_as = opts.invoked_as.clone().unwrap_or_else(|| {
+        std::env::args()
+            .next()
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned())
+    });
+    let result = run(opts, config).await;
+    telemetry.shutdown();
+
+    if let Err(err) = result {
+        let Some(error) = err.downcast_ref::<crate::Error>() else {
+            return Err(err);
+        };
+        let Some(status) = error.exit_status() else {
+            return Err(err);
+        };
+        match status {
+            EXIT_DEADLINE_EXCEEDED => error!(%error, "Sync overran its window"),
+            EXIT_CANCELLED => error!(%error, "Sync interrupted"),
+            _ => error!(%error, "Re-authentication needed"),
+        }
+        eprintln!("Error: {:?}", err);
+        let providers = error.reauthentication_required();
+        if !providers.is_empty() {
+            eprintln!("To re-authenticate, run:");
+            eprintln!("  {}", auth_command(&invoked_as, &config_path, &providers));
+        }
+        std::process::exit(status);
+    }
+
+    Ok(())
+}
+
+/// Syncs the providers with a `schedule` as a [`Scheduler`] calls for
+/// them; for `schedule`, and the combined `scraper schedule`.
+#[derive(Clone)]
+pub struct ScheduledSyncs {
+    config: Arc<ScraperConfig>,
+    client_creds: ClientCreds,
+    health: Health,
+    cnx: CancellationToken,
+}
+
+impl ScheduledSyncs {
+    /// Runs stop early, as with an interrupt, once `cnx` is cancelled.
+    pub async fn load(config_path: &Path, cnx: CancellationToken) -> Result<Self> {
+        let config = ScraperConfig::load(config_path)?;
+        let client_creds = match config.credentials().await {
+            Ok(creds) => creds,
+            Err(error) => {
+                let providers = scheduled(&config).map(str::to_owned).collect::<Vec<_>>();
+                ping_failed(&config, &providers, &error).await;
+                return Err(error);
+            }
+        };
+        Ok(Self::new(Arc::new(config), client_creds, cnx))
+    }
+
+    fn new(config: Arc<ScraperConfig>, client_creds: ClientCreds, cnx: CancellationToken) -> Self {
+        let health = Health::new(config.main.health_stall_timeout_s.map(Duration::from_secs));
+        // So that `/readyz` covers each provider before its first run.
+        for (name, provider) in config.providers.iter() {
+            if provider.schedule.is_none() {
+                continue;
+            }
+            match tl_client(&config, &client_creds, provider) {
+                Ok(tl) => health.register_provider(name, Arc::new(tl)),
+                Err(error) => warn!(provider=%name, ?error, "Failed to set up provider"),
+            }
+        }
+        Self {
+            config,
+            client_creds,
+            health,
+            cnx,
+        }
+    }
+
+    /// Each provider with a `schedule`, by name.
+    pub fn schedules(&self) -> impl Iterator<Item = (&str, &SyncSchedule)> {
+        self.config
+            .providers
+            .iter()
+            .filter_map(|(name, p)| Some((name.as_str(), p.schedule.as_ref()?)))
+    }
+
+    /// Serves health checks and metrics, where configured, until cancelled.
+    pub async fn serve(self) -> Result<()> {
+        let health = async {
+            match self.config.main.health_listen {
+                Some(addr) => self.health.clone().serve(addr, self.cnx.clone()).await,
+                None => Ok(()),
+            }
+        };
+        let metrics = async {
+            match self.config.main.metrics.listen {
+                Some(addr) => metrics::serve(addr, self.cnx.clone()).await,
+                None => Ok(()),
+            }
+        };
+        futures::try_join!(health, metrics)?;
+        Ok(())
+    }
+
+    /// Syncs the last `lookback_days` of `provider_name`.
+    pub fn sync(&self, provider_name: String) -> impl Future<Output = Result<()>> + Send + 'static {
+        let syncs = self.clone();
+        async move {
+            let config = &syncs.config;
+            let lookback =
+                chrono::Duration::days(config.main.schedule.lookback_days.unwrap_or(31).into());
+            let today = Utc::now().date_naive();
+            let options = SyncOptions {
+                incremental: true,
+                dry_run: false,
+                wait_for_lock: false,
+                keep_going: false,
+                resume: false,
+            };
+            let provider_names = [provider_name];
+            ping_started(config, &provider_names).await;
+            let run = run_sync(
+                config,
+                &syncs.client_creds,
+                &syncs.health,
+                &provider_names,
+                (today - lookback)..=today,
+                options,
+                PoolOptions {
+                    concurrency: config.main.schedule.concurrency.unwrap_or(1),
+                    cancel: syncs.cnx.clone(),
+                    progress: false,
+                },
+            )
+            .await;
+            let run = match run {
+                Ok(run) => run,
+                Err(error) => {
+                    ping_failed(config, &provider_names, &error).await;
+                    return Err(error);
+                }
+            };
+            let (summary, result) = run.finish();
+            info!(?summary, "Scheduled run finished");
+            ping_finished(config, &provider_names, &summary).await;
+            Ok(result?)
+        }
+    }
+}
+
+/// The names of the providers with a `schedule`.
+fn scheduled(config: &ScraperConfig) -> impl Iterator<Item = &str> {
+    config
+        .providers
+        .iter()
+        .filter(|(_, p)| p.schedule.is_some())
+        .map(|(name, _)| name.as_str())
+}
+
+/// Tells each of `providers`' healthchecks, if any, that its run started.
+async fn ping_started(config: &ScraperConfig, providers: &[String]) {
+    for name in providers.iter() {
+        if let Some(healthcheck) = ping_config(config, name) {
+            crate::ping_start(name, healthcheck).await;
+        }
+    }
+}
+
+/// Tells each of `providers`' healthchecks how its run went.
+async fn ping_finished(config: &ScraperConfig, providers: &[String], summary: &SyncSummary) {
+    for name in providers.iter() {
+        if let Some(healthcheck) = ping_config(config, name) {
+            crate::ping(name, healthcheck, &summary.for_provider(name)).await;
+        }
+    }
+}
+
+/// Tells each of `providers`' healthchecks that its run couldn't be set up.
+async fn ping_failed(config: &ScraperConfig, providers: &[String], error: &anyhow::Error) {
+    for name in providers.iter() {
+        if let Some(healthcheck) = ping_config(config, name) {
+            crate::ping_failure(name, healthcheck, error).await;
+        }
+    }
+}
+
+fn ping_config<'a>(config: &'a ScraperConfig, name: &str) -> Option<&'a PingConfig> {
+    config.providers.get(name)?.healthcheck.as_ref()
+}
+
+/// What to run to re-authenticate `providers`.
+fn auth_command(invoked_as: &str, config_path: &Path, providers: &BTreeSet<&str>) -> String {
+    let mut command = format!("{} -c {} auth", invoked_as, config_path.display());
+    for provider in providers.iter() {
+        command.push_str(&format!(" -p {}", provider));
+    }
+    command
+}
+
+async fn run(opts: Options, config: ScraperConfig) -> Result<()> {
+    let client_creds = match config.credentials().await {
+        Ok(creds) => creds,
+        Err(error) => {
+            let providers = match opts.command {
+                Commands::Sync(ref sync_opts) => sync_opts.provider_names(&config),
+                Commands::Schedule => scheduled(&config).map(str::to_owned).collect(),
+                _ => Vec::new(),
+            };
+            ping_failed(&config, &providers, &error).await;
+            return Err(error);
+        }
+    };
+
+    let tl_client = |provider: &ProviderConfig| tl_client(&config, &client_creds, provider);
+
+    match opts.command {
+        Commands::Auth(ref auth_opts) => {
+            let provider_names = if auth_opts.all_providers {
+                config.providers.keys().cloned().collect::<BTreeSet<_>>()
+            } else {
+                auth_opts.provider.iter().cloned().collect()
+            };
+            let clients = provider_names
+                .iter()
+                .map(|name| -> Result<_> {
+                    let provider: &ProviderConfig = config.provider(name)?;
+                    let mut tl = tl_client(provider)?;
+                    if !auth_opts.scopes.is_empty() {
+                        tl = tl.with_scopes(auth_opts.scopes.clone());
+                    }
+                    if let Some(scopes) = tl.scopes() {
+                        crate::check_scopes(scopes)?;
+                    }
+                    Ok((name.clone(), Arc::new(tl)))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()?;
+            let configured_redirect_uris = provider_names
+                .iter()
+                .filter_map(|name| config.providers[name].redirect_uri.clone())
+                .collect::<BTreeSet<_>>();
+            if auth_opts.redirect_uri.is_none() && configured_redirect_uris.len() > 1 {
+                anyhow::bail!(
+                    "Providers have different redirect URIs; choose one with --redirect-uri: {:?}",
+                    configured_redirect_uris
+                );
+            }
+            let default_listen = config
+                .main
+                .auth_listen
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 5500)));
+            let tls = auth_opts
+                .tls
+                .then(|| config.main.auth_tls.clone().unwrap_or_default());
+            let options = AuthServerOptions {
+                listen: SocketAddr::new(
+                    auth_opts.address.unwrap_or(default_listen.ip()),
+                    auth_opts.port.unwrap_or(default_listen.port()),
+                ),
+                redirect_uri: auth_opts
+                    .redirect_uri
+                    .clone()
+                    .or_else(|| configured_redirect_uris.into_iter().next()),
+                tls,
+                registered_redirect_uris: config.main.registered_redirect_uris.clone(),
+                config_path: Some(opts.config.clone()),
+            };
+            if auth_opts.manual {
+                for (name, tl) in clients.iter() {
+                    eprintln!("Authenticating {}", name);
+                    crate::authenticate_manually(tl, &options).await?;
+                }
+            } else {
+                crate::authenticate(clients, &options).await?;
+            }
+        }
+        Commands::Revoke { provider } => {
+            let provider: &ProviderConfig = config.provider(&provider)?;
+            tl_client(provider)?.revoke_token().await?;
+            eprintln!("Revoked; removed token from {}", provider.token_store()?);
+        }
+        Commands::Refresh(ref refresh_opts) => {
+            let provider_names = if refresh_opts.provider.is_empty() {
+                config.providers.keys().cloned().collect::<Vec<_>>()
+            } else {
+                refresh_opts.provider.clone()
+            };
+            let clients = provider_names
+                .iter()
+                .map(|name| -> Result<_> {
+                    let provider: &ProviderConfig = config.provider(name)?;
+                    Ok((name.clone(), Arc::new(tl_client(provider)?)))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()?;
+            let refresher = TokenRefresher::new(
+                clients,
+                config.main.refresh_margin_s.map(Duration::from_secs),
+            );
+            if refresh_opts.keep_alive {
+                let cnx = CancellationToken::new();
+                tokio::spawn({
+                    let cnx = cnx.clone();
+                    async move {
+                        if tokio::signal::ctrl_c().await.is_ok() {
+                            info!("Interrupted; stopping");
+                            cnx.cancel();
+                        }
+                    }
+                });
+                refresher.keep_alive(cnx).await?;
+            } else {
+                refresher.refresh_all().await?;
+            }
+        }
+        #[cfg(feature = "keyring")]
+        Commands::KeyringImport => {
+            use crate::{FileTokenStore, KeyringTokenStore, SecretStore, TokenStore};
+
+            if config.main.credentials_store == SecretStore::Keyring {
+                let path = &config.main.client_credentials;
+                config.file_credentials()?;
+                let entry = KeyringTokenStore::new(&path.to_string_lossy());
+                entry.set(std::fs::read_to_string(path)?).await?;
+                eprintln!("Imported {:?} into {}", path, entry);
+            }
+            for (name, provider) in config.providers.iter() {
+                if provider.token_store != SecretStore::Keyring {
+                    continue;
+                }
+                let file = FileTokenStore::new(&provider.user_token);
+                let Some(data) = file.load().await? else {
+                    eprintln!("{}: no token in {}", name, file);
+                    continue;
+                };
+                let entry = provider.token_store()?;
+                entry.save(&data).await?;
+                eprintln!("{}: imported {} into {}", name, file, entry);
+            }
+        }
+        Commands::Sync(ref sync_opts) => {
+            let health = Health::new(config.main.health_stall_timeout_s.map(Duration::from_secs));
+            let provider_names = sync_opts.provider_names(&config);
+            let options = SyncOptions {
+                incremental: sync_opts.incremental,
+                dry_run: sync_opts.dry_run,
+                wait_for_lock: sync_opts.wait,
+                keep_going: sync_opts.keep_going,
+                resume: sync_opts.resume,
+            };
+
+            let cnx = CancellationToken::new();
+            let health_server = config
+                .main
+                .health_listen
+                .map(|addr| tokio::spawn(health.clone().serve(addr, cnx.clone())));
+            let metrics_server = config
+                .main
+                .metrics
+                .listen
+                .map(|addr| tokio::spawn(metrics::serve(addr, cnx.clone())));
+            // Separate from `cnx`, so the servers keep running while we
+            // wind down.
+            let stop = CancellationToken::new();
+            tokio::spawn({
+                let stop = stop.clone();
+                async move {
+                    if shutdown_signal().await.is_ok() {
+                        info!("Interrupted; finishing in-flight fetches");
+                        stop.cancel();
+                    }
+                }
+            });
+
+            if !sync_opts.dry_run {
+                ping_started(&config, &provider_names).await;
+            }
+            let run = run_sync(
+                &config,
+                &client_creds,
+                &health,
+                &provider_names,
+                sync_opts.from_date..=sync_opts.to_date,
+                options,
+                PoolOptions {
+                    concurrency: sync_opts.concurrency.unwrap_or(1),
+                    cancel: stop.clone(),
+                    progress: sync_opts.progress && std::io::stderr().is_terminal(),
+                },
+            )
+            .await;
+            let run = match run {
+                Ok(run) => run,
+                Err(error) if !sync_opts.dry_run => {
+                    ping_failed(&config, &provider_names, &error).await;
+                    return Err(error);
+                }
+                Err(error) => return Err(error),
+            };
+            let plan = run
+                .providers
+                .iter()
+                .map(|(name, p)| (name.clone(), p.planned()))
+                .collect::<BTreeMap<_, _>>();
+            let (summary, result) = run.finish();
+
+            if sync_opts.dry_run {
+                match sync_opts.summary_format {
+                    SummaryFormat::Text => {
+                        for (name, planned) in plan.iter() {
+                            for fetch in planned.iter() {
+                                println!(
+                                    "{}: {} -> {}",
+                                    name,
+                                    fetch.endpoint,
+                                    fetch.output.display()
+                                );
+                            }
+                        }
+                    }
+                    SummaryFormat::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+                }
+            } else {
+                match sync_opts.summary_format {
+                    SummaryFormat::Text => print!("{}", summary),
+                    SummaryFormat::Json => println!("{}", serde_json::to_string_pretty(&summary)?),
+                }
+                ping_finished(&config, &provider_names, &summary).await;
+            }
+
+            cnx.cancel();
+            if let Some(server) = health_server {
+                server.await??;
+            }
+            if let Some(server) = metrics_server {
+                server.await??;
+            }
+            result?;
+        }
+        Commands::Backfill(ref backfill) => {
+            let provider: &ProviderConfig = config.provider(&backfill.provider)?;
+            let tl = Arc::new(tl_client(provider)?);
+            let stop = CancellationToken::new();
+            tokio::spawn({
+                let stop = stop.clone();
+                async move {
+                    if shutdown_signal().await.is_ok() {
+                        info!("Interrupted; stopping after the month in progress");
+                        stop.cancel();
+                    }
+                }
+            });
+            let report = crate::Backfill::new(&backfill.provider, tl, provider, backfill.from_year)
+                .with_delay(Duration::from_secs(backfill.delay_s))
+                .with_concurrency(backfill.concurrency.unwrap_or(1))
+                .with_cancellation(stop)
+                .run()
+                .await?;
+            println!(
+                "Synced {} months ({} done already)",
+                report.months_synced, report.months_skipped
+            );
+            if let Some(earliest) = report.earliest {
+                println!("Earliest month: {}", earliest.format("%Y-%m"));
+            }
+            if let Some(refused) = report.refused {
+                println!("Provider refused {} and earlier", refused.format("%Y-%m"));
+            }
+            if report.cancelled {
+                anyhow::bail!("Interrupted; re-run to carry on");
+            }
+        }
+        Commands::Schedule => {
+            let cnx = CancellationToken::new();
+            let scheduler = Scheduler::new(
+                config
+                    .providers
+                    .iter()
+                    .filter_map(|(name, p)| Some((name.as_str(), p.schedule.as_ref()?))),
+                config.main.schedule.jitter_s.map(Duration::from_secs),
+            )?;
+            let syncs = ScheduledSyncs::new(Arc::new(config), client_creds, cnx.clone());
+            let servers = tokio::spawn(syncs.clone().serve());
+            tokio::spawn({
+                let cnx = cnx.clone();
+                async move {
+                    if shutdown_signal().await.is_ok() {
+                        info!("Interrupted; stopping syncs in progress");
+                        cnx.cancel();
+                    }
+                }
+            });
+
+            scheduler
+                .run(|provider| syncs.sync(provider), cnx.clone())
+                .await?;
+
+            cnx.cancel();
+            servers.await??;
+        }
+        Commands::Report(ref report_opts) => {
+            let provider: &ProviderConfig = config.provider(&report_opts.provider)?;
+            let rules = match provider.category_rules.as_ref() {
+                Some(path) => Some(crate::CategoryRules::load(path).await?),
+                None => None,
+            };
+            let report = crate::classification_report(
+                &provider.target_dir,
+                &provider.layout,
+                report_opts.from_date..=report_opts.to_date,
+                rules,
+            )
+            .await?;
+            if report_opts.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                for (account, histogram) in report.accounts.iter() {
+                    println!("{}", account);
+                    for (classification, totals) in histogram.iter() {
+                        println!(
+                            "  {:>6} {:>12} {}",
+                            totals.count, totals.total, classification
+                        );
+                    }
+                }
+            }
+        }
+        Commands::Verify(ref verify) => {
+            let provider: &ProviderConfig = config.provider(&verify.provider)?;
+            let report = crate::verify_hashes(&provider.target_dir).await?;
+            match verify.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+                OutputFormat::Table => {
+                    for path in report.modified.iter() {
+                        println!("modified {}", path.display());
+                    }
+                    for path in report.missing.iter() {
+                        println!("missing  {}", path.display());
+                    }
+                }
+            }
+            if !report.is_ok() {
+                anyhow::bail!(
+                    "{} of {} files failed verification",
+                    report.modified.len() + report.missing.len(),
+                    report.checked
+                );
+            }
+        }
+        Commands::Reconcile(ref reconcile) => {
+            let provider: &ProviderConfig = config.provider(&reconcile.provider)?;
+            let report = crate::reconcile(
+                &provider.target_dir,
+                &provider.layout,
+                reconcile.from_date..=reconcile.to_date,
+            )
+            .await?;
+            match reconcile.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+                OutputFormat::Table => {
+                    for (name, account) in report.accounts.iter() {
+                        println!("{} ({} transactions)", name, account.transactions);
+                        for month in account.missing_months.iter() {
+                            println!("  missing month {}", month.format("%Y-%m"));
+                        }
+                        for gap in account.balance_gaps.iter() {
+                            println!(
+                                "  gap before {} {}: expected {}, running balance {}",
+                                gap.timestamp.date_naive(),
+                                gap.transaction_id.as_deref().unwrap_or("(no id)"),
+                                gap.expected,
+                                gap.running_balance
+                            );
+                        }
+                        if let Some(mismatch) = account.current_balance.as_ref() {
+                            println!(
+                                "  latest running balance {} differs from current balance {}",
+                                mismatch.running_balance, mismatch.current
+                            );
+                        }
+                    }
+                }
+            }
+            let failed = report
+                .accounts
+                .values()
+                .filter(|account| !account.is_ok())
+                .count();
+            if failed > 0 {
+                anyhow::bail!(
+                    "{} of {} accounts failed reconciliation",
+                    failed,
+                    report.accounts.len()
+                );
+            }
+        }
+        Commands::Accounts(ref show) => {
+            let tl = tl_client(config.provider(&show.provider)?)?;
+            let mut accounts = tl.fetch_accounts().await?.results;
+            if show.redact {
+                accounts.iter_mut().for_each(redact::account);
+            }
+            match show.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&accounts)?),
+                OutputFormat::Table => {
+                    for account in accounts.iter() {
+                        let number = match (
+                            account.account_number.sort_code.as_ref(),
+                            account.account_number.number.as_ref(),
+                        ) {
+                            (Some(sort_code), Some(number)) => format!("{} {}", sort_code, number),
+                            _ => account.account_number.iban.clone().unwrap_or_default(),
+                        };
+                        println!(
+                            "{:<36} {:<14} {:<4} {:<22} {}",
+                            account.account_id,
+                            account.account_type,
+                            account.currency,
+                            number,
+                            account.display_name
+                        );
+                    }
+                }
+            }
+        }
+        Commands::Cards(ref show) => {
+            let tl = tl_client(config.provider(&show.provider)?)?;
+            let mut cards = tl.fetch_cards().await?.results;
+            if show.redact {
+                cards.iter_mut().for_each(redact::card);
+            }
+            match show.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&cards)?),
+                OutputFormat::Table => {
+                    for card in cards.iter() {
+                        println!(
+                            "{:<36} {:<10} {:<4} {:<6} {}",
+                            card.account_id,
+                            card.card_network,
+                            card.currency,
+                            card.partial_card_number,
+                            card.display_name
+                        );
+                    }
+                }
+            }
+        }
+        Commands::CheckConsent(ref check) => {
+            let provider_names = if check.provider.is_empty() {
+                config.providers.keys().cloned().collect::<BTreeSet<_>>()
+            } else {
+                check.provider.iter().cloned().collect()
+            };
+            let warning = chrono::Duration::days(check.within_days);
+            let mut statuses = BTreeMap::new();
+            for name in provider_names {
+                let tl = tl_client(config.provider(&name)?)?;
+                statuses.insert(name, ConsentStatus::check(&tl, warning).await?);
+            }
+            match check.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&statuses)?),
+                OutputFormat::Table => {
+                    for (name, status) in statuses.iter() {
+                        println!(
+                            "{:<20} {:<10} {}",
+                            name,
+                            status.expires_at.map_or_else(
+                                || "unknown".to_owned(),
+                                |at| at.format("%Y-%m-%d").to_string()
+                            ),
+                            if status.reauth_needed {
+                                "re-auth needed"
+                            } else {
+                                "ok"
+                            }
+                        );
+                    }
+                }
+            }
+            let due = statuses
+                .into_iter()
+                .filter(|(_, status)| status.reauth_needed)
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>();
+            if !due.is_empty() {
+                return Err(crate::Error::ConsentDue(due).into());
+            }
+        }
+        Commands::Status(ref status) => {
+            let provider_names = if status.provider.is_empty() {
+                config.providers.keys().cloned().collect::<BTreeSet<_>>()
+            } else {
+                status.provider.iter().cloned().collect()
+            };
+            let mut statuses = BTreeMap::new();
+            for name in provider_names {
+                let provider = config.provider(&name)?;
+                let tl = tl_client(provider)?;
+                statuses.insert(name, ProviderStatus::check(&tl, provider).await?);
+            }
+            match status.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&statuses)?),
+                OutputFormat::Table => {
+                    let now = Utc::now();
+                    let or_never = |at: Option<String>| at.unwrap_or_else(|| "-".to_owned());
+                    println!(
+                        "{:<20} {:<17} {:<12} {:<17} {:>8} {:<7}",
+                        "provider",
+                        "token expires",
+                        "refresh age",
+                        "last synced",
+                        "accounts",
+                        "month"
+                    );
+                    for (name, status) in statuses.iter() {
+                        println!(
+                            "{:<20} {:<17} {:<12} {:<17} {:>8} {:<7}",
+                            name,
+                            status.token_expires_at.map_or_else(
+                                || "no token".to_owned(),
+                                |at| at.format("%Y-%m-%d %H:%M").to_string()
+                            ),
+                            or_never(
+                                status
+                                    .refresh_token_age(now)
+                                    .map(|age| format!("{}d", age.num_days()))
+                            ),
+                            or_never(
+                                status
+                                    .last_synced_at
+                                    .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+                            ),
+                            status.accounts,
+                            or_never(status.latest_month.map(|m| m.format("%Y-%m").to_string())),
+                        );
+                    }
+                }
+            }
+        }
+        Commands::Info(ref show) => {
+            let tl = tl_client(config.provider(&show.provider)?)?;
+            let mut info = tl.fetch_info().await?.results;
+            if show.redact {
+                info.iter_mut().for_each(redact::info);
+            }
+            match show.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&info)?),
+                OutputFormat::Table => {
+                    for user in info.iter() {
+                        println!("{}", user.full_name);
+                    }
+                }
+            }
+        }
+    };
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or on unix, `SIGTERM`.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = term.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+fn tl_client(
+    config: &ScraperConfig,
+    client_creds: &ClientCreds,
+    provider: &ProviderConfig,
+) -> crate::Result<TlClient> {
+    let mut builder = TlClient::builder(
+        config.main.environment.clone(),
+        &provider.user_token,
+        client_creds,
+    )
+    .with_config(&config.main)
+    .token_store(provider.token_store()?);
+    if let Some(scopes) = provider.scopes.clone() {
+        builder = builder.scopes(scopes);
+    }
+    if let Some(filter) = provider.provider_filter.as_deref() {
+        builder = builder.provider_filter(filter);
+    }
+    if let Some(cache) = provider.http_cache() {
+        builder = builder.http_cache(cache);
+    }
+    if provider.async_data {
+        builder = builder.async_data(Duration::from_secs(
+            config
+                .main
+                .async_max_wait_s
+                .unwrap_or(DEFAULT_ASYNC_MAX_WAIT_S),
+        ));
+    }
+    builder.build()
+}
+
+/// How a sync run's job pool behaves.
+struct PoolOptions {
+    concurrency: usize,
+    /// Stops the run gracefully once cancelled.
+    cancel: CancellationToken,
+    /// Show a progress bar on stderr.
+    progress: bool,
+}
+
+struct SyncRun {
+    providers: BTreeMap<String, Arc<ProviderSync>>,
+    stats: PoolStats,
+    /// How each provider's sync went; their jobs are tagged by provider, so
+    /// that one failing doesn't stop the others.
+    results: BTreeMap<String, crate::Result<()>>,
+    /// Why the run as a whole stopped short, eg: at its deadline.
+    stopped: Option<crate::Error>,
+    /// How many providers may fail before the run as a whole does.
+    max_failed: usize,
+}
+
+impl SyncRun {
+    /// Summarises the run; see [`crate::Error::providers_failed`]. A run that
+    /// stopped short fails regardless.
+    fn finish(self) -> (SyncSummary, crate::Result<()>) {
+        let mut summaries = BTreeMap::new();
+        let mut failed = BTreeMap::new();
+        for (name, result) in self.results {
+            let mut summary = self
+                .providers
+                .get(&name)
+                .map(|p| p.summary())
+                .unwrap_or_default();
+            if let Err(error) = result {
+                summary.error = Some(format!("{:#}", error));
+                failed.insert(name.clone(), error);
+            }
+            summaries.insert(name, summary);
+        }
+        let result = match self.stopped {
+            Some(error) => {
+                for (name, provider) in self.providers.iter() {
+                    summaries.entry(name.clone()).or_insert_with(|| {
+                        let mut summary = provider.summary();
+                        summary.error = Some(format!("{:#}", error));
+                        summary
+                    });
+                }
+                Err(error)
+            }
+            None => crate::Error::providers_failed(failed, self.max_failed),
+        };
+        let summary = SyncSummary::new(summaries, &self.stats, &result);
+        (summary, result)
+    }
+}
+
+/// Syncs the named providers in a single job pool, with each provider's jobs
+/// tagged so that one failing doesn't stop the others. Only fails if the
+/// sync couldn't be set up; the outcome of each provider's sync is in the
+/// results.
+async fn run_sync(
+    config: &ScraperConfig,
+    client_creds: &ClientCreds,
+    health: &Health,
+    provider_names: &[String],
+    dates: RangeInclusive<NaiveDate>,
+    options: SyncOptions,
+    pool_options: PoolOptions,
+) -> Result<SyncRun> {
+    let provider_configs = provider_names
+        .iter()
+        .map(|name| Ok((name.clone(), config.provider(name)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut providers = BTreeMap::new();
+    let mut results = BTreeMap::new();
+    for (provider_name, provider) in provider_configs {
+        let tl = match tl_client(config, client_creds, provider) {
+            Ok(tl) => Arc::new(tl),
+            Err(error) => {
+                error!(provider=%provider_name, ?error, "Failed to set up provider");
+                results.insert(provider_name, Err(error));
+                continue;
+            }
+        };
+        health.register_provider(&provider_name, tl.clone());
+        let sync = Arc::new(ProviderSync::new(
+            &provider_name,
+            tl,
+            provider,
+            options.clone(),
+        ));
+        providers.insert(provider_name, sync);
+    }
+
+    let (pool, handle) = job_pool(config, &pool_options, &options);
+    let monitor = handle.monitor();
+    health.watch_pool(monitor.clone());
+    let progress = pool_options
+        .progress
+        .then(|| Progress::start(monitor.clone()));
+    let outcome =
+        scraper_sdk::sync_each(providers.values().cloned().collect(), dates, (pool, handle)).await;
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    let stopped = match outcome {
+        Ok(outcomes) => {
+            let now = Utc::now();
+            for (provider_name, result) in outcomes {
+                match result.as_ref() {
+                    Ok(()) => {
+                        health.record_success(&provider_name, now);
+                        metrics::record_success(&provider_name, now);
+                        info!(provider=%provider_name, "Sync complete");
+                    }
+                    Err(error) => error!(provider=%provider_name, %error, "Sync failed"),
+                }
+                results.insert(provider_name, result);
+            }
+            None
+        }
+        Err(error) => {
+            error!(%error, "Sync stopped short");
+            Some(error)
+        }
+    };
+
+    if let Some(path) = config.main.metrics.textfile.as_ref() {
+        if let Err(error) = metrics::write_textfile(path).await {
+            warn!(?error, ?path, "Failed to write metrics");
+        }
+    }
+
+    Ok(SyncRun {
+        providers,
+        stats: monitor.stats(),
+        results,
+        stopped,
+        // Failing every provider always fails the run.
+        max_failed: config
+            .main
+            .max_failed_providers
+            .unwrap_or(usize::MAX)
+            .min(provider_names.len().saturating_sub(1)),
+    })
+}
+
+fn job_pool(
+    config: &ScraperConfig,
+    pool_options: &PoolOptions,
+    options: &SyncOptions,
+) -> (JobPool, JobHandle) {
+    let grace = Duration::from_secs(config.main.shutdown_grace_s.unwrap_or(30));
+    let (mut pool, handle) = JobPool::new(pool_options.concurrency);
+    pool = pool.with_cancellation(pool_options.cancel.clone(), grace);
+    if options.keep_going {
+        pool = pool.with_error_policy(ErrorPolicy::KeepGoing);
+    }
+    if let Some(max_duration) = config.main.max_run_duration_s {
+        pool = pool.with_deadline(Duration::from_secs(max_duration), grace);
+    }
+    if let Some(timeout) = config.main.job_timeout_s {
+        pool = pool.with_job_timeout(
+            Duration::from_secs(timeout),
+            config.main.job_timeout_retries.unwrap_or(0),
+        );
+    }
+    (pool, handle)
+}
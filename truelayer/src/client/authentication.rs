@@ -1,21 +1,21 @@
-use std::{
-    fs::File,
-    io::{ErrorKind, Write},
-    path::PathBuf,
-};
+use std::{fmt, sync::Arc};
 
 use again::RetryPolicy;
-use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, Duration, Utc};
 use reqwest::Client;
-use secrecy::{Secret, SecretString};
+use secrecy::{ExposeSecret, Secret, SecretString};
 use serde::{Deserialize, Serialize};
-use tempfile::NamedTempFile;
-use tokio::{sync::Mutex, task::spawn_blocking};
-use tracing::{debug, info, instrument, trace, Span};
+use tokio::sync::Mutex;
+use tracing::{debug, info, instrument, trace, warn};
+
+use crate::{
+    client::{RateLimiter, TokenStore},
+    perform_request, serialize_optional_secret, serialize_secret, Environment, Error, Result,
+};
 
-use crate::Environment;
-use crate::{perform_request, serialize_optional_secret, serialize_secret};
+/// How long before a token expires we treat it as expired, to allow for
+/// clock skew and requests in flight.
+pub(crate) const DEFAULT_EXPIRY_MARGIN: Duration = Duration::seconds(60);
 
 #[derive(Debug, Serialize, Deserialize)]
 enum GrantType {
@@ -63,10 +63,13 @@ pub struct ClientCreds {
 pub(crate) struct Authenticator {
     client: Client,
     env: Environment,
-    token_path: PathBuf,
+    tokens: Arc<dyn TokenStore>,
     credentials: ClientCreds,
     cached_auth_data: Mutex<Option<AuthData>>,
     retry_policy: RetryPolicy,
+    /// Shared with the client, as token requests count towards its limit.
+    limiter: Arc<RateLimiter>,
+    expiry_margin: Duration,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -81,13 +84,42 @@ pub struct AuthData {
     redirect_uri: String,
     #[serde(default)]
     authed_at: Option<DateTime<Utc>>,
+    /// When the current refresh token was issued; TrueLayer issues a new
+    /// one with each refresh.
+    #[serde(default)]
+    refreshed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    environment: Option<Environment>,
+}
+
+impl ClientCreds {
+    pub fn new(id: String, secret: SecretString) -> Self {
+        Self { id, secret }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn secret(&self) -> &SecretString {
+        &self.secret
+    }
+}
+
+/// The stored token was issued for a different environment than configured.
+#[derive(Debug)]
+pub struct EnvironmentMismatch {
+    pub token: Environment,
+    pub configured: Environment,
+    /// Where the token was read from.
+    pub token_store: String,
 }
 
 impl Authenticator {
     pub(crate) fn new(
         client: Client,
         env: Environment,
-        token_path: PathBuf,
+        tokens: Arc<dyn TokenStore>,
         credentials: &ClientCreds,
     ) -> Authenticator {
         let retry_policy =
@@ -96,13 +128,35 @@ impl Authenticator {
         Self {
             client,
             env,
-            token_path,
+            tokens,
             credentials: credentials.clone(),
             cached_auth_data: Mutex::new(None),
             retry_policy,
+            limiter: Arc::new(RateLimiter::unlimited()),
+            expiry_margin: DEFAULT_EXPIRY_MARGIN,
         }
     }
 
+    pub(crate) fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub(crate) fn with_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    pub(crate) fn with_token_store(mut self, tokens: Arc<dyn TokenStore>) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    pub(crate) fn with_expiry_margin(mut self, margin: Duration) -> Self {
+        self.expiry_margin = margin;
+        self
+    }
+
     pub fn client_id(&self) -> &str {
         &self.credentials.id
     }
@@ -120,8 +174,11 @@ impl Authenticator {
             AuthData::from_response(token_response, fetched_at, redirect_uri.to_owned())?;
 
         state.authed_at = Some(fetched_at);
+        state.environment = Some(self.env.clone());
 
+        let _lock = self.tokens.lock().await?;
         self.write_auth_data(&state).await?;
+        *self.cached_auth_data.lock().await = Some(state);
 
         Ok(())
     }
@@ -132,19 +189,29 @@ impl Authenticator {
         let at: DateTime<Utc> = Utc::now();
 
         if let Some(data) = cached_auth_data.as_ref() {
-            if !data.is_expired(at) {
+            if !data.is_expired(at, self.expiry_margin) {
                 trace!("Re-used cached access token");
                 return Ok(data.access_token.clone());
             }
         }
         let data = self.read_auth_data().await?;
 
-        if !data.is_expired(at) {
+        if !data.is_expired(at, self.expiry_margin) {
             trace!("Re-used read access token");
             *cached_auth_data = Some(data.clone());
             return Ok(data.access_token);
         }
 
+        // Another process may have refreshed it while we waited, in which
+        // case our refresh token will have been invalidated.
+        let _lock = self.tokens.lock().await?;
+        let data = self.read_auth_data().await?;
+        if !data.is_expired(at, self.expiry_margin) {
+            debug!("Access token refreshed elsewhere");
+            *cached_auth_data = Some(data.clone());
+            return Ok(data.access_token);
+        }
+
         debug!("Access token expired, refreshing");
         let data = self.refresh_access_token(&data, at).await?;
         self.write_auth_data(&data).await?;
@@ -153,6 +220,80 @@ impl Authenticator {
         Ok(data.access_token)
     }
 
+    /// Refreshes the access token regardless of its expiry, which also
+    /// keeps the refresh token alive. Returns the new expiry.
+    #[instrument(skip_all)]
+    pub(crate) async fn refresh(&self) -> Result<DateTime<Utc>> {
+        let mut cached_auth_data = self.cached_auth_data.lock().await;
+        let _lock = self.tokens.lock().await?;
+        let at: DateTime<Utc> = Utc::now();
+        let data = self.read_auth_data().await?;
+        let data = self.refresh_access_token(&data, at).await?;
+        self.write_auth_data(&data).await?;
+        debug!(expires_at=%data.expires_at, "Refreshed access token");
+        let expires_at = data.expires_at;
+        *cached_auth_data = Some(data);
+        Ok(expires_at)
+    }
+
+    /// Deletes the connection at TrueLayer, then the local token file.
+    #[instrument(skip_all)]
+    pub(crate) async fn revoke(&self) -> Result<()> {
+        let access_token = self.access_token().await?;
+        let url = self
+            .env
+            .auth_url_builder()
+            .path_and_query("/api/delete")
+            .build()?;
+        self.retry_policy
+            .retry(|| async {
+                self.limiter.acquire().await;
+                let res = self
+                    .client
+                    .delete(url.to_string())
+                    .bearer_auth(access_token.expose_secret())
+                    .send()
+                    .await?;
+                self.limiter.observe(&res);
+                res.error_for_status()
+            })
+            .await?;
+        info!("Revoked connection");
+
+        let mut cached_auth_data = self.cached_auth_data.lock().await;
+        self.tokens.remove().await?;
+        *cached_auth_data = None;
+        debug!(tokens=%self.tokens, "Removed token");
+        Ok(())
+    }
+
+    pub(crate) async fn token_expires_at(&self) -> Result<DateTime<Utc>> {
+        Ok(self.current_auth_data().await?.expires_at)
+    }
+
+    pub(crate) async fn authed_at(&self) -> Result<Option<DateTime<Utc>>> {
+        Ok(self.current_auth_data().await?.authed_at)
+    }
+
+    pub(crate) async fn refresh_token_issued_at(&self) -> Result<Option<DateTime<Utc>>> {
+        let auth_data = self.current_auth_data().await?;
+        Ok(auth_data.refreshed_at.or(auth_data.authed_at))
+    }
+
+    pub(crate) async fn granted_scopes(&self) -> Result<Vec<String>> {
+        let scope = self.current_auth_data().await?.scope;
+        Ok(scope
+            .map(|scope| scope.split_whitespace().map(str::to_owned).collect())
+            .unwrap_or_default())
+    }
+
+    async fn current_auth_data(&self) -> Result<AuthData> {
+        if let Some(data) = self.cached_auth_data.lock().await.as_ref() {
+            return Ok(data.clone());
+        }
+        self.read_auth_data().await
+    }
+
     async fn fetch_access_token(
         &self,
         access_code: &Secret<String>,
@@ -171,7 +312,7 @@ impl Authenticator {
             code: Some(access_code.clone()),
             refresh_token: None,
         };
-        let token_response = perform_request(&self.retry_policy, || {
+        let token_response = perform_request(&self.retry_policy, &self.limiter, None, None, || {
             self.client
                 .post(url.to_string())
                 .form(&fetch_access_token_request)
@@ -195,47 +336,56 @@ impl Authenticator {
             refresh_token: Some(data.refresh_token.clone()),
         };
 
-        let token_response = perform_request(&self.retry_policy, || {
+        let token_response = perform_request(&self.retry_policy, &self.limiter, None, None, || {
             self.client
                 .post(url.to_string())
                 .form(&fetch_access_token_request)
         })
-        .await?;
+        .await
+        .map_err(|error| match error.api_error() {
+            // The refresh token has expired or been revoked.
+            Some(api_error) if api_error.error == "invalid_grant" => {
+                Error::ReauthenticationRequired {
+                    provider: None,
+                    reason: api_error.clone(),
+                }
+            }
+            _ => error,
+        })?;
 
         data.update_from_response(token_response, at, data.redirect_uri.clone())
     }
 
-    async fn read_auth_data(&self) -> Result<AuthData, anyhow::Error> {
-        let token_path = self.token_path.to_owned();
+    async fn read_auth_data(&self) -> Result<AuthData> {
+        let Some(data) = self.tokens.load().await? else {
+            return Err(Error::Auth(format!(
+                "No cached authentication token: {}",
+                self.tokens
+            )));
+        };
 
-        let data: AuthData = spawn_blocking(move || match File::open(&token_path) {
-            Ok(f) => Ok(serde_json::from_reader(f)?),
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                bail!("No cached authentication token: {:?}", token_path)
-            }
-            Err(e) => Err(e.into()),
-        })
-        .await??;
+        debug!(tokens=%self.tokens, "Read access token");
 
-        debug!(token_path=?self.token_path, "Read access token");
+        match data.environment {
+            Some(token) if token != self.env => {
+                return Err(EnvironmentMismatch {
+                    token,
+                    configured: self.env.clone(),
+                    token_store: self.tokens.to_string(),
+                }
+                .into())
+            }
+            Some(_) => {}
+            None => {
+                warn!(tokens=%self.tokens, "Token does not record its environment; re-authenticate to check it")
+            }
+        }
 
         Ok(data)
     }
 
     async fn write_auth_data(&self, state: &AuthData) -> Result<()> {
-        let state = state.clone();
-        let token_path = self.token_path.to_owned();
-        let span = Span::current();
-        spawn_blocking(move || {
-            let _entered = span.enter();
-            let mut tmpf = NamedTempFile::new_in(".")?;
-            serde_json::to_writer_pretty(&mut tmpf, &state)?;
-            tmpf.as_file_mut().flush()?;
-            tmpf.persist(&token_path)?;
-            debug!(?token_path, "Stored auth data");
-            Ok(())
-        })
-        .await?
+        self.tokens.save(state).await
     }
 }
 
@@ -258,11 +408,14 @@ impl AuthData {
             token_type,
             scope,
             expires_at: fetched_at
-                + Duration::try_seconds(expires_in)
-                    .ok_or_else(|| anyhow!("Invalid expires_in provided: {}", expires_in))?,
+                + Duration::try_seconds(expires_in).ok_or_else(|| {
+                    Error::InvalidResponse(format!("Invalid expires_in: {}", expires_in))
+                })?,
             refresh_token,
             redirect_uri,
             authed_at: None,
+            refreshed_at: Some(fetched_at),
+            environment: None,
         };
         Ok(auth_data)
     }
@@ -286,16 +439,32 @@ impl AuthData {
             token_type,
             scope,
             expires_at: fetched_at
-                + Duration::try_seconds(expires_in)
-                    .ok_or_else(|| anyhow!("Invalid expires_in provided: {}", expires_in))?,
+                + Duration::try_seconds(expires_in).ok_or_else(|| {
+                    Error::InvalidResponse(format!("Invalid expires_in: {}", expires_in))
+                })?,
             refresh_token,
             redirect_uri,
+            refreshed_at: Some(fetched_at),
             ..self.clone()
         };
         Ok(auth_data)
     }
 
-    fn is_expired(&self, at: DateTime<Utc>) -> bool {
-        self.expires_at <= at
+    /// Whether the token has expired, or will within `margin` of `at`, so
+    /// that it doesn't expire while a request is in flight.
+    fn is_expired(&self, at: DateTime<Utc>, margin: Duration) -> bool {
+        self.expires_at - margin <= at
     }
 }
+
+impl fmt::Display for EnvironmentMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Token from {} was issued for the {:?} environment, but {:?} is configured",
+            self.token_store, self.token, self.configured
+        )
+    }
+}
+
+impl std::error::Error for EnvironmentMismatch {}
@@ -0,0 +1,232 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use again::RetryPolicy;
+
+use crate::{
+    Cassette, ClientCreds, Environment, Error, HttpCache, MainConfig, PoolConfig, Result, TlClient,
+    TokenStore,
+};
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Configures the HTTP client underlying a [`TlClient`].
+#[derive(Clone)]
+pub struct TlClientBuilder {
+    env: Environment,
+    token_path: PathBuf,
+    credentials: ClientCreds,
+    request_timeout: Duration,
+    connect_timeout: Option<Duration>,
+    proxy: Option<String>,
+    user_agent: String,
+    pool: PoolConfig,
+    retry_policy: Option<RetryPolicy>,
+    requests_per_second: Option<f64>,
+    rate_limit_floor: Option<u64>,
+    token_store: Option<Arc<dyn TokenStore>>,
+    expiry_margin: Option<chrono::Duration>,
+    async_max_wait: Option<Duration>,
+    cassette: Option<Cassette>,
+    http_cache: Option<HttpCache>,
+    scopes: Option<Vec<String>>,
+    provider_filter: Option<String>,
+}
+
+impl TlClientBuilder {
+    pub fn new(env: Environment, token_path: &Path, credentials: &ClientCreds) -> Self {
+        Self {
+            env,
+            token_path: token_path.to_owned(),
+            credentials: credentials.clone(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            connect_timeout: None,
+            proxy: None,
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            pool: PoolConfig::default(),
+            retry_policy: None,
+            requests_per_second: None,
+            rate_limit_floor: None,
+            token_store: None,
+            expiry_margin: None,
+            async_max_wait: None,
+            cassette: None,
+            http_cache: None,
+            scopes: None,
+            provider_filter: None,
+        }
+    }
+
+    /// Applies the HTTP, retry and rate limit settings from `config`.
+    pub fn with_config(mut self, config: &MainConfig) -> Self {
+        if let Some(timeout) = config.request_timeout_s {
+            self.request_timeout = Duration::from_secs(timeout);
+        }
+        self.connect_timeout = config.connect_timeout_s.map(Duration::from_secs);
+        self.proxy = config.proxy.clone();
+        if let Some(user_agent) = config.user_agent.as_ref() {
+            self.user_agent = user_agent.clone();
+        }
+        self.pool = config.http_pool.clone();
+        self.retry_policy = Some(config.retry.policy());
+        self.requests_per_second = config.requests_per_second;
+        self.rate_limit_floor = config.rate_limit_floor;
+        self.expiry_margin = config
+            .token_expiry_margin_s
+            .map(|secs| chrono::Duration::seconds(secs as i64));
+        self.cassette = config.cassette.clone();
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Send all requests via the proxy at `url`.
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_owned());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_owned();
+        self
+    }
+
+    /// Tune connection reuse, eg: for long backfills.
+    pub fn pool(mut self, pool: PoolConfig) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.requests_per_second = Some(requests_per_second);
+        self
+    }
+
+    /// See [`TlClient::with_rate_limit_floor`].
+    pub fn rate_limit_floor(mut self, floor: u64) -> Self {
+        self.rate_limit_floor = Some(floor);
+        self
+    }
+
+    /// Keep tokens in `tokens` rather than at the token path.
+    pub fn token_store(mut self, tokens: Arc<dyn TokenStore>) -> Self {
+        self.token_store = Some(tokens);
+        self
+    }
+
+    /// See [`TlClient::with_expiry_margin`].
+    pub fn expiry_margin(mut self, margin: chrono::Duration) -> Self {
+        self.expiry_margin = Some(margin);
+        self
+    }
+
+    /// See [`TlClient::with_async_data`].
+    pub fn async_data(mut self, max_wait: Duration) -> Self {
+        self.async_max_wait = Some(max_wait);
+        self
+    }
+
+    /// See [`TlClient::with_cassette`].
+    pub fn cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// See [`TlClient::with_http_cache`].
+    pub fn http_cache(mut self, cache: HttpCache) -> Self {
+        self.http_cache = Some(cache);
+        self
+    }
+
+    /// See [`TlClient::with_scopes`].
+    pub fn scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = Some(scopes);
+        self
+    }
+
+    /// See [`TlClient::with_provider_filter`].
+    pub fn provider_filter(mut self, filter: &str) -> Self {
+        self.provider_filter = Some(filter.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<TlClient> {
+        let mut http = reqwest::Client::builder()
+            .timeout(self.request_timeout)
+            .user_agent(self.user_agent);
+        if let Some(timeout) = self.connect_timeout {
+            http = http.connect_timeout(timeout);
+        }
+        if let Some(proxy) = self.proxy.as_ref() {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| Error::Config(format!("Proxy {:?}: {}", proxy, e)))?;
+            http = http.proxy(proxy);
+        }
+        if let Some(max_idle) = self.pool.max_idle_per_host {
+            http = http.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(timeout) = self.pool.idle_timeout_s {
+            http = http.pool_idle_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(interval) = self.pool.tcp_keepalive_s {
+            http = http.tcp_keepalive(Duration::from_secs(interval));
+        }
+        if self.pool.http2_prior_knowledge {
+            http = http.http2_prior_knowledge();
+        }
+        if let Some(interval) = self.pool.http2_keep_alive_interval_s {
+            http = http
+                .http2_keep_alive_interval(Duration::from_secs(interval))
+                .http2_keep_alive_while_idle(true);
+        }
+        let http = http.build()?;
+
+        let mut client = TlClient::new(http, self.env, &self.token_path, &self.credentials)
+            .with_rate_limit(self.requests_per_second);
+        if let Some(floor) = self.rate_limit_floor {
+            client = client.with_rate_limit_floor(floor);
+        }
+        if let Some(retry_policy) = self.retry_policy {
+            client = client.with_retry_policy(retry_policy);
+        }
+        if let Some(tokens) = self.token_store {
+            client = client.with_token_store(tokens);
+        }
+        if let Some(margin) = self.expiry_margin {
+            client = client.with_expiry_margin(margin);
+        }
+        if let Some(max_wait) = self.async_max_wait {
+            client = client.with_async_data(max_wait);
+        }
+        if let Some(cassette) = self.cassette {
+            client = client.with_cassette(cassette);
+        }
+        if let Some(cache) = self.http_cache {
+            client = client.with_http_cache(cache);
+        }
+        if let Some(scopes) = self.scopes {
+            client = client.with_scopes(scopes);
+        }
+        if let Some(filter) = self.provider_filter {
+            client = client.with_provider_filter(filter);
+        }
+        Ok(client)
+    }
+}
@@ -0,0 +1,124 @@
+//! Recording API responses to disk, and serving them back in place of the
+//! network, so that we can debug against payloads we've seen before.
+//!
+//! Each response is kept in its own file in the cassette directory, named
+//! after the request's method, path and query. Token requests are never
+//! recorded, as their responses hold secrets.
+
+use std::{collections::BTreeMap, io, path::PathBuf};
+
+use hyper::http;
+use reqwest::{Client, Request, ResponseBuilderExt};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{Error, Result};
+
+const BODY_HEADERS: &[http::HeaderName] = &[
+    http::header::CONTENT_LENGTH,
+    http::header::CONTENT_ENCODING,
+    http::header::TRANSFER_ENCODING,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cassette {
+    /// Make requests as usual, and save each response into the directory.
+    Record(PathBuf),
+    /// Answer requests from the directory, rather than the network.
+    Replay(PathBuf),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Recording {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    /// Kept as JSON where possible, to make recordings easier to read.
+    body: serde_json::Value,
+}
+
+impl Cassette {
+    pub fn is_replay(&self) -> bool {
+        matches!(self, Cassette::Replay(_))
+    }
+
+    fn dir(&self) -> &PathBuf {
+        match self {
+            Cassette::Record(dir) | Cassette::Replay(dir) => dir,
+        }
+    }
+
+    pub(crate) async fn send(&self, client: &Client, req: Request) -> Result<reqwest::Response> {
+        let path = self.dir().join(file_name(&req));
+        match self {
+            Cassette::Record(_) => {
+                let res = client.execute(req).await?;
+                let url = res.url().clone();
+                let recording = Recording {
+                    status: res.status().as_u16(),
+                    headers: res
+                        .headers()
+                        .iter()
+                        // The body is stored decoded, and may be re-encoded differently.
+                        .filter(|(name, _)| !BODY_HEADERS.contains(name))
+                        .filter_map(|(name, value)| {
+                            Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                        })
+                        .collect(),
+                    body: {
+                        let text = res.text().await?;
+                        serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text))
+                    },
+                };
+                tokio::fs::create_dir_all(self.dir()).await?;
+                let json = serde_json::to_vec_pretty(&recording)?;
+                tokio::fs::write(&path, json).await?;
+                debug!(?path, "Recorded response");
+                recording.into_response(url)
+            }
+            Cassette::Replay(_) => {
+                let json = match tokio::fs::read(&path).await {
+                    Ok(json) => json,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        return Err(Error::NotRecorded {
+                            request: format!("{} {}", req.method(), req.url()),
+                            path,
+                        })
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let recording: Recording = serde_json::from_slice(&json)?;
+                debug!(?path, "Replayed response");
+                recording.into_response(req.url().clone())
+            }
+        }
+    }
+}
+
+impl Recording {
+    fn into_response(self, url: url::Url) -> Result<reqwest::Response> {
+        let mut builder = http::Response::builder().status(self.status).url(url);
+        for (name, value) in self.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        let body = match self.body {
+            serde_json::Value::String(text) => text,
+            json => json.to_string(),
+        };
+        let res = builder
+            .body(body)
+            .map_err(|e| Error::InvalidResponse(format!("Recorded response: {}", e)))?;
+        Ok(res.into())
+    }
+}
+
+/// Ignores the host, so recordings can be replayed against any environment.
+fn file_name(req: &Request) -> String {
+    let url = req.url();
+    let mut key = format!("{} {}", req.method(), url.path());
+    if let Some(query) = url.query() {
+        key.push('?');
+        key.push_str(query);
+    }
+    format!("{}.json", urlencoding::encode(&key))
+}
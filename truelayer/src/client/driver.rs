@@ -1,26 +1,72 @@
-use std::{path::Path, time::Duration};
+use std::{
+    ops::RangeInclusive,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use again::RetryPolicy;
-use anyhow::Result;
 use chrono::{DateTime, NaiveDate, Utc};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use hyper::{http::uri, Uri};
 use reqwest::Client;
 use rust_decimal::Decimal;
 use secrecy::{ExposeSecret, Secret};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::debug;
 
-use crate::{client::authentication::Authenticator, perform_request, ClientCreds};
+use crate::{
+    client::{
+        authentication::Authenticator, Cassette, FileTokenStore, HttpCache, RateLimitQuota,
+        RateLimiter, TlClientBuilder, TokenStore,
+    },
+    perform_request, ClientCreds, Error, Result,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response<T> {
     #[serde(rename = "results")]
     pub results: Vec<T>,
+    /// Where to fetch the rest of the results from, when truncated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserInfoResult {
     #[serde(rename = "full_name")]
     pub full_name: String,
+    #[serde(flatten)]
+    pub other: serde_json::Value,
+}
+
+/// Metadata about the connection to the provider, from `/data/v1/me`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consent_status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consent_created_at: Option<DateTime<Utc>>,
+    /// When the user will next need to grant consent again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consent_expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<ConnectionProvider>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(flatten)]
+    pub other: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionProvider {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub provider_id: String,
+    #[serde(flatten)]
+    pub other: serde_json::Value,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -35,6 +81,8 @@ pub struct AccountsResult {
     #[serde(rename = "account_number")]
     pub account_number: AccountNumber,
     pub provider: AccountsProvider,
+    #[serde(flatten)]
+    pub other: serde_json::Value,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -81,6 +129,8 @@ pub struct CardsResult {
     #[serde(rename = "valid_to")]
     pub valid_to: Option<String>,
     pub provider: CardsProvider,
+    #[serde(flatten)]
+    pub other: serde_json::Value,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -101,6 +151,8 @@ pub struct BalanceResult {
     pub available: Decimal,
     pub current: Decimal,
     pub overdraft: Option<Decimal>,
+    #[serde(flatten)]
+    pub other: serde_json::Value,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -134,7 +186,35 @@ pub struct TransactionsResult {
     pub merchant_name: Option<String>,
     #[serde(rename = "running_balance")]
     pub running_balance: Option<TransactionsRunningBalance>,
-    pub meta: serde_json::Value,
+    #[serde(default)]
+    pub meta: TransactionMeta,
+    #[serde(flatten)]
+    pub other: serde_json::Value,
+}
+
+/// The provider specific details of a transaction. Which of these are
+/// present varies by provider; anything else they send ends up in `other`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionMeta {
+    /// The provider's own code for the kind of transaction, eg: `DEB`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_transaction_category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_merchant_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter_party_preferred_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter_party_iban: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debtor_account_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creditor_account_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
     #[serde(flatten)]
     pub other: serde_json::Value,
 }
@@ -148,21 +228,37 @@ pub struct TransactionsRunningBalance {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StandingOrderResult {
     #[serde(flatten)]
-    inner: serde_json::Value,
+    pub(crate) inner: serde_json::Value,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DirectDebitResult {
     #[serde(flatten)]
-    inner: serde_json::Value,
+    pub(crate) inner: serde_json::Value,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledPaymentResult {
+    #[serde(flatten)]
+    pub(crate) inner: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BeneficiaryResult {
+    #[serde(flatten)]
+    pub(crate) inner: serde_json::Value,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub enum Environment {
     #[serde(rename = "sandbox")]
     Sandbox,
     #[serde(rename = "live")]
     Live,
+    /// Somewhere else, such as a mock server. Only the scheme and authority
+    /// of each base URL are used.
+    #[serde(rename = "custom")]
+    Custom { api: String, auth: String },
 }
 
 pub struct TlClient {
@@ -170,6 +266,32 @@ pub struct TlClient {
     env: Environment,
     auth: Authenticator,
     retry_policy: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+    /// Use async data retrieval, waiting up to this long for each result.
+    async_max_wait: Option<Duration>,
+    cassette: Option<Arc<Cassette>>,
+    cache: Option<Arc<HttpCache>>,
+    /// What to ask for access to when authenticating, if not the default.
+    scopes: Option<Vec<String>>,
+    /// Which banks the consent screen offers, if not all of them.
+    provider_filter: Option<String>,
+}
+
+/// What the API returns in place of the data for an async request.
+#[derive(Debug, Deserialize)]
+struct AsyncTask {
+    results_uri: String,
+    status: String,
+    task_id: String,
+}
+
+enum Page {
+    First {
+        path: String,
+        from_date: NaiveDate,
+        to_date: NaiveDate,
+    },
+    Next(String),
 }
 
 const SANDBOX_API_HOST: &str = "api.truelayer-sandbox.com";
@@ -177,6 +299,9 @@ const SANDBOX_AUTH_HOST: &str = "auth.truelayer-sandbox.com";
 const LIVE_API_HOST: &str = "api.truelayer.com";
 const LIVE_AUTH_HOST: &str = "auth.truelayer.com";
 
+const ASYNC_POLL_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const ASYNC_POLL_MAX_DELAY: Duration = Duration::from_secs(30);
+
 impl TlClient {
     pub fn new(
         client: reqwest::Client,
@@ -184,19 +309,122 @@ impl TlClient {
         token_path: &Path,
         credentials: &ClientCreds,
     ) -> Self {
-        let token_path = token_path.to_owned();
-        let auth = Authenticator::new(client.clone(), env, token_path, credentials);
+        let tokens = Arc::new(FileTokenStore::new(token_path));
+        let limiter = Arc::new(RateLimiter::unlimited());
+        let auth = Authenticator::new(client.clone(), env.clone(), tokens, credentials)
+            .with_limiter(limiter.clone());
         let retry_policy = RetryPolicy::exponential(Duration::from_secs(1)).with_jitter(true);
         Self {
             client,
             env,
             auth,
             retry_policy,
+            limiter,
+            async_max_wait: None,
+            cassette: None,
+            cache: None,
+            scopes: None,
+            provider_filter: None,
         }
     }
 
-    pub fn env(&self) -> Environment {
-        self.env
+    /// Use `retry_policy` for both API and token requests.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.auth = self.auth.with_retry_policy(retry_policy.clone());
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Keep tokens in `tokens` rather than the file given to [`TlClient::new`].
+    pub fn with_token_store(mut self, tokens: Arc<dyn TokenStore>) -> Self {
+        self.auth = self.auth.with_token_store(tokens);
+        self
+    }
+
+    /// Refresh the access token once it's within `margin` of expiring,
+    /// rather than the default of a minute.
+    pub fn with_expiry_margin(mut self, margin: chrono::Duration) -> Self {
+        self.auth = self.auth.with_expiry_margin(margin);
+        self
+    }
+
+    /// Ask for data asynchronously, polling for each result for up to
+    /// `max_wait`; for providers that are slow to respond.
+    pub fn with_async_data(mut self, max_wait: Duration) -> Self {
+        self.async_max_wait = Some(max_wait);
+        self
+    }
+
+    /// Record data API responses to, or replay them from, `cassette`.
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(Arc::new(cassette));
+        self
+    }
+
+    /// Make data API requests conditional on the responses cached in
+    /// `cache`, and reuse those the server says are unchanged.
+    pub fn with_http_cache(mut self, cache: HttpCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Request only `scopes` when authenticating. `offline_access` is always
+    /// requested too, as we need a refresh token.
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = Some(scopes);
+        self
+    }
+
+    /// Offer only the banks matching `filter` when authenticating, in
+    /// TrueLayer's `providers` syntax, eg: `uk-ob-monzo`.
+    pub fn with_provider_filter(mut self, filter: String) -> Self {
+        self.provider_filter = Some(filter);
+        self
+    }
+
+    /// Limit requests made with this client's token to `requests_per_second`.
+    pub fn with_rate_limit(self, requests_per_second: Option<f64>) -> Self {
+        let limiter = RateLimiter::new(requests_per_second).with_floor(self.limiter.floor());
+        self.with_limiter(limiter)
+    }
+
+    /// Pause requests once the provider reports `floor` or fewer remaining
+    /// in its rate limit quota, until the quota resets.
+    pub fn with_rate_limit_floor(self, floor: u64) -> Self {
+        let limiter = RateLimiter::new(self.limiter.rate()).with_floor(floor);
+        self.with_limiter(limiter)
+    }
+
+    /// Token requests are limited along with the rest.
+    fn with_limiter(mut self, limiter: RateLimiter) -> Self {
+        let limiter = Arc::new(limiter);
+        self.auth = self.auth.with_limiter(limiter.clone());
+        self.limiter = limiter;
+        self
+    }
+
+    /// The provider's rate limit quota, as of the latest response that
+    /// reported one.
+    pub fn rate_limit_quota(&self) -> Option<RateLimitQuota> {
+        self.limiter.quota()
+    }
+
+    pub fn builder(
+        env: Environment,
+        token_path: &Path,
+        credentials: &ClientCreds,
+    ) -> TlClientBuilder {
+        TlClientBuilder::new(env, token_path, credentials)
+    }
+
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+    pub fn scopes(&self) -> Option<&[String]> {
+        self.scopes.as_deref()
+    }
+    pub fn provider_filter(&self) -> Option<&str> {
+        self.provider_filter.as_deref()
     }
     pub fn client_id(&self) -> &str {
         self.auth.client_id()
@@ -212,20 +440,76 @@ impl TlClient {
         Ok(())
     }
 
+    /// Disconnects the provider and deletes the local token.
+    pub async fn revoke_token(&self) -> Result<()> {
+        self.auth.revoke().await
+    }
+
+    /// Refreshes the access token now; returns when the new one expires.
+    pub async fn refresh_token(&self) -> Result<DateTime<Utc>> {
+        self.auth.refresh().await
+    }
+
+    /// When the current access token expires; fails if we hold no token.
+    pub async fn token_expires_at(&self) -> Result<DateTime<Utc>> {
+        self.auth.token_expires_at().await
+    }
+
+    /// When the user last went through the consent flow, if known.
+    pub async fn authed_at(&self) -> Result<Option<DateTime<Utc>>> {
+        self.auth.authed_at().await
+    }
+
+    /// When the current refresh token was issued, if known; tokens from
+    /// before we kept track fall back to when the user last authenticated.
+    pub async fn refresh_token_issued_at(&self) -> Result<Option<DateTime<Utc>>> {
+        self.auth.refresh_token_issued_at().await
+    }
+
+    /// The scopes granted with the current token, if TrueLayer said.
+    pub async fn granted_scopes(&self) -> Result<Vec<String>> {
+        self.auth.granted_scopes().await
+    }
+
+    /// Replayed requests don't need a real token, so don't try to get one.
+    async fn access_token(&self) -> Result<Secret<String>> {
+        match self.cassette.as_deref() {
+            Some(cassette) if cassette.is_replay() => Ok(Secret::new("replayed".to_owned())),
+            _ => self.auth.access_token().await,
+        }
+    }
+
     pub async fn fetch_info(&self) -> Result<Response<UserInfoResult>> {
         let url = self
             .env
             .api_url_builder()
             .path_and_query("/data/v1/info")
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let info_response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
+        self.get_data(url, None).await
+    }
+
+    pub async fn fetch_connection(&self) -> Result<Response<ConnectionResult>> {
+        let url = self
+            .env
+            .api_url_builder()
+            .path_and_query("/data/v1/me")
+            .build()?;
+        // Describes the connection rather than fetching from the provider, so
+        // is always answered directly.
+        let access_token = self.access_token().await?;
+        let me_response = perform_request(
+            &self.retry_policy,
+            &self.limiter,
+            self.cassette.as_deref(),
+            None,
+            || {
+                self.client
+                    .get(url.to_string())
+                    .bearer_auth(access_token.expose_secret())
+            },
+        )
         .await?;
-        Ok(info_response)
+        Ok(me_response)
     }
 
     pub async fn fetch_accounts(&self) -> Result<Response<AccountsResult>> {
@@ -234,14 +518,7 @@ impl TlClient {
             .api_url_builder()
             .path_and_query("/data/v1/accounts")
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let info_response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(info_response)
+        self.get_data(url, None).await
     }
 
     pub async fn account_balance(&self, account_id: &str) -> Result<BalanceResponse> {
@@ -253,14 +530,7 @@ impl TlClient {
                 account = urlencoding::encode(account_id)
             ))
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(response)
+        self.get_data(url, None).await
     }
 
     pub async fn account_pending(&self, account_id: &str) -> Result<Response<TransactionsResult>> {
@@ -272,14 +542,7 @@ impl TlClient {
                 account = urlencoding::encode(account_id)
             ))
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(response)
+        self.get_data(url, None).await
     }
 
     pub async fn account_standing_orders(
@@ -294,14 +557,7 @@ impl TlClient {
                 account = urlencoding::encode(account_id)
             ))
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(response)
+        self.get_data(url, None).await
     }
 
     pub async fn account_direct_debits(
@@ -316,39 +572,87 @@ impl TlClient {
                 account = urlencoding::encode(account_id)
             ))
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(response)
+        self.get_data(url, None).await
     }
 
-    pub async fn account_transactions(
+    /// Future dated one-off payments. Not every provider offers these, and
+    /// those that don't respond with `501 Not Implemented`.
+    pub async fn account_scheduled_payments(
         &self,
         account_id: &str,
-        from_date: NaiveDate,
-        to_date: NaiveDate,
-    ) -> Result<Response<TransactionsResult>> {
+    ) -> Result<Response<ScheduledPaymentResult>> {
         let url = self
             .env
             .api_url_builder()
             .path_and_query(format!(
-                "/data/v1/accounts/{account}/transactions",
+                "/data/v1/accounts/{account}/scheduled_payments",
                 account = urlencoding::encode(account_id)
             ))
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .query(&[("from", &from_date), ("to", &to_date)])
-                .bearer_auth(access_token.expose_secret())
+        self.get_data(url, None).await
+    }
+
+    /// The payees set up on the account; as with scheduled payments, only
+    /// where the provider offers them.
+    pub async fn account_beneficiaries(
+        &self,
+        account_id: &str,
+    ) -> Result<Response<BeneficiaryResult>> {
+        let url = self
+            .env
+            .api_url_builder()
+            .path_and_query(format!(
+                "/data/v1/accounts/{account}/beneficiaries",
+                account = urlencoding::encode(account_id)
+            ))
+            .build()?;
+        self.get_data(url, None).await
+    }
+
+    /// Fetches every page of transactions in the date range.
+    pub async fn account_transactions(
+        &self,
+        account_id: &str,
+        from_date: NaiveDate,
+        to_date: NaiveDate,
+    ) -> Result<Response<TransactionsResult>> {
+        let results = self
+            .account_transactions_paged(account_id, from_date, to_date)
+            .try_collect()
+            .await?;
+        Ok(Response {
+            results,
+            next: None,
         })
-        .await?;
-        Ok(response)
+    }
+
+    pub fn account_transactions_paged(
+        &self,
+        account_id: &str,
+        from_date: NaiveDate,
+        to_date: NaiveDate,
+    ) -> impl Stream<Item = Result<TransactionsResult>> + Send + '_ {
+        let path = format!(
+            "/data/v1/accounts/{account}/transactions",
+            account = urlencoding::encode(account_id)
+        );
+        self.paginate(path, from_date, to_date)
+    }
+
+    /// Every transaction on the account over `period`, fetched a month at a
+    /// time from the earliest, so that long periods needn't be held in
+    /// memory at once.
+    pub fn stream_account_transactions(
+        &self,
+        account_id: &str,
+        period: RangeInclusive<NaiveDate>,
+    ) -> impl Stream<Item = Result<TransactionsResult>> + Send + '_ {
+        let account_id = account_id.to_owned();
+        stream::iter(scraper_sdk::months(period))
+            .map(move |month| {
+                self.account_transactions_paged(&account_id, *month.start(), *month.end())
+            })
+            .flatten()
     }
 
     pub async fn fetch_cards(&self) -> Result<Response<CardsResult>> {
@@ -357,14 +661,7 @@ impl TlClient {
             .api_url_builder()
             .path_and_query("/data/v1/cards")
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(response)
+        self.get_data(url, None).await
     }
 
     pub async fn card_balance(&self, card_id: &str) -> Result<BalanceResponse> {
@@ -376,14 +673,7 @@ impl TlClient {
                 account = urlencoding::encode(card_id)
             ))
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(response)
+        self.get_data(url, None).await
     }
 
     pub async fn card_pending(&self, account_id: &str) -> Result<Response<TransactionsResult>> {
@@ -395,56 +685,245 @@ impl TlClient {
                 account = urlencoding::encode(account_id)
             ))
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
-                .get(url.to_string())
-                .bearer_auth(access_token.expose_secret())
-        })
-        .await?;
-        Ok(response)
+        self.get_data(url, None).await
     }
 
+    /// Fetches every page of transactions in the date range.
     pub async fn card_transactions(
         &self,
         card_id: &str,
         from_date: NaiveDate,
         to_date: NaiveDate,
     ) -> Result<Response<TransactionsResult>> {
+        let results = self
+            .card_transactions_paged(card_id, from_date, to_date)
+            .try_collect()
+            .await?;
+        Ok(Response {
+            results,
+            next: None,
+        })
+    }
+
+    pub fn card_transactions_paged(
+        &self,
+        card_id: &str,
+        from_date: NaiveDate,
+        to_date: NaiveDate,
+    ) -> impl Stream<Item = Result<TransactionsResult>> + Send + '_ {
+        let path = format!(
+            "/data/v1/cards/{account}/transactions",
+            account = urlencoding::encode(card_id)
+        );
+        self.paginate(path, from_date, to_date)
+    }
+
+    /// Every transaction on the card over `period`, a month at a time; see
+    /// [`TlClient::stream_account_transactions`].
+    pub fn stream_card_transactions(
+        &self,
+        card_id: &str,
+        period: RangeInclusive<NaiveDate>,
+    ) -> impl Stream<Item = Result<TransactionsResult>> + Send + '_ {
+        let card_id = card_id.to_owned();
+        stream::iter(scraper_sdk::months(period))
+            .map(move |month| self.card_transactions_paged(&card_id, *month.start(), *month.end()))
+            .flatten()
+    }
+
+    /// Follows `next` links from the first page at `path` until exhausted.
+    fn paginate<T: DeserializeOwned + Send + 'static>(
+        &self,
+        path: String,
+        from_date: NaiveDate,
+        to_date: NaiveDate,
+    ) -> impl Stream<Item = Result<T>> + Send + '_ {
+        let first = Page::First {
+            path,
+            from_date,
+            to_date,
+        };
+        stream::try_unfold(Some(first), move |page| async move {
+            let Some(page) = page else {
+                return Ok::<_, Error>(None);
+            };
+            let response: Response<T> = self.fetch_page(page).await?;
+            let next = response.next.map(Page::Next);
+            if next.is_some() {
+                debug!(count=%response.results.len(), "Following next page");
+            }
+            let results = stream::iter(response.results.into_iter().map(Ok));
+            Ok(Some((results, next)))
+        })
+        .try_flatten()
+    }
+
+    async fn fetch_page<T: DeserializeOwned>(&self, page: Page) -> Result<Response<T>> {
+        let (path_and_query, query) = match page {
+            Page::First {
+                path,
+                from_date,
+                to_date,
+            } => (path, Some([("from", from_date), ("to", to_date)])),
+            // Only ever send our token to the API host.
+            Page::Next(link) => {
+                let parsed = link.parse::<Uri>().map_err(|e| {
+                    Error::InvalidResponse(format!("Next page link {:?}: {}", link, e))
+                })?;
+                let path_and_query = parsed.path_and_query().ok_or_else(|| {
+                    Error::InvalidResponse(format!("Next page link has no path: {:?}", link))
+                })?;
+                (path_and_query.to_string(), None)
+            }
+        };
         let url = self
             .env
             .api_url_builder()
-            .path_and_query(format!(
-                "/data/v1/cards/{account}/transactions",
-                account = urlencoding::encode(card_id)
-            ))
+            .path_and_query(path_and_query)
             .build()?;
-        let access_token = self.auth.access_token().await?;
-        let response = perform_request(&self.retry_policy, || {
-            self.client
+        self.get_data(url, query).await
+    }
+
+    /// Fetches `url`, via TrueLayer's async data retrieval when enabled, so
+    /// that slow providers can take their time without our request timing
+    /// out.
+    async fn get_data<R: DeserializeOwned>(
+        &self,
+        url: Uri,
+        query: Option<[(&str, NaiveDate); 2]>,
+    ) -> Result<R> {
+        let access_token = self.access_token().await?;
+        let request = |async_data: bool| {
+            let mut req = self
+                .client
                 .get(url.to_string())
-                .query(&[("from", &from_date), ("to", &to_date)])
-                .bearer_auth(access_token.expose_secret())
-        })
+                .bearer_auth(access_token.expose_secret());
+            if let Some(query) = query.as_ref() {
+                req = req.query(query);
+            }
+            if async_data {
+                req = req.query(&[("async", "true")]);
+            }
+            req
+        };
+        let Some(max_wait) = self.async_max_wait else {
+            return perform_request(
+                &self.retry_policy,
+                &self.limiter,
+                self.cassette.as_deref(),
+                self.cache.as_deref(),
+                || request(false),
+            )
+            .await;
+        };
+        let task: AsyncTask = perform_request(
+            &self.retry_policy,
+            &self.limiter,
+            self.cassette.as_deref(),
+            None,
+            || request(true),
+        )
         .await?;
-        Ok(response)
+        debug!(task_id=%task.task_id, status=%task.status, "Queued async request");
+        self.poll_results(task, max_wait).await
+    }
+
+    /// Polls the task's results, backing off between attempts, until it
+    /// completes or `max_wait` has passed.
+    async fn poll_results<R: DeserializeOwned>(
+        &self,
+        task: AsyncTask,
+        max_wait: Duration,
+    ) -> Result<R> {
+        // Only ever send our token to the API host.
+        let link = task.results_uri.parse::<Uri>().map_err(|e| {
+            Error::InvalidResponse(format!("Results link {:?}: {}", task.results_uri, e))
+        })?;
+        let path_and_query = link.path_and_query().ok_or_else(|| {
+            Error::InvalidResponse(format!("Results link has no path: {:?}", task.results_uri))
+        })?;
+        let url = self
+            .env
+            .api_url_builder()
+            .path_and_query(path_and_query.as_str())
+            .build()?;
+
+        let started = Instant::now();
+        let mut delay = ASYNC_POLL_INITIAL_DELAY;
+        loop {
+            tokio::time::sleep(delay).await;
+            let access_token = self.access_token().await?;
+            let results: serde_json::Value = perform_request(
+                &self.retry_policy,
+                &self.limiter,
+                self.cassette.as_deref(),
+                None,
+                || {
+                    self.client
+                        .get(url.to_string())
+                        .bearer_auth(access_token.expose_secret())
+                },
+            )
+            .await?;
+            match results.get("status").and_then(|status| status.as_str()) {
+                Some("Queued") | Some("Running") => {}
+                Some("Failed") => {
+                    return Err(Error::AsyncFailed {
+                        task_id: task.task_id,
+                        results,
+                    })
+                }
+                _ => return Ok(serde_json::from_value(results)?),
+            }
+            if started.elapsed() >= max_wait {
+                return Err(Error::AsyncTimedOut {
+                    task_id: task.task_id,
+                    waited: started.elapsed(),
+                });
+            }
+            debug!(task_id=%task.task_id, ?delay, "Async request not complete yet");
+            delay = std::cmp::min(delay * 2, ASYNC_POLL_MAX_DELAY);
+        }
     }
 }
 
 impl Environment {
+    /// As it's written in the config, eg: `sandbox`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Environment::Sandbox => "sandbox",
+            Environment::Live => "live",
+            Environment::Custom { .. } => "custom",
+        }
+    }
+
     fn api_url_builder(&self) -> uri::Builder {
-        let host = match self {
-            Environment::Sandbox => SANDBOX_API_HOST,
-            Environment::Live => LIVE_API_HOST,
-        };
-        Uri::builder().scheme("https").authority(host)
+        match self {
+            Environment::Sandbox => https_builder(SANDBOX_API_HOST),
+            Environment::Live => https_builder(LIVE_API_HOST),
+            Environment::Custom { api, .. } => base_url_builder(api),
+        }
     }
 
     pub(crate) fn auth_url_builder(&self) -> uri::Builder {
-        let host = match self {
-            Environment::Sandbox => SANDBOX_AUTH_HOST,
-            Environment::Live => LIVE_AUTH_HOST,
-        };
-        Uri::builder().scheme("https").authority(host)
+        match self {
+            Environment::Sandbox => https_builder(SANDBOX_AUTH_HOST),
+            Environment::Live => https_builder(LIVE_AUTH_HOST),
+            Environment::Custom { auth, .. } => base_url_builder(auth),
+        }
+    }
+}
+
+fn https_builder(host: &str) -> uri::Builder {
+    Uri::builder().scheme("https").authority(host)
+}
+
+/// Any problem with `base` is reported when the URI is built.
+fn base_url_builder(base: &str) -> uri::Builder {
+    match base.parse::<Uri>() {
+        Ok(base) => Uri::builder()
+            .scheme(base.scheme_str().unwrap_or("https"))
+            .authority(base.authority().map_or("", |authority| authority.as_str())),
+        Err(_) => Uri::builder().authority(base),
     }
 }
@@ -0,0 +1,90 @@
+//! Conditional requests, so re-fetching data that hasn't changed costs the
+//! provider a `304 Not Modified` rather than the whole response.
+//!
+//! Each cacheable response is kept in its own file in the cache directory,
+//! named after the request's path and query, along with the `ETag` and
+//! `Last-Modified` validators it came with.
+
+use std::path::PathBuf;
+
+use hyper::http::{header, HeaderMap};
+use reqwest::Request;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use url::Url;
+
+/// Where cached responses are kept.
+#[derive(Debug, Clone)]
+pub struct HttpCache {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CachedResponse {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    pub(crate) body: serde_json::Value,
+}
+
+impl HttpCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Makes `req` conditional on the cached response for its URL, if
+    /// there is one, returning that response. Problems reading the cache are
+    /// logged, and the request made unconditionally.
+    pub(crate) async fn prepare(&self, req: &mut Request) -> Option<CachedResponse> {
+        let path = self.path(req.url());
+        let cached: CachedResponse = match scraper_sdk::load_state(&path).await {
+            Ok(cached) => cached?,
+            Err(error) => {
+                warn!(?path, %error, "Ignoring unreadable cached response");
+                return None;
+            }
+        };
+        let headers = req.headers_mut();
+        if let Some(value) = cached.etag.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(header::IF_NONE_MATCH, value);
+        }
+        if let Some(value) = cached.last_modified.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(header::IF_MODIFIED_SINCE, value);
+        }
+        Some(cached)
+    }
+
+    /// Remembers `body` as the response for `url`, if the server gave us
+    /// anything to validate it with next time.
+    pub(crate) async fn store(&self, url: &Url, headers: &HeaderMap, body: &serde_json::Value) {
+        let validator = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let (etag, last_modified) = (validator(header::ETAG), validator(header::LAST_MODIFIED));
+        if etag.is_none() && last_modified.is_none() {
+            return;
+        }
+        let path = self.path(url);
+        let cached = CachedResponse {
+            etag,
+            last_modified,
+            body: body.clone(),
+        };
+        match scraper_sdk::write_json_atomically(&path, cached).await {
+            Ok(_) => debug!(?path, "Cached response"),
+            Err(error) => warn!(?path, %error, "Failed to cache response"),
+        }
+    }
+
+    /// Ignores the host, as the cache belongs to a single provider.
+    fn path(&self, url: &Url) -> PathBuf {
+        let mut key = url.path().to_owned();
+        if let Some(query) = url.query() {
+            key.push('?');
+            key.push_str(query);
+        }
+        self.dir.join(format!("{}.json", urlencoding::encode(&key)))
+    }
+}
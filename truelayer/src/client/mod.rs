@@ -1,5 +1,24 @@
 mod authentication;
+mod builder;
+mod cassette;
 mod driver;
+mod http_cache;
+mod rate_limit;
+mod token_store;
 
-pub use authentication::ClientCreds;
-pub use driver::{AccountsResult, CardsResult, Environment, TlClient};
+pub use authentication::{AuthData, ClientCreds, EnvironmentMismatch};
+pub use builder::TlClientBuilder;
+pub use cassette::Cassette;
+pub use driver::{
+    AccountNumber, AccountsProvider, AccountsResult, BalanceResult, BeneficiaryResult,
+    CardsProvider, CardsResult, ConnectionProvider, ConnectionResult, DirectDebitResult,
+    Environment, Response, ScheduledPaymentResult, StandingOrderResult, TlClient, TransactionMeta,
+    TransactionsResult, TransactionsRunningBalance, UserInfoResult,
+};
+pub use http_cache::HttpCache;
+#[cfg(feature = "keyring")]
+pub use token_store::KeyringTokenStore;
+pub use token_store::{FileTokenStore, TokenLock, TokenStore};
+
+pub use rate_limit::RateLimitQuota;
+pub(crate) use rate_limit::{retry_after, RateLimiter};
@@ -0,0 +1,200 @@
+use std::{convert::TryFrom, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{header::RETRY_AFTER, Response};
+use serde::Serialize;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, warn};
+
+const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+const X_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+
+/// Values of `X-RateLimit-*` reset headers above this are taken to be Unix
+/// timestamps, rather than a number of seconds from now.
+const RESET_EPOCH_THRESHOLD: i64 = 1_000_000_000;
+
+/// A token bucket shared by every request made with a given token, so that
+/// raising the job concurrency doesn't trip the provider's rate limits.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// Requests per second; `None` to only honour `Retry-After`.
+    rate: Option<f64>,
+    /// Stop sending requests when the server reports this many or fewer
+    /// remaining, until its quota resets.
+    floor: u64,
+    state: Mutex<Bucket>,
+}
+
+/// The provider's quota, as of the latest response that reported it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RateLimitQuota {
+    pub limit: Option<u64>,
+    pub remaining: u64,
+    pub resets_at: Option<DateTime<Utc>>,
+    pub observed_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// Set from a `Retry-After` header; nothing is sent before this.
+    paused_until: Option<Instant>,
+    quota: Option<RateLimitQuota>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_second: Option<f64>) -> Self {
+        let rate = requests_per_second.filter(|r| *r > 0.0);
+        Self {
+            rate,
+            floor: 0,
+            state: Mutex::new(Bucket {
+                tokens: rate.map_or(0.0, burst),
+                refilled_at: Instant::now(),
+                paused_until: None,
+                quota: None,
+            }),
+        }
+    }
+
+    pub(crate) fn with_floor(mut self, floor: u64) -> Self {
+        self.floor = floor;
+        self
+    }
+
+    pub(crate) fn floor(&self) -> u64 {
+        self.floor
+    }
+
+    pub(crate) fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    pub(crate) fn quota(&self) -> Option<RateLimitQuota> {
+        self.state.lock().expect("lock").quota.clone()
+    }
+
+    pub(crate) fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    /// Waits until a request may be sent.
+    pub(crate) async fn acquire(&self) {
+        while let Some(wait_until) = self.try_acquire() {
+            sleep_until(wait_until).await;
+        }
+    }
+
+    /// Takes a token if one is available, otherwise returns when to try again.
+    fn try_acquire(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut bucket = self.state.lock().expect("lock");
+        if let Some(until) = bucket.paused_until {
+            if until > now {
+                return Some(until);
+            }
+            bucket.paused_until = None;
+        }
+
+        let rate = self.rate?;
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst(rate));
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            let shortfall = (1.0 - bucket.tokens) / rate;
+            Some(now + Duration::from_secs_f64(shortfall))
+        }
+    }
+
+    /// Records the server's reported quota, and holds off all requests if
+    /// it asked us to back off, or the quota is down to the floor.
+    pub(crate) fn observe(&self, res: &Response) {
+        if let Some(quota) = quota(res) {
+            debug!(?quota, "Rate limit quota");
+            if quota.remaining <= self.floor {
+                let delay = quota
+                    .resets_at
+                    .and_then(|at| (at - quota.observed_at).to_std().ok());
+                match delay {
+                    Some(delay) => {
+                        warn!(remaining=%quota.remaining, floor=%self.floor, ?delay, "Rate limit quota exhausted; pausing until it resets");
+                        self.pause(delay);
+                    }
+                    None => {
+                        warn!(remaining=%quota.remaining, floor=%self.floor, "Rate limit quota exhausted, with no reset time")
+                    }
+                }
+            }
+            self.state.lock().expect("lock").quota = Some(quota);
+        }
+
+        if let Some(delay) = retry_after(res) {
+            warn!(status=%res.status(), ?delay, "Server asked us to back off");
+            self.pause(delay);
+        }
+    }
+
+    fn pause(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut bucket = self.state.lock().expect("lock");
+        if bucket.paused_until.map_or(true, |current| current < until) {
+            bucket.paused_until = Some(until);
+        }
+    }
+}
+
+fn burst(rate: f64) -> f64 {
+    rate.max(1.0)
+}
+
+fn quota(res: &Response) -> Option<RateLimitQuota> {
+    let header = |name: &str| -> Option<i64> {
+        let value = res.headers().get(name)?.to_str().ok()?;
+        match value.trim().parse() {
+            Ok(value) => Some(value),
+            Err(error) => {
+                debug!(%error, %name, ?value, "Unparseable rate limit header");
+                None
+            }
+        }
+    };
+    let remaining = u64::try_from(header(X_RATELIMIT_REMAINING)?).ok()?;
+    let observed_at = Utc::now();
+    let resets_at = header(X_RATELIMIT_RESET).and_then(|reset| {
+        if reset > RESET_EPOCH_THRESHOLD {
+            DateTime::from_timestamp(reset, 0)
+        } else {
+            Some(observed_at + chrono::Duration::seconds(reset))
+        }
+    });
+    Some(RateLimitQuota {
+        limit: header(X_RATELIMIT_LIMIT).and_then(|l| u64::try_from(l).ok()),
+        remaining,
+        resets_at,
+        observed_at,
+    })
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+pub(crate) fn retry_after(res: &Response) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    match DateTime::parse_from_rfc2822(value) {
+        Ok(at) => Some(
+            (at.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default(),
+        ),
+        Err(error) => {
+            debug!(%error, ?value, "Unparseable Retry-After");
+            None
+        }
+    }
+}
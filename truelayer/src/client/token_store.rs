@@ -0,0 +1,239 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+use futures::{future::BoxFuture, FutureExt};
+use tempfile::NamedTempFile;
+use tokio::task::spawn_blocking;
+use tracing::{debug, Span};
+
+use crate::{client::authentication::AuthData, Result};
+
+/// Where a provider's tokens are kept between runs.
+pub trait TokenStore: Send + Sync + fmt::Display {
+    /// Returns `None` if nothing has been stored yet.
+    fn load(&self) -> BoxFuture<'_, Result<Option<AuthData>>>;
+    fn save<'a>(&'a self, data: &'a AuthData) -> BoxFuture<'a, Result<()>>;
+    /// Succeeds if there was nothing to remove.
+    fn remove(&self) -> BoxFuture<'_, Result<()>>;
+    /// Waits for exclusive use of the token, across processes, so that only
+    /// one of them refreshes it at a time. Stores that can't be shared
+    /// needn't lock anything.
+    fn lock(&self) -> BoxFuture<'_, Result<TokenLock>> {
+        async { Ok(TokenLock::default()) }.boxed()
+    }
+}
+
+/// Exclusive use of a stored token, until dropped.
+#[derive(Debug, Default)]
+pub struct TokenLock {
+    file: Option<(File, PathBuf)>,
+}
+
+/// Stores tokens as JSON in a file.
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> BoxFuture<'_, Result<Option<AuthData>>> {
+        let path = self.path.clone();
+        async move {
+            let data = spawn_blocking(move || match File::open(path) {
+                Ok(f) => Ok(Some(serde_json::from_reader(f)?)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(Error::from(e)),
+            })
+            .await??;
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn save<'a>(&'a self, data: &'a AuthData) -> BoxFuture<'a, Result<()>> {
+        let data = data.clone();
+        let path = self.path.clone();
+        let span = Span::current();
+        async move {
+            spawn_blocking(move || {
+                let _entered = span.enter();
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                let mut tmpf = NamedTempFile::new_in(dir)?;
+                serde_json::to_writer_pretty(&mut tmpf, &data)?;
+                tmpf.as_file_mut().flush()?;
+                tmpf.persist(&path).map_err(|e| e.error)?;
+                debug!(?path, "Stored auth data");
+                Ok(())
+            })
+            .await?
+        }
+        .boxed()
+    }
+
+    fn remove(&self) -> BoxFuture<'_, Result<()>> {
+        async move {
+            match tokio::fs::remove_file(&self.path).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        }
+        .boxed()
+    }
+
+    /// Locks a `.lock` file alongside the token, as the token file itself
+    /// is replaced whenever it's saved.
+    fn lock(&self) -> BoxFuture<'_, Result<TokenLock>> {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".lock");
+        let path = self.path.with_file_name(name);
+        let span = Span::current();
+        async move {
+            spawn_blocking(move || {
+                let _entered = span.enter();
+                let file = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&path)?;
+                if let Err(e) = file.try_lock_exclusive() {
+                    if e.kind() != fs2::lock_contended_error().kind() {
+                        return Err(e.into());
+                    }
+                    debug!(?path, "Waiting for another process to release the token");
+                    file.lock_exclusive()?;
+                }
+                debug!(?path, "Locked token");
+                Ok(TokenLock {
+                    file: Some((file, path)),
+                })
+            })
+            .await?
+        }
+        .boxed()
+    }
+}
+
+impl Drop for TokenLock {
+    fn drop(&mut self) {
+        if let Some((file, path)) = self.file.take() {
+            if let Err(error) = file.unlock() {
+                debug!(?path, %error, "Failed to unlock token");
+            }
+        }
+    }
+}
+
+impl fmt::Display for FileTokenStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.path)
+    }
+}
+
+#[cfg(feature = "keyring")]
+pub use self::keyring_store::KeyringTokenStore;
+
+#[cfg(feature = "keyring")]
+mod keyring_store {
+    use std::fmt;
+
+    use futures::{future::BoxFuture, FutureExt};
+    use tokio::task::spawn_blocking;
+
+    use super::TokenStore;
+    use crate::{client::authentication::AuthData, Result};
+
+    const SERVICE: &str = "tl-scraper";
+
+    /// Stores tokens as JSON in the operating system's keyring, under the
+    /// given user name.
+    #[derive(Debug, Clone)]
+    pub struct KeyringTokenStore {
+        user: String,
+    }
+
+    impl KeyringTokenStore {
+        pub fn new(user: &str) -> Self {
+            Self {
+                user: user.to_owned(),
+            }
+        }
+
+        /// Reads the raw secret stored under this entry.
+        pub async fn get(&self) -> Result<Option<String>> {
+            let user = self.user.clone();
+            spawn_blocking(move || -> Result<_> {
+                match entry(&user)?.get_password() {
+                    Ok(secret) => Ok(Some(secret)),
+                    Err(keyring::Error::NoEntry) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            })
+            .await?
+        }
+
+        pub async fn set(&self, secret: String) -> Result<()> {
+            let user = self.user.clone();
+            spawn_blocking(move || -> Result<()> { Ok(entry(&user)?.set_password(&secret)?) })
+                .await?
+        }
+    }
+
+    impl TokenStore for KeyringTokenStore {
+        fn load(&self) -> BoxFuture<'_, Result<Option<AuthData>>> {
+            async move {
+                let Some(secret) = self.get().await? else {
+                    return Ok(None);
+                };
+                let data = serde_json::from_str(&secret)?;
+                Ok(Some(data))
+            }
+            .boxed()
+        }
+
+        fn save<'a>(&'a self, data: &'a AuthData) -> BoxFuture<'a, Result<()>> {
+            async move { Ok(self.set(serde_json::to_string(data)?).await?) }.boxed()
+        }
+
+        fn remove(&self) -> BoxFuture<'_, Result<()>> {
+            let user = self.user.clone();
+            async move {
+                spawn_blocking(move || -> Result<()> {
+                    match entry(&user)?.delete_password() {
+                        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                        Err(e) => Err(e.into()),
+                    }
+                })
+                .await??;
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    fn entry(user: &str) -> Result<keyring::Entry> {
+        Ok(keyring::Entry::new(SERVICE, user)?)
+    }
+
+    impl fmt::Display for KeyringTokenStore {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "keyring entry {}/{}", SERVICE, self.user)
+        }
+    }
+}
@@ -1,34 +1,485 @@
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::File,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context, Result};
+use again::RetryPolicy;
+use anyhow::{anyhow, bail, Context, Result};
+use scraper_sdk::{ScheduleConfig, SyncSchedule};
+use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::{ClientCreds, Environment};
+#[cfg(feature = "keyring")]
+use crate::KeyringTokenStore;
+use crate::{
+    filter::AccountFilter,
+    fx::FxConfig,
+    notify::NotifyConfig,
+    ping::PingConfig,
+    s3::{S3Config, S3Connection},
+    store::{Compression, Layout, TransactionFormat},
+    telemetry::LogFormat,
+    upload::UploadConfig,
+    Cassette, ClientCreds, Environment, FileTokenStore, HttpCache, TokenStore,
+};
+
+/// Our table in a config file shared with other scrapers.
+const SHARED_CONFIG_SECTION: &str = "truelayer";
+const HTTP_CACHE_DIR: &str = ".cache";
+const DEFAULT_CACHE_DIR: &str = "~/.cache/tl-scraper/{provider}/{env}";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MainConfig {
+    /// Not needed when the credentials come from `credentials_env` or
+    /// `secret_cmd`.
+    #[serde(default)]
     pub client_credentials: PathBuf,
+    /// Where to read `client_credentials` from; when in the keyring, the
+    /// path is only used to name the entry.
+    #[serde(default)]
+    pub credentials_store: SecretStore,
+    /// Read the client id and secret from these environment variables, in
+    /// place of `client_credentials`.
+    pub credentials_env: Option<CredentialsEnv>,
+    /// Run this with `sh -c` for the credentials, eg: `pass show truelayer`.
+    /// It should print either what `client_credentials` would hold, or just
+    /// the secret on its first line when `client_id` is given. Takes
+    /// precedence over `credentials_env`.
+    pub secret_cmd: Option<String>,
+    /// The client id to go with a secret printed by `secret_cmd`.
+    pub client_id: Option<String>,
     pub environment: Environment,
+    /// Defaults to 60 seconds.
     pub request_timeout_s: Option<u64>,
+    pub connect_timeout_s: Option<u64>,
+    /// How long to poll for the results of an async request, for providers
+    /// with `async_data`; defaults to five minutes.
+    pub async_max_wait_s: Option<u64>,
+    /// Send API requests via this HTTP proxy.
+    pub proxy: Option<String>,
+    /// Defaults to the crate name and version.
+    pub user_agent: Option<String>,
+    /// Per-provider limit on API requests; unlimited if unset.
+    pub requests_per_second: Option<f64>,
+    /// Pause once the provider reports this many or fewer requests left in
+    /// its quota, until it resets.
+    pub rate_limit_floor: Option<u64>,
+    /// Serve `/healthz` and `/readyz` on this address while running.
+    pub health_listen: Option<SocketAddr>,
+    /// How long jobs may be in flight without progress before `/healthz` fails.
+    pub health_stall_timeout_s: Option<u64>,
+    /// Cancel any single fetch that takes longer than this.
+    pub job_timeout_s: Option<u64>,
+    /// How many times to retry a fetch that timed out; defaults to none.
+    pub job_timeout_retries: Option<usize>,
+    /// Stop starting new jobs once a sync has run for this long.
+    pub max_run_duration_s: Option<u64>,
+    /// How long in-flight jobs may continue after `max_run_duration_s`, or
+    /// once interrupted; defaults to 30 seconds.
+    pub shutdown_grace_s: Option<u64>,
+    /// How many providers may fail before a sync of several fails as a
+    /// whole; by default, it only fails if every provider does.
+    pub max_failed_providers: Option<usize>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub http_pool: PoolConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    /// Overridden by `--log-format`.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Record API responses, or replay them rather than calling the API, eg:
+    /// `cassette = { replay = "cassettes/lloyds" }`. Overridden by
+    /// `--record` and `--replay`.
+    pub cassette: Option<Cassette>,
+    /// Export traces to an OpenTelemetry collector; requires the `otlp`
+    /// feature.
+    pub otlp: Option<OtlpConfig>,
+    /// How long before expiry `refresh --keep-alive` refreshes tokens.
+    pub refresh_margin_s: Option<u64>,
+    /// Treat access tokens as expired this long before they actually do,
+    /// to allow for clock skew and slow requests; defaults to 60 seconds.
+    pub token_expiry_margin_s: Option<u64>,
+    /// The certificate to serve the auth flow with, when run with `--tls`.
+    pub auth_tls: Option<TlsConfig>,
+    /// Where the auth flow listens; defaults to 127.0.0.1:5500.
+    pub auth_listen: Option<SocketAddr>,
+    /// The redirect URIs registered for the client with TrueLayer, to check
+    /// ours against before starting the auth flow.
+    #[serde(default)]
+    pub registered_redirect_uris: Vec<String>,
+}
+
+/// Where to export metrics, if anywhere.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MetricsConfig {
+    /// Serve Prometheus metrics on `/metrics` at this address while running.
+    pub listen: Option<SocketAddr>,
+    /// Write metrics here after each sync, for node_exporter's textfile
+    /// collector; should end in `.prom`.
+    pub textfile: Option<PathBuf>,
+}
+
+/// An OTLP/HTTP collector to send spans to.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OtlpConfig {
+    /// The collector's traces endpoint, eg: `http://localhost:4318/v1/traces`.
+    pub endpoint: String,
+    /// Defaults to the crate name.
+    pub service_name: Option<String>,
+    /// Sent with each export, eg: for authentication.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// PEM encoded certificate chain and private key; when neither is given, a
+/// self-signed certificate is generated for each run.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretStore {
+    #[default]
+    File,
+    /// The operating system keyring; requires the `keyring` feature.
+    Keyring,
+}
+
+/// The names of the environment variables holding the client credentials,
+/// eg: `{ id = "TL_CLIENT_ID", secret = "TL_CLIENT_SECRET" }`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CredentialsEnv {
+    pub id: String,
+    pub secret: String,
+}
+
+/// How failed requests to TrueLayer are retried, with exponential backoff.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RetryConfig {
+    /// Defaults to 5.
+    pub max_retries: Option<usize>,
+    /// Delay before the first retry; defaults to one second.
+    pub base_delay_ms: Option<u64>,
+    /// Upper bound on the delay between retries.
+    pub max_delay_s: Option<u64>,
+    /// Randomise delays; defaults to on.
+    pub jitter: Option<bool>,
+}
+
+/// How connections to TrueLayer are kept around between requests; unset
+/// values are left at reqwest's defaults.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PoolConfig {
+    /// Idle connections kept open per host.
+    pub max_idle_per_host: Option<usize>,
+    /// Close idle connections after this long.
+    pub idle_timeout_s: Option<u64>,
+    /// Send TCP keepalive probes this often.
+    pub tcp_keepalive_s: Option<u64>,
+    /// Speak HTTP/2 without negotiating it first.
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    /// Ping HTTP/2 connections this often, so they aren't dropped while idle.
+    pub http2_keep_alive_interval_s: Option<u64>,
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProviderConfig {
     pub user_token: PathBuf,
+    /// Where to keep tokens; when in the keyring, `user_token` is only used
+    /// to name the entry.
+    #[serde(default)]
+    pub token_store: SecretStore,
+    /// May start with `~`, and include `{provider}` and `{env}`, for the
+    /// provider's name and the environment. May be a bucket, eg:
+    /// `s3://finance/{provider}`, in which case it's replaced by `cache_dir`
+    /// once loaded, and files written there are uploaded.
     pub target_dir: PathBuf,
+    /// Where to keep a copy of what's in an `s3://` `target_dir`, along with
+    /// the sync's state; as with `target_dir`, defaults to
+    /// `~/.cache/tl-scraper/{provider}/{env}`. Locking it only keeps other
+    /// syncs on this machine out.
+    pub cache_dir: Option<PathBuf>,
+    /// How to reach an `s3://` `target_dir`, eg:
+    /// `s3 = { endpoint = "https://s3.eu-west-2.amazonaws.com", region = "eu-west-2" }`.
+    #[serde(default)]
+    pub s3: S3Connection,
+    /// The bucket `target_dir` named, if any.
+    #[serde(skip)]
+    pub remote: Option<S3Config>,
     #[serde(default)]
     pub scrape_accounts: bool,
     #[serde(default)]
     pub scrape_cards: bool,
     #[serde(default)]
     pub scrape_info: bool,
+    /// Only sync the accounts and cards matching one of these, if given.
+    #[serde(default)]
+    pub include_accounts: Vec<AccountFilter>,
+    /// Never sync the accounts and cards matching any of these.
+    #[serde(default)]
+    pub exclude_accounts: Vec<AccountFilter>,
+    /// Only available shortly after authenticating; see [`RECENT_AUTH_WINDOW`].
+    ///
+    /// [`RECENT_AUTH_WINDOW`]: crate::RECENT_AUTH_WINDOW
+    #[serde(default)]
+    pub scrape_standing_orders: bool,
+    #[serde(default)]
+    pub scrape_direct_debits: bool,
+    /// As with standing orders, and skipped for providers that don't offer
+    /// them.
+    #[serde(default)]
+    pub scrape_scheduled_payments: bool,
+    /// As with scheduled payments.
+    #[serde(default)]
+    pub scrape_beneficiaries: bool,
+    /// Keep a timestamped snapshot of each account's balance per run, as well
+    /// as the latest in `balance.jsons`.
+    #[serde(default)]
+    pub balance_history: bool,
+    /// Keep each account's pending transactions from before they were last
+    /// replaced, timestamped, for this many days; eg: to find out what
+    /// became of ones that vanished.
+    pub pending_retention_days: Option<u32>,
+    /// How to write monthly transaction files.
+    #[serde(default)]
+    pub transaction_format: TransactionFormat,
+    /// Compress JSON lines files, eg: `compression = "zstd"`; what's stored
+    /// is read back either way.
+    #[serde(default)]
+    pub compression: Compression,
+    /// How files are arranged under `target_dir`; see [`Layout`].
+    #[serde(default)]
+    pub layout: Layout,
+    /// Mask account and card numbers, IBANs and names in what's written;
+    /// accounts are then always named by id.
+    #[serde(default)]
+    pub redact: bool,
+    /// Commit any changes to `target_dir` after each sync; it must be in a
+    /// git repository. Requires the `git` feature.
+    #[serde(default)]
+    pub git_commit: bool,
+    /// When re-fetching a month, keep any stored transactions the provider
+    /// no longer returns, rather than overwriting the month.
+    #[serde(default)]
+    pub merge_months: bool,
+    /// Maintain a per-account bloom filter of seen transaction ids.
+    #[serde(default)]
+    pub seen_index: bool,
+    /// Rules for bucketing transactions consistently across providers,
+    /// used by `report`; see [`CategoryRules`].
+    ///
+    /// [`CategoryRules`]: crate::CategoryRules
+    pub category_rules: Option<PathBuf>,
+    /// Also store each transaction's `category` and `tags`, as assigned by
+    /// `category_rules`.
+    #[serde(default)]
+    pub store_categories: bool,
+    /// Also store each transaction's amount in a base currency.
+    pub fx: Option<FxConfig>,
+    /// What to ask the user for access to when authenticating, eg:
+    /// `["accounts", "balance", "transactions"]`; defaults to everything we
+    /// can sync. Overridden by `auth --scope`.
+    pub scopes: Option<Vec<String>>,
+    /// Send the consent screen straight to one bank, or offer only some,
+    /// in TrueLayer's `providers` syntax, eg: `uk-ob-monzo`.
+    pub provider_filter: Option<String>,
+    /// Publicly visible redirect URI to use instead of the local listener's
+    /// address, eg: when behind a reverse proxy. Must be registered with
+    /// TrueLayer, and route to the auth listener.
+    pub redirect_uri: Option<String>,
+    /// How many months before the stored watermark to re-fetch when syncing
+    /// incrementally.
+    pub incremental_overlap_months: Option<u32>,
+    /// Sync this provider from `schedule`; unscheduled providers are skipped.
+    pub schedule: Option<SyncSchedule>,
+    /// Ping a healthcheck service when `sync` or a scheduled run starts and
+    /// finishes.
+    pub healthcheck: Option<PingConfig>,
+    /// Send transactions that weren't stored before somewhere, eg:
+    /// `notify = { ntfy = { url = "https://ntfy.sh/my-topic" } }`.
+    pub notify: Option<NotifyConfig>,
+    /// Send the files each sync changed elsewhere once it's finished, eg:
+    /// `upload = [{ s3 = { bucket = "finance", prefix = "monzo" } }]`.
+    #[serde(default)]
+    pub upload: Vec<UploadConfig>,
+    /// Request data asynchronously and poll for the results, for providers
+    /// too slow to answer within `request_timeout_s`.
+    #[serde(default)]
+    pub async_data: bool,
+    /// Keep responses under `target_dir/.cache`, and make conditional
+    /// requests for them, so unchanged data doesn't count against the
+    /// provider's quota. Ignored when redacting.
+    #[serde(default)]
+    pub http_cache: bool,
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ScraperConfig {
     pub main: MainConfig,
     pub providers: HashMap<String, ProviderConfig>,
 }
+impl RetryConfig {
+    pub fn policy(&self) -> RetryPolicy {
+        let base_delay = Duration::from_millis(self.base_delay_ms.unwrap_or(1000));
+        let mut policy = RetryPolicy::exponential(base_delay)
+            .with_jitter(self.jitter.unwrap_or(true))
+            .with_max_retries(self.max_retries.unwrap_or(5));
+        if let Some(max_delay) = self.max_delay_s {
+            policy = policy.with_max_delay(Duration::from_secs(max_delay));
+        }
+        policy
+    }
+}
+
+impl ProviderConfig {
+    pub fn token_store(&self) -> crate::Result<Arc<dyn TokenStore>> {
+        match self.token_store {
+            SecretStore::File => Ok(Arc::new(FileTokenStore::new(&self.user_token))),
+            #[cfg(feature = "keyring")]
+            SecretStore::Keyring => Ok(Arc::new(KeyringTokenStore::new(
+                &self.user_token.to_string_lossy(),
+            ))),
+            #[cfg(not(feature = "keyring"))]
+            SecretStore::Keyring => Err(no_keyring_support()),
+        }
+    }
+
+    /// Responses are cached as they were received, so aren't cached at all
+    /// when redacting.
+    pub fn http_cache(&self) -> Option<HttpCache> {
+        if self.http_cache && self.redact {
+            warn!("Not caching responses, as they'd keep what's redacted");
+            return None;
+        }
+        self.http_cache
+            .then(|| HttpCache::new(self.target_dir.join(HTTP_CACHE_DIR)))
+    }
+}
+
 impl ScraperConfig {
-    pub fn credentials(&self) -> Result<ClientCreds> {
+    /// Reads the config from `path`: either a config of its own, or a
+    /// config shared with other scrapers, with ours in a `truelayer` table.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading config file: {:?}", path))?;
+        let mut table: toml::Table = toml::from_str(&content).context("Parse toml")?;
+        let config = match table.remove(SHARED_CONFIG_SECTION) {
+            Some(section) => section.try_into(),
+            None => table.try_into(),
+        };
+        let mut config: Self = config.context("Parse config")?;
+        config.expand_target_dirs()?;
+        Ok(config)
+    }
+
+    /// Expands a leading `~`, and the `{provider}` and `{env}` placeholders,
+    /// in each provider's `target_dir`; so that many providers can share a
+    /// pattern, eg: `~/finance/{provider}/{env}`. A bucket is moved to
+    /// `remote`, leaving the cache as the `target_dir`.
+    fn expand_target_dirs(&mut self) -> Result<()> {
+        let env = self.main.environment.name();
+        for (name, provider) in self.providers.iter_mut() {
+            provider.target_dir = expand_target_dir(&provider.target_dir, name, env)
+                .with_context(|| format!("Expanding target_dir for {}", name))?;
+            let remote = provider
+                .target_dir
+                .to_str()
+                .and_then(|url| S3Config::from_url(url, &provider.s3));
+            if let Some(remote) = remote {
+                let cache_dir = provider
+                    .cache_dir
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_CACHE_DIR.into());
+                provider.target_dir = expand_target_dir(&cache_dir, name, env)
+                    .with_context(|| format!("Expanding cache_dir for {}", name))?;
+                provider.remote = Some(remote);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn credentials(&self) -> Result<ClientCreds> {
+        if let Some(cmd) = self.main.secret_cmd.as_ref() {
+            return self.command_credentials(cmd).await;
+        }
+        if let Some(env) = self.main.credentials_env.as_ref() {
+            let var = |name: &str| {
+                std::env::var(name)
+                    .with_context(|| format!("Reading client credentials from ${}", name))
+            };
+            return Ok(ClientCreds::new(
+                var(&env.id)?,
+                SecretString::new(var(&env.secret)?),
+            ));
+        }
+        match self.main.credentials_store {
+            SecretStore::File => self.file_credentials(),
+            #[cfg(feature = "keyring")]
+            SecretStore::Keyring => {
+                let path = &self.main.client_credentials;
+                let entry = KeyringTokenStore::new(&path.to_string_lossy());
+                let secret = entry
+                    .get()
+                    .await?
+                    .ok_or_else(|| anyhow!("No client credentials in {}", entry))?;
+                let client_creds = serde_json::from_str(&secret)
+                    .with_context(|| format!("Decoding client credentials from {}", entry))?;
+                Ok(client_creds)
+            }
+            #[cfg(not(feature = "keyring"))]
+            SecretStore::Keyring => Err(no_keyring_support().into()),
+        }
+    }
+
+    async fn command_credentials(&self, cmd: &str) -> Result<ClientCreds> {
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .await
+            .with_context(|| format!("Running secret_cmd: {:?}", cmd))?;
+        if !output.status.success() {
+            bail!("secret_cmd {:?} failed: {}", cmd, output.status);
+        }
+        let stdout = String::from_utf8(output.stdout)
+            .map_err(|_| anyhow!("secret_cmd {:?} printed invalid UTF-8", cmd))?;
+        if let Some(id) = self.main.client_id.as_ref() {
+            let secret = stdout
+                .lines()
+                .next()
+                .filter(|line| !line.is_empty())
+                .ok_or_else(|| anyhow!("secret_cmd {:?} printed no secret", cmd))?;
+            return Ok(ClientCreds::new(
+                id.clone(),
+                SecretString::new(secret.to_owned()),
+            ));
+        }
+        // The parse error isn't passed on, as it may quote the secret.
+        serde_json::from_str(&stdout).map_err(|_| {
+            anyhow!(
+                "Decoding client credentials from secret_cmd {:?}; set `client_id` if it prints only the secret",
+                cmd
+            )
+        })
+    }
+
+    /// Reads the client credentials from the configured file, regardless of
+    /// `credentials_store`.
+    pub fn file_credentials(&self) -> Result<ClientCreds> {
         let rdr = File::open(&self.main.client_credentials).with_context(|| {
             format!(
                 "Opening client credentials: {:?}",
@@ -56,3 +507,41 @@ impl ScraperConfig {
         }
     }
 }
+
+fn expand_target_dir(path: &Path, provider: &str, env: &str) -> Result<PathBuf> {
+    let Some(template) = path.to_str() else {
+        return Ok(path.to_owned());
+    };
+    let mut expanded = String::new();
+    let mut rest = template;
+    if rest == "~" || rest.starts_with("~/") {
+        let home = std::env::var("HOME").context("Expanding ~ without $HOME set")?;
+        expanded.push_str(&home);
+        rest = &rest[1..];
+    }
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed placeholder in {:?}", template))?;
+        match &rest[start + 1..start + end] {
+            "provider" => expanded.push_str(provider),
+            "env" => expanded.push_str(env),
+            other => bail!(
+                "Unknown placeholder {{{}}} in {:?}; expected {{provider}} or {{env}}",
+                other,
+                template
+            ),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn no_keyring_support() -> crate::Error {
+    crate::Error::Config(
+        "Keyring storage configured, but built without the `keyring` feature".to_owned(),
+    )
+}
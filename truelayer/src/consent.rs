@@ -0,0 +1,65 @@
+//! Working out when a provider's consent lapses, so that users can
+//! re-authenticate before syncs start failing.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::{ConnectionResult, Result, TlClient};
+
+/// How long consent lasts before the user must grant it again.
+const CONSENT_PERIOD: Duration = Duration::days(90);
+/// How long before consent expires we start warning about it.
+pub const CONSENT_WARNING: Duration = Duration::days(14);
+
+/// A provider's consent, as checked by `check-consent`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsentStatus {
+    /// When consent expires, if we can tell.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Whether the user needs to re-run `auth`, either because consent is
+    /// about to expire, or our tokens no longer work.
+    pub reauth_needed: bool,
+}
+
+/// When consent expires, preferring what the provider reports over
+/// counting from when we last authenticated.
+pub fn expires_at(
+    authed_at: Option<DateTime<Utc>>,
+    connection: Option<&ConnectionResult>,
+) -> Option<DateTime<Utc>> {
+    connection
+        .and_then(|connection| connection.consent_expires_at)
+        .or_else(|| authed_at.map(|at| at + CONSENT_PERIOD))
+}
+
+/// Whether consent expiring at `expires_at` is within `warning` of `now`.
+pub fn is_due(expires_at: DateTime<Utc>, warning: Duration, now: DateTime<Utc>) -> bool {
+    expires_at - warning <= now
+}
+
+impl ConsentStatus {
+    pub async fn check(tl: &TlClient, warning: Duration) -> Result<Self> {
+        let authed_at = match tl.authed_at().await {
+            Ok(authed_at) => authed_at,
+            Err(err) if err.needs_reauthentication() => return Ok(Self::reauth_needed()),
+            Err(err) => return Err(err),
+        };
+        let connection = match tl.fetch_connection().await {
+            Ok(connection) => connection.results.into_iter().next(),
+            Err(err) if err.needs_reauthentication() => return Ok(Self::reauth_needed()),
+            Err(err) => return Err(err),
+        };
+        let expires_at = expires_at(authed_at, connection.as_ref());
+        Ok(Self {
+            expires_at,
+            reauth_needed: expires_at.map_or(false, |at| is_due(at, warning, Utc::now())),
+        })
+    }
+
+    fn reauth_needed() -> Self {
+        Self {
+            expires_at: None,
+            reauth_needed: true,
+        }
+    }
+}
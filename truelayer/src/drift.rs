@@ -0,0 +1,61 @@
+//! Spotting fields the API sends that we don't model, which otherwise
+//! only end up in an `other` catch-all, so that upstream changes get noticed.
+
+use crate::client::{
+    AccountsResult, BalanceResult, CardsResult, ConnectionResult, TransactionsResult,
+    UserInfoResult,
+};
+
+pub(crate) trait UnknownFields {
+    /// The names of the fields kept only as raw JSON.
+    fn unknown_fields(&self) -> Vec<String>;
+}
+
+impl UnknownFields for TransactionsResult {
+    /// `meta` is provider specific, so what's in its `other` isn't drift.
+    fn unknown_fields(&self) -> Vec<String> {
+        keys("", &self.other).collect()
+    }
+}
+
+impl UnknownFields for ConnectionResult {
+    fn unknown_fields(&self) -> Vec<String> {
+        let provider = self
+            .provider
+            .iter()
+            .flat_map(|provider| keys("provider.", &provider.other));
+        keys("", &self.other).chain(provider).collect()
+    }
+}
+
+impl UnknownFields for AccountsResult {
+    fn unknown_fields(&self) -> Vec<String> {
+        keys("", &self.other).collect()
+    }
+}
+
+impl UnknownFields for CardsResult {
+    fn unknown_fields(&self) -> Vec<String> {
+        keys("", &self.other).collect()
+    }
+}
+
+impl UnknownFields for BalanceResult {
+    fn unknown_fields(&self) -> Vec<String> {
+        keys("", &self.other).collect()
+    }
+}
+
+impl UnknownFields for UserInfoResult {
+    fn unknown_fields(&self) -> Vec<String> {
+        keys("", &self.other).collect()
+    }
+}
+
+fn keys<'a>(prefix: &'a str, other: &'a serde_json::Value) -> impl Iterator<Item = String> + 'a {
+    other
+        .as_object()
+        .into_iter()
+        .flat_map(|fields| fields.keys())
+        .map(move |name| format!("{}{}", prefix, name))
+}
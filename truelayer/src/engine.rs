@@ -0,0 +1,192 @@
+//! Syncing a provider from a library, without going through the command
+//! line: build a [`SyncEngine`], then [`SyncEngine::run`] it.
+
+use std::{collections::BTreeMap, ops::RangeInclusive, sync::Arc, time::Duration};
+
+use chrono::NaiveDate;
+use scraper_sdk::ErrorPolicy;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument};
+
+use crate::{
+    store::Store, Error, JobPool, PlannedFetch, ProviderConfig, ProviderSync, Result, SyncObserver,
+    SyncOptions, SyncSummary, TlClient,
+};
+
+/// Syncs a single provider over a period, in its own job pool.
+pub struct SyncEngine {
+    name: String,
+    provider: Arc<ProviderSync>,
+    period: RangeInclusive<NaiveDate>,
+    options: SyncOptions,
+    concurrency: usize,
+    cancel: Option<(CancellationToken, Duration)>,
+    deadline: Option<Duration>,
+}
+
+/// Configures a [`SyncEngine`]; see [`SyncEngine::builder`].
+pub struct SyncEngineBuilder {
+    name: String,
+    client: Arc<TlClient>,
+    config: ProviderConfig,
+    store: Option<Arc<dyn Store>>,
+    observer: Option<Arc<dyn SyncObserver>>,
+    period: Option<RangeInclusive<NaiveDate>>,
+    options: SyncOptions,
+    concurrency: usize,
+    cancel: Option<(CancellationToken, Duration)>,
+    deadline: Option<Duration>,
+}
+
+/// What a [`SyncEngine`] run did.
+#[derive(Debug)]
+pub struct SyncReport {
+    pub summary: SyncSummary,
+    /// What a dry run would have fetched.
+    pub planned: Vec<PlannedFetch>,
+    /// Why the run failed, if it did.
+    pub error: Option<Error>,
+}
+
+impl SyncEngine {
+    /// Syncs the provider `name` with `client`, per its `config`.
+    pub fn builder(
+        name: &str,
+        client: Arc<TlClient>,
+        config: &ProviderConfig,
+    ) -> SyncEngineBuilder {
+        SyncEngineBuilder {
+            name: name.to_owned(),
+            client,
+            config: config.clone(),
+            store: None,
+            observer: None,
+            period: None,
+            options: SyncOptions::default(),
+            concurrency: 1,
+            cancel: None,
+            deadline: None,
+        }
+    }
+
+    /// Runs the sync to completion. The outcome is in the report, rather
+    /// than the result, so that what was done before a failure isn't lost.
+    #[instrument(skip_all, fields(provider=%self.name))]
+    pub async fn run(self) -> SyncReport {
+        let (mut pool, handle) = JobPool::new(self.concurrency);
+        if let Some((token, grace)) = self.cancel.clone() {
+            pool = pool.with_cancellation(token, grace);
+        }
+        if let Some(deadline) = self.deadline {
+            let grace = self
+                .cancel
+                .as_ref()
+                .map_or(Duration::ZERO, |(_, grace)| *grace);
+            pool = pool.with_deadline(deadline, grace);
+        }
+        if self.options.keep_going {
+            pool = pool.with_error_policy(ErrorPolicy::KeepGoing);
+        }
+        let monitor = handle.monitor();
+        let result =
+            scraper_sdk::sync_all(vec![self.provider.clone()], self.period, (pool, handle)).await;
+        if result.is_ok() {
+            info!("Sync complete");
+        }
+
+        let summary = SyncSummary::new(
+            BTreeMap::from([(self.name, self.provider.summary())]),
+            &monitor.stats(),
+            &result,
+        );
+        SyncReport {
+            summary,
+            planned: self.provider.planned(),
+            error: result.err(),
+        }
+    }
+}
+
+impl SyncEngineBuilder {
+    /// Write synced data to `store` rather than the configured `target_dir`.
+    pub fn store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Tell `observer` how the sync is going.
+    pub fn observer(mut self, observer: Arc<dyn SyncObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// The dates to sync; required.
+    pub fn period(mut self, period: RangeInclusive<NaiveDate>) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    pub fn options(mut self, options: SyncOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How many fetches to run at once; defaults to one.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Stop starting new fetches once `token` is cancelled, giving those in
+    /// flight `grace` to finish.
+    pub fn cancellation(mut self, token: CancellationToken, grace: Duration) -> Self {
+        self.cancel = Some((token, grace));
+        self
+    }
+
+    /// Give up on runs that take longer than `max_duration`.
+    pub fn deadline(mut self, max_duration: Duration) -> Self {
+        self.deadline = Some(max_duration);
+        self
+    }
+
+    pub fn build(self) -> Result<SyncEngine> {
+        let Some(period) = self.period else {
+            return Err(Error::Config(format!(
+                "No period given to sync {}",
+                self.name
+            )));
+        };
+        let mut provider =
+            ProviderSync::new(&self.name, self.client, &self.config, self.options.clone());
+        if let Some(observer) = self.observer {
+            provider = provider.with_observer(observer);
+        }
+        if let Some(store) = self.store {
+            provider = provider.with_store(store);
+        }
+        Ok(SyncEngine {
+            name: self.name,
+            provider: Arc::new(provider),
+            period,
+            options: self.options,
+            concurrency: self.concurrency,
+            cancel: self.cancel,
+            deadline: self.deadline,
+        })
+    }
+}
+
+impl SyncReport {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The summary, or why the run failed.
+    pub fn into_result(self) -> Result<SyncSummary> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.summary),
+        }
+    }
+}
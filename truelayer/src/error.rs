@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    io,
+    path::PathBuf,
+    process::ExitStatus,
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+use scraper_sdk::{JobTimedOut, JobsFailed, PoolClosed, RunCancelled, RunDeadlineExceeded};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinError;
+use tracing::warn;
+
+use crate::EnvironmentMismatch;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// As used by `timeout(1)`.
+pub const EXIT_DEADLINE_EXCEEDED: i32 = 124;
+/// A provider needs the user to re-run `auth`.
+pub const EXIT_REAUTH_NEEDED: i32 = 3;
+/// As shells report for a process killed by `SIGINT`.
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// What TrueLayer says when asked for transactions from before what the
+/// provider will share.
+const HISTORY_UNAVAILABLE_ERRORS: &[&str] = &["sca_exceeded", "invalid_date_range"];
+
+/// Errors from talking to TrueLayer and syncing what it returns.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// We have no usable token for the provider; re-run `auth`.
+    #[error("Not authenticated: {0}")]
+    Auth(String),
+    /// TrueLayer refused to refresh the token, as the refresh token has
+    /// expired or been revoked; re-run `auth`.
+    #[error("Re-authentication required{}: {reason}", .provider.as_ref().map(|p| format!(" for {}", p)).unwrap_or_default())]
+    ReauthenticationRequired {
+        /// Filled in once the error reaches the provider's sync.
+        provider: Option<String>,
+        reason: ApiError,
+    },
+    #[error(transparent)]
+    EnvironmentMismatch(#[from] EnvironmentMismatch),
+    /// The API responded with an error status.
+    #[error("HTTP {status} from {url}{}", .api_error.as_ref().map(|e| format!(": {}", e)).unwrap_or_default())]
+    Http {
+        status: StatusCode,
+        url: String,
+        /// The raw response body, if it could be read.
+        body: Option<String>,
+        /// The body, if it was one of TrueLayer's error payloads.
+        api_error: Option<ApiError>,
+    },
+    /// The API said a response was unchanged, but we'd nothing cached to
+    /// use in its place.
+    #[error("{url} was not modified, but we have no cached response for it")]
+    CacheMiss { url: String },
+    /// The API sent something we can't use, eg: a next page link without a
+    /// path.
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("Async request {task_id} failed: {results}")]
+    AsyncFailed {
+        task_id: String,
+        results: serde_json::Value,
+    },
+    #[error("Async request {task_id} not complete after {waited:?}")]
+    AsyncTimedOut { task_id: String, waited: Duration },
+    /// Replaying responses, and nothing was recorded for the request.
+    #[error("No recording of {request} at {path:?}")]
+    NotRecorded { request: String, path: PathBuf },
+    /// The API responded with `429 Too Many Requests`.
+    #[error("Rate limited{}", .retry_after.map(|d| format!("; retry after {:?}", d)).unwrap_or_default())]
+    RateLimited { retry_after: Option<Duration> },
+    /// The request could not be sent, or its response read.
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Invalid URI: {0}")]
+    Uri(#[from] hyper::http::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// As [`Error::Io`], noting what we were doing at the time.
+    #[error("{context}: {source}")]
+    IoContext {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("JSON error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    DeadlineExceeded(#[from] RunDeadlineExceeded),
+    /// The run was interrupted before all jobs had run.
+    #[error(transparent)]
+    Cancelled(#[from] RunCancelled),
+    #[error(transparent)]
+    PoolClosed(#[from] PoolClosed),
+    #[error(transparent)]
+    TimedOut(#[from] JobTimedOut),
+    /// Jobs that failed when running with `--keep-going`.
+    #[error(transparent)]
+    JobsFailed(#[from] JobsFailed<Error>),
+    /// Providers whose consent has expired, or soon will.
+    #[error("Re-authentication needed for: {}", .0.join(", "))]
+    ConsentDue(Vec<String>),
+    /// Providers whose syncs failed, when more did than the run allows.
+    #[error("Sync failed for: {}", .0.keys().cloned().collect::<Vec<_>>().join(", "))]
+    ProvidersFailed(BTreeMap<String, Error>),
+    #[error("Background task failed: {0}")]
+    Join(#[from] JoinError),
+    /// One of the auth flow's pages couldn't be rendered.
+    #[error("Rendering page: {0}")]
+    Template(#[from] askama::Error),
+    /// The configuration can't be used as it is, eg: as it asks for
+    /// something this build doesn't support.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// A stored file isn't as we wrote it.
+    #[error("Invalid stored data: {0}")]
+    Corrupt(String),
+    #[error("Parquet error: {0}")]
+    Parquet(String),
+    #[error("Git error: {0}")]
+    Git(String),
+    #[error("Keyring error: {0}")]
+    Keyring(String),
+    /// An upload command exited unsuccessfully.
+    #[error("Upload command {command:?} failed: {status}")]
+    UploadFailed { command: String, status: ExitStatus },
+    /// A bucket responded with an error status.
+    #[error("{method} {url} failed: HTTP {status}: {body}")]
+    S3 {
+        method: reqwest::Method,
+        url: String,
+        status: StatusCode,
+        body: String,
+    },
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Error::Parquet(e.to_string())
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<arrow_schema::ArrowError> for Error {
+    fn from(e: arrow_schema::ArrowError) -> Self {
+        Error::Parquet(e.to_string())
+    }
+}
+
+#[cfg(feature = "git")]
+impl From<git2::Error> for Error {
+    fn from(e: git2::Error) -> Self {
+        Error::Git(e.to_string())
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl From<keyring::Error> for Error {
+    fn from(e: keyring::Error) -> Self {
+        Error::Keyring(e.to_string())
+    }
+}
+
+/// Notes what we were doing when an I/O error happened.
+pub(crate) trait Context<T> {
+    fn context<C: Display>(self, context: C) -> Result<T>;
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|source| Error::IoContext {
+            context: context().to_string(),
+            source,
+        })
+    }
+}
+
+/// TrueLayer's error payload, as returned with most 4xx responses.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiError {
+    /// A machine readable code, eg: `invalid_grant` or
+    /// `account_not_available`.
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_details: Option<serde_json::Value>,
+}
+
+impl Error {
+    /// TrueLayer's description of what went wrong, if it sent one.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            Error::Http { api_error, .. } => api_error.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Whether the provider doesn't offer the endpoint at all, as TrueLayer
+    /// reports with `501 Not Implemented`.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self, Error::Http { status, .. } if *status == StatusCode::NOT_IMPLEMENTED)
+    }
+
+    /// Whether the provider refused to go back as far as we asked, eg: as
+    /// older transactions need a fresh authentication, and nothing else
+    /// went wrong.
+    pub fn is_history_unavailable(&self) -> bool {
+        match self {
+            Error::JobsFailed(failed) => {
+                !failed.errors.is_empty() && failed.errors.iter().all(Error::is_history_unavailable)
+            }
+            error => error.api_error().map_or(false, |e| {
+                HISTORY_UNAVAILABLE_ERRORS.contains(&e.error.as_str())
+            }),
+        }
+    }
+
+    /// Whether re-running `auth` is likely to fix this.
+    pub fn needs_reauthentication(&self) -> bool {
+        match self {
+            Error::Auth(_)
+            | Error::ReauthenticationRequired { .. }
+            | Error::EnvironmentMismatch(_)
+            | Error::ConsentDue(_) => true,
+            Error::JobsFailed(failed) => failed.errors.iter().any(Error::needs_reauthentication),
+            Error::ProvidersFailed(failed) => failed.values().any(Error::needs_reauthentication),
+            _ => false,
+        }
+    }
+
+    /// The providers whose refresh tokens were refused, and so must be
+    /// re-authenticated before they can be synced again.
+    pub fn reauthentication_required(&self) -> BTreeSet<&str> {
+        match self {
+            Error::ReauthenticationRequired {
+                provider: Some(provider),
+                ..
+            } => BTreeSet::from([provider.as_str()]),
+            Error::JobsFailed(failed) => failed
+                .errors
+                .iter()
+                .flat_map(Error::reauthentication_required)
+                .collect(),
+            Error::ProvidersFailed(failed) => failed
+                .values()
+                .flat_map(Error::reauthentication_required)
+                .collect(),
+            _ => BTreeSet::new(),
+        }
+    }
+
+    /// Whether the user must re-run `auth` before syncing again.
+    fn must_reauthenticate(&self) -> bool {
+        matches!(self, Error::ConsentDue(_)) || !self.reauthentication_required().is_empty()
+    }
+
+    /// Whether this, or any of the failures it's made up of, is `matches`.
+    fn any(&self, matches: &dyn Fn(&Error) -> bool) -> bool {
+        matches(self)
+            || match self {
+                Error::JobsFailed(failed) => failed.errors.iter().any(|e| e.any(matches)),
+                Error::ProvidersFailed(failed) => failed.values().any(|e| e.any(matches)),
+                _ => false,
+            }
+    }
+
+    /// A more telling exit status than 1, for whatever ran us, if there is
+    /// one: whether the run was interrupted, overran or needs the user to
+    /// re-authenticate; in that order, when providers failed in several
+    /// ways.
+    pub fn exit_status(&self) -> Option<i32> {
+        if self.any(&|e| matches!(e, Error::Cancelled(_))) {
+            Some(EXIT_CANCELLED)
+        } else if self.any(&|e| matches!(e, Error::DeadlineExceeded(_))) {
+            Some(EXIT_DEADLINE_EXCEEDED)
+        } else if self.any(&Error::must_reauthenticate) {
+            Some(EXIT_REAUTH_NEEDED)
+        } else {
+            None
+        }
+    }
+
+    /// The outcome of syncing several providers, given those that `failed`:
+    /// fine so long as no more than `max_failed` did, and none was
+    /// interrupted, overran or needs re-authenticating; otherwise the
+    /// provider's own error if only one failed.
+    pub fn providers_failed(failed: BTreeMap<String, Error>, max_failed: usize) -> Result<()> {
+        if failed.is_empty() {
+            return Ok(());
+        }
+        if failed.len() <= max_failed && !failed.values().any(|e| e.exit_status().is_some()) {
+            warn!(failed=?failed.keys(), "Some providers failed to sync");
+            return Ok(());
+        }
+        if failed.len() == 1 {
+            Err(failed.into_values().next().expect("failed provider"))
+        } else {
+            Err(Error::ProvidersFailed(failed))
+        }
+    }
+
+    /// Notes which provider we were syncing, if this error needs it.
+    pub(crate) fn for_provider(self, name: &str) -> Self {
+        match self {
+            Error::ReauthenticationRequired {
+                provider: None,
+                reason,
+            } => Error::ReauthenticationRequired {
+                provider: Some(name.to_owned()),
+                reason,
+            },
+            error => error,
+        }
+    }
+}
+
+impl ApiError {
+    pub(crate) fn parse(body: &str) -> Option<Self> {
+        serde_json::from_str(body).ok()
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)?;
+        if let Some(description) = self.error_description.as_ref() {
+            write!(f, " ({})", description)?;
+        }
+        Ok(())
+    }
+}
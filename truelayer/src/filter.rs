@@ -0,0 +1,66 @@
+//! Choosing which of the accounts and cards a token can see get synced.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{store::AccountKey, Error, Result};
+
+/// Matches accounts or cards, eg: `{ name = "^Joint" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountFilter {
+    Id(String),
+    /// A regular expression, matched against the display name.
+    Name(String),
+    /// Only ever matches accounts, as cards don't have one.
+    SortCode(String),
+}
+
+impl AccountFilter {
+    /// Fails if a name pattern isn't a valid regular expression.
+    pub fn check(&self) -> Result<()> {
+        if let AccountFilter::Name(pattern) = self {
+            name_regex(pattern)?;
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, key: &AccountKey) -> Result<bool> {
+        let matched = match (self, key) {
+            (AccountFilter::Id(id), key) => key.account_id() == id,
+            (AccountFilter::Name(pattern), AccountKey::Account(account)) => {
+                name_regex(pattern)?.is_match(&account.display_name)
+            }
+            (AccountFilter::Name(pattern), AccountKey::Card(card)) => {
+                name_regex(pattern)?.is_match(&card.display_name)
+            }
+            (AccountFilter::SortCode(sort_code), AccountKey::Account(account)) => {
+                account.account_number.sort_code.as_ref() == Some(sort_code)
+            }
+            (AccountFilter::SortCode(_), AccountKey::Card(_)) => false,
+        };
+        Ok(matched)
+    }
+}
+
+/// Whether `key` should be synced: it must match one of `include`, if any
+/// are given, and none of `exclude`.
+pub(crate) fn is_included(
+    include: &[AccountFilter],
+    exclude: &[AccountFilter],
+    key: &AccountKey,
+) -> Result<bool> {
+    let mut included = include.is_empty();
+    for filter in include {
+        included |= filter.matches(key)?;
+    }
+    for filter in exclude {
+        included &= !filter.matches(key)?;
+    }
+    Ok(included)
+}
+
+fn name_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern)
+        .map_err(|e| Error::Config(format!("Account name pattern {:?}: {}", pattern, e)))
+}
@@ -0,0 +1,196 @@
+//! Converting transaction amounts into a single base currency, for users
+//! with accounts in several.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{client::TransactionsResult, error::Context, Error, Result};
+
+/// The ECB quotes everything per euro.
+const EURO: &str = "EUR";
+
+/// Annotates each stored transaction with its amount in `base_currency`,
+/// eg: `fx = { base_currency = "GBP", rates = { ecb = { path = "eurofxref-hist.csv" } } }`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FxConfig {
+    pub base_currency: String,
+    pub rates: RatesConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RatesConfig {
+    /// The ECB's reference rates, as downloaded from
+    /// `eurofxref-hist.csv` or the daily `eurofxref.csv`.
+    Ecb { path: PathBuf },
+    /// Fixed rates, as what one unit of each currency is worth in the base
+    /// currency, eg: `{ USD = "0.79", EUR = "0.85" }`.
+    Table { rates: BTreeMap<String, Decimal> },
+}
+
+/// Where exchange rates come from.
+pub trait RateSource: Send + Sync + fmt::Debug {
+    /// What one unit of `from` was worth in `to` on `date`, if known.
+    fn rate(&self, from: &str, to: &str, date: NaiveDate) -> Option<Decimal>;
+}
+
+/// Converts amounts into the base currency with rates from a source.
+#[derive(Debug, Clone)]
+pub struct FxConverter {
+    base_currency: String,
+    rates: Arc<dyn RateSource>,
+}
+
+/// The ECB's daily reference rates, by date.
+#[derive(Debug, Clone, Default)]
+pub struct EcbRates {
+    per_euro: BTreeMap<NaiveDate, HashMap<String, Decimal>>,
+}
+
+/// The same rates whatever the date.
+#[derive(Debug, Clone)]
+pub struct FixedRates {
+    base_currency: String,
+    in_base: BTreeMap<String, Decimal>,
+}
+
+impl FxConverter {
+    pub fn new(base_currency: &str, rates: Arc<dyn RateSource>) -> Self {
+        Self {
+            base_currency: base_currency.to_owned(),
+            rates,
+        }
+    }
+
+    pub async fn load(config: &FxConfig) -> Result<Self> {
+        let rates: Arc<dyn RateSource> = match &config.rates {
+            RatesConfig::Ecb { path } => Arc::new(EcbRates::load(path).await?),
+            RatesConfig::Table { rates } => {
+                Arc::new(FixedRates::new(&config.base_currency, rates.clone()))
+            }
+        };
+        Ok(Self::new(&config.base_currency, rates))
+    }
+
+    /// `amount` of `currency` in the base currency, on `date`.
+    pub fn convert(&self, amount: Decimal, currency: &str, date: NaiveDate) -> Option<Decimal> {
+        if currency == self.base_currency {
+            return Some(amount);
+        }
+        let rate = self.rates.rate(currency, &self.base_currency, date)?;
+        Some((amount * rate).round_dp(2))
+    }
+
+    /// Adds `base_amount` and `base_currency` fields to `tx`, when there's a
+    /// rate for its currency on the day.
+    pub(crate) fn annotate(&self, tx: &mut TransactionsResult) {
+        let date = tx.timestamp.date_naive();
+        let Some(amount) = self.convert(tx.amount, &tx.currency, date) else {
+            debug!(currency=%tx.currency, %date, "No exchange rate; leaving unconverted");
+            return;
+        };
+        if let Some(fields) = tx.other.as_object_mut() {
+            fields.insert("base_amount".to_owned(), serde_json::json!(amount));
+            fields.insert(
+                "base_currency".to_owned(),
+                self.base_currency.clone().into(),
+            );
+        }
+    }
+}
+
+impl EcbRates {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Reading exchange rates: {:?}", path))?;
+        Self::parse(&content)
+            .map_err(|e| Error::Config(format!("Exchange rates {:?}: {}", path, e)))
+    }
+
+    /// Rows of a date followed by the rate for each currency in the header,
+    /// with `N/A` where there's none.
+    fn parse(content: &str) -> std::result::Result<Self, String> {
+        let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+        let header = lines.next().ok_or("Empty rates file")?;
+        let currencies = header
+            .split(',')
+            .skip(1)
+            .map(|c| c.trim().to_owned())
+            .collect::<Vec<_>>();
+        let mut rates = Self::default();
+        for line in lines {
+            let mut fields = line.split(',').map(str::trim);
+            let date = fields.next().unwrap_or_default();
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .or_else(|_| NaiveDate::parse_from_str(date, "%d %B %Y"))
+                .map_err(|e| format!("Date {:?}: {}", date, e))?;
+            let day = rates.per_euro.entry(date).or_default();
+            for (currency, rate) in currencies.iter().zip(fields) {
+                if currency.is_empty() || rate.is_empty() || rate == "N/A" {
+                    continue;
+                }
+                let rate = rate
+                    .parse::<Decimal>()
+                    .map_err(|e| format!("Rate for {} on {} {:?}: {}", currency, date, rate, e))?;
+                day.insert(currency.clone(), rate);
+            }
+        }
+        Ok(rates)
+    }
+
+    fn per_euro(day: &HashMap<String, Decimal>, currency: &str) -> Option<Decimal> {
+        if currency == EURO {
+            Some(Decimal::ONE)
+        } else {
+            day.get(currency).copied()
+        }
+    }
+}
+
+impl RateSource for EcbRates {
+    /// Uses the latest rates published on or before `date`, as there are
+    /// none for weekends and holidays.
+    fn rate(&self, from: &str, to: &str, date: NaiveDate) -> Option<Decimal> {
+        let (_, day) = self.per_euro.range(..=date).next_back()?;
+        let from = Self::per_euro(day, from)?;
+        let to = Self::per_euro(day, to)?;
+        (!from.is_zero()).then(|| to / from)
+    }
+}
+
+impl FixedRates {
+    /// `in_base` gives what one unit of each currency is worth in
+    /// `base_currency`.
+    pub fn new(base_currency: &str, in_base: BTreeMap<String, Decimal>) -> Self {
+        Self {
+            base_currency: base_currency.to_owned(),
+            in_base,
+        }
+    }
+
+    fn in_base(&self, currency: &str) -> Option<Decimal> {
+        if currency == self.base_currency {
+            Some(Decimal::ONE)
+        } else {
+            self.in_base.get(currency).copied()
+        }
+    }
+}
+
+impl RateSource for FixedRates {
+    fn rate(&self, from: &str, to: &str, _date: NaiveDate) -> Option<Decimal> {
+        let from = self.in_base(from)?;
+        let to = self.in_base(to)?;
+        (!to.is_zero()).then(|| from / to)
+    }
+}
@@ -0,0 +1,112 @@
+//! Committing what a sync changed, for target directories kept in a git
+//! repository, so there's a history of each run.
+
+use std::path::Path;
+
+use crate::{Error, Result};
+
+/// Fails if committing isn't supported by this build.
+pub(crate) fn check_supported() -> Result<()> {
+    if cfg!(feature = "git") {
+        Ok(())
+    } else {
+        Err(Error::Config(
+            "`git_commit` requires building with the `git` feature".to_owned(),
+        ))
+    }
+}
+
+/// Stages everything synced under `target_dir` and commits it with
+/// `message`, unless nothing has changed. Dot-files, such as the lock,
+/// caches and journals, are left out, other than the hash index.
+#[cfg(feature = "git")]
+pub(crate) async fn commit(target_dir: &Path, message: String) -> Result<()> {
+    let target_dir = target_dir.to_owned();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        commit_blocking(&target_dir, &message)
+    })
+    .await?
+}
+
+#[cfg(not(feature = "git"))]
+pub(crate) async fn commit(_: &Path, _: String) -> Result<()> {
+    check_supported()
+}
+
+#[cfg(feature = "git")]
+fn commit_blocking(target_dir: &Path, message: &str) -> Result<()> {
+    use git2::{IndexAddOption, Repository, Signature};
+    use std::path::Component;
+    use tracing::{debug, info};
+
+    use crate::hashes::HASHES_FILE;
+
+    let repo = Repository::discover(target_dir).map_err(|e| {
+        Error::Git(format!(
+            "Finding git repository for {:?}: {}",
+            target_dir,
+            e.message()
+        ))
+    })?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| Error::Git(format!("Git repository is bare: {:?}", repo.path())))?;
+    let prefix = target_dir
+        .canonicalize()?
+        .strip_prefix(workdir.canonicalize()?)
+        .map_err(|_| {
+            Error::Git(format!(
+                "Target directory {:?} is outside the repository's working tree",
+                target_dir
+            ))
+        })?
+        .to_owned();
+    let pathspec = if prefix.as_os_str().is_empty() {
+        ".".to_owned()
+    } else {
+        prefix.to_string_lossy().into_owned()
+    };
+
+    let mut index = repo.index()?;
+    let mut synced_only = |path: &Path, _: &[u8]| {
+        let relative = path.strip_prefix(&prefix).unwrap_or(path);
+        let hidden = relative.components().any(|component| {
+            matches!(component, Component::Normal(name) if name.to_string_lossy().starts_with('.'))
+        });
+        if hidden && relative != Path::new(HASHES_FILE) {
+            1
+        } else {
+            0
+        }
+    };
+    index.add_all([&pathspec], IndexAddOption::DEFAULT, Some(&mut synced_only))?;
+    index.update_all([&pathspec], Some(&mut synced_only))?;
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(err) if err.code() == git2::ErrorCode::UnbornBranch => None,
+        Err(err) => return Err(err.into()),
+    };
+    if parent.as_ref().map(|parent| parent.tree_id()) == Some(tree.id()) {
+        debug!("Nothing changed; not committing");
+        return Ok(());
+    }
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now(env!("CARGO_PKG_NAME"), "tl-scraper@localhost"))?;
+    let parents = parent.iter().collect::<Vec<_>>();
+    let id = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )?;
+    info!(%id, "Committed sync");
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! A record of what each stored file should contain, so that corrupted or
+//! externally modified files can be spotted before they're trusted.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use scraper_sdk::{load_state, write_json_atomically};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{sync::Mutex, task::spawn_blocking};
+use tracing::debug;
+
+use crate::{error::Context, Error, Result};
+
+/// Maps paths relative to the target directory to the SHA-256 of their
+/// content, as last written.
+pub const HASHES_FILE: &str = ".hashes";
+
+type Hashes = BTreeMap<PathBuf, String>;
+
+/// The `.hashes` index for a target directory, loaded on first use and
+/// rewritten as files are stored.
+#[derive(Debug)]
+pub(crate) struct HashIndex {
+    target_dir: PathBuf,
+    hashes: Mutex<Option<Hashes>>,
+}
+
+/// The outcome of checking a target directory against its `.hashes` index.
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    /// Files whose content differs from what was written.
+    pub modified: Vec<PathBuf>,
+    /// Files that were written, but have since gone.
+    pub missing: Vec<PathBuf>,
+}
+
+impl HashIndex {
+    pub(crate) fn new(target_dir: &Path) -> Self {
+        Self {
+            target_dir: target_dir.to_owned(),
+            hashes: Mutex::new(None),
+        }
+    }
+
+    /// Records the current content of `path`, relative to the target
+    /// directory.
+    pub(crate) async fn record(&self, path: &Path) -> Result<()> {
+        let hash = hash_file(&self.target_dir.join(path))
+            .await?
+            .ok_or_else(|| Error::IoContext {
+                context: format!("Hashing {:?}", path),
+                source: io::ErrorKind::NotFound.into(),
+            })?;
+        let mut hashes = self.hashes.lock().await;
+        if hashes.is_none() {
+            *hashes = Some(load(&self.target_dir).await?);
+        }
+        let hashes = hashes.as_mut().expect("loaded");
+        if hashes.get(path) == Some(&hash) {
+            return Ok(());
+        }
+        debug!(?path, %hash, "Recording hash");
+        hashes.insert(path.to_owned(), hash);
+        write_json_atomically(&self.target_dir.join(HASHES_FILE), hashes.clone()).await?;
+        Ok(())
+    }
+
+    /// Stops tracking `path`, as it was removed on purpose.
+    pub(crate) async fn forget(&self, path: &Path) -> Result<()> {
+        let mut hashes = self.hashes.lock().await;
+        if hashes.is_none() {
+            *hashes = Some(load(&self.target_dir).await?);
+        }
+        let hashes = hashes.as_mut().expect("loaded");
+        if hashes.remove(path).is_some() {
+            debug!(?path, "Forgetting hash");
+            write_json_atomically(&self.target_dir.join(HASHES_FILE), hashes.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Tracks the files under `from` as being under `to`, as the directory
+    /// was moved.
+    pub(crate) async fn rename_dir(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut hashes = self.hashes.lock().await;
+        if hashes.is_none() {
+            *hashes = Some(load(&self.target_dir).await?);
+        }
+        let hashes = hashes.as_mut().expect("loaded");
+        let moved = hashes
+            .keys()
+            .filter_map(|path| Some((path.clone(), to.join(path.strip_prefix(from).ok()?))))
+            .collect::<Vec<_>>();
+        if moved.is_empty() {
+            return Ok(());
+        }
+        for (old, new) in moved {
+            let hash = hashes.remove(&old).expect("recorded");
+            hashes.insert(new, hash);
+        }
+        debug!(?from, ?to, "Moved hashes");
+        write_json_atomically(&self.target_dir.join(HASHES_FILE), hashes.clone()).await?;
+        Ok(())
+    }
+}
+
+/// Checks every file recorded in `target_dir`'s `.hashes` index against its
+/// current content.
+pub async fn verify_hashes(target_dir: &Path) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    for (path, expected) in load(target_dir).await? {
+        report.checked += 1;
+        match hash_file(&target_dir.join(&path)).await? {
+            None => report.missing.push(path),
+            Some(actual) if actual != expected => report.modified.push(path),
+            Some(_) => {}
+        }
+    }
+    Ok(report)
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty()
+    }
+}
+
+async fn load(target_dir: &Path) -> Result<Hashes> {
+    let path = target_dir.join(HASHES_FILE);
+    let hashes = load_state(&path)
+        .await
+        .with_context(|| format!("Reading {:?}", path))?;
+    Ok(hashes.unwrap_or_default())
+}
+
+/// The hex SHA-256 of the file at `path`, or `None` if there isn't one.
+async fn hash_file(path: &Path) -> Result<Option<String>> {
+    let path = path.to_owned();
+    spawn_blocking(move || {
+        let content = match std::fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Reading {:?}", path)),
+        };
+        Ok(Some(format!("{:x}", Sha256::digest(&content))))
+    })
+    .await?
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use scraper_sdk::PoolMonitor;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument};
+
+use crate::{consent, RateLimitQuota, TlClient};
+
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Shared view of scheduler and provider state, served as `/healthz` (is the
+/// scheduler making progress) and `/readyz` (can every provider authenticate).
+#[derive(Clone)]
+pub struct Health {
+    inner: Arc<Mutex<HealthInner>>,
+    stall_timeout: Duration,
+}
+
+#[derive(Default)]
+struct HealthInner {
+    providers: BTreeMap<String, ProviderHealth>,
+    pool: Option<PoolMonitor>,
+}
+
+struct ProviderHealth {
+    client: Arc<TlClient>,
+    last_success: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct LivenessReport {
+    jobs_submitted: usize,
+    jobs_started: usize,
+    jobs_completed: usize,
+    seconds_since_progress: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ProviderReport {
+    /// Whether there's a token we can still use, either as it is, or by
+    /// refreshing it while consent lasts.
+    token_valid: bool,
+    token_expires_at: Option<DateTime<Utc>>,
+    consent_expires_at: Option<DateTime<Utc>>,
+    last_success: Option<DateTime<Utc>>,
+    rate_limit: Option<RateLimitQuota>,
+}
+
+impl Health {
+    pub fn new(stall_timeout: Option<Duration>) -> Self {
+        Self {
+            inner: Default::default(),
+            stall_timeout: stall_timeout.unwrap_or(DEFAULT_STALL_TIMEOUT),
+        }
+    }
+
+    /// Registering a provider again, eg: for each scheduled run, keeps its
+    /// last success.
+    pub fn register_provider(&self, name: &str, client: Arc<TlClient>) {
+        self.inner
+            .lock()
+            .expect("lock")
+            .providers
+            .entry(name.to_owned())
+            .and_modify(|provider| provider.client = client.clone())
+            .or_insert(ProviderHealth {
+                client,
+                last_success: None,
+            });
+    }
+
+    pub fn watch_pool(&self, pool: PoolMonitor) {
+        self.inner.lock().expect("lock").pool = Some(pool);
+    }
+
+    pub fn record_success(&self, name: &str, at: DateTime<Utc>) {
+        if let Some(provider) = self.inner.lock().expect("lock").providers.get_mut(name) {
+            provider.last_success = Some(at);
+        }
+    }
+
+    /// Serves the health endpoints until `cnx` is cancelled.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn serve(self, addr: SocketAddr, cnx: CancellationToken) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Bind health listener: {}", addr))?;
+        info!("Serving health endpoints");
+        let app = Router::new()
+            .route("/healthz", get(Self::healthz))
+            .route("/readyz", get(Self::readyz))
+            .with_state(self);
+        axum::serve(listener, app)
+            .with_graceful_shutdown(cnx.cancelled_owned())
+            .await
+            .context("Running health server")?;
+        Ok(())
+    }
+
+    async fn healthz(State(state): State<Health>) -> impl IntoResponse {
+        let stats = state
+            .inner
+            .lock()
+            .expect("lock")
+            .pool
+            .as_ref()
+            .map(|pool| pool.stats())
+            .unwrap_or_default();
+        let since_progress = stats.last_progress.map(|at| at.elapsed());
+        let in_flight = stats.jobs_started > stats.jobs_completed;
+        let wedged = in_flight && since_progress.map_or(false, |d| d > state.stall_timeout);
+
+        let report = LivenessReport {
+            jobs_submitted: stats.jobs_submitted,
+            jobs_started: stats.jobs_started,
+            jobs_completed: stats.jobs_completed,
+            seconds_since_progress: since_progress.map(|d| d.as_secs()),
+        };
+        let status = if wedged {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        };
+        (status, Json(report))
+    }
+
+    async fn readyz(State(state): State<Health>) -> impl IntoResponse {
+        let providers = state
+            .inner
+            .lock()
+            .expect("lock")
+            .providers
+            .iter()
+            .map(|(name, p)| (name.clone(), p.client.clone(), p.last_success))
+            .collect::<Vec<_>>();
+
+        let mut reports = BTreeMap::new();
+        let now = Utc::now();
+        for (name, client, last_success) in providers {
+            let token_expires_at = client.token_expires_at().await.ok();
+            let authed_at = client.authed_at().await.ok().flatten();
+            let consent_expires_at = consent::expires_at(authed_at, None);
+            // Tokens from before we kept track of consent get the benefit of
+            // the doubt.
+            let token_valid = token_expires_at.map_or(false, |at| {
+                at > now || consent_expires_at.map_or(true, |at| at > now)
+            });
+            reports.insert(
+                name,
+                ProviderReport {
+                    token_valid,
+                    token_expires_at,
+                    consent_expires_at,
+                    last_success,
+                    rate_limit: client.rate_limit_quota(),
+                },
+            );
+        }
+
+        let status = if reports.values().all(|r| r.token_valid) {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(reports))
+    }
+}
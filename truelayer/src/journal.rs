@@ -0,0 +1,139 @@
+//! A journal of the months fetched so far in a sync, so that a run that dies
+//! partway through can be resumed without refetching everything.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+use crate::{error::Context, state::Kind, Result};
+
+pub(crate) const JOURNAL_FILE: &str = ".journal.json";
+
+/// Months completed per account and card during a run over `from_date` to
+/// `to_date`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Journal {
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+    #[serde(default)]
+    accounts: BTreeMap<String, BTreeSet<NaiveDate>>,
+    #[serde(default)]
+    cards: BTreeMap<String, BTreeSet<NaiveDate>>,
+}
+
+/// The journal for the current run; empty until [`RunJournal::start`]ed.
+#[derive(Debug, Default)]
+pub(crate) struct RunJournal {
+    inner: Mutex<Option<(PathBuf, Journal)>>,
+}
+
+impl Journal {
+    fn new(period: &RangeInclusive<NaiveDate>) -> Self {
+        Self {
+            from_date: *period.start(),
+            to_date: *period.end(),
+            accounts: BTreeMap::new(),
+            cards: BTreeMap::new(),
+        }
+    }
+
+    fn months(&self, kind: Kind) -> &BTreeMap<String, BTreeSet<NaiveDate>> {
+        match kind {
+            Kind::Account => &self.accounts,
+            Kind::Card => &self.cards,
+        }
+    }
+
+    fn months_mut(&mut self, kind: Kind) -> &mut BTreeMap<String, BTreeSet<NaiveDate>> {
+        match kind {
+            Kind::Account => &mut self.accounts,
+            Kind::Card => &mut self.cards,
+        }
+    }
+}
+
+impl RunJournal {
+    /// Starts journalling a run over `period`. When `resume` is set, picks
+    /// up what an earlier, unfinished run over the same period completed.
+    pub(crate) async fn start(
+        &self,
+        target_dir: &Path,
+        period: &RangeInclusive<NaiveDate>,
+        resume: bool,
+    ) -> Result<()> {
+        let path = target_dir.join(JOURNAL_FILE);
+        let mut journal = Journal::new(period);
+        if resume {
+            let previous: Option<Journal> = scraper_sdk::load_state(&path)
+                .await
+                .with_context(|| format!("Loading run journal: {:?}", path))?;
+            match previous {
+                Some(previous)
+                    if previous.from_date == journal.from_date
+                        && previous.to_date == journal.to_date =>
+                {
+                    info!(?path, "Resuming earlier run");
+                    journal = previous;
+                }
+                Some(previous) => info!(
+                    from_date=%previous.from_date,
+                    to_date=%previous.to_date,
+                    "Earlier run covered a different period; starting afresh"
+                ),
+                None => info!("No earlier run to resume"),
+            }
+        }
+        *self.inner.lock().await = Some((path, journal));
+        Ok(())
+    }
+
+    /// Whether `month` was fetched for the account with `id` in this run,
+    /// or the one it resumes.
+    pub(crate) async fn is_complete(&self, kind: Kind, id: &str, month: NaiveDate) -> bool {
+        let inner = self.inner.lock().await;
+        inner.as_ref().map_or(false, |(_, journal)| {
+            journal
+                .months(kind)
+                .get(id)
+                .map_or(false, |months| months.contains(&month))
+        })
+    }
+
+    pub(crate) async fn complete(&self, kind: Kind, id: &str, month: NaiveDate) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        let Some((path, journal)) = inner.as_mut() else {
+            return Ok(());
+        };
+        journal
+            .months_mut(kind)
+            .entry(id.to_owned())
+            .or_default()
+            .insert(month);
+        scraper_sdk::store_state(path, journal.clone())
+            .await
+            .with_context(|| format!("Storing run journal: {:?}", path))?;
+        Ok(())
+    }
+
+    /// Removes the journal once the run has completed, as there's nothing
+    /// left to resume.
+    pub(crate) async fn finish(&self) -> Result<()> {
+        let Some((path, _)) = self.inner.lock().await.take() else {
+            return Ok(());
+        };
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => debug!(?path, "Removed run journal"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Removing {:?}", path)),
+        }
+        Ok(())
+    }
+}
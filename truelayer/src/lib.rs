@@ -1,21 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use again::RetryPolicy;
-use anyhow::Result;
-use reqwest::RequestBuilder;
+use reqwest::{RequestBuilder, StatusCode};
 use secrecy::{ExposeSecret, Secret, Zeroize};
 use serde::{de::DeserializeOwned, Serialize, Serializer};
 use tracing::{debug, error};
 
+use crate::client::{retry_after, HttpCache, RateLimiter};
+
 mod auth;
+mod backfill;
+mod categories;
+pub mod cli;
 mod client;
 mod config;
-mod join_pool;
+pub mod consent;
+mod drift;
+mod engine;
+mod error;
+mod filter;
+mod fx;
+mod git;
+mod hashes;
+mod health;
+mod journal;
+mod manifest;
+pub mod metrics;
+mod notify;
+mod observer;
+mod pending;
+mod ping;
+pub mod progress;
+mod reconcile;
+mod redact;
+mod refresh;
+mod report;
+mod s3;
+mod state;
+mod status;
+pub mod store;
+mod summary;
 mod sync;
+pub mod telemetry;
+mod upload;
+
+pub use auth::{authenticate, authenticate_manually, check_scopes, AuthServerOptions};
+pub use backfill::{Backfill, BackfillReport};
+pub use categories::{Category, CategoryRules};
+#[cfg(feature = "keyring")]
+pub use client::KeyringTokenStore;
+pub use client::{
+    AccountNumber, AccountsProvider, AccountsResult, AuthData, BalanceResult, BeneficiaryResult,
+    CardsProvider, CardsResult, Cassette, ClientCreds, ConnectionProvider, ConnectionResult,
+    DirectDebitResult, Environment, EnvironmentMismatch, FileTokenStore, HttpCache, RateLimitQuota,
+    Response, ScheduledPaymentResult, StandingOrderResult, TlClient, TlClientBuilder, TokenLock,
+    TokenStore, TransactionMeta, TransactionsResult, TransactionsRunningBalance, UserInfoResult,
+};
+pub use config::{
+    CredentialsEnv, MainConfig, MetricsConfig, OtlpConfig, PoolConfig, ProviderConfig, RetryConfig,
+    ScraperConfig, SecretStore, TlsConfig,
+};
+pub use engine::{SyncEngine, SyncEngineBuilder, SyncReport};
+pub use error::{
+    ApiError, Error, Result, EXIT_CANCELLED, EXIT_DEADLINE_EXCEEDED, EXIT_REAUTH_NEEDED,
+};
+pub use filter::AccountFilter;
+pub use fx::{EcbRates, FixedRates, FxConfig, FxConverter, RateSource, RatesConfig};
+pub use hashes::{verify_hashes, VerifyReport, HASHES_FILE};
+pub use health::Health;
+pub use manifest::{Manifest, ManifestAccount};
+pub use notify::{NewTransactions, NotifyConfig};
+pub use observer::SyncObserver;
+pub use ping::{ping, ping_failure, ping_start, PingConfig};
+pub use reconcile::{
+    reconcile, AccountReconciliation, BalanceGap, BalanceMismatch, ReconciliationReport,
+};
+pub use refresh::TokenRefresher;
+pub use report::{classification_report, ClassificationReport, ClassificationTotals};
+pub use s3::{S3Config, S3Connection};
+pub use state::{AccountState, SyncState};
+pub use status::ProviderStatus;
+pub use summary::{ProviderSummary, SyncSummary};
+pub use sync::{
+    sync_accounts, sync_cards, sync_info, PlannedFetch, ProviderSync, SyncContext, SyncOptions,
+    RECENT_AUTH_WINDOW,
+};
+pub use upload::UploadConfig;
+
+pub use scraper_sdk::{ScheduleConfig, SyncSchedule};
 
-pub use auth::authenticate;
-pub use client::{ClientCreds, Environment, TlClient};
-pub use config::{MainConfig, ProviderConfig, ScraperConfig};
-pub use join_pool::{JobHandle, JobPool};
-pub use sync::{sync_accounts, sync_cards, sync_info};
+pub type JobPool = scraper_sdk::JobPool<Error>;
+pub type JobHandle = scraper_sdk::JobHandle<Error>;
 
 fn serialize_secret<T: Zeroize + Serialize, S: Serializer>(
     secret: &Secret<T>,
@@ -33,23 +108,94 @@ fn serialize_optional_secret<T: Zeroize + Serialize, S: Serializer>(
         .serialize(serializer)
 }
 
+/// Sends the request from `build`, retrying per `retry_policy`. With a
+/// `cassette`, the response is recorded, or replayed without sending. With a
+/// `cache`, the request is made conditional on any cached response, which is
+/// used should the server say it's still current.
 async fn perform_request<R: DeserializeOwned, B: Fn() -> RequestBuilder>(
     retry_policy: &RetryPolicy,
+    limiter: &RateLimiter,
+    cassette: Option<&Cassette>,
+    cache: Option<&HttpCache>,
     build: B,
 ) -> Result<R> {
-    async fn inner<R: DeserializeOwned, B: Fn() -> RequestBuilder>(build: B) -> Result<R> {
-        let res = build().send().await?;
-        if let Err(error) = res.error_for_status_ref() {
-            error!(%error, status=?res.status(), "Failed response");
-            if let Ok(body) = res.text().await {
-                debug!(%error, ?body, "Response body");
+    async fn inner<R: DeserializeOwned, B: Fn() -> RequestBuilder>(
+        limiter: &RateLimiter,
+        cassette: Option<&Cassette>,
+        cache: Option<&HttpCache>,
+        build: B,
+    ) -> Result<R> {
+        let (client, req) = build().build_split();
+        let mut req = req?;
+        let cached = match cache {
+            Some(cache) => cache.prepare(&mut req).await,
+            None => None,
+        };
+        let res = match cassette {
+            Some(cassette) if cassette.is_replay() => cassette.send(&client, req).await?,
+            Some(cassette) => {
+                limiter.acquire().await;
+                cassette.send(&client, req).await?
+            }
+            None => {
+                limiter.acquire().await;
+                client.execute(req).await?
+            }
+        };
+        limiter.observe(&res);
+        let status = res.status();
+        if status == StatusCode::NOT_MODIFIED {
+            // We only send validators along with a cached response, so
+            // there's nothing to fall back on without one.
+            let cached = cached.ok_or_else(|| Error::CacheMiss {
+                url: res.url().to_string(),
+            })?;
+            debug!(url=%res.url(), "Not modified; using cached response");
+            metrics::not_modified();
+            return Ok(serde_json::from_value(cached.body)?);
+        }
+        if status.is_client_error() || status.is_server_error() {
+            let url = res.url().to_string();
+            error!(%status, %url, "Failed response");
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                return Err(Error::RateLimited {
+                    retry_after: retry_after(&res),
+                });
+            }
+            let body = res.text().await.ok();
+            debug!(%status, ?body, "Response body");
+            let api_error = body.as_deref().and_then(ApiError::parse);
+            if status == StatusCode::UNAUTHORIZED {
+                let reason =
+                    api_error.map_or_else(|| "no reason given".to_owned(), |e| e.to_string());
+                return Err(Error::Auth(format!(
+                    "{} rejected our access token: {}",
+                    url, reason
+                )));
             }
-            Err(error.into())
+            Err(Error::Http {
+                status,
+                url,
+                body,
+                api_error,
+            })
+        } else if let Some(cache) = cache {
+            let url = res.url().clone();
+            let headers = res.headers().clone();
+            let body: serde_json::Value = res.json().await?;
+            cache.store(&url, &headers, &body).await;
+            Ok(serde_json::from_value(body)?)
         } else {
             let result = res.json().await?;
             Ok(result)
         }
     }
 
-    retry_policy.retry(|| inner(&build)).await
+    let attempted = AtomicBool::new(false);
+    retry_policy
+        .retry(|| {
+            metrics::request(attempted.swap(true, Ordering::Relaxed));
+            inner(limiter, cassette, cache, &build)
+        })
+        .await
 }
@@ -0,0 +1,88 @@
+//! A summary of what a sync produced, so downstream consumers don't have to
+//! walk the target directory to find out.
+
+use std::{ops::RangeInclusive, path::PathBuf, sync::Mutex};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::store::AccountKey;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub provider: String,
+    pub from_date: NaiveDate,
+    pub to_date: NaiveDate,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+    pub accounts: Vec<ManifestAccount>,
+    pub cards: Vec<ManifestAccount>,
+    /// Paths written during the sync, relative to the target directory.
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestAccount {
+    pub account_id: String,
+    pub display_name: String,
+    pub currency: String,
+    /// Relative to the target directory.
+    pub dir: PathBuf,
+}
+
+/// Collects manifest entries as the sync progresses.
+#[derive(Debug, Default)]
+pub(crate) struct ManifestRecorder {
+    inner: Mutex<Recorded>,
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    period: Option<(RangeInclusive<NaiveDate>, DateTime<Utc>)>,
+    accounts: Vec<ManifestAccount>,
+    cards: Vec<ManifestAccount>,
+}
+
+impl ManifestRecorder {
+    pub(crate) fn started(&self, period: RangeInclusive<NaiveDate>) {
+        self.inner.lock().expect("lock").period = Some((period, Utc::now()));
+    }
+
+    /// `dir` is where the account's files go, relative to the target directory.
+    pub(crate) fn record(&self, key: &AccountKey, dir: PathBuf) {
+        let (display_name, currency) = match key {
+            AccountKey::Account(account) => (&account.display_name, &account.currency),
+            AccountKey::Card(card) => (&card.display_name, &card.currency),
+        };
+        let entry = ManifestAccount {
+            account_id: key.account_id().to_owned(),
+            display_name: display_name.clone(),
+            currency: currency.clone(),
+            dir,
+        };
+        let mut inner = self.inner.lock().expect("lock");
+        match key {
+            AccountKey::Account(_) => inner.accounts.push(entry),
+            AccountKey::Card(_) => inner.cards.push(entry),
+        }
+    }
+
+    /// Returns `None` if the sync was never started. The file list is left
+    /// for the [`Store`](crate::store::Store) to fill in.
+    pub(crate) fn finish(&self, provider: &str) -> Option<Manifest> {
+        let inner = self.inner.lock().expect("lock");
+        let (period, started_at) = inner.period.clone()?;
+        Some(Manifest {
+            provider: provider.to_owned(),
+            from_date: *period.start(),
+            to_date: *period.end(),
+            started_at,
+            completed_at: Utc::now(),
+            accounts: inner.accounts.clone(),
+            cards: inner.cards.clone(),
+            files: Vec::new(),
+        })
+    }
+}
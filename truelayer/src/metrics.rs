@@ -0,0 +1,197 @@
+//! Process-wide counters for monitoring scheduled scrapes, exported in the
+//! Prometheus text format, either over HTTP or to a textfile collector.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::Write as _,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use axum::{http::header::CONTENT_TYPE, routing::get, Router};
+use chrono::{DateTime, Utc};
+use tempfile::NamedTempFile;
+use tokio::{net::TcpListener, task::spawn_blocking};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument};
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+static METRICS: Metrics = Metrics::new();
+
+struct Metrics {
+    requests: AtomicU64,
+    retries: AtomicU64,
+    not_modified: AtomicU64,
+    jobs: AtomicU64,
+    job_micros: AtomicU64,
+    bytes_written: AtomicU64,
+    last_success: Mutex<BTreeMap<String, DateTime<Utc>>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            not_modified: AtomicU64::new(0),
+            jobs: AtomicU64::new(0),
+            job_micros: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            last_success: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// An HTTP request to TrueLayer; `retry` if it's a repeat attempt.
+pub(crate) fn request(retry: bool) {
+    METRICS.requests.fetch_add(1, Ordering::Relaxed);
+    if retry {
+        METRICS.retries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A conditional request answered from the HTTP cache.
+pub(crate) fn not_modified() {
+    METRICS.not_modified.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn job_finished(duration: Duration) {
+    METRICS.jobs.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .job_micros
+        .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn bytes_written(bytes: u64) {
+    METRICS.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_success(provider: &str, at: DateTime<Utc>) {
+    METRICS
+        .last_success
+        .lock()
+        .expect("lock")
+        .insert(provider.to_owned(), at);
+}
+
+/// The current values, in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    // Each sample is a suffix, such as labels, to append to the name.
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (suffix, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, suffix, value);
+        }
+    };
+    let value = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed) as f64)];
+
+    metric(
+        "tl_scraper_requests_total",
+        "counter",
+        "HTTP requests made to TrueLayer, including retries.",
+        &value(&METRICS.requests),
+    );
+    metric(
+        "tl_scraper_request_retries_total",
+        "counter",
+        "HTTP requests to TrueLayer that were retries.",
+        &value(&METRICS.retries),
+    );
+    metric(
+        "tl_scraper_requests_not_modified_total",
+        "counter",
+        "HTTP requests to TrueLayer answered with 304 Not Modified.",
+        &value(&METRICS.not_modified),
+    );
+    let job_seconds = METRICS.job_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    metric(
+        "tl_scraper_job_duration_seconds",
+        "summary",
+        "How long sync jobs took to run.",
+        &[
+            ("_sum".to_owned(), job_seconds),
+            (
+                "_count".to_owned(),
+                METRICS.jobs.load(Ordering::Relaxed) as f64,
+            ),
+        ],
+    );
+    metric(
+        "tl_scraper_bytes_written_total",
+        "counter",
+        "Bytes written to changed output files.",
+        &value(&METRICS.bytes_written),
+    );
+    let last_success = METRICS
+        .last_success
+        .lock()
+        .expect("lock")
+        .iter()
+        .map(|(provider, at)| {
+            (
+                format!("{{provider=\"{}\"}}", escape(provider)),
+                at.timestamp() as f64,
+            )
+        })
+        .collect::<Vec<_>>();
+    metric(
+        "tl_scraper_last_success_timestamp_seconds",
+        "gauge",
+        "When each provider last synced successfully.",
+        &last_success,
+    );
+    out
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `/metrics` until `cnx` is cancelled.
+#[instrument(skip_all, fields(%addr))]
+pub async fn serve(addr: SocketAddr, cnx: CancellationToken) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Bind metrics listener: {}", addr))?;
+    info!("Serving metrics");
+    let app = Router::new().route(
+        "/metrics",
+        get(|| async { ([(CONTENT_TYPE, CONTENT_TYPE_TEXT)], render()) }),
+    );
+    axum::serve(listener, app)
+        .with_graceful_shutdown(cnx.cancelled_owned())
+        .await
+        .context("Running metrics server")?;
+    Ok(())
+}
+
+/// Writes the current values to `path` for node_exporter's textfile
+/// collector, replacing it atomically.
+pub async fn write_textfile(path: &Path) -> Result<()> {
+    let path = path.to_owned();
+    let content = render();
+    spawn_blocking(move || {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut tmpf = NamedTempFile::new_in(dir)?;
+        tmpf.write_all(content.as_bytes())?;
+        tmpf.as_file_mut().flush()?;
+        tmpf.persist(&path)?;
+        debug!(?path, "Wrote metrics");
+        Ok::<_, anyhow::Error>(())
+    })
+    .await?
+    .context("Writing metrics textfile")
+}
@@ -0,0 +1,234 @@
+//! Telling the user about transactions a sync found that we hadn't stored
+//! before, eg: as a push notification via ntfy.
+//!
+//! Accounts synced for the first time are skipped, so that the initial
+//! import doesn't send the account's whole history.
+//!
+//! New transactions are kept in the target directory until they've been
+//! sent, since once stored, the next sync won't see them as new.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    client::TransactionsResult, error::Context, store::AccountKey, sync::transaction_id, Result,
+};
+
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// New transactions found, but not yet sent, relative to the target directory.
+const UNSENT_FILE: &str = ".unsent-notifications.json";
+
+/// Where to send new transactions once a provider's sync finishes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyConfig {
+    /// POST the new transactions as JSON.
+    Webhook { url: String },
+    /// Publish a summary to an ntfy topic, eg: `https://ntfy.sh/my-topic`.
+    Ntfy { url: String },
+}
+
+/// The new transactions for a single account or card.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NewTransactions {
+    pub account_id: String,
+    pub display_name: String,
+    pub transactions: Vec<TransactionsResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookBody<'a> {
+    provider: &'a str,
+    accounts: &'a [NewTransactions],
+}
+
+/// Collects new transactions as each month is fetched.
+#[derive(Debug, Default)]
+pub(crate) struct NewTransactionsRecorder {
+    watching: Mutex<HashSet<String>>,
+    found: Mutex<BTreeMap<String, NewTransactions>>,
+}
+
+impl NewTransactionsRecorder {
+    /// Look out for new transactions in `key`; only worthwhile for accounts
+    /// that have been synced before.
+    pub(crate) fn watch(&self, key: &AccountKey) {
+        self.watching
+            .lock()
+            .expect("lock")
+            .insert(key.account_id().to_owned());
+    }
+
+    pub(crate) fn is_watching(&self, key: &AccountKey) -> bool {
+        self.watching
+            .lock()
+            .expect("lock")
+            .contains(key.account_id())
+    }
+
+    /// Notes which of `fetched` aren't amongst the `stored` transactions,
+    /// returning whether there were any.
+    pub(crate) fn compare(
+        &self,
+        key: &AccountKey,
+        stored: &[TransactionsResult],
+        fetched: &[TransactionsResult],
+    ) -> bool {
+        let stored = stored
+            .iter()
+            .filter_map(transaction_id)
+            .collect::<HashSet<_>>();
+        let new = fetched
+            .iter()
+            .filter(|tx| transaction_id(tx).map_or(false, |id| !stored.contains(&id)))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.found(key, new)
+    }
+
+    /// Notes `new` transactions, eg: those a seen index doesn't know about,
+    /// returning whether there were any.
+    pub(crate) fn found(&self, key: &AccountKey, new: Vec<TransactionsResult>) -> bool {
+        if new.is_empty() {
+            return false;
+        }
+        debug!(account_id=%key.account_id(), new=%new.len(), "Found new transactions");
+        let display_name = match key {
+            AccountKey::Account(account) => &account.display_name,
+            AccountKey::Card(card) => &card.display_name,
+        };
+        self.add(NewTransactions {
+            account_id: key.account_id().to_owned(),
+            display_name: display_name.clone(),
+            transactions: new,
+        });
+        true
+    }
+
+    /// Merges `account` into what's been found, skipping transactions we
+    /// already have, eg: from a sync that failed before storing them.
+    fn add(&self, account: NewTransactions) {
+        let mut found = self.found.lock().expect("lock");
+        let entry = found
+            .entry(account.account_id.clone())
+            .or_insert_with(|| NewTransactions {
+                transactions: Vec::new(),
+                ..account.clone()
+            });
+        let known = entry
+            .transactions
+            .iter()
+            .filter_map(transaction_id)
+            .collect::<HashSet<_>>();
+        entry.transactions.extend(
+            account
+                .transactions
+                .into_iter()
+                .filter(|tx| transaction_id(tx).map_or(true, |id| !known.contains(&id))),
+        );
+    }
+
+    /// Picks up whatever earlier syncs found but didn't send.
+    pub(crate) async fn restore(&self, target_dir: &Path) -> Result<()> {
+        let path = target_dir.join(UNSENT_FILE);
+        let unsent: Vec<NewTransactions> = scraper_sdk::load_state(&path)
+            .await
+            .with_context(|| format!("Reading {:?}", path))?
+            .unwrap_or_default();
+        if !unsent.is_empty() {
+            debug!(accounts=%unsent.len(), "Restored unsent notifications");
+        }
+        unsent.into_iter().for_each(|account| self.add(account));
+        Ok(())
+    }
+
+    /// Keeps what's been found so far, in case the sync fails before
+    /// sending it.
+    pub(crate) async fn save(&self, target_dir: &Path) -> Result<()> {
+        let found = self
+            .found
+            .lock()
+            .expect("lock")
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        write_unsent(target_dir, found).await
+    }
+
+    pub(crate) fn take(&self) -> Vec<NewTransactions> {
+        std::mem::take(&mut *self.found.lock().expect("lock"))
+            .into_values()
+            .collect()
+    }
+}
+
+async fn write_unsent(target_dir: &Path, accounts: Vec<NewTransactions>) -> Result<()> {
+    let path = target_dir.join(UNSENT_FILE);
+    scraper_sdk::write_json_atomically(&path, accounts)
+        .await
+        .with_context(|| format!("Writing {:?}", path))?;
+    Ok(())
+}
+
+/// Sends `accounts` to `config`, if there are any. Failures are logged
+/// rather than returned, so as not to fail an otherwise successful sync;
+/// the accounts are kept in `target_dir` to try again next time.
+#[instrument(skip_all, fields(%provider))]
+pub(crate) async fn notify(
+    provider: &str,
+    config: &NotifyConfig,
+    target_dir: &Path,
+    accounts: Vec<NewTransactions>,
+) -> Result<()> {
+    if accounts.is_empty() {
+        return Ok(());
+    }
+    match send(provider, config, &accounts).await {
+        Ok(()) => write_unsent(target_dir, Vec::new()).await,
+        Err(error) => {
+            warn!(?error, "Failed to send new transaction notification");
+            write_unsent(target_dir, accounts).await
+        }
+    }
+}
+
+async fn send(provider: &str, config: &NotifyConfig, accounts: &[NewTransactions]) -> Result<()> {
+    let client = reqwest::Client::builder().timeout(NOTIFY_TIMEOUT).build()?;
+    let req = match config {
+        NotifyConfig::Webhook { url } => client.post(url).json(&WebhookBody { provider, accounts }),
+        NotifyConfig::Ntfy { url } => client
+            .post(url)
+            .header("Title", format!("New transactions: {}", provider))
+            .body(ntfy_message(accounts)),
+    };
+    // reqwest's errors name the URL already.
+    let res = req.send().await?;
+    res.error_for_status_ref()?;
+    debug!(status = %res.status(), "Sent new transaction notification");
+    Ok(())
+}
+
+/// Eg: "3 new transactions on Current Account".
+fn ntfy_message(accounts: &[NewTransactions]) -> String {
+    accounts
+        .iter()
+        .map(|account| {
+            let count = account.transactions.len();
+            format!(
+                "{} new transaction{} on {}",
+                count,
+                if count == 1 { "" } else { "s" },
+                account.display_name
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
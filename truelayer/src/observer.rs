@@ -0,0 +1,58 @@
+//! Hooks for embedders to follow a sync as it goes, eg: to drive a progress
+//! display or pass results on elsewhere, without parsing logs.
+
+use std::{
+    fmt,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use chrono::NaiveDate;
+
+use crate::{store::AccountKey, Error};
+
+/// Told about a provider's sync as it progresses. Every method does nothing
+/// by default. They're called from the sync's jobs, so should return
+/// promptly.
+pub trait SyncObserver: Send + Sync + fmt::Debug {
+    /// An account or card that passed any filters, and so will be synced.
+    fn on_account_discovered(&self, _key: &AccountKey) {}
+    /// A month of transactions, starting on `month`, was fetched and stored.
+    fn on_month_fetched(&self, _key: &AccountKey, _month: NaiveDate, _transactions: usize) {}
+    /// A file was written with new content; `path` is relative to the
+    /// target directory. Only reported by the default [`FsStore`].
+    ///
+    /// [`FsStore`]: crate::store::FsStore
+    fn on_file_written(&self, _path: &Path) {}
+    /// The job fetching `job` failed. The sync stops, unless it was told to
+    /// keep going.
+    fn on_error(&self, _job: &str, _error: &Error) {}
+}
+
+/// What a sync is observed by when nobody asked.
+#[derive(Debug)]
+pub(crate) struct Unobserved;
+
+impl SyncObserver for Unobserved {}
+
+/// Passes the files the store writes on to whichever observer the sync is
+/// given, so that the store needn't be rebuilt when that changes.
+#[derive(Debug)]
+pub(crate) struct FileObserver(RwLock<Arc<dyn SyncObserver>>);
+
+impl FileObserver {
+    pub(crate) fn new() -> Self {
+        Self(RwLock::new(Arc::new(Unobserved)))
+    }
+
+    pub(crate) fn set(&self, observer: Arc<dyn SyncObserver>) {
+        *self.0.write().expect("lock") = observer;
+    }
+}
+
+impl SyncObserver for FileObserver {
+    fn on_file_written(&self, path: &Path) {
+        let observer = self.0.read().expect("lock").clone();
+        observer.on_file_written(path);
+    }
+}
@@ -0,0 +1,101 @@
+//! Pruning pending transactions that have since been booked.
+//!
+//! A pending transaction usually gets a fresh `transaction_id` once booked,
+//! but the provider's own id carries over; so we hold on to each account's
+//! pending transactions until its booked transactions are in, and drop any
+//! that match before writing them out. Each account's are written as soon as
+//! its months are done, so that a failure elsewhere doesn't lose them.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+};
+
+use tracing::debug;
+
+use crate::{client::TransactionsResult, store::AccountKey};
+
+#[derive(Debug, Default)]
+pub(crate) struct PendingReconciler {
+    accounts: Mutex<BTreeMap<String, AccountPending>>,
+}
+
+#[derive(Debug, Default)]
+struct AccountPending {
+    months_left: usize,
+    booked: HashSet<String>,
+    pending: Option<(AccountKey, Vec<TransactionsResult>)>,
+}
+
+/// An account's pending transactions, ready to write, and how many were
+/// pruned.
+pub(crate) type Reconciled = (AccountKey, Vec<TransactionsResult>, usize);
+
+impl PendingReconciler {
+    /// Hold `key`'s pending transactions until `months` have been fetched.
+    pub(crate) fn expect(&self, key: &AccountKey, months: usize) {
+        self.accounts
+            .lock()
+            .expect("lock")
+            .entry(key.account_id().to_owned())
+            .or_default()
+            .months_left += months;
+    }
+
+    /// Notes a month's booked transactions; once it's the last we're waiting
+    /// for, returns the account's pending transactions, if they're in.
+    pub(crate) fn booked(
+        &self,
+        key: &AccountKey,
+        txes: &[TransactionsResult],
+    ) -> Option<Reconciled> {
+        let mut accounts = self.accounts.lock().expect("lock");
+        let account = accounts.entry(key.account_id().to_owned()).or_default();
+        account
+            .booked
+            .extend(txes.iter().filter_map(provider_id).map(str::to_owned));
+        account.months_left = account.months_left.saturating_sub(1);
+        Self::ready(&mut accounts, key.account_id())
+    }
+
+    /// Returns `txes`, pruned, if `key`'s months have all been fetched.
+    pub(crate) fn pending(
+        &self,
+        key: &AccountKey,
+        txes: Vec<TransactionsResult>,
+    ) -> Option<Reconciled> {
+        let mut accounts = self.accounts.lock().expect("lock");
+        accounts
+            .entry(key.account_id().to_owned())
+            .or_default()
+            .pending = Some((key.clone(), txes));
+        Self::ready(&mut accounts, key.account_id())
+    }
+
+    fn ready(
+        accounts: &mut BTreeMap<String, AccountPending>,
+        account_id: &str,
+    ) -> Option<Reconciled> {
+        let account = accounts.get(account_id)?;
+        if account.months_left > 0 || account.pending.is_none() {
+            return None;
+        }
+        let AccountPending {
+            booked, pending, ..
+        } = accounts.remove(account_id)?;
+        let (key, mut txes) = pending?;
+        let before = txes.len();
+        txes.retain(|tx| provider_id(tx).map_or(true, |id| !booked.contains(id)));
+        let pruned = before - txes.len();
+        if pruned > 0 {
+            debug!(%account_id, %pruned, "Pruned booked pending transactions");
+        }
+        Some((key, txes, pruned))
+    }
+}
+
+fn provider_id(tx: &TransactionsResult) -> Option<&str> {
+    tx.normalised_provider_transaction_id
+        .as_deref()
+        .or(tx.provider_transaction_id.as_deref())
+}
@@ -0,0 +1,84 @@
+//! Telling a dead man's switch, such as healthchecks.io, how a sync went.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use crate::SyncSummary;
+
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to POST the run summary once a provider's sync finishes, and to
+/// say that it's started.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PingConfig {
+    /// Pinged when the run succeeds.
+    pub success_url: String,
+    /// Pinged when the run fails; defaults to `success_url` with `/fail`
+    /// appended, as healthchecks.io expects.
+    pub failure_url: Option<String>,
+    /// Pinged when the run starts; defaults to `success_url` with `/start`
+    /// appended, as healthchecks.io expects.
+    pub start_url: Option<String>,
+}
+
+impl PingConfig {
+    fn url(&self, success: bool) -> String {
+        match (success, self.failure_url.as_ref()) {
+            (true, _) => self.success_url.clone(),
+            (false, Some(url)) => url.clone(),
+            (false, None) => format!("{}/fail", self.success_url.trim_end_matches('/')),
+        }
+    }
+
+    fn start_url(&self) -> String {
+        match self.start_url.as_ref() {
+            Some(url) => url.clone(),
+            None => format!("{}/start", self.success_url.trim_end_matches('/')),
+        }
+    }
+}
+
+/// Pings `config` with `summary` as the body. Failures are logged rather
+/// than returned, so as not to fail an otherwise successful sync.
+#[instrument(skip_all, fields(%provider))]
+pub async fn ping(provider: &str, config: &PingConfig, summary: &SyncSummary) {
+    let url = config.url(summary.error.is_none());
+    if let Err(error) = send(&url, Some(summary)).await {
+        warn!(?error, "Failed to send healthcheck ping");
+    }
+}
+
+/// Pings `config` to say a run has started, so that one which never
+/// finishes shows up as such.
+#[instrument(skip_all, fields(%provider))]
+pub async fn ping_start(provider: &str, config: &PingConfig) {
+    if let Err(error) = send(&config.start_url(), None).await {
+        warn!(?error, "Failed to send healthcheck start ping");
+    }
+}
+
+/// Pings `config` with a failure, for a run that couldn't be set up, and so
+/// has no summary of its own.
+pub async fn ping_failure(provider: &str, config: &PingConfig, error: &anyhow::Error) {
+    let summary = SyncSummary {
+        error: Some(format!("{:#}", error)),
+        ..Default::default()
+    };
+    ping(provider, config, &summary).await
+}
+
+async fn send(url: &str, summary: Option<&SyncSummary>) -> Result<()> {
+    let client = reqwest::Client::builder().timeout(PING_TIMEOUT).build()?;
+    let mut req = client.post(url);
+    if let Some(summary) = summary {
+        req = req.json(summary);
+    }
+    let res = req.send().await.with_context(|| format!("POST {}", url))?;
+    res.error_for_status_ref()
+        .with_context(|| format!("POST {}", url))?;
+    debug!(status = %res.status(), "Pinged healthcheck");
+    Ok(())
+}
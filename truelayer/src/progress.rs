@@ -0,0 +1,49 @@
+//! A progress bar for interactive runs, driven by the pool's stats.
+
+use std::time::Duration;
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use scraper_sdk::PoolMonitor;
+use tokio::{task::JoinHandle, time::interval};
+
+const REFRESH: Duration = Duration::from_millis(200);
+
+pub struct Progress {
+    bar: ProgressBar,
+    task: JoinHandle<()>,
+}
+
+impl Progress {
+    /// Draws progress on stderr until [`Progress::finish`] is called.
+    pub fn start(monitor: PoolMonitor) -> Self {
+        let bar = ProgressBar::with_draw_target(Some(0), ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40} {pos}/{len} jobs ({prefix} running) {wide_msg}",
+            )
+            .expect("valid template"),
+        );
+        let task = tokio::spawn({
+            let bar = bar.clone();
+            async move {
+                let mut ticks = interval(REFRESH);
+                loop {
+                    ticks.tick().await;
+                    let stats = monitor.stats();
+                    bar.set_length(stats.jobs_submitted as u64);
+                    bar.set_position(stats.jobs_completed as u64);
+                    bar.set_prefix(stats.running_jobs.len().to_string());
+                    let mut running = stats.running_jobs;
+                    running.sort();
+                    bar.set_message(running.first().cloned().unwrap_or_default());
+                }
+            }
+        });
+        Self { bar, task }
+    }
+
+    pub fn finish(self) {
+        self.task.abort();
+        self.bar.finish_and_clear();
+    }
+}
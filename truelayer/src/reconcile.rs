@@ -0,0 +1,137 @@
+//! Checking stored transactions against the balances the provider reports,
+//! to spot scrapes that missed something.
+
+use std::{collections::BTreeMap, ops::RangeInclusive, path::Path};
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use scraper_sdk::months;
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+
+use crate::store::{Layout, Reader, StoredAccount};
+
+/// What's amiss with each account directory's stored transactions (eg:
+/// `accounts/12-34-56 12345678`).
+#[derive(Debug, Default, Serialize)]
+pub struct ReconciliationReport {
+    pub accounts: BTreeMap<String, AccountReconciliation>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct AccountReconciliation {
+    pub transactions: usize,
+    /// Months in the period with nothing stored.
+    pub missing_months: Vec<NaiveDate>,
+    /// Transactions whose running balance doesn't follow from the one
+    /// before, suggesting that something in between is missing.
+    pub balance_gaps: Vec<BalanceGap>,
+    /// The latest running balance, when it disagrees with the stored
+    /// current balance. Pending transactions, or a period ending before the
+    /// balance was fetched, can account for this.
+    pub current_balance: Option<BalanceMismatch>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceGap {
+    pub transaction_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// The previous running balance, plus this transaction's amount.
+    pub expected: Decimal,
+    pub running_balance: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceMismatch {
+    pub running_balance: Decimal,
+    pub current: Decimal,
+}
+
+/// Builds a [`ReconciliationReport`] from the transactions and balances
+/// stored under `target_dir`, arranged per `layout`, for the given period.
+pub async fn reconcile(
+    target_dir: &Path,
+    layout: &Layout,
+    period: RangeInclusive<NaiveDate>,
+) -> Result<ReconciliationReport> {
+    let reader = Reader::new(target_dir).with_layout(layout.clone());
+    spawn_blocking(move || {
+        let mut report = ReconciliationReport::default();
+        for account in reader.accounts()? {
+            let reconciled = reconcile_account(&reader, &account, period.clone())?;
+            report.accounts.insert(account.name, reconciled);
+        }
+        Ok(report)
+    })
+    .await?
+}
+
+impl ReconciliationReport {
+    pub fn is_ok(&self) -> bool {
+        self.accounts.values().all(AccountReconciliation::is_ok)
+    }
+}
+
+impl AccountReconciliation {
+    /// A current balance mismatch alone doesn't count, as it's expected
+    /// while transactions are pending.
+    pub fn is_ok(&self) -> bool {
+        self.missing_months.is_empty() && self.balance_gaps.is_empty()
+    }
+}
+
+fn reconcile_account(
+    reader: &Reader,
+    stored: &StoredAccount,
+    period: RangeInclusive<NaiveDate>,
+) -> Result<AccountReconciliation> {
+    let mut account = AccountReconciliation::default();
+    let mut txes = Vec::new();
+    for month in months(period.clone()) {
+        let Some(month_txes) = reader.month_transactions(stored, *month.start())? else {
+            account.missing_months.push(*month.start());
+            continue;
+        };
+        txes.extend(
+            month_txes
+                .into_iter()
+                .filter(|tx| period.contains(&tx.timestamp.date_naive())),
+        );
+    }
+    // Stable, so same-timestamp transactions keep the order they were
+    // stored in.
+    txes.sort_by_key(|tx| tx.timestamp);
+    account.transactions = txes.len();
+
+    let mut previous: Option<Decimal> = None;
+    for tx in txes.iter() {
+        let Some(running) = tx.running_balance.as_ref() else {
+            previous = None;
+            continue;
+        };
+        if let Some(previous) = previous {
+            let expected = previous + tx.amount;
+            if expected != running.amount {
+                account.balance_gaps.push(BalanceGap {
+                    transaction_id: tx.transaction_id.clone(),
+                    timestamp: tx.timestamp,
+                    expected,
+                    running_balance: running.amount,
+                });
+            }
+        }
+        previous = Some(running.amount);
+    }
+
+    let balances = reader.balance(stored)?;
+    if let (Some(running), Some(balance)) = (previous, balances.first()) {
+        if running != balance.current {
+            account.current_balance = Some(BalanceMismatch {
+                running_balance: running,
+                current: balance.current,
+            });
+        }
+    }
+    Ok(account)
+}
@@ -0,0 +1,248 @@
+//! Masking identifiers, such as account and card numbers, in what we write
+//! out, so it can be shared or backed up without exposing them.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::future::BoxFuture;
+use serde_json::Value;
+
+use crate::{
+    client::{
+        AccountsResult, BalanceResult, BeneficiaryResult, CardsResult, ConnectionResult,
+        DirectDebitResult, ScheduledPaymentResult, StandingOrderResult, TransactionsResult,
+        UserInfoResult,
+    },
+    manifest::Manifest,
+    state::SyncState,
+    store::{AccountKey, Store, StoreStats},
+    Result,
+};
+
+/// How many trailing characters of a number are left visible.
+const VISIBLE_DIGITS: usize = 4;
+const REDACTED_NAME: &str = "REDACTED";
+
+/// Payee details in beneficiaries, payments, standing orders and direct
+/// debits, which we keep as the provider sent them.
+const PAYEE_NUMBER_FIELDS: &[&str] = &["number", "account_number", "sort_code", "iban"];
+const PAYEE_NAME_FIELDS: &[&str] = &["name", "display_name", "payee", "reference"];
+
+pub(crate) fn account(account: &mut AccountsResult) {
+    let number = &mut account.account_number;
+    mask_option(&mut number.iban);
+    mask_option(&mut number.number);
+}
+
+pub(crate) fn card(card: &mut CardsResult) {
+    mask(&mut card.partial_card_number);
+    card.name_on_card = REDACTED_NAME.to_owned();
+}
+
+pub(crate) fn transaction(tx: &mut TransactionsResult) {
+    let meta = &mut tx.meta;
+    mask_option(&mut meta.counter_party_iban);
+    for name in [
+        &mut meta.counter_party_preferred_name,
+        &mut meta.debtor_account_name,
+        &mut meta.creditor_account_name,
+    ] {
+        if let Some(name) = name.as_mut() {
+            *name = REDACTED_NAME.to_owned();
+        }
+    }
+}
+
+pub(crate) fn info(info: &mut UserInfoResult) {
+    info.full_name = REDACTED_NAME.to_owned();
+}
+
+pub(crate) fn standing_order(order: &mut StandingOrderResult) {
+    payee(&mut order.inner);
+}
+
+pub(crate) fn direct_debit(debit: &mut DirectDebitResult) {
+    payee(&mut debit.inner);
+}
+
+pub(crate) fn beneficiary(beneficiary: &mut BeneficiaryResult) {
+    payee(&mut beneficiary.inner);
+}
+
+pub(crate) fn scheduled_payment(payment: &mut ScheduledPaymentResult) {
+    payee(&mut payment.inner);
+}
+
+/// Masks account numbers and sort codes, and replaces names and references,
+/// however deeply nested in `value`.
+fn payee(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                match value {
+                    Value::String(value) if PAYEE_NUMBER_FIELDS.contains(&name.as_str()) => {
+                        mask(value)
+                    }
+                    Value::String(value) if PAYEE_NAME_FIELDS.contains(&name.as_str()) => {
+                        *value = REDACTED_NAME.to_owned()
+                    }
+                    value => payee(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(payee),
+        _ => {}
+    }
+}
+
+/// Replaces all but the last few characters with `*`.
+fn mask(value: &mut String) {
+    let visible = value.chars().count().saturating_sub(VISIBLE_DIGITS);
+    *value = value
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if i < visible { '*' } else { c })
+        .collect();
+}
+
+fn mask_option(value: &mut Option<String>) {
+    if let Some(value) = value.as_mut() {
+        mask(value);
+    }
+}
+
+fn each<T>(mut items: Vec<T>, redact: fn(&mut T)) -> Vec<T> {
+    items.iter_mut().for_each(redact);
+    items
+}
+
+/// Redacts everything on its way into another store.
+pub(crate) struct RedactedStore(pub(crate) Arc<dyn Store>);
+
+impl Store for RedactedStore {
+    fn preflight(&self) -> BoxFuture<'_, Result<()>> {
+        self.0.preflight()
+    }
+
+    fn get_state(&self) -> BoxFuture<'_, Result<Option<SyncState>>> {
+        self.0.get_state()
+    }
+
+    fn put_state(&self, state: SyncState) -> BoxFuture<'_, Result<()>> {
+        self.0.put_state(state)
+    }
+
+    fn put_seen<'a>(&'a self, key: &'a AccountKey, ids: Vec<String>) -> BoxFuture<'a, Result<()>> {
+        self.0.put_seen(key, ids)
+    }
+
+    fn get_unseen<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<Option<Vec<String>>>> {
+        self.0.get_unseen(key, ids)
+    }
+
+    fn put_info(&self, info: Vec<UserInfoResult>) -> BoxFuture<'_, Result<()>> {
+        self.0.put_info(each(info, self::info))
+    }
+
+    fn put_connection(&self, connection: Vec<ConnectionResult>) -> BoxFuture<'_, Result<()>> {
+        self.0.put_connection(connection)
+    }
+
+    fn put_accounts(&self, accounts: Vec<AccountsResult>) -> BoxFuture<'_, Result<()>> {
+        self.0.put_accounts(each(accounts, account))
+    }
+
+    fn put_cards(&self, cards: Vec<CardsResult>) -> BoxFuture<'_, Result<()>> {
+        self.0.put_cards(each(cards, card))
+    }
+
+    fn put_balance<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0.put_balance(key, balance)
+    }
+
+    fn put_balance_snapshot<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        at: DateTime<Utc>,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0.put_balance_snapshot(key, at, balance)
+    }
+
+    fn put_pending<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        pending: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0.put_pending(key, each(pending, transaction))
+    }
+
+    fn put_transactions<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        month: NaiveDate,
+        transactions: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0
+            .put_transactions(key, month, each(transactions, transaction))
+    }
+
+    fn get_transactions<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        month: NaiveDate,
+    ) -> BoxFuture<'a, Result<Option<Vec<TransactionsResult>>>> {
+        self.0.get_transactions(key, month)
+    }
+
+    fn put_standing_orders<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        orders: Vec<StandingOrderResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0
+            .put_standing_orders(key, each(orders, standing_order))
+    }
+
+    fn put_direct_debits<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        debits: Vec<DirectDebitResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0.put_direct_debits(key, each(debits, direct_debit))
+    }
+
+    fn put_scheduled_payments<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        payments: Vec<ScheduledPaymentResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0
+            .put_scheduled_payments(key, each(payments, scheduled_payment))
+    }
+
+    fn put_beneficiaries<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        beneficiaries: Vec<BeneficiaryResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.0
+            .put_beneficiaries(key, each(beneficiaries, beneficiary))
+    }
+
+    fn put_manifest(&self, manifest: Manifest) -> BoxFuture<'_, Result<()>> {
+        self.0.put_manifest(manifest)
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.0.stats()
+    }
+}
@@ -0,0 +1,86 @@
+//! Keeping provider tokens fresh between syncs.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument, warn};
+
+use crate::TlClient;
+
+const DEFAULT_MARGIN: Duration = Duration::from_secs(300);
+/// How long to wait before trying again after a failed refresh.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Refreshes each provider's access token ahead of its expiry, so that
+/// refresh tokens don't lapse when a provider isn't synced for a while.
+pub struct TokenRefresher {
+    clients: BTreeMap<String, Arc<TlClient>>,
+    margin: Duration,
+}
+
+impl TokenRefresher {
+    /// `margin` is how long before expiry to refresh; defaults to five
+    /// minutes.
+    pub fn new(clients: BTreeMap<String, Arc<TlClient>>, margin: Option<Duration>) -> Self {
+        Self {
+            clients,
+            margin: margin.unwrap_or(DEFAULT_MARGIN),
+        }
+    }
+
+    /// Refreshes every provider once, failing if any of them did.
+    pub async fn refresh_all(&self) -> Result<()> {
+        let (_, failed) = self.refresh_round().await;
+        if !failed.is_empty() {
+            bail!("Failed to refresh tokens for: {}", failed.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Refreshes tokens until `cnx` is cancelled, waking up `margin` before
+    /// the earliest expiry.
+    pub async fn keep_alive(&self, cnx: CancellationToken) -> Result<()> {
+        loop {
+            let (earliest, failed) = self.refresh_round().await;
+            let wait = if !failed.is_empty() {
+                RETRY_INTERVAL
+            } else if let Some(expires_at) = earliest {
+                let until = (expires_at - Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .saturating_sub(self.margin);
+                until.max(RETRY_INTERVAL)
+            } else {
+                bail!("No providers to refresh");
+            };
+            info!(?wait, "Next refresh");
+
+            tokio::select! {
+                _ = cnx.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
+    /// Returns the earliest new expiry, and the providers that failed.
+    #[instrument(skip_all)]
+    async fn refresh_round(&self) -> (Option<DateTime<Utc>>, Vec<String>) {
+        let mut earliest: Option<DateTime<Utc>> = None;
+        let mut failed = Vec::new();
+        for (name, client) in self.clients.iter() {
+            match client.refresh_token().await {
+                Ok(expires_at) => {
+                    info!(provider=%name, %expires_at, "Refreshed token");
+                    earliest = Some(earliest.map_or(expires_at, |e| e.min(expires_at)));
+                }
+                Err(error) => {
+                    warn!(provider=%name, ?error, "Failed to refresh token");
+                    failed.push(name.clone());
+                }
+            }
+        }
+        (earliest, failed)
+    }
+}
@@ -0,0 +1,60 @@
+use std::{collections::BTreeMap, ops::RangeInclusive, path::Path};
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+
+use crate::{
+    store::{Layout, Reader},
+    CategoryRules,
+};
+
+const UNCLASSIFIED: &str = "(unclassified)";
+
+/// Counts and totals of `transaction_classification` values, per account
+/// directory (eg: `accounts/12-34-56 12345678`).
+#[derive(Debug, Default, Serialize)]
+pub struct ClassificationReport {
+    pub accounts: BTreeMap<String, BTreeMap<String, ClassificationTotals>>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ClassificationTotals {
+    pub count: usize,
+    pub total: Decimal,
+}
+
+/// Builds a [`ClassificationReport`] from the transactions stored under
+/// `target_dir`, arranged per `layout`, for the given period. With `rules`,
+/// transactions they match are counted under their category instead.
+pub async fn classification_report(
+    target_dir: &Path,
+    layout: &Layout,
+    period: RangeInclusive<NaiveDate>,
+    rules: Option<CategoryRules>,
+) -> Result<ClassificationReport> {
+    let reader = Reader::new(target_dir).with_layout(layout.clone());
+    spawn_blocking(move || {
+        let mut report = ClassificationReport::default();
+        for account in reader.accounts()? {
+            let histogram = report.accounts.entry(account.name.clone()).or_default();
+            for tx in reader.transactions(&account, period.clone())? {
+                let category = rules.as_ref().and_then(|rules| rules.categorise(&tx));
+                let classification = if let Some(category) = category {
+                    category.category.clone()
+                } else if tx.transaction_classification.is_empty() {
+                    UNCLASSIFIED.to_owned()
+                } else {
+                    tx.transaction_classification.join(" / ")
+                };
+                let totals = histogram.entry(classification).or_default();
+                totals.count += 1;
+                totals.total += tx.amount;
+            }
+        }
+        Ok(report)
+    })
+    .await?
+}
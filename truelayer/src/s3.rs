@@ -0,0 +1,285 @@
+//! Just enough of the S3 API to keep files in a bucket, on AWS or anything
+//! compatible with it, eg: MinIO, Cloudflare R2 or Backblaze B2.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, Response, StatusCode};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::{Error, Result};
+
+const S3_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_ACCESS_KEY_ENV: &str = "AWS_ACCESS_KEY_ID";
+const DEFAULT_SECRET_KEY_ENV: &str = "AWS_SECRET_ACCESS_KEY";
+pub(crate) const S3_SCHEME: &str = "s3://";
+
+/// A bucket, and where in it to keep files, eg:
+/// `{ bucket = "finance", prefix = "monzo", endpoint = "https://s3.eu-west-2.amazonaws.com", region = "eu-west-2" }`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to each file's path, eg: `finance/monzo`.
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(flatten)]
+    pub connection: S3Connection,
+}
+
+/// How to reach a bucket, wherever it is.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct S3Connection {
+    /// Defaults to AWS in `region`.
+    pub endpoint: Option<String>,
+    /// Defaults to `us-east-1`.
+    pub region: Option<String>,
+    /// The environment variable holding the access key id; defaults to
+    /// `AWS_ACCESS_KEY_ID`.
+    pub access_key_id_env: Option<String>,
+    /// The environment variable holding the secret access key; defaults to
+    /// `AWS_SECRET_ACCESS_KEY`.
+    pub secret_access_key_env: Option<String>,
+}
+
+impl S3Config {
+    /// Parses a URL such as `s3://finance/monzo`, reaching the bucket per
+    /// `connection`; `None` if it isn't one.
+    pub(crate) fn from_url(url: &str, connection: &S3Connection) -> Option<Self> {
+        let rest = url.strip_prefix(S3_SCHEME)?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        Some(Self {
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+            connection: connection.clone(),
+        })
+    }
+}
+
+/// Signs requests to a bucket with AWS Signature Version 4, addressing it
+/// by path, as every S3-compatible service supports that.
+#[derive(Debug)]
+pub(crate) struct S3Client {
+    http: reqwest::Client,
+    endpoint: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: SecretString,
+}
+
+impl S3Client {
+    pub(crate) fn new(config: &S3Config) -> Result<Self> {
+        let connection = &config.connection;
+        let region = connection
+            .region
+            .clone()
+            .unwrap_or_else(|| DEFAULT_REGION.to_owned());
+        let endpoint = connection
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let var = |name: &Option<String>, default: &str| {
+            let name = name.as_deref().unwrap_or(default);
+            std::env::var(name)
+                .map_err(|e| Error::Config(format!("Reading S3 credentials from ${}: {}", name, e)))
+        };
+        Ok(Self {
+            http: reqwest::Client::builder().timeout(S3_TIMEOUT).build()?,
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.trim_matches('/').to_owned(),
+            region,
+            access_key_id: var(&connection.access_key_id_env, DEFAULT_ACCESS_KEY_ENV)?,
+            secret_access_key: SecretString::new(var(
+                &connection.secret_access_key_env,
+                DEFAULT_SECRET_KEY_ENV,
+            )?),
+        })
+    }
+
+    /// Stores `body` at `path`, relative to the prefix.
+    pub(crate) async fn put(&self, path: &str, body: Vec<u8>) -> Result<()> {
+        self.send(Method::PUT, path, &[], body).await?;
+        Ok(())
+    }
+
+    /// Copies `from` to `to`, both relative to the prefix, within the
+    /// bucket.
+    pub(crate) async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let source = format!("/{}/{}", encode(&self.bucket), encode_key(&self.key(from)));
+        self.send(
+            Method::PUT,
+            to,
+            &[("x-amz-copy-source", source)],
+            Vec::new(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn delete(&self, path: &str) -> Result<()> {
+        self.send(Method::DELETE, path, &[], Vec::new()).await?;
+        Ok(())
+    }
+
+    /// What's stored at `path`, relative to the prefix; `None` if nothing
+    /// is.
+    pub(crate) async fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let res = self.execute(Method::GET, path, &[], Vec::new()).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let res = self.check(&Method::GET, path, res).await?;
+        let body = res.bytes().await?;
+        Ok(Some(body.to_vec()))
+    }
+
+    /// Where `path`, relative to the prefix, is kept in the bucket.
+    fn key(&self, path: &str) -> String {
+        if self.prefix.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<Response> {
+        let res = self.execute(method.clone(), path, headers, body).await?;
+        self.check(&method, path, res).await
+    }
+
+    /// Signs and sends a request, whatever its response.
+    async fn execute(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<Response> {
+        let key = self.key(path);
+        let uri = format!("/{}/{}", encode(&self.bucket), encode_key(&key));
+        let url = format!("{}{}", self.endpoint, uri);
+        let parsed = reqwest::Url::parse(&url)
+            .map_err(|e| Error::Config(format!("S3 URL {:?}: {}", url, e)))?;
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| Error::Config(format!("No host in S3 endpoint: {:?}", self.endpoint)))?;
+        let host = match parsed.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = format!("{:x}", Sha256::digest(&body));
+        let mut signed = vec![
+            ("host".to_owned(), host),
+            ("x-amz-content-sha256".to_owned(), payload_hash.clone()),
+            ("x-amz-date".to_owned(), amz_date.clone()),
+        ];
+        signed.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_owned())),
+        );
+        signed.sort();
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method,
+            uri,
+            signed
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", now.format("%Y%m%d"), self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let signing_key = [
+            now.format("%Y%m%d").to_string().as_str(),
+            self.region.as_str(),
+            "s3",
+            "aws4_request",
+        ]
+        .iter()
+        .fold(
+            format!("AWS4{}", self.secret_access_key.expose_secret()).into_bytes(),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+
+        let mut request = self
+            .http
+            .request(method.clone(), &url)
+            .header("authorization", authorization)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let res = request.body(body).send().await?;
+        debug!(%method, bucket=%self.bucket, %key, status=%res.status(), "S3 request");
+        Ok(res)
+    }
+
+    /// Fails unless `res` was successful.
+    async fn check(&self, method: &Method, path: &str, res: Response) -> Result<Response> {
+        let status = res.status();
+        if !status.is_success() {
+            return Err(Error::S3 {
+                method: method.clone(),
+                url: format!("s3://{}/{}", self.bucket, self.key(path)),
+                status,
+                body: res.text().await.unwrap_or_default(),
+            });
+        }
+        Ok(res)
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, as SigV4 expects.
+fn encode(s: &str) -> String {
+    urlencoding::encode(s).into_owned()
+}
+
+/// Encodes each part of `key`, keeping the `/`s between them.
+fn encode_key(key: &str) -> String {
+    key.split('/').map(encode).collect::<Vec<_>>().join("/")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{error::Context, store::Store, Result};
+
+pub(crate) const STATE_FILE: &str = "state.json";
+
+/// Persisted per-provider sync progress, stored as `state.json` under the
+/// provider's `target_dir` by default.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SyncState {
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountState>,
+    #[serde(default)]
+    pub cards: BTreeMap<String, AccountState>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AccountState {
+    /// Start of the latest month for which it and all earlier months in the
+    /// same run were synced successfully.
+    pub last_synced_month: Option<NaiveDate>,
+    pub last_synced_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Kind {
+    Account,
+    Card,
+}
+
+/// Tracks which months have completed during a run, and advances each
+/// account's watermark once every earlier month scheduled has completed too.
+pub(crate) struct StateTracker {
+    store: Arc<dyn Store>,
+    inner: Mutex<TrackerInner>,
+}
+
+struct TrackerInner {
+    state: SyncState,
+    scheduled: HashMap<(Kind, String), BTreeMap<NaiveDate, bool>>,
+}
+
+impl SyncState {
+    pub async fn load(target_dir: &Path) -> Result<Self> {
+        let path = target_dir.join(STATE_FILE);
+        let state = scraper_sdk::load_state(&path)
+            .await
+            .with_context(|| format!("Loading sync state: {:?}", path))?
+            .unwrap_or_default();
+        Ok(state)
+    }
+
+    fn accounts_mut(&mut self, kind: Kind) -> &mut BTreeMap<String, AccountState> {
+        match kind {
+            Kind::Account => &mut self.accounts,
+            Kind::Card => &mut self.cards,
+        }
+    }
+
+    fn accounts(&self, kind: Kind) -> &BTreeMap<String, AccountState> {
+        match kind {
+            Kind::Account => &self.accounts,
+            Kind::Card => &self.cards,
+        }
+    }
+}
+
+impl StateTracker {
+    pub(crate) async fn load(store: Arc<dyn Store>) -> Result<Self> {
+        let state = store.get_state().await?.unwrap_or_default();
+        Ok(Self {
+            store,
+            inner: Mutex::new(TrackerInner {
+                state,
+                scheduled: HashMap::new(),
+            }),
+        })
+    }
+
+    pub(crate) async fn watermark(&self, kind: Kind, id: &str) -> Option<NaiveDate> {
+        let inner = self.inner.lock().await;
+        inner
+            .state
+            .accounts(kind)
+            .get(id)
+            .and_then(|a| a.last_synced_month)
+    }
+
+    pub(crate) async fn schedule(
+        &self,
+        kind: Kind,
+        id: &str,
+        months: impl IntoIterator<Item = NaiveDate>,
+    ) {
+        let mut inner = self.inner.lock().await;
+        let scheduled = inner.scheduled.entry((kind, id.to_owned())).or_default();
+        scheduled.extend(months.into_iter().map(|m| (m, false)));
+    }
+
+    pub(crate) async fn complete(&self, kind: Kind, id: &str, month: NaiveDate) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        let Some(scheduled) = inner.scheduled.get_mut(&(kind, id.to_owned())) else {
+            return Ok(());
+        };
+        scheduled.insert(month, true);
+        let Some(done_until) = scheduled
+            .iter()
+            .take_while(|(_, done)| **done)
+            .map(|(m, _)| *m)
+            .last()
+        else {
+            return Ok(());
+        };
+
+        let account = inner
+            .state
+            .accounts_mut(kind)
+            .entry(id.to_owned())
+            .or_default();
+        account.last_synced_month = account.last_synced_month.max(Some(done_until));
+        account.last_synced_at = Some(Utc::now());
+        debug!(%id, %done_until, "Advanced sync watermark");
+
+        self.store.put_state(inner.state.clone()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+//! A quick look at each provider's tokens and sync progress, as shown by
+//! `status`, to check on before or after scheduled runs.
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::{ProviderConfig, Result, SyncState, TlClient};
+
+/// A provider's tokens and how far its syncs have got.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderStatus {
+    /// When the access token expires; `None` if we hold no token.
+    pub token_expires_at: Option<DateTime<Utc>>,
+    /// When the refresh token was issued, if known.
+    pub refresh_token_issued_at: Option<DateTime<Utc>>,
+    /// When any account or card last synced a month successfully.
+    pub last_synced_at: Option<DateTime<Utc>>,
+    /// How many accounts and cards have been synced.
+    pub accounts: usize,
+    /// The start of the latest month synced for any account or card.
+    pub latest_month: Option<NaiveDate>,
+}
+
+impl ProviderStatus {
+    /// Reads the stored token and the sync state, without calling the API.
+    pub async fn check(tl: &TlClient, config: &ProviderConfig) -> Result<Self> {
+        let (token_expires_at, refresh_token_issued_at) = match tl.token_expires_at().await {
+            Ok(expires_at) => (Some(expires_at), tl.refresh_token_issued_at().await?),
+            Err(err) if err.needs_reauthentication() => (None, None),
+            Err(err) => return Err(err),
+        };
+        let state = SyncState::load(&config.target_dir).await?;
+        let synced = state.accounts.values().chain(state.cards.values());
+        Ok(Self {
+            token_expires_at,
+            refresh_token_issued_at,
+            last_synced_at: synced.clone().filter_map(|a| a.last_synced_at).max(),
+            accounts: state.accounts.len() + state.cards.len(),
+            latest_month: synced.filter_map(|a| a.last_synced_month).max(),
+        })
+    }
+
+    /// How long ago the refresh token was issued, as of `now`.
+    pub fn refresh_token_age(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.refresh_token_issued_at.map(|at| now - at)
+    }
+}
@@ -0,0 +1,132 @@
+//! Compressing JSON lines files as they're written, and reading them back
+//! however they were written.
+
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Context, Error},
+    Result,
+};
+
+/// How `.jsons` files are compressed, if at all. Compressed files are
+/// written with a `.gz` or `.zst` suffix, eg: `2024-06.jsons.zst`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    /// Requires the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    const ALL: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Where `path` is written with this compression.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        let mut name = OsString::from(path);
+        name.push(self.suffix());
+        PathBuf::from(name)
+    }
+
+    /// Where `path` would be written with any other compression.
+    pub(crate) fn others(&self, path: &Path) -> impl Iterator<Item = PathBuf> {
+        let this = *self;
+        let path = path.to_owned();
+        Self::ALL
+            .iter()
+            .filter(move |c| **c != this)
+            .map(move |c| c.path(&path))
+    }
+
+    /// Fails if this compression isn't supported by this build.
+    pub fn check_supported(&self) -> Result<()> {
+        match self {
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(no_zstd_support()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn compress(&self, content: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(content),
+            Compression::Gzip => {
+                // Without a timestamp in the header, so that the same content
+                // always compresses to the same bytes.
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&content)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(content.as_slice(), 0),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                no_zstd_support().to_string(),
+            )),
+        }
+    }
+
+    fn decompress(&self, content: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(content),
+            Compression::Gzip => {
+                let mut decompressed = Vec::new();
+                flate2::read::GzDecoder::new(content.as_slice()).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::decode_all(content.as_slice()),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                no_zstd_support().to_string(),
+            )),
+        }
+    }
+}
+
+/// Where `path` was written, with whichever compression it was, if at all.
+pub(crate) fn find(path: &Path) -> Option<(PathBuf, Compression)> {
+    Compression::ALL
+        .iter()
+        .map(|c| (c.path(path), *c))
+        .find(|(path, _)| path.exists())
+}
+
+/// The content of `path`, decompressed, however it was written; `None` if
+/// it wasn't.
+pub(crate) fn read_to_string(path: &Path) -> Result<Option<String>> {
+    let Some((path, compression)) = find(path) else {
+        return Ok(None);
+    };
+    let content = std::fs::read(&path).with_context(|| format!("Reading {:?}", path))?;
+    let content = compression
+        .decompress(content)
+        .with_context(|| format!("Decompressing {:?}", path))?;
+    let content = String::from_utf8(content)
+        .map_err(|e| Error::Corrupt(format!("Decoding {:?}: {}", path, e)))?;
+    Ok(Some(content))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn no_zstd_support() -> Error {
+    Error::Config("Zstd compression requires building with the `zstd` feature".to_owned())
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use chrono::NaiveDate;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{account_dir_name, card_dir_name, AccountKey};
+use crate::{client::CardsResult, Error, Result};
+
+/// Where files go under the target directory. Templates may use `/` to
+/// nest directories, and these placeholders:
+///
+/// * `account_dir`: `{kind}` (`accounts` or `cards`), `{account}` (named as
+///   per `account_naming` or `card_naming`), `{account_id}` and
+///   `{display_name}`.
+/// * `month_file`: `{year}` and `{month}`; the extension is added for you.
+///
+/// The classification report only understands the default layout.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Layout {
+    pub account_dir: String,
+    pub month_file: String,
+    pub account_naming: AccountNaming,
+    pub card_naming: CardNaming,
+    /// Filled in as cards are listed, rather than configured.
+    #[serde(skip)]
+    pub card_clashes: CardClashes,
+}
+
+/// How `{account}` names an account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountNaming {
+    /// `<sort code> <number>`, or the id for accounts without them.
+    #[default]
+    SortCode,
+    Id,
+}
+
+/// How `{account}` names a card. Switching from `id` moves the existing
+/// directories on the next sync, leaving a symlink at the old path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CardNaming {
+    #[default]
+    Id,
+    /// `<display name> <partial card number>`, eg: `Platinum Cashback 1234`.
+    DisplayName,
+}
+
+/// The ids of cards that `display_name` naming would give the same
+/// directory as another card, eg: a replacement card, which are named by id
+/// instead. Shared by every copy of a layout, as the cards are only known
+/// once they're listed.
+#[derive(Debug, Clone, Default)]
+pub struct CardClashes(Arc<RwLock<BTreeSet<String>>>);
+
+/// Layouts are the same whatever cards they've seen.
+impl PartialEq for CardClashes {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for CardClashes {}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            account_dir: "{kind}/{account}".to_owned(),
+            month_file: "{year}-{month}".to_owned(),
+            account_naming: AccountNaming::default(),
+            card_naming: CardNaming::default(),
+            card_clashes: CardClashes::default(),
+        }
+    }
+}
+
+impl Layout {
+    /// Fails if either template uses an unknown placeholder, or could put
+    /// different accounts in the same directory.
+    pub fn check(&self) -> Result<()> {
+        if !["{account}", "{account_id}"]
+            .iter()
+            .any(|id| self.account_dir.contains(id))
+        {
+            return Err(Error::Config(format!(
+                "Layout account_dir must include {{account}} or {{account_id}}: {:?}",
+                self.account_dir
+            )));
+        }
+        render(&self.account_dir, &account_vars("kind", "id", "id", "name"))?;
+        render(&self.month_file, &month_vars("2000", "01"))?;
+        Ok(())
+    }
+
+    /// Notes any of `cards` that would share a directory under
+    /// `display_name` naming, so that they're named by id instead.
+    pub fn note_cards(&self, cards: &[CardsResult]) {
+        let mut by_name = BTreeMap::<_, Vec<_>>::new();
+        for card in cards {
+            by_name.entry(card_dir_name(card)).or_default().push(card);
+        }
+        let mut clashes = self.card_clashes.0.write().expect("lock");
+        for (name, cards) in by_name.into_iter().filter(|(_, cards)| cards.len() > 1) {
+            for card in cards {
+                if clashes.insert(card.account_id.clone())
+                    && self.card_naming == CardNaming::DisplayName
+                {
+                    warn!(%name, card=%card.account_id, "Cards share a name; naming by id");
+                }
+            }
+        }
+    }
+
+    /// The directory for this account, relative to the target directory.
+    pub fn account_dir(&self, key: &AccountKey) -> Result<PathBuf> {
+        let (kind, account, display_name) = match key {
+            AccountKey::Account(account) => (
+                "accounts",
+                match self.account_naming {
+                    AccountNaming::SortCode => account_dir_name(account),
+                    AccountNaming::Id => account.account_id.clone(),
+                },
+                &account.display_name,
+            ),
+            AccountKey::Card(card) => (
+                "cards",
+                match self.card_naming {
+                    CardNaming::DisplayName if !self.clashes(card) => card_dir_name(card),
+                    _ => card.account_id.clone(),
+                },
+                &card.display_name,
+            ),
+        };
+        let vars = account_vars(kind, &account, key.account_id(), display_name);
+        Ok(render(&self.account_dir, &vars)?.into())
+    }
+
+    fn clashes(&self, card: &CardsResult) -> bool {
+        let clashes = self.card_clashes.0.read().expect("lock");
+        clashes.contains(&card.account_id)
+    }
+
+    /// The transaction file for the month starting at `month`, relative to
+    /// the account's directory.
+    pub fn month_file(&self, month: NaiveDate, extension: &str) -> Result<PathBuf> {
+        let year = month.format("%Y").to_string();
+        let month = month.format("%m").to_string();
+        let name = render(&self.month_file, &month_vars(&year, &month))?;
+        Ok(format!("{}.{}", name, extension).into())
+    }
+
+    /// Matches the JSON lines transaction files written per `month_file`,
+    /// relative to the account's directory and with `/` separators,
+    /// capturing the `year` and `month`.
+    pub(crate) fn month_file_pattern(&self) -> Result<Regex> {
+        let mut pattern = String::from("^");
+        let mut rest = self.month_file.as_str();
+        while let Some(start) = rest.find('{') {
+            pattern.push_str(&regex::escape(&rest[..start]));
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| unclosed_placeholder(&self.month_file))?;
+            match &rest[start + 1..start + end] {
+                "year" => pattern.push_str(r"(?P<year>\d{4})"),
+                "month" => pattern.push_str(r"(?P<month>\d{2})"),
+                name => return Err(unknown_placeholder(name, &self.month_file)),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        pattern.push_str(&regex::escape(rest));
+        pattern.push_str(r"\.jsons(?:\.gz|\.zst)?$");
+        Regex::new(&pattern).map_err(|e| Error::Config(format!("Layout month_file: {}", e)))
+    }
+}
+
+fn account_vars<'a>(
+    kind: &'a str,
+    account: &'a str,
+    account_id: &'a str,
+    display_name: &'a str,
+) -> [(&'static str, &'a str); 4] {
+    [
+        ("kind", kind),
+        ("account", account),
+        ("account_id", account_id),
+        ("display_name", display_name),
+    ]
+}
+
+fn month_vars<'a>(year: &'a str, month: &'a str) -> [(&'static str, &'a str); 2] {
+    [("year", year), ("month", month)]
+}
+
+/// Substitutes each `{name}` in `template`. Values can't add directories.
+fn render(template: &str, vars: &[(&str, &str)]) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| unclosed_placeholder(template))?;
+        let name = &rest[start + 1..start + end];
+        let value = vars
+            .iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| unknown_placeholder(name, template))?;
+        out.push_str(&value.replace('/', "-"));
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn unclosed_placeholder(template: &str) -> Error {
+    Error::Config(format!("Unclosed placeholder in layout {:?}", template))
+}
+
+fn unknown_placeholder(name: &str, template: &str) -> Error {
+    Error::Config(format!(
+        "Unknown placeholder {{{}}} in layout {:?}",
+        name, template
+    ))
+}
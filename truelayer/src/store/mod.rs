@@ -0,0 +1,817 @@
+//! Where synced data ends up.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use scraper_sdk::{
+    check_target_dir, write_encoded_jsons_atomically, write_json_atomically, SeenIndexes,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task::spawn_blocking;
+use tracing::{debug, info, warn};
+
+use crate::{
+    client::{
+        AccountsResult, BalanceResult, BeneficiaryResult, CardsResult, ConnectionResult,
+        DirectDebitResult, ScheduledPaymentResult, StandingOrderResult, TransactionsResult,
+        UserInfoResult,
+    },
+    error::Context,
+    hashes::HashIndex,
+    manifest::{Manifest, MANIFEST_FILE},
+    metrics,
+    observer::{SyncObserver, Unobserved},
+    state::{SyncState, STATE_FILE},
+    sync::transaction_id,
+    Result,
+};
+
+mod compression;
+mod layout;
+#[cfg(feature = "parquet")]
+mod parquet;
+mod reader;
+mod remote;
+
+pub use compression::Compression;
+pub use layout::{AccountNaming, CardClashes, CardNaming, Layout};
+pub(crate) use reader::slash_separated;
+pub use reader::{Reader, StoredAccount};
+pub use remote::S3Store;
+
+const BALANCE_HISTORY_DIR: &str = "balances";
+const PENDING_HISTORY_DIR: &str = "pending-history";
+const SNAPSHOT_TIMESTAMP: &str = "%Y-%m-%dT%H%M%SZ";
+const SEEN_INDEX_DIR: &str = ".seen";
+
+/// How monthly transaction files are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionFormat {
+    /// One JSON document per line, as with everything else.
+    #[default]
+    Jsonl,
+    /// Parquet with a typed schema; requires the `parquet` feature.
+    Parquet,
+}
+
+/// Identifies the account or card that data belongs to.
+#[derive(Debug, Clone)]
+pub enum AccountKey {
+    Account(AccountsResult),
+    Card(CardsResult),
+}
+
+/// Persistence for synced data. Implementations should make each `put_*`
+/// replace whatever was previously stored for the same key.
+pub trait Store: Send + Sync {
+    /// Checks that the store can be written to, before anything's fetched.
+    fn preflight(&self) -> BoxFuture<'_, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+    /// How far each account has been synced, or `None` if this store
+    /// doesn't keep track.
+    fn get_state(&self) -> BoxFuture<'_, Result<Option<SyncState>>> {
+        async { Ok(None) }.boxed()
+    }
+    fn put_state(&self, _state: SyncState) -> BoxFuture<'_, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+    /// Notes that the transactions with `ids` have been stored for `key`.
+    fn put_seen<'a>(
+        &'a self,
+        _key: &'a AccountKey,
+        _ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+    /// Those of `ids` that definitely haven't been stored for `key`, or
+    /// `None` if this store can't tell.
+    fn get_unseen<'a>(
+        &'a self,
+        _key: &'a AccountKey,
+        _ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<Option<Vec<String>>>> {
+        async { Ok(None) }.boxed()
+    }
+    fn put_info(&self, info: Vec<UserInfoResult>) -> BoxFuture<'_, Result<()>>;
+    fn put_connection(&self, connection: Vec<ConnectionResult>) -> BoxFuture<'_, Result<()>>;
+    fn put_accounts(&self, accounts: Vec<AccountsResult>) -> BoxFuture<'_, Result<()>>;
+    fn put_cards(&self, cards: Vec<CardsResult>) -> BoxFuture<'_, Result<()>>;
+    fn put_balance<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    /// Records `balance` as of `at`, without replacing earlier snapshots.
+    fn put_balance_snapshot<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        at: DateTime<Utc>,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    fn put_pending<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        pending: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    /// Stores the transactions for the month starting at `month`.
+    fn put_transactions<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        month: NaiveDate,
+        transactions: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    /// The transactions stored for the month starting at `month`, or `None`
+    /// if this store can't read them back.
+    fn get_transactions<'a>(
+        &'a self,
+        _key: &'a AccountKey,
+        _month: NaiveDate,
+    ) -> BoxFuture<'a, Result<Option<Vec<TransactionsResult>>>> {
+        async { Ok(None) }.boxed()
+    }
+    fn put_standing_orders<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        orders: Vec<StandingOrderResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    fn put_direct_debits<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        debits: Vec<DirectDebitResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    fn put_scheduled_payments<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        payments: Vec<ScheduledPaymentResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    fn put_beneficiaries<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        beneficiaries: Vec<BeneficiaryResult>,
+    ) -> BoxFuture<'a, Result<()>>;
+    /// Called once at the end of a successful sync. Stores that know where
+    /// they put things should fill in `manifest.files`.
+    fn put_manifest(&self, manifest: Manifest) -> BoxFuture<'_, Result<()>>;
+    /// What this store has done so far, where it keeps track.
+    fn stats(&self) -> StoreStats {
+        StoreStats::default()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StoreStats {
+    pub files_written: usize,
+    /// Files rewritten with exactly what they already held.
+    pub files_unchanged: usize,
+    /// The files written, relative to the target directory.
+    pub written: Vec<PathBuf>,
+}
+
+/// Stores each kind of data as JSON lines files under a target directory.
+#[derive(Debug, Clone)]
+pub struct FsStore {
+    target_dir: PathBuf,
+    written: Arc<Mutex<BTreeSet<PathBuf>>>,
+    unchanged: Arc<Mutex<BTreeSet<PathBuf>>>,
+    hashes: Arc<HashIndex>,
+    observer: Arc<dyn SyncObserver>,
+    merge_transactions: bool,
+    transaction_format: TransactionFormat,
+    compression: Compression,
+    layout: Layout,
+    pending_retention: Option<chrono::Duration>,
+    seen: Option<Arc<SeenIndexes>>,
+}
+
+impl AccountKey {
+    pub fn account_id(&self) -> &str {
+        match self {
+            AccountKey::Account(account) => &account.account_id,
+            AccountKey::Card(card) => &card.account_id,
+        }
+    }
+
+    /// The directory for this account, relative to the target directory,
+    /// in the default [`Layout`].
+    pub fn dir(&self) -> PathBuf {
+        match self {
+            AccountKey::Account(account) => Path::new("accounts").join(account_dir_name(account)),
+            AccountKey::Card(card) => Path::new("cards").join(&card.account_id),
+        }
+    }
+}
+
+impl FsStore {
+    pub fn new(target_dir: &Path) -> Self {
+        Self {
+            target_dir: target_dir.to_owned(),
+            written: Default::default(),
+            unchanged: Default::default(),
+            hashes: Arc::new(HashIndex::new(target_dir)),
+            observer: Arc::new(Unobserved),
+            merge_transactions: false,
+            transaction_format: TransactionFormat::default(),
+            compression: Compression::default(),
+            layout: Layout::default(),
+            pending_retention: None,
+            seen: None,
+        }
+    }
+
+    /// Merging only applies to JSONL.
+    pub fn with_transaction_format(mut self, format: TransactionFormat) -> Self {
+        self.transaction_format = format;
+        self
+    }
+
+    /// Keep transactions already stored for a month that are missing when
+    /// it's re-fetched, rather than replacing the month outright.
+    pub fn with_merge_transactions(mut self, merge: bool) -> Self {
+        self.merge_transactions = merge;
+        self
+    }
+
+    /// Compress JSON lines files as they're written; the `.gz` or `.zst`
+    /// suffix is added to their names.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Before replacing an account's pending transactions with different
+    /// ones, keep the old ones in `pending-history`, named for when they
+    /// were replaced, for `retention`.
+    pub fn with_pending_retention(mut self, retention: chrono::Duration) -> Self {
+        self.pending_retention = Some(retention);
+        self
+    }
+
+    /// Keep a bloom filter of the transaction ids stored for each account,
+    /// so that new ones can be found without reading back each month.
+    pub fn with_seen_index(mut self, enabled: bool) -> Self {
+        self.seen = enabled.then(Default::default);
+        self
+    }
+
+    /// Tell `observer` about each file written.
+    pub fn with_observer(mut self, observer: Arc<dyn SyncObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    fn seen_path(&self, key: &AccountKey) -> Result<PathBuf> {
+        Ok(self
+            .target_dir
+            .join(SEEN_INDEX_DIR)
+            .join(self.layout.account_dir(key)?))
+    }
+
+    fn account_file(&self, key: &AccountKey, name: &str) -> Result<PathBuf> {
+        Ok(self.layout.account_dir(key)?.join(name))
+    }
+
+    fn month_path(&self, key: &AccountKey, month: NaiveDate) -> Result<PathBuf> {
+        let file = self
+            .layout
+            .month_file(month, self.transaction_format.extension())?;
+        Ok(self.layout.account_dir(key)?.join(file))
+    }
+
+    /// Moves a card's files from the directory it'd have when named by id,
+    /// when it's now named otherwise, leaving a symlink in its place on unix
+    /// so that anything reading from the old path still finds them.
+    async fn migrate_card_dir(&self, key: &AccountKey) -> Result<()> {
+        let dir = self.layout.account_dir(key)?;
+        let by_id = Layout {
+            card_naming: CardNaming::Id,
+            ..self.layout.clone()
+        }
+        .account_dir(key)?;
+        if dir == by_id {
+            return Ok(());
+        }
+        let (from, to) = (self.target_dir.join(&by_id), self.target_dir.join(&dir));
+        match tokio::fs::symlink_metadata(&from).await {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Checking {:?}", from)),
+        }
+        if tokio::fs::try_exists(&to).await? {
+            warn!(
+                ?from,
+                ?to,
+                "Both old and new card directories exist; leaving both"
+            );
+            return Ok(());
+        }
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&from, &to)
+            .await
+            .with_context(|| format!("Moving {:?} to {:?}", from, to))?;
+        self.hashes.rename_dir(&by_id, &dir).await?;
+        #[cfg(unix)]
+        {
+            let target = if by_id.parent() == dir.parent() {
+                PathBuf::from(dir.file_name().expect("card directory name"))
+            } else {
+                to.clone()
+            };
+            tokio::fs::symlink(&target, &from)
+                .await
+                .with_context(|| format!("Linking {:?} to {:?}", from, target))?;
+        }
+        info!(?from, ?to, "Moved card directory");
+        Ok(())
+    }
+
+    /// Keeps what's stored at `path` in the pending history if `pending` is
+    /// going to replace it, and removes snapshots older than `retention`.
+    async fn archive_pending(
+        &self,
+        key: &AccountKey,
+        path: &Path,
+        pending: &[TransactionsResult],
+        retention: chrono::Duration,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let history = self.account_file(key, PENDING_HISTORY_DIR)?;
+        let previous: Vec<TransactionsResult> = read_jsons(&self.target_dir.join(path)).await?;
+        if !previous.is_empty() && previous != pending {
+            let name = format!("{}.jsons", now.format(SNAPSHOT_TIMESTAMP));
+            debug!(?history, %name, "Archiving replaced pending transactions");
+            self.write_jsons(history.join(name), previous).await?;
+        }
+
+        let full_history = self.target_dir.join(&history);
+        let mut entries = match tokio::fs::read_dir(&full_history).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Listing {:?}", full_history)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(taken_at) = name
+                .to_str()
+                .and_then(|name| name.split('.').next())
+                .and_then(|stamp| NaiveDateTime::parse_from_str(stamp, SNAPSHOT_TIMESTAMP).ok())
+            else {
+                continue;
+            };
+            if taken_at.and_utc() + retention < now {
+                debug!(?name, "Removing expired pending snapshot");
+                tokio::fs::remove_file(entry.path())
+                    .await
+                    .with_context(|| format!("Removing {:?}", entry.path()))?;
+                self.hashes.forget(&history.join(&name)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to `path`, relative to the target directory, with the
+    /// configured compression. Removes any copy written with another.
+    async fn write_jsons<T: Serialize + Send + 'static>(
+        &self,
+        path: PathBuf,
+        data: Vec<T>,
+    ) -> Result<()> {
+        for other in self.compression.others(&path) {
+            match tokio::fs::remove_file(self.target_dir.join(&other)).await {
+                Ok(()) => {
+                    debug!(path=?other, "Removed copy with other compression");
+                    self.hashes.forget(&other).await?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("Removing {:?}", other)),
+            }
+        }
+        let path = self.compression.path(&path);
+        let full_path = self.target_dir.join(&path);
+        let compression = self.compression;
+        let changed = write_encoded_jsons_atomically(&full_path, data, move |content| {
+            compression.compress(content)
+        })
+        .await?;
+        self.hashes.record(&path).await?;
+        if changed {
+            record_bytes_written(&full_path).await;
+            self.observer.on_file_written(&path);
+            self.written.lock().expect("lock").insert(path);
+        } else {
+            self.unchanged.lock().expect("lock").insert(path);
+        }
+        Ok(())
+    }
+}
+
+impl FsStore {
+    #[cfg(feature = "parquet")]
+    async fn write_parquet(&self, path: PathBuf, txes: Vec<TransactionsResult>) -> Result<()> {
+        let full_path = self.target_dir.join(&path);
+        let changed = parquet::write_transactions(&full_path, txes).await?;
+        self.hashes.record(&path).await?;
+        if changed {
+            record_bytes_written(&full_path).await;
+            self.observer.on_file_written(&path);
+            self.written.lock().expect("lock").insert(path);
+        } else {
+            self.unchanged.lock().expect("lock").insert(path);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    async fn write_parquet(&self, _: PathBuf, _: Vec<TransactionsResult>) -> Result<()> {
+        Err(no_parquet_support())
+    }
+
+    #[cfg(feature = "parquet")]
+    async fn read_parquet(&self, path: &Path) -> Result<Vec<TransactionsResult>> {
+        parquet::read_transactions(&self.target_dir.join(path)).await
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    async fn read_parquet(&self, _: &Path) -> Result<Vec<TransactionsResult>> {
+        Err(no_parquet_support())
+    }
+}
+
+impl TransactionFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TransactionFormat::Jsonl => "jsons",
+            TransactionFormat::Parquet => "parquet",
+        }
+    }
+
+    /// Fails if this format isn't supported by this build.
+    pub fn check_supported(&self) -> Result<()> {
+        match self {
+            #[cfg(not(feature = "parquet"))]
+            TransactionFormat::Parquet => Err(no_parquet_support()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+fn no_parquet_support() -> crate::Error {
+    crate::Error::Config("Parquet output requires building with the `parquet` feature".to_owned())
+}
+
+impl Store for FsStore {
+    fn preflight(&self) -> BoxFuture<'_, Result<()>> {
+        async move {
+            check_target_dir(&self.target_dir).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_state(&self) -> BoxFuture<'_, Result<Option<SyncState>>> {
+        async move { Ok(Some(SyncState::load(&self.target_dir).await?)) }.boxed()
+    }
+
+    fn put_state(&self, state: SyncState) -> BoxFuture<'_, Result<()>> {
+        async move {
+            let path = self.target_dir.join(STATE_FILE);
+            scraper_sdk::store_state(&path, state)
+                .await
+                .with_context(|| format!("Storing sync state: {:?}", path))?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_seen<'a>(&'a self, key: &'a AccountKey, ids: Vec<String>) -> BoxFuture<'a, Result<()>> {
+        async move {
+            if let Some(seen) = self.seen.as_ref() {
+                let new = seen.record(&self.seen_path(key)?, ids).await?;
+                info!(%new, "New transactions seen");
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_unseen<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<Option<Vec<String>>>> {
+        async move {
+            match self.seen.as_ref() {
+                Some(seen) => Ok(seen.unseen(&self.seen_path(key)?, ids).await?),
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    fn put_info(&self, info: Vec<UserInfoResult>) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.write_jsons("user-info.jsons".into(), info).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_connection(&self, connection: Vec<ConnectionResult>) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.write_jsons("connection.jsons".into(), connection)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_accounts(&self, accounts: Vec<AccountsResult>) -> BoxFuture<'_, Result<()>> {
+        async move {
+            for account in accounts {
+                let key = AccountKey::Account(account.clone());
+                self.write_jsons(self.account_file(&key, "account.jsons")?, vec![account])
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_cards(&self, cards: Vec<CardsResult>) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.write_jsons("cards.jsons".into(), cards.clone())
+                .await?;
+            self.layout.note_cards(&cards);
+            for card in cards {
+                let key = AccountKey::Card(card.clone());
+                self.migrate_card_dir(&key).await?;
+                self.write_jsons(self.account_file(&key, "account.jsons")?, vec![card])
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_balance<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.write_jsons(self.account_file(key, "balance.jsons")?, balance)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_balance_snapshot<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        at: DateTime<Utc>,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let name = format!("{}.jsons", at.format(SNAPSHOT_TIMESTAMP));
+            let path = self.account_file(key, BALANCE_HISTORY_DIR)?.join(name);
+            self.write_jsons(path, balance).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_pending<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        pending: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let mut pending = pending;
+            sort_transactions(&mut pending);
+            let path = self.account_file(key, "pending.jsons")?;
+            if let Some(retention) = self.pending_retention {
+                self.archive_pending(key, &path, &pending, retention)
+                    .await?;
+            }
+            self.write_jsons(path, pending).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_transactions<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        month: NaiveDate,
+        transactions: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let path = self.layout.account_dir(key)?.join(
+                self.layout
+                    .month_file(month, self.transaction_format.extension())?,
+            );
+            let mut transactions = transactions;
+            sort_transactions(&mut transactions);
+            let parquet = self.transaction_format == TransactionFormat::Parquet;
+            let transactions = if self.merge_transactions {
+                let existing = if parquet {
+                    self.read_parquet(&path).await?
+                } else {
+                    read_jsons(&self.target_dir.join(&path)).await?
+                };
+                merge_transactions(existing, transactions)
+            } else {
+                transactions
+            };
+            if parquet {
+                self.write_parquet(path, transactions).await
+            } else {
+                self.write_jsons(path, transactions).await
+            }
+        }
+        .boxed()
+    }
+
+    fn get_transactions<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        month: NaiveDate,
+    ) -> BoxFuture<'a, Result<Option<Vec<TransactionsResult>>>> {
+        async move {
+            let path = self.month_path(key, month)?;
+            if self.transaction_format == TransactionFormat::Parquet {
+                return Ok(Some(self.read_parquet(&path).await?));
+            }
+            Ok(Some(read_jsons(&self.target_dir.join(path)).await?))
+        }
+        .boxed()
+    }
+
+    fn put_standing_orders<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        orders: Vec<StandingOrderResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.write_jsons(self.account_file(key, "standing-orders.jsons")?, orders)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_direct_debits<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        debits: Vec<DirectDebitResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.write_jsons(self.account_file(key, "direct-debits.jsons")?, debits)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_scheduled_payments<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        payments: Vec<ScheduledPaymentResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.write_jsons(
+                self.account_file(key, "scheduled-payments.jsons")?,
+                payments,
+            )
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn put_beneficiaries<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        beneficiaries: Vec<BeneficiaryResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.write_jsons(
+                self.account_file(key, "beneficiaries.jsons")?,
+                beneficiaries,
+            )
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn stats(&self) -> StoreStats {
+        let written = self.written.lock().expect("lock");
+        StoreStats {
+            files_written: written.len(),
+            files_unchanged: self.unchanged.lock().expect("lock").len(),
+            written: written.iter().cloned().collect(),
+        }
+    }
+
+    fn put_manifest(&self, mut manifest: Manifest) -> BoxFuture<'_, Result<()>> {
+        async move {
+            let written = self.written.lock().expect("lock").clone();
+            let unchanged = self.unchanged.lock().expect("lock").clone();
+            manifest.files = written.union(&unchanged).cloned().collect();
+            write_json_atomically(&self.target_dir.join(MANIFEST_FILE), manifest).await?;
+            self.hashes.record(Path::new(MANIFEST_FILE)).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+async fn record_bytes_written(path: &Path) {
+    match tokio::fs::metadata(path).await {
+        Ok(meta) => metrics::bytes_written(meta.len()),
+        Err(error) => debug!(?path, %error, "Could not stat written file"),
+    }
+}
+
+/// As [`reader::read_jsons`], without blocking.
+async fn read_jsons<T: DeserializeOwned + Send + 'static>(path: &Path) -> Result<Vec<T>> {
+    let path = path.to_owned();
+    spawn_blocking(move || reader::read_jsons(&path)).await?
+}
+
+/// The union of `existing` and `fetched`, preferring the fetched version of
+/// any transaction in both, in timestamp order.
+fn merge_transactions(
+    existing: Vec<TransactionsResult>,
+    fetched: Vec<TransactionsResult>,
+) -> Vec<TransactionsResult> {
+    let fetched_keys = fetched.iter().map(merge_key).collect::<HashSet<_>>();
+    let retained = existing
+        .into_iter()
+        .filter(|tx| !fetched_keys.contains(&merge_key(tx)))
+        .collect::<Vec<_>>();
+    if retained.is_empty() {
+        return fetched;
+    }
+    debug!(retained = %retained.len(), "Keeping transactions missing from the response");
+    let mut merged = fetched;
+    merged.extend(retained);
+    sort_transactions(&mut merged);
+    merged
+}
+
+/// Identifies a transaction across fetches: by its id, or for those without
+/// one, by a hash of what it says.
+fn merge_key(tx: &TransactionsResult) -> String {
+    transaction_id(tx).unwrap_or_else(|| {
+        let content = serde_json::to_vec(tx).expect("serialise transaction");
+        format!("{:x}", Sha256::digest(content))
+    })
+}
+
+/// Orders by timestamp, then id, so that re-fetching the same transactions
+/// writes them out the same way whatever order the provider sent them in.
+fn sort_transactions(transactions: &mut [TransactionsResult]) {
+    transactions.sort_by_cached_key(|tx| (tx.timestamp, transaction_id(tx)));
+}
+
+/// `<display name> <partial card number>`, with any `/` replaced, or the id
+/// for cards with neither, or whose name isn't usable as a directory.
+pub(crate) fn card_dir_name(card: &CardsResult) -> String {
+    let name = format!("{} {}", card.display_name, card.partial_card_number).replace('/', "-");
+    match name.trim() {
+        "" | "." | ".." => card.account_id.clone(),
+        name => name.to_owned(),
+    }
+}
+
+pub(crate) fn account_dir_name(account: &AccountsResult) -> String {
+    let account_path = if let (Some(sort_code), Some(number)) = (
+        account.account_number.sort_code.as_ref(),
+        account.account_number.number.as_ref(),
+    ) {
+        format!("{} {}", sort_code, number)
+    } else {
+        account.account_id.clone()
+    };
+    account_path
+}
@@ -0,0 +1,262 @@
+//! Monthly transaction files as Parquet, for querying directly with the
+//! likes of DuckDB or Polars.
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use arrow_array::{
+    builder::{Decimal128Builder, ListBuilder, StringBuilder, TimestampMicrosecondBuilder},
+    cast::AsArray,
+    types::{Decimal128Type, TimestampMicrosecondType},
+    Array, ArrayRef, Decimal128Array, ListArray, PrimitiveArray, RecordBatch, StringArray,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::DateTime;
+use parquet::arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter};
+use rust_decimal::Decimal;
+use tempfile::NamedTempFile;
+use tokio::task::spawn_blocking;
+use tracing::{debug, Span};
+
+use crate::{
+    client::{TransactionsResult, TransactionsRunningBalance},
+    error::Context,
+    Error, Result,
+};
+
+const DECIMAL_PRECISION: u8 = 38;
+/// Enough for any currency's minor units.
+const DECIMAL_SCALE: u32 = 4;
+
+fn schema() -> Schema {
+    let decimal = DataType::Decimal128(DECIMAL_PRECISION, DECIMAL_SCALE as i8);
+    Schema::new(vec![
+        Field::new("transaction_id", DataType::Utf8, true),
+        Field::new("normalised_provider_transaction_id", DataType::Utf8, true),
+        Field::new("provider_transaction_id", DataType::Utf8, true),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("description", DataType::Utf8, false),
+        Field::new("amount", decimal.clone(), false),
+        Field::new("currency", DataType::Utf8, false),
+        Field::new("transaction_type", DataType::Utf8, false),
+        Field::new("transaction_category", DataType::Utf8, false),
+        Field::new(
+            "transaction_classification",
+            DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+            false,
+        ),
+        Field::new("merchant_name", DataType::Utf8, true),
+        Field::new("running_balance_amount", decimal, true),
+        Field::new("running_balance_currency", DataType::Utf8, true),
+        // Provider specific, so kept as JSON.
+        Field::new("meta", DataType::Utf8, false),
+    ])
+}
+
+/// Writes `txes` to `path` as a single row group, replacing it atomically.
+/// Returns false, leaving the file be, if it already held exactly that.
+pub(super) async fn write_transactions(path: &Path, txes: Vec<TransactionsResult>) -> Result<bool> {
+    let path = path.to_owned();
+    let span = Span::current();
+    spawn_blocking(move || {
+        let _entered = span.enter();
+        let batch = record_batch(&txes)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        let mut tmpf = NamedTempFile::new_in(dir)?;
+        let mut writer = ArrowWriter::try_new(tmpf.as_file_mut(), batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        tmpf.as_file_mut().flush()?;
+        match std::fs::read(&path) {
+            Ok(existing) if existing == std::fs::read(tmpf.path())? => {
+                debug!(?path, "Unchanged parquet");
+                return Ok(false);
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Reading {:?}", path)),
+        }
+        tmpf.persist(&path).map_err(|e| e.error)?;
+        debug!(?path, rows = %batch.num_rows(), "Stored parquet");
+        Ok(true)
+    })
+    .await?
+}
+
+/// Reads back what [`write_transactions`] wrote to `path`, if anything.
+pub(super) async fn read_transactions(path: &Path) -> Result<Vec<TransactionsResult>> {
+    let path = path.to_owned();
+    let span = Span::current();
+    spawn_blocking(move || {
+        let _entered = span.enter();
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Opening {:?}", path)),
+        };
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+        let mut txes = Vec::new();
+        for batch in reader {
+            txes.extend(transactions(&batch?).map_err(|e| corrupt(&path, e))?);
+        }
+        Ok(txes)
+    })
+    .await?
+}
+
+fn record_batch(txes: &[TransactionsResult]) -> Result<RecordBatch> {
+    let decimal = || {
+        Decimal128Builder::with_capacity(txes.len())
+            .with_precision_and_scale(DECIMAL_PRECISION, DECIMAL_SCALE as i8)
+    };
+    let mut transaction_id = StringBuilder::new();
+    let mut normalised_id = StringBuilder::new();
+    let mut provider_id = StringBuilder::new();
+    let mut timestamp = TimestampMicrosecondBuilder::with_capacity(txes.len()).with_timezone("UTC");
+    let mut description = StringBuilder::new();
+    let mut amount = decimal()?;
+    let mut currency = StringBuilder::new();
+    let mut transaction_type = StringBuilder::new();
+    let mut category = StringBuilder::new();
+    let mut classification = ListBuilder::new(StringBuilder::new());
+    let mut merchant_name = StringBuilder::new();
+    let mut balance_amount = decimal()?;
+    let mut balance_currency = StringBuilder::new();
+    let mut meta = StringBuilder::new();
+
+    for tx in txes {
+        transaction_id.append_option(tx.transaction_id.as_deref());
+        normalised_id.append_option(tx.normalised_provider_transaction_id.as_deref());
+        provider_id.append_option(tx.provider_transaction_id.as_deref());
+        timestamp.append_value(tx.timestamp.timestamp_micros());
+        description.append_value(&tx.description);
+        amount.append_value(scaled(tx.amount));
+        currency.append_value(&tx.currency);
+        transaction_type.append_value(&tx.transaction_type);
+        category.append_value(&tx.transaction_category);
+        for class in tx.transaction_classification.iter() {
+            classification.values().append_value(class);
+        }
+        classification.append(true);
+        merchant_name.append_option(tx.merchant_name.as_deref());
+        balance_amount.append_option(tx.running_balance.as_ref().map(|b| scaled(b.amount)));
+        balance_currency.append_option(tx.running_balance.as_ref().map(|b| &b.currency));
+        meta.append_value(serde_json::to_string(&tx.meta)?);
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(transaction_id.finish()),
+        Arc::new(normalised_id.finish()),
+        Arc::new(provider_id.finish()),
+        Arc::new(timestamp.finish()),
+        Arc::new(description.finish()),
+        Arc::new(amount.finish()),
+        Arc::new(currency.finish()),
+        Arc::new(transaction_type.finish()),
+        Arc::new(category.finish()),
+        Arc::new(classification.finish()),
+        Arc::new(merchant_name.finish()),
+        Arc::new(balance_amount.finish()),
+        Arc::new(balance_currency.finish()),
+        Arc::new(meta.finish()),
+    ];
+    Ok(RecordBatch::try_new(Arc::new(schema()), columns)?)
+}
+
+fn scaled(mut amount: Decimal) -> i128 {
+    amount.rescale(DECIMAL_SCALE);
+    amount.mantissa()
+}
+
+fn unscaled(amount: i128) -> Decimal {
+    Decimal::from_i128_with_scale(amount, DECIMAL_SCALE)
+}
+
+/// What's wrong with a file that doesn't hold what we'd write.
+fn corrupt(path: &Path, problem: String) -> Error {
+    Error::Corrupt(format!("{:?}: {}", path, problem))
+}
+
+fn transactions(batch: &RecordBatch) -> std::result::Result<Vec<TransactionsResult>, String> {
+    let transaction_id = strings(batch, "transaction_id")?;
+    let normalised_id = strings(batch, "normalised_provider_transaction_id")?;
+    let provider_id = strings(batch, "provider_transaction_id")?;
+    let timestamp: &PrimitiveArray<TimestampMicrosecondType> = column(batch, "timestamp")?
+        .as_primitive_opt()
+        .ok_or("timestamp isn't a timestamp")?;
+    let description = strings(batch, "description")?;
+    let amount = decimals(batch, "amount")?;
+    let currency = strings(batch, "currency")?;
+    let transaction_type = strings(batch, "transaction_type")?;
+    let category = strings(batch, "transaction_category")?;
+    let classification: &ListArray = column(batch, "transaction_classification")?
+        .as_list_opt()
+        .ok_or("transaction_classification isn't a list")?;
+    let merchant_name = strings(batch, "merchant_name")?;
+    let balance_amount = decimals(batch, "running_balance_amount")?;
+    let balance_currency = strings(batch, "running_balance_currency")?;
+    let meta = strings(batch, "meta")?;
+
+    let optional =
+        |array: &StringArray, i: usize| array.is_valid(i).then(|| array.value(i).to_owned());
+    (0..batch.num_rows())
+        .map(|i| {
+            let classes = classification.value(i);
+            let classes = classes
+                .as_string_opt::<i32>()
+                .ok_or("transaction_classification isn't strings")?;
+            Ok(TransactionsResult {
+                transaction_id: optional(transaction_id, i),
+                normalised_provider_transaction_id: optional(normalised_id, i),
+                provider_transaction_id: optional(provider_id, i),
+                timestamp: DateTime::from_timestamp_micros(timestamp.value(i))
+                    .ok_or("Timestamp out of range")?,
+                description: description.value(i).to_owned(),
+                amount: unscaled(amount.value(i)),
+                currency: currency.value(i).to_owned(),
+                transaction_type: transaction_type.value(i).to_owned(),
+                transaction_category: category.value(i).to_owned(),
+                transaction_classification: classes.iter().flatten().map(str::to_owned).collect(),
+                merchant_name: optional(merchant_name, i),
+                running_balance: match (balance_amount.is_valid(i), optional(balance_currency, i)) {
+                    (true, Some(currency)) => Some(TransactionsRunningBalance {
+                        amount: unscaled(balance_amount.value(i)),
+                        currency,
+                    }),
+                    _ => None,
+                },
+                meta: serde_json::from_str(meta.value(i)).map_err(|e| e.to_string())?,
+                other: serde_json::Value::Object(Default::default()),
+            })
+        })
+        .collect()
+}
+
+fn column<'a>(batch: &'a RecordBatch, name: &str) -> std::result::Result<&'a ArrayRef, String> {
+    batch
+        .column_by_name(name)
+        .ok_or_else(|| format!("Missing column: {}", name))
+}
+
+fn strings<'a>(batch: &'a RecordBatch, name: &str) -> std::result::Result<&'a StringArray, String> {
+    column(batch, name)?
+        .as_string_opt()
+        .ok_or_else(|| format!("{} isn't a string", name))
+}
+
+fn decimals<'a>(
+    batch: &'a RecordBatch,
+    name: &str,
+) -> std::result::Result<&'a Decimal128Array, String> {
+    column(batch, name)?
+        .as_primitive_opt::<Decimal128Type>()
+        .ok_or_else(|| format!("{} isn't a decimal", name))
+}
@@ -0,0 +1,223 @@
+//! Reading back what an [`FsStore`](super::FsStore) wrote, for reports and
+//! anything else that works from a target directory rather than the API.
+
+use std::{
+    io,
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::NaiveDate;
+use scraper_sdk::months;
+use serde::de::DeserializeOwned;
+
+use super::{compression, Layout, BALANCE_HISTORY_DIR, PENDING_HISTORY_DIR};
+use crate::{
+    client::{BalanceResult, TransactionsResult},
+    error::Context,
+    manifest::{Manifest, MANIFEST_FILE},
+    Error, Result,
+};
+
+/// Reads the accounts, cards and transactions stored under a target
+/// directory, however they were compressed. Reads block, so use
+/// `spawn_blocking` from async code.
+///
+/// Accounts are found by listing the `accounts` and `cards` directories, or
+/// with a custom [`Layout`], from the manifest of the latest sync.
+#[derive(Debug, Clone)]
+pub struct Reader {
+    target_dir: PathBuf,
+    layout: Layout,
+}
+
+/// An account or card with data under the target directory.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoredAccount {
+    /// The account's directory, relative to the target directory, with `/`
+    /// separators, eg: `accounts/12-34-56 12345678`.
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl Reader {
+    pub fn new(target_dir: &Path) -> Self {
+        Self {
+            target_dir: target_dir.to_owned(),
+            layout: Layout::default(),
+        }
+    }
+
+    /// Read files arranged per `layout`, as given to the sync.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn accounts(&self) -> Result<Vec<StoredAccount>> {
+        let mut accounts = if self.layout.account_dir == Layout::default().account_dir {
+            self.listed_accounts()?
+        } else {
+            self.manifest_accounts()?
+        };
+        accounts.sort();
+        Ok(accounts)
+    }
+
+    /// The start of each month with transactions stored for `account`.
+    pub fn months(&self, account: &StoredAccount) -> Result<Vec<NaiveDate>> {
+        let pattern = self.layout.month_file_pattern()?;
+        let mut months = Vec::new();
+        for file in files_under(&account.dir)? {
+            let Some(captures) = pattern.captures(&file) else {
+                continue;
+            };
+            let (Some(year), Some(month)) = (captures.name("year"), captures.name("month")) else {
+                continue;
+            };
+            let date = format!("{}-{}-01", year.as_str(), month.as_str());
+            if let Ok(month) = date.parse() {
+                months.push(month);
+            }
+        }
+        months.sort();
+        months.dedup();
+        Ok(months)
+    }
+
+    /// The transactions stored for the month starting at `month`, or `None`
+    /// if there's no file for it.
+    pub fn month_transactions(
+        &self,
+        account: &StoredAccount,
+        month: NaiveDate,
+    ) -> Result<Option<Vec<TransactionsResult>>> {
+        let path = account.dir.join(self.layout.month_file(month, "jsons")?);
+        if compression::find(&path).is_none() {
+            return Ok(None);
+        }
+        Ok(Some(read_jsons(&path)?))
+    }
+
+    /// The transactions stored for `account` within `period`, skipping any
+    /// months that are missing.
+    pub fn transactions(
+        &self,
+        account: &StoredAccount,
+        period: RangeInclusive<NaiveDate>,
+    ) -> Result<Vec<TransactionsResult>> {
+        let mut txes = Vec::new();
+        for month in months(period.clone()) {
+            let stored = self
+                .month_transactions(account, *month.start())?
+                .unwrap_or_default();
+            txes.extend(
+                stored
+                    .into_iter()
+                    .filter(|tx| period.contains(&tx.timestamp.date_naive())),
+            );
+        }
+        Ok(txes)
+    }
+
+    /// The balance as of the latest sync, if one was stored.
+    pub fn balance(&self, account: &StoredAccount) -> Result<Vec<BalanceResult>> {
+        read_jsons(&account.dir.join("balance.jsons"))
+    }
+
+    fn listed_accounts(&self) -> Result<Vec<StoredAccount>> {
+        let mut accounts = Vec::new();
+        for kind in ["accounts", "cards"] {
+            let kind_dir = self.target_dir.join(kind);
+            let entries = match std::fs::read_dir(&kind_dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("Listing {:?}", kind_dir)),
+            };
+            for entry in entries {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                accounts.push(StoredAccount {
+                    name: format!("{}/{}", kind, entry.file_name().to_string_lossy()),
+                    dir: entry.path(),
+                });
+            }
+        }
+        Ok(accounts)
+    }
+
+    fn manifest_accounts(&self) -> Result<Vec<StoredAccount>> {
+        let path = self.target_dir.join(MANIFEST_FILE);
+        let manifest: Manifest = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| parse_error(&path, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Reading {:?}", path)),
+        };
+        Ok(manifest
+            .accounts
+            .iter()
+            .chain(manifest.cards.iter())
+            .map(|account| StoredAccount {
+                name: slash_separated(&account.dir),
+                dir: self.target_dir.join(&account.dir),
+            })
+            .collect())
+    }
+}
+
+/// Reads a JSON lines file, however it was compressed, which is taken as
+/// empty if it's missing.
+pub(crate) fn read_jsons<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let Some(content) = compression::read_to_string(path)? else {
+        return Ok(Vec::new());
+    };
+    content
+        .lines()
+        .map(|line| serde_json::from_str(line).map_err(|e| parse_error(path, e)))
+        .collect()
+}
+
+fn parse_error(path: &Path, e: serde_json::Error) -> Error {
+    Error::Corrupt(format!("Parsing {:?}: {}", path, e))
+}
+
+/// Every file under `dir`, relative to it with `/` separators, other than
+/// balance and pending snapshots.
+fn files_under(dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let path = dir.join(&relative);
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Listing {:?}", path)),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let relative = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                if relative != Path::new(BALANCE_HISTORY_DIR)
+                    && relative != Path::new(PENDING_HISTORY_DIR)
+                {
+                    pending.push(relative);
+                }
+            } else {
+                files.push(slash_separated(&relative));
+            }
+        }
+    }
+    Ok(files)
+}
+
+pub(crate) fn slash_separated(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
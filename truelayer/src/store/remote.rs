@@ -0,0 +1,332 @@
+//! Keeping synced data in an S3-compatible bucket, via a local copy.
+
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use tokio::sync::{Mutex as AsyncMutex, OnceCell};
+use tracing::{debug, warn};
+
+use super::{
+    compression, slash_separated, AccountKey, FsStore, Store, StoreStats, TransactionFormat,
+};
+use crate::{
+    client::{
+        AccountsResult, BalanceResult, BeneficiaryResult, CardsResult, ConnectionResult,
+        DirectDebitResult, ScheduledPaymentResult, StandingOrderResult, TransactionsResult,
+        UserInfoResult,
+    },
+    error::Context,
+    hashes::HASHES_FILE,
+    manifest::{Manifest, MANIFEST_FILE},
+    observer::SyncObserver,
+    s3::{S3Client, S3Config},
+    state::SyncState,
+    Result,
+};
+
+/// Writes everything to a local [`FsStore`], which serves as a cache, then
+/// uploads each file it changed to a bucket. Unchanged files aren't sent
+/// again. Months missing from the cache, eg: when it's new, are fetched from
+/// the bucket before they're read or merged.
+///
+/// Files waiting to be uploaded are listed in the cache, so that any a sync
+/// fails to upload are retried by the next. Files the local store removes,
+/// such as expired pending snapshots, are left in the bucket.
+#[derive(Debug)]
+pub struct S3Store {
+    local: FsStore,
+    remote: S3Config,
+    client: OnceCell<S3Client>,
+    uploads: Arc<UploadQueue>,
+    /// Read from the cache on first use.
+    pending: AsyncMutex<Option<BTreeSet<PathBuf>>>,
+}
+
+/// Lists the files yet to be uploaded, relative to the cache.
+const PENDING_UPLOADS_FILE: &str = ".pending-uploads.json";
+
+/// Collects the files the local store writes, until they're uploaded.
+#[derive(Debug)]
+struct UploadQueue {
+    written: Mutex<Vec<PathBuf>>,
+    observer: Arc<dyn SyncObserver>,
+}
+
+impl SyncObserver for UploadQueue {
+    fn on_file_written(&self, path: &Path) {
+        self.written.lock().expect("lock").push(path.to_owned());
+        self.observer.on_file_written(path);
+    }
+}
+
+impl S3Store {
+    /// Credentials are only read once there's something to upload.
+    pub fn new(local: FsStore, remote: S3Config) -> Self {
+        let uploads = Arc::new(UploadQueue {
+            written: Default::default(),
+            observer: local.observer.clone(),
+        });
+        Self {
+            local: local.with_observer(uploads.clone()),
+            remote,
+            client: OnceCell::new(),
+            uploads,
+            pending: AsyncMutex::new(None),
+        }
+    }
+
+    /// Runs `put`, then uploads what it wrote, along with anything left
+    /// over from before.
+    fn uploading<'a>(&'a self, put: BoxFuture<'a, Result<()>>) -> BoxFuture<'a, Result<()>> {
+        async move {
+            put.await?;
+            let written = std::mem::take(&mut *self.uploads.written.lock().expect("lock"));
+            let mut pending = self.pending.lock().await;
+            if pending.is_none() {
+                *pending = Some(self.load_pending().await?);
+            }
+            let pending = pending.as_mut().expect("loaded");
+            if written.is_empty() && pending.is_empty() {
+                return Ok(());
+            }
+            pending.extend(written);
+            self.save_pending(pending).await?;
+            while let Some(path) = pending.iter().next().cloned() {
+                let uploaded = self.upload(&path).await;
+                if uploaded.is_err() {
+                    self.save_pending(pending).await?;
+                }
+                uploaded?;
+                pending.remove(&path);
+            }
+            self.save_pending(pending).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    async fn load_pending(&self) -> Result<BTreeSet<PathBuf>> {
+        let path = self.local.target_dir.join(PENDING_UPLOADS_FILE);
+        let pending: BTreeSet<PathBuf> = scraper_sdk::load_state(&path)
+            .await
+            .with_context(|| format!("Reading {:?}", path))?
+            .unwrap_or_default();
+        if !pending.is_empty() {
+            warn!(count=%pending.len(), "Retrying uploads left from an earlier sync");
+        }
+        Ok(pending)
+    }
+
+    async fn save_pending(&self, pending: &BTreeSet<PathBuf>) -> Result<()> {
+        let path = self.local.target_dir.join(PENDING_UPLOADS_FILE);
+        scraper_sdk::write_json_atomically(&path, pending.clone())
+            .await
+            .with_context(|| format!("Writing {:?}", path))?;
+        Ok(())
+    }
+
+    async fn client(&self) -> Result<&S3Client> {
+        self.client
+            .get_or_try_init(|| async { S3Client::new(&self.remote) })
+            .await
+    }
+
+    /// Copies the month's transactions from the bucket into the cache,
+    /// unless it already has them, or the bucket doesn't.
+    async fn hydrate(&self, key: &AccountKey, month: NaiveDate) -> Result<()> {
+        if self.local.transaction_format != TransactionFormat::Jsonl {
+            return Ok(());
+        }
+        let path = self.local.month_path(key, month)?;
+        if compression::find(&self.local.target_dir.join(&path)).is_some() {
+            return Ok(());
+        }
+        let path = self.local.compression.path(&path);
+        let Some(body) = self.client().await?.get(&slash_separated(&path)).await? else {
+            return Ok(());
+        };
+        let full_path = self.local.target_dir.join(&path);
+        if let Some(parent) = full_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Creating {:?}", parent))?;
+        }
+        tokio::fs::write(&full_path, body)
+            .await
+            .with_context(|| format!("Writing {:?}", full_path))?;
+        self.local.hashes.record(&path).await?;
+        debug!(bucket=%self.remote.bucket, ?path, "Fetched into cache");
+        Ok(())
+    }
+
+    /// Uploads `path` under a temporary name, then copies it into place, so
+    /// that it's never seen part written under its own name.
+    async fn upload(&self, path: &Path) -> Result<()> {
+        let client = self.client().await?;
+        let full_path = self.local.target_dir.join(path);
+        let body = match tokio::fs::read(&full_path).await {
+            Ok(body) => body,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!(?path, "Removed since it was written; not uploading");
+                return Ok(());
+            }
+            Err(e) => return Err(e).with_context(|| format!("Reading {:?} to upload", full_path)),
+        };
+        let key = slash_separated(path);
+        let temp = format!("{}.{:016x}.tmp", key, rand::random::<u64>());
+        client.put(&temp, body).await?;
+        client.copy(&temp, &key).await?;
+        client.delete(&temp).await?;
+        debug!(bucket=%self.remote.bucket, %key, "Uploaded");
+        Ok(())
+    }
+}
+
+impl Store for S3Store {
+    // Bookkeeping is kept in the cache, and isn't uploaded.
+    fn preflight(&self) -> BoxFuture<'_, Result<()>> {
+        self.local.preflight()
+    }
+
+    fn get_state(&self) -> BoxFuture<'_, Result<Option<SyncState>>> {
+        self.local.get_state()
+    }
+
+    fn put_state(&self, state: SyncState) -> BoxFuture<'_, Result<()>> {
+        self.local.put_state(state)
+    }
+
+    fn put_seen<'a>(&'a self, key: &'a AccountKey, ids: Vec<String>) -> BoxFuture<'a, Result<()>> {
+        self.local.put_seen(key, ids)
+    }
+
+    fn get_unseen<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<Option<Vec<String>>>> {
+        self.local.get_unseen(key, ids)
+    }
+
+    fn put_info(&self, info: Vec<UserInfoResult>) -> BoxFuture<'_, Result<()>> {
+        self.uploading(self.local.put_info(info))
+    }
+
+    fn put_connection(&self, connection: Vec<ConnectionResult>) -> BoxFuture<'_, Result<()>> {
+        self.uploading(self.local.put_connection(connection))
+    }
+
+    fn put_accounts(&self, accounts: Vec<AccountsResult>) -> BoxFuture<'_, Result<()>> {
+        self.uploading(self.local.put_accounts(accounts))
+    }
+
+    fn put_cards(&self, cards: Vec<CardsResult>) -> BoxFuture<'_, Result<()>> {
+        self.uploading(self.local.put_cards(cards))
+    }
+
+    fn put_balance<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.uploading(self.local.put_balance(key, balance))
+    }
+
+    fn put_balance_snapshot<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        at: DateTime<Utc>,
+        balance: Vec<BalanceResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.uploading(self.local.put_balance_snapshot(key, at, balance))
+    }
+
+    fn put_pending<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        pending: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.uploading(self.local.put_pending(key, pending))
+    }
+
+    fn put_transactions<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        month: NaiveDate,
+        transactions: Vec<TransactionsResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.hydrate(key, month).await?;
+            self.uploading(self.local.put_transactions(key, month, transactions))
+                .await
+        }
+        .boxed()
+    }
+
+    fn get_transactions<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        month: NaiveDate,
+    ) -> BoxFuture<'a, Result<Option<Vec<TransactionsResult>>>> {
+        async move {
+            self.hydrate(key, month).await?;
+            self.local.get_transactions(key, month).await
+        }
+        .boxed()
+    }
+
+    fn put_standing_orders<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        orders: Vec<StandingOrderResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.uploading(self.local.put_standing_orders(key, orders))
+    }
+
+    fn put_direct_debits<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        debits: Vec<DirectDebitResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.uploading(self.local.put_direct_debits(key, debits))
+    }
+
+    fn put_scheduled_payments<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        payments: Vec<ScheduledPaymentResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.uploading(self.local.put_scheduled_payments(key, payments))
+    }
+
+    fn put_beneficiaries<'a>(
+        &'a self,
+        key: &'a AccountKey,
+        beneficiaries: Vec<BeneficiaryResult>,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.uploading(self.local.put_beneficiaries(key, beneficiaries))
+    }
+
+    fn put_manifest(&self, manifest: Manifest) -> BoxFuture<'_, Result<()>> {
+        let put = async move {
+            self.local.put_manifest(manifest).await?;
+            // Neither is reported as written, but both change with every
+            // sync that writes anything.
+            let mut written = self.uploads.written.lock().expect("lock");
+            written.push(HASHES_FILE.into());
+            written.push(MANIFEST_FILE.into());
+            Ok(())
+        };
+        self.uploading(put.boxed())
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.local.stats()
+    }
+}
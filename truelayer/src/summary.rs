@@ -0,0 +1,225 @@
+//! End-of-run reporting of what a sync did.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use scraper_sdk::PoolStats;
+use serde::Serialize;
+
+use crate::{consent, drift::UnknownFields};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncSummary {
+    pub providers: BTreeMap<String, ProviderSummary>,
+    pub jobs_submitted: usize,
+    pub jobs_completed: usize,
+    pub jobs_failed: usize,
+    /// Job attempts cancelled for taking too long, including any retried.
+    pub jobs_timed_out: usize,
+    /// Which jobs failed, by name.
+    pub failed_jobs: Vec<String>,
+    /// Why the run failed, if it did.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProviderSummary {
+    pub accounts: usize,
+    pub cards: usize,
+    pub months_fetched: usize,
+    pub transactions_written: usize,
+    pub files_written: usize,
+    /// Re-fetched files left as they were, as nothing had changed.
+    pub files_unchanged: usize,
+    /// Pending transactions dropped because they've since been booked.
+    pub pending_resolved: usize,
+    /// When the user will next need to re-run `auth`, if known.
+    pub consent_expires_at: Option<DateTime<Utc>>,
+    /// Whether that's soon enough to warn about.
+    pub consent_due: bool,
+    /// Fields the API sent that we don't model, by endpoint; usually a
+    /// sign that the API has changed.
+    pub unknown_fields: BTreeMap<String, BTreeSet<String>>,
+    /// Why the provider's sync failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Counts what each provider's jobs did as they go.
+#[derive(Debug, Default)]
+pub(crate) struct SummaryRecorder {
+    accounts: AtomicUsize,
+    cards: AtomicUsize,
+    months_fetched: AtomicUsize,
+    transactions_written: AtomicUsize,
+    pending_resolved: AtomicUsize,
+    consent_expires_at: Mutex<Option<DateTime<Utc>>>,
+    unknown_fields: Mutex<BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl SyncSummary {
+    pub fn new(
+        providers: BTreeMap<String, ProviderSummary>,
+        stats: &PoolStats,
+        result: &crate::Result<()>,
+    ) -> Self {
+        Self {
+            providers,
+            jobs_submitted: stats.jobs_submitted,
+            jobs_completed: stats.jobs_completed,
+            jobs_failed: stats.jobs_failed,
+            jobs_timed_out: stats.jobs_timed_out,
+            failed_jobs: stats.failed_jobs.clone(),
+            error: result.as_ref().err().map(|err| format!("{:#}", err)),
+        }
+    }
+
+    /// The same run, as seen by a single provider.
+    pub fn for_provider(&self, name: &str) -> Self {
+        Self {
+            providers: self
+                .providers
+                .iter()
+                .filter(|(provider, _)| *provider == name)
+                .map(|(provider, summary)| (provider.clone(), summary.clone()))
+                .collect(),
+            failed_jobs: self.failed_jobs.clone(),
+            error: self.error.clone(),
+            ..*self
+        }
+    }
+}
+
+impl SummaryRecorder {
+    pub(crate) fn account(&self) {
+        self.accounts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn card(&self) {
+        self.cards.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn month(&self, transactions: usize) {
+        self.months_fetched.fetch_add(1, Ordering::Relaxed);
+        self.transactions_written
+            .fetch_add(transactions, Ordering::Relaxed);
+    }
+
+    pub(crate) fn pending_resolved(&self, resolved: usize) {
+        self.pending_resolved.fetch_add(resolved, Ordering::Relaxed);
+    }
+
+    pub(crate) fn consent_expires(&self, at: DateTime<Utc>) {
+        *self.consent_expires_at.lock().expect("lock") = Some(at);
+    }
+
+    pub(crate) fn consent_expires_at(&self) -> Option<DateTime<Utc>> {
+        *self.consent_expires_at.lock().expect("lock")
+    }
+
+    pub(crate) fn unknown_fields<'a, T: UnknownFields + 'a>(
+        &self,
+        endpoint: &str,
+        items: impl IntoIterator<Item = &'a T>,
+    ) {
+        let fields = items
+            .into_iter()
+            .flat_map(|item| item.unknown_fields())
+            .collect::<BTreeSet<_>>();
+        if fields.is_empty() {
+            return;
+        }
+        self.unknown_fields
+            .lock()
+            .expect("lock")
+            .entry(endpoint.to_owned())
+            .or_default()
+            .extend(fields);
+    }
+
+    /// `files_written` and `files_unchanged` are left for the caller to fill in from the store.
+    pub(crate) fn snapshot(&self) -> ProviderSummary {
+        ProviderSummary {
+            accounts: self.accounts.load(Ordering::Relaxed),
+            cards: self.cards.load(Ordering::Relaxed),
+            months_fetched: self.months_fetched.load(Ordering::Relaxed),
+            transactions_written: self.transactions_written.load(Ordering::Relaxed),
+            files_written: 0,
+            files_unchanged: 0,
+            pending_resolved: self.pending_resolved.load(Ordering::Relaxed),
+            consent_expires_at: self.consent_expires_at(),
+            consent_due: self.consent_expires_at().map_or(false, |at| {
+                consent::is_due(at, consent::CONSENT_WARNING, Utc::now())
+            }),
+            unknown_fields: self.unknown_fields.lock().expect("lock").clone(),
+            error: None,
+        }
+    }
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, provider) in self.providers.iter() {
+            writeln!(f, "{}", name)?;
+            writeln!(f, "  accounts:             {:>8}", provider.accounts)?;
+            writeln!(f, "  cards:                {:>8}", provider.cards)?;
+            writeln!(f, "  months fetched:       {:>8}", provider.months_fetched)?;
+            writeln!(
+                f,
+                "  transactions written: {:>8}",
+                provider.transactions_written
+            )?;
+            writeln!(f, "  files written:        {:>8}", provider.files_written)?;
+            writeln!(f, "  files unchanged:      {:>8}", provider.files_unchanged)?;
+            writeln!(
+                f,
+                "  pending resolved:     {:>8}",
+                provider.pending_resolved
+            )?;
+            if let Some(expires_at) = provider.consent_expires_at {
+                writeln!(
+                    f,
+                    "  consent expires:      {}{}",
+                    expires_at.format("%Y-%m-%d"),
+                    if provider.consent_due {
+                        " (re-run auth soon)"
+                    } else {
+                        ""
+                    }
+                )?;
+            }
+            for (endpoint, fields) in provider.unknown_fields.iter() {
+                writeln!(
+                    f,
+                    "  unknown fields:       {}: {}",
+                    endpoint,
+                    fields.iter().cloned().collect::<Vec<_>>().join(", ")
+                )?;
+            }
+            if let Some(error) = provider.error.as_ref() {
+                writeln!(f, "  failed:               {}", error)?;
+            }
+        }
+        writeln!(
+            f,
+            "jobs: {} submitted, {} completed, {} failed",
+            self.jobs_submitted, self.jobs_completed, self.jobs_failed
+        )?;
+        if self.jobs_timed_out > 0 {
+            writeln!(f, "  timed out: {}", self.jobs_timed_out)?;
+        }
+        for job in self.failed_jobs.iter() {
+            writeln!(f, "  failed: {}", job)?;
+        }
+        if let Some(error) = self.error.as_ref() {
+            writeln!(f, "error: {}", error)?;
+        }
+        Ok(())
+    }
+}
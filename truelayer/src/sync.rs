@@ -1,28 +1,589 @@
-use std::{cmp::min, io::Write, ops::RangeInclusive, path::Path, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashSet},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use anyhow::Result;
-use chrono::{Datelike, NaiveDate};
+use chrono::{DateTime, Duration, Months, NaiveDate, Utc};
+use futures::Future;
+use scraper_sdk::{months, Aggregator, Priority, TargetLock};
 use serde::Serialize;
-use tempfile::NamedTempFile;
-use tokio::task::spawn_blocking;
-use tracing::{debug, info, instrument, Instrument, Span};
+use tracing::{debug, info, instrument, warn, Instrument, Span};
 
 use crate::{
-    client::{AccountsResult, CardsResult},
-    JobHandle, TlClient,
+    categories::CategoryRules,
+    client::{AccountsResult, CardsResult, TransactionsResult},
+    consent,
+    error::Context,
+    filter,
+    fx::FxConverter,
+    git,
+    hashes::HASHES_FILE,
+    journal::RunJournal,
+    manifest::{ManifestRecorder, MANIFEST_FILE},
+    metrics,
+    notify::{self, NewTransactionsRecorder},
+    observer::{FileObserver, SyncObserver, Unobserved},
+    pending::{PendingReconciler, Reconciled},
+    redact::RedactedStore,
+    state::{Kind, StateTracker},
+    store::{AccountKey, AccountNaming, CardNaming, FsStore, S3Store, Store},
+    summary::{ProviderSummary, SummaryRecorder},
+    upload::{self, UploadBacklog},
+    Error, JobHandle, ProviderConfig, Result, TlClient,
 };
 
-#[instrument(skip_all)]
-pub async fn sync_accounts(
+/// Standing orders and direct debits can only be fetched this soon after the
+/// user has authenticated.
+pub const RECENT_AUTH_WINDOW: Duration = Duration::minutes(5);
+
+/// Per-run options, typically from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Only fetch months from each account's stored watermark onwards.
+    pub incremental: bool,
+    /// Discover accounts and cards, but only record what else would be
+    /// fetched rather than fetching or writing anything.
+    pub dry_run: bool,
+    /// If another sync holds the target directory's lock, wait for it rather
+    /// than failing.
+    pub wait_for_lock: bool,
+    /// Carry on with the remaining jobs when one fails, and report every
+    /// failure at the end.
+    pub keep_going: bool,
+    /// Skip months already fetched by an earlier, unfinished run over the
+    /// same period.
+    pub resume: bool,
+}
+
+/// A fetch that a dry run skipped.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedFetch {
+    pub endpoint: String,
+    /// Relative to the target directory.
+    pub output: PathBuf,
+}
+
+/// Syncs a single configured provider.
+pub struct ProviderSync {
+    name: String,
+    tl: Arc<TlClient>,
+    config: Arc<ProviderConfig>,
+    options: Arc<SyncOptions>,
+    store: Arc<dyn Store>,
+    manifest: Arc<ManifestRecorder>,
+    summary: Arc<SummaryRecorder>,
+    plan: Arc<Mutex<Vec<PlannedFetch>>>,
+    pending: Arc<PendingReconciler>,
+    new_transactions: Arc<NewTransactionsRecorder>,
+    journal: Arc<RunJournal>,
+    observer: Arc<dyn SyncObserver>,
+    /// Tells `observer` about the files the configured store writes.
+    files: Arc<FileObserver>,
+    fx: Option<Arc<FxConverter>>,
+    lock: Mutex<Option<TargetLock>>,
+}
+
+/// Shared by all of the jobs syncing a single provider.
+#[derive(Clone)]
+pub struct SyncContext {
+    provider: Arc<str>,
     tl: Arc<TlClient>,
     target_dir: Arc<Path>,
-    period: RangeInclusive<NaiveDate>,
     jobs: JobHandle,
-) -> Result<(), anyhow::Error> {
+    config: Arc<ProviderConfig>,
+    options: Arc<SyncOptions>,
+    state: Arc<StateTracker>,
+    journal: Arc<RunJournal>,
+    observer: Arc<dyn SyncObserver>,
+    store: Arc<dyn Store>,
+    manifest: Arc<ManifestRecorder>,
+    summary: Arc<SummaryRecorder>,
+    plan: Arc<Mutex<Vec<PlannedFetch>>>,
+    pending: Arc<PendingReconciler>,
+    new_transactions: Arc<NewTransactionsRecorder>,
+    categories: Option<Arc<CategoryRules>>,
+    fx: Option<Arc<FxConverter>>,
+    recently_authed: bool,
+    /// What the token may fetch, unless TrueLayer didn't say.
+    granted: Option<Arc<BTreeSet<String>>>,
+}
+
+impl ProviderSync {
+    pub fn new(
+        name: &str,
+        tl: Arc<TlClient>,
+        config: &ProviderConfig,
+        options: SyncOptions,
+    ) -> Self {
+        let mut config = config.clone();
+        if config.redact {
+            // Otherwise account and card numbers would end up in
+            // directory names.
+            config.layout.account_naming = AccountNaming::Id;
+            config.layout.card_naming = CardNaming::Id;
+        }
+        let files = Arc::new(FileObserver::new());
+        Self {
+            name: name.to_owned(),
+            tl,
+            store: store(&config, files.clone()),
+            config: Arc::new(config),
+            options: Arc::new(options),
+            manifest: Default::default(),
+            summary: Default::default(),
+            plan: Default::default(),
+            pending: Default::default(),
+            new_transactions: Default::default(),
+            journal: Default::default(),
+            observer: Arc::new(Unobserved),
+            files,
+            fx: None,
+            lock: Default::default(),
+        }
+    }
+
+    /// Convert amounts with `fx`, rather than per the configured `fx`.
+    pub fn with_fx(mut self, fx: FxConverter) -> Self {
+        self.fx = Some(Arc::new(fx));
+        self
+    }
+
+    /// Tell `observer` how the sync is going. Stores given to
+    /// [`ProviderSync::with_store`] don't report the files they write.
+    pub fn with_observer(mut self, observer: Arc<dyn SyncObserver>) -> Self {
+        self.files.set(observer.clone());
+        self.observer = observer;
+        self
+    }
+
+    /// Write synced data to `store` rather than the configured `target_dir`.
+    pub fn with_store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = redacted(&self.config, store);
+        self
+    }
+
+    /// What has been synced so far.
+    pub fn summary(&self) -> ProviderSummary {
+        let stats = self.store.stats();
+        ProviderSummary {
+            files_written: stats.files_written,
+            files_unchanged: stats.files_unchanged,
+            ..self.summary.snapshot()
+        }
+    }
+
+    fn commit_message(&self, period: Option<RangeInclusive<NaiveDate>>, complete: bool) -> String {
+        let summary = self.summary();
+        let mut message = format!("Sync {}", self.name);
+        if let Some(period) = period {
+            message.push_str(&format!(": {} to {}", period.start(), period.end()));
+        }
+        if !complete {
+            message.push_str(" (stopped early)");
+        }
+        message.push_str(&format!(
+            "\n\n{} accounts, {} cards, {} transactions, {} files written\n",
+            summary.accounts, summary.cards, summary.transactions_written, summary.files_written
+        ));
+        message
+    }
+
+    /// What a dry run would have fetched.
+    pub fn planned(&self) -> Vec<PlannedFetch> {
+        self.plan.lock().expect("lock").clone()
+    }
+
+    /// Commits and uploads what this run wrote, as configured.
+    async fn persist(&self, commit_message: String, wrote_manifest: bool) -> Result<()> {
+        if self.config.git_commit {
+            git::commit(&self.config.target_dir, commit_message).await?;
+        }
+        if !self.config.upload.is_empty() {
+            let mut files = upload::backlog(&self.config.target_dir).await?;
+            files.extend(self.store.stats().written);
+            if wrote_manifest {
+                files.insert(MANIFEST_FILE.into());
+                files.insert(HASHES_FILE.into());
+            }
+            let files = files.into_iter().collect::<Vec<_>>();
+            upload::upload(
+                &self.name,
+                &self.config.target_dir,
+                &self.config.upload,
+                &files,
+            )
+            .await?;
+            upload::clear_backlog(&self.config.target_dir).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(provider=%self.name))]
+    async fn schedule_jobs(
+        &self,
+        period: RangeInclusive<NaiveDate>,
+        handle: JobHandle,
+    ) -> Result<()> {
+        self.config.transaction_format.check_supported()?;
+        self.config.compression.check_supported()?;
+        self.config.layout.check()?;
+        if self.config.git_commit {
+            git::check_supported()?;
+        }
+        if self.config.store_categories && self.config.category_rules.is_none() {
+            return Err(Error::Config(
+                "`store_categories` needs `category_rules` to be set".to_owned(),
+            ));
+        }
+        for filter in self
+            .config
+            .include_accounts
+            .iter()
+            .chain(self.config.exclude_accounts.iter())
+        {
+            filter.check()?;
+        }
+        if !self.options.dry_run {
+            let lock = TargetLock::acquire(&self.config.target_dir, self.options.wait_for_lock)
+                .await
+                .context("Locking target directory")?;
+            *self.lock.lock().expect("lock") = Some(lock);
+        }
+        self.journal
+            .start(&self.config.target_dir, &period, self.options.resume)
+            .await?;
+        let ctx = SyncContext::new(self, handle).await?;
+        if !self.options.dry_run {
+            self.store.preflight().await?;
+            self.manifest.started(period.clone());
+        }
+        if self.config.scrape_info && ctx.granted("info") {
+            debug!("Scraping info");
+            let planned = PlannedFetch {
+                endpoint: "/data/v1/info".to_owned(),
+                output: "user-info.jsons".into(),
+            };
+            ctx.spawn_or_plan(planned, Priority::High, (), |ctx, ()| sync_info(ctx))?;
+            let planned = PlannedFetch {
+                endpoint: "/data/v1/me".to_owned(),
+                output: "connection.jsons".into(),
+            };
+            ctx.spawn_or_plan(planned, Priority::High, (), |ctx, ()| sync_connection(ctx))?;
+        }
+        if self.config.scrape_accounts && ctx.granted("accounts") {
+            debug!("Scraping accounts");
+            let job = sync_accounts(ctx.clone(), period.clone());
+            ctx.jobs.spawn_named(
+                "/data/v1/accounts",
+                Priority::High,
+                ctx.observed("/data/v1/accounts", job)
+                    .instrument(Span::current()),
+            )?;
+        }
+        if self.config.scrape_cards && ctx.granted("cards") {
+            debug!("Scraping cards");
+            let job = sync_cards(ctx.clone(), period.clone());
+            ctx.jobs.spawn_named(
+                "/data/v1/cards",
+                Priority::High,
+                ctx.observed("/data/v1/cards", job)
+                    .instrument(Span::current()),
+            )?;
+        }
+        debug!("Scheduled sync tasks");
+        Ok(())
+    }
+}
+
+impl Aggregator for ProviderSync {
+    type Error = Error;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn schedule(
+        self: Arc<Self>,
+        period: RangeInclusive<NaiveDate>,
+        jobs: JobHandle,
+    ) -> Result<()> {
+        // Left unwrapped, so callers can still tell what went wrong.
+        self.schedule_jobs(period, jobs).await
+    }
+
+    async fn finish(self: Arc<Self>) -> Result<()> {
+        for (endpoint, fields) in self.summary.snapshot().unknown_fields {
+            warn!(provider=%self.name, %endpoint, ?fields, "API sent fields we don't recognise");
+        }
+        if !self.options.dry_run {
+            self.journal.finish().await?;
+        }
+        let manifest = self.manifest.finish(&self.name);
+        let period = manifest
+            .as_ref()
+            .map(|manifest| manifest.from_date..=manifest.to_date);
+        let wrote_manifest = manifest.is_some();
+        if let Some(manifest) = manifest {
+            self.store.put_manifest(manifest).await?;
+        }
+        if !self.options.dry_run {
+            self.persist(self.commit_message(period, true), wrote_manifest)
+                .await?;
+        }
+        if let Some(config) = self.config.notify.as_ref() {
+            notify::notify(
+                &self.name,
+                config,
+                &self.config.target_dir,
+                self.new_transactions.take(),
+            )
+            .await?;
+        }
+        self.lock.lock().expect("lock").take();
+        Ok(())
+    }
+
+    /// Commits and uploads what was written before the run stopped, and
+    /// leaves the journal for `--resume` to pick up from.
+    async fn checkpoint(self: Arc<Self>) -> Result<()> {
+        let Some(lock) = self.lock.lock().expect("lock").take() else {
+            return Ok(());
+        };
+        warn!(provider=%self.name, "Sync stopped early; keeping what it fetched");
+        self.persist(self.commit_message(None, false), false)
+            .await?;
+        drop(lock);
+        Ok(())
+    }
+}
+
+impl SyncContext {
+    async fn new(provider: &ProviderSync, jobs: JobHandle) -> Result<Self> {
+        let ProviderSync {
+            name, tl, config, ..
+        } = provider;
+        let target_dir: Arc<Path> = Arc::from(config.target_dir.clone().into_boxed_path());
+        let state = Arc::new(StateTracker::load(provider.store.clone()).await?);
+        if config.notify.is_some() {
+            provider.new_transactions.restore(&target_dir).await?;
+        }
+        let authed_at = tl.authed_at().await?;
+        if let Some(expires_at) = consent::expires_at(authed_at, None) {
+            consent_expires(&provider.summary, expires_at);
+        }
+        let recently_authed = if config.scrape_standing_orders
+            || config.scrape_direct_debits
+            || config.scrape_scheduled_payments
+            || config.scrape_beneficiaries
+        {
+            let recent = authed_at.map_or(false, |at| Utc::now() - at <= RECENT_AUTH_WINDOW);
+            if !recent {
+                warn!(
+                    ?authed_at,
+                    "Standing orders, direct debits, scheduled payments and beneficiaries need a recent authentication; skipping them. Re-run `auth` shortly before syncing to include them"
+                );
+            }
+            recent
+        } else {
+            false
+        };
+        let granted = tl.granted_scopes().await?;
+        let granted = (!granted.is_empty()).then(|| Arc::new(granted.into_iter().collect()));
+        let categories = match config.category_rules.as_ref() {
+            Some(path) if config.store_categories => {
+                Some(Arc::new(CategoryRules::load(path).await?))
+            }
+            _ => None,
+        };
+        let fx = match (provider.fx.as_ref(), config.fx.as_ref()) {
+            (Some(fx), _) => Some(fx.clone()),
+            (None, Some(fx)) => Some(Arc::new(FxConverter::load(fx).await?)),
+            (None, None) => None,
+        };
+        let ctx = Self {
+            provider: Arc::from(name.as_str()),
+            tl: tl.clone(),
+            target_dir,
+            jobs,
+            config: config.clone(),
+            options: provider.options.clone(),
+            state,
+            journal: provider.journal.clone(),
+            observer: provider.observer.clone(),
+            store: provider.store.clone(),
+            manifest: provider.manifest.clone(),
+            summary: provider.summary.clone(),
+            plan: provider.plan.clone(),
+            pending: provider.pending.clone(),
+            new_transactions: provider.new_transactions.clone(),
+            categories,
+            fx,
+            recently_authed,
+            granted,
+        };
+        ctx.warn_ungranted();
+        Ok(ctx)
+    }
+
+    /// Whether the token was granted `scope`; assumed so if we don't know.
+    fn granted(&self, scope: &str) -> bool {
+        self.granted
+            .as_ref()
+            .map_or(true, |granted| granted.contains(scope))
+    }
+
+    fn warn_ungranted(&self) {
+        let config = &self.config;
+        let any_accounts = config.scrape_accounts || config.scrape_cards;
+        let missing = [
+            (config.scrape_info, "info"),
+            (config.scrape_accounts, "accounts"),
+            (config.scrape_cards, "cards"),
+            (any_accounts, "balance"),
+            (any_accounts, "transactions"),
+            (config.scrape_standing_orders, "standing_orders"),
+            (config.scrape_direct_debits, "direct_debits"),
+        ]
+        .into_iter()
+        .filter(|(wanted, scope)| *wanted && !self.granted(scope))
+        .map(|(_, scope)| scope)
+        .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            warn!(
+                ?missing,
+                "Scopes not granted; skipping what needs them. Re-run `auth` to grant them"
+            );
+        }
+    }
+
+    /// Runs `job` with `args`, unless this is a dry run, in which case we
+    /// just note that we would have. The job may be re-run should it time
+    /// out.
+    fn spawn_or_plan<A, F, Fut>(
+        &self,
+        planned: PlannedFetch,
+        priority: Priority,
+        args: A,
+        job: F,
+    ) -> Result<()>
+    where
+        A: Clone + Send + 'static,
+        F: Fn(SyncContext, A) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        if self.options.dry_run {
+            self.plan.lock().expect("lock").push(planned);
+        } else {
+            let ctx = self.clone();
+            let span = Span::current();
+            let endpoint = planned.endpoint.clone();
+            self.jobs
+                .spawn_retryable(planned.endpoint, priority, move || {
+                    let job = ctx.observed(&endpoint, job(ctx.clone(), args.clone()));
+                    async move {
+                        let started = Instant::now();
+                        let res = job.await;
+                        metrics::job_finished(started.elapsed());
+                        res
+                    }
+                    .instrument(span.clone())
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Tags errors from `job` with the provider, so that the user can be
+    /// told which one to re-authenticate, and reports them to the observer.
+    fn observed(
+        &self,
+        name: &str,
+        job: impl Future<Output = Result<()>>,
+    ) -> impl Future<Output = Result<()>> {
+        let provider = self.provider.clone();
+        let observer = self.observer.clone();
+        let name = name.to_owned();
+        async move {
+            job.await.map_err(|error| {
+                let error = error.for_provider(&provider);
+                observer.on_error(&name, &error);
+                error
+            })
+        }
+    }
+
+    /// The months in `period` to fetch for an account, taking the stored
+    /// watermark into account when running incrementally, and leaving out
+    /// those already fetched by a run we're resuming.
+    async fn months(
+        &self,
+        kind: Kind,
+        id: &str,
+        period: RangeInclusive<NaiveDate>,
+    ) -> Vec<RangeInclusive<NaiveDate>> {
+        let mut from = *period.start();
+        if self.options.incremental {
+            if let Some(watermark) = self.state.watermark(kind, id).await {
+                let overlap = self.config.incremental_overlap_months.unwrap_or(1);
+                let resume_at = (watermark + Months::new(1)) - Months::new(overlap);
+                debug!(%watermark, %resume_at, "Resuming from watermark");
+                from = from.max(resume_at);
+            }
+        }
+        let mut to_fetch = Vec::new();
+        for month in months(period).filter(|m| *m.end() >= from) {
+            if self.journal.is_complete(kind, id, *month.start()).await {
+                debug!(month=%month.start(), "Already fetched; skipping");
+            } else {
+                to_fetch.push(month);
+            }
+        }
+        self.state
+            .schedule(kind, id, to_fetch.iter().map(|m| *m.start()))
+            .await;
+        to_fetch
+    }
+
+    /// Only accounts synced before get notified about, so that the initial
+    /// import doesn't send the account's whole history.
+    async fn watch_for_new(&self, kind: Kind, key: &AccountKey) {
+        if self.config.notify.is_some()
+            && !self.options.dry_run
+            && self.state.watermark(kind, key.account_id()).await.is_some()
+        {
+            self.new_transactions.watch(key);
+        }
+    }
+
+    /// Whether `include_accounts` and `exclude_accounts` let `key` be synced.
+    fn is_included(&self, key: &AccountKey) -> Result<bool> {
+        let included = filter::is_included(
+            &self.config.include_accounts,
+            &self.config.exclude_accounts,
+            key,
+        )?;
+        if !included {
+            info!(account_id=%key.account_id(), "Skipping filtered account");
+        }
+        Ok(included)
+    }
+
+    /// Where `month`'s transactions go, relative to the account's directory.
+    fn month_file(&self, month: &RangeInclusive<NaiveDate>) -> Result<PathBuf> {
+        self.config
+            .layout
+            .month_file(*month.start(), self.config.transaction_format.extension())
+    }
+}
+
+#[instrument(skip_all)]
+pub async fn sync_accounts(ctx: SyncContext, period: RangeInclusive<NaiveDate>) -> Result<()> {
     info!(?period, "Scraping accounts for specified period");
-    let accounts = accounts(tl.clone(), target_dir.clone()).await?;
+    let accounts = accounts(&ctx).await?;
     for account_item in accounts {
-        account(&jobs, &tl, &target_dir, account_item, period.clone())
+        account(&ctx, account_item, period.clone())
             .instrument(Span::current())
             .await?;
     }
@@ -31,51 +592,88 @@ pub async fn sync_accounts(
 
 #[instrument(skip_all, fields(account_id=%account.account_id))]
 async fn account(
-    jobs: &JobHandle,
-    tl: &Arc<TlClient>,
-    target_dir: &Arc<Path>,
+    ctx: &SyncContext,
     account: AccountsResult,
     period: RangeInclusive<NaiveDate>,
-) -> Result<(), anyhow::Error> {
-    jobs.spawn(
-        account_balance(tl.clone(), target_dir.clone(), account.clone())
-            .instrument(Span::current()),
-    )?;
-    jobs.spawn(
-        account_pending(tl.clone(), target_dir.clone(), account.clone())
-            .instrument(Span::current()),
-    )?;
-    for month in months(period) {
-        jobs.spawn(
-            account_tx(tl.clone(), target_dir.clone(), account.clone(), month)
-                .instrument(Span::current()),
+) -> Result<()> {
+    let key = AccountKey::Account(account);
+    let dir = ctx.config.layout.account_dir(&key)?;
+    ctx.manifest.record(&key, dir.clone());
+    ctx.summary.account();
+    ctx.observer.on_account_discovered(&key);
+    if ctx.granted("balance") {
+        ctx.spawn_or_plan(
+            PlannedFetch::new(&key, "balance", dir.join("balance.jsons")),
+            Priority::High,
+            key.clone(),
+            balance,
         )?;
     }
+    if ctx.granted("transactions") {
+        let months = ctx.months(Kind::Account, key.account_id(), period).await;
+        ctx.pending.expect(&key, months.len());
+        ctx.spawn_or_plan(
+            PlannedFetch::new(&key, "transactions/pending", dir.join("pending.jsons")),
+            Priority::High,
+            key.clone(),
+            pending,
+        )?;
+        ctx.watch_for_new(Kind::Account, &key).await;
+        for month in months {
+            ctx.spawn_or_plan(
+                PlannedFetch::transactions(&key, &month, dir.join(ctx.month_file(&month)?)),
+                Priority::Normal,
+                (key.clone(), month),
+                |ctx, (key, month)| transactions(ctx, key, month),
+            )?;
+        }
+    }
 
-    if false {
-        // Only available when you've _recently_ authenticated.
-        jobs.spawn(
-            account_standing_orders(tl.clone(), target_dir.clone(), account.clone())
-                .instrument(Span::current()),
+    // Only available when you've _recently_ authenticated.
+    if ctx.recently_authed && ctx.config.scrape_standing_orders && ctx.granted("standing_orders") {
+        ctx.spawn_or_plan(
+            PlannedFetch::new(&key, "standing_orders", dir.join("standing-orders.jsons")),
+            Priority::Normal,
+            key.clone(),
+            account_standing_orders,
         )?;
-        jobs.spawn(
-            account_direct_debits(tl.clone(), target_dir.clone(), account.clone())
-                .instrument(Span::current()),
+    }
+    if ctx.recently_authed && ctx.config.scrape_direct_debits && ctx.granted("direct_debits") {
+        ctx.spawn_or_plan(
+            PlannedFetch::new(&key, "direct_debits", dir.join("direct-debits.jsons")),
+            Priority::Normal,
+            key.clone(),
+            account_direct_debits,
+        )?;
+    }
+    if ctx.recently_authed && ctx.config.scrape_scheduled_payments {
+        ctx.spawn_or_plan(
+            PlannedFetch::new(
+                &key,
+                "scheduled_payments",
+                dir.join("scheduled-payments.jsons"),
+            ),
+            Priority::Normal,
+            key.clone(),
+            account_scheduled_payments,
+        )?;
+    }
+    if ctx.recently_authed && ctx.config.scrape_beneficiaries {
+        ctx.spawn_or_plan(
+            PlannedFetch::new(&key, "beneficiaries", dir.join("beneficiaries.jsons")),
+            Priority::Normal,
+            key.clone(),
+            account_beneficiaries,
         )?;
     }
     Ok(())
 }
 
 #[instrument(skip_all)]
-pub async fn sync_cards(
-    tl: Arc<TlClient>,
-    target_dir: Arc<Path>,
-    period: RangeInclusive<NaiveDate>,
-    jobs: JobHandle,
-) -> Result<(), anyhow::Error> {
-    let cards = cards(tl.clone(), target_dir.clone()).await?;
+pub async fn sync_cards(ctx: SyncContext, period: RangeInclusive<NaiveDate>) -> Result<()> {
+    let cards = cards(&ctx).await?;
     for card_result in cards {
-        card(&jobs, &tl, &target_dir, card_result, period.clone())
+        card(&ctx, card_result, period.clone())
             .instrument(Span::current())
             .await?;
     }
@@ -84,268 +682,356 @@ pub async fn sync_cards(
 
 #[instrument(skip_all, fields(account_id=%card.account_id))]
 async fn card(
-    jobs: &JobHandle,
-    tl: &Arc<TlClient>,
-    target_dir: &Arc<Path>,
+    ctx: &SyncContext,
     card: CardsResult,
     period: RangeInclusive<NaiveDate>,
-) -> Result<(), anyhow::Error> {
-    jobs.spawn(
-        card_balance(tl.clone(), target_dir.clone(), card.account_id.clone())
-            .instrument(Span::current()),
-    )?;
-    jobs.spawn(
-        card_pending(tl.clone(), target_dir.clone(), card.account_id.clone())
-            .instrument(Span::current()),
-    )?;
-    for month in months(period) {
-        jobs.spawn(
-            card_tx(
-                tl.clone(),
-                target_dir.clone(),
-                card.account_id.clone(),
-                month,
-            )
-            .instrument(Span::current()),
-        )?
+) -> Result<()> {
+    let key = AccountKey::Card(card);
+    let dir = ctx.config.layout.account_dir(&key)?;
+    ctx.manifest.record(&key, dir.clone());
+    ctx.summary.card();
+    ctx.observer.on_account_discovered(&key);
+    if ctx.granted("balance") {
+        ctx.spawn_or_plan(
+            PlannedFetch::new(&key, "balance", dir.join("balance.jsons")),
+            Priority::High,
+            key.clone(),
+            balance,
+        )?;
+    }
+    if ctx.granted("transactions") {
+        let months = ctx.months(Kind::Card, key.account_id(), period).await;
+        ctx.pending.expect(&key, months.len());
+        ctx.spawn_or_plan(
+            PlannedFetch::new(&key, "transactions/pending", dir.join("pending.jsons")),
+            Priority::High,
+            key.clone(),
+            pending,
+        )?;
+        ctx.watch_for_new(Kind::Card, &key).await;
+        for month in months {
+            ctx.spawn_or_plan(
+                PlannedFetch::transactions(&key, &month, dir.join(ctx.month_file(&month)?)),
+                Priority::Normal,
+                (key.clone(), month),
+                |ctx, (key, month)| transactions(ctx, key, month),
+            )?;
+        }
     }
     Ok(())
 }
 
 #[instrument(skip_all)]
-pub async fn sync_info(tl: Arc<TlClient>, target_dir: Arc<Path>) -> Result<()> {
-    let user_info = tl.fetch_info().await?;
-    write_jsons_atomically(&target_dir.join("user-info.jsons"), user_info.results).await?;
+pub async fn sync_info(ctx: SyncContext) -> Result<()> {
+    let user_info = ctx.tl.fetch_info().await?;
+    ctx.summary.unknown_fields("info", user_info.results.iter());
+    ctx.store.put_info(user_info.results).await?;
     Ok(())
 }
 
+/// Records when consent expires, amongst other things, so users can see
+/// when they will next need to re-authenticate.
 #[instrument(skip_all)]
-async fn accounts(tl: Arc<TlClient>, target_dir: Arc<Path>) -> Result<Vec<AccountsResult>> {
-    let accounts = tl.fetch_accounts().await?;
-    for account in accounts.results.iter().cloned() {
-        let path = target_dir
-            .join("accounts")
-            .join(account_dir_name(&account))
-            .join("account.jsons");
-        write_jsons_atomically(&path, vec![account]).await?;
-    }
-    Ok(accounts.results)
+pub async fn sync_connection(ctx: SyncContext) -> Result<()> {
+    let connection = ctx.tl.fetch_connection().await?;
+    let authed_at = ctx.tl.authed_at().await?;
+    if let Some(expires_at) = consent::expires_at(authed_at, connection.results.first()) {
+        consent_expires(&ctx.summary, expires_at);
+    }
+    ctx.summary
+        .unknown_fields("connection", connection.results.iter());
+    ctx.store.put_connection(connection.results).await?;
+    Ok(())
 }
 
-#[instrument(skip_all)]
-async fn account_balance(
-    tl: Arc<TlClient>,
-    target_dir: Arc<Path>,
-    account: AccountsResult,
-) -> Result<()> {
-    info!("Fetch balance");
-    let bal = tl.account_balance(&account.account_id).await?;
-    let path = &target_dir
-        .join("accounts")
-        .join(account_dir_name(&account))
-        .join("balance.jsons");
-    write_jsons_atomically(path, bal.results).await?;
-    Ok(())
+/// Notes when consent expires, warning as soon as we know it's due for
+/// renewal, so that runs that go on to fail still say so.
+fn consent_expires(summary: &SummaryRecorder, expires_at: DateTime<Utc>) {
+    let now = Utc::now();
+    let was_due = summary.consent_expires_at().map_or(false, |at| {
+        consent::is_due(at, consent::CONSENT_WARNING, now)
+    });
+    summary.consent_expires(expires_at);
+    if !was_due && consent::is_due(expires_at, consent::CONSENT_WARNING, now) {
+        warn!(%expires_at, "Consent expires soon; re-run `auth` to renew it");
+    }
 }
 
-fn account_dir_name(account: &AccountsResult) -> String {
-    let account_path = if let (Some(sort_code), Some(number)) = (
-        account.account_number.sort_code.as_ref(),
-        account.account_number.number.as_ref(),
-    ) {
-        format!("{} {}", sort_code, number)
-    } else {
-        account.account_id.clone()
-    };
-    account_path
+#[instrument(skip_all)]
+async fn accounts(ctx: &SyncContext) -> Result<Vec<AccountsResult>> {
+    let mut accounts = Vec::new();
+    let fetched = ctx.tl.fetch_accounts().await?.results;
+    ctx.summary.unknown_fields("accounts", fetched.iter());
+    for account in fetched {
+        if ctx.is_included(&AccountKey::Account(account.clone()))? {
+            accounts.push(account);
+        }
+    }
+    if !ctx.options.dry_run {
+        ctx.store.put_accounts(accounts.clone()).await?;
+    }
+    Ok(accounts)
 }
 
 #[instrument(skip_all)]
-async fn account_pending(
-    tl: Arc<TlClient>,
-    target_dir: Arc<Path>,
-    account: AccountsResult,
-) -> Result<()> {
-    info!("Fetch pending transactions");
-    let bal = tl.account_pending(&account.account_id).await?;
-    let path = &target_dir
-        .join("accounts")
-        .join(account_dir_name(&account))
-        .join("pending.jsons");
-    write_jsons_atomically(path, bal.results).await?;
-    Ok(())
+async fn cards(ctx: &SyncContext) -> Result<Vec<CardsResult>> {
+    let mut cards = Vec::new();
+    let fetched = ctx.tl.fetch_cards().await?.results;
+    ctx.summary.unknown_fields("cards", fetched.iter());
+    ctx.config.layout.note_cards(&fetched);
+    for card in fetched {
+        if ctx.is_included(&AccountKey::Card(card.clone()))? {
+            cards.push(card);
+        }
+    }
+    if !ctx.options.dry_run {
+        ctx.store.put_cards(cards.clone()).await?;
+    }
+    Ok(cards)
 }
 
 #[instrument(skip_all)]
-async fn account_standing_orders(
-    tl: Arc<TlClient>,
-    target_dir: Arc<Path>,
-    account: AccountsResult,
-) -> Result<()> {
-    info!("Fetch standing orders");
-    let bal = tl.account_standing_orders(&account.account_id).await?;
-    let path = &target_dir
-        .join("accounts")
-        .join(account_dir_name(&account))
-        .join("standing-orders.jsons");
-    write_jsons_atomically(path, bal.results).await?;
+async fn balance(ctx: SyncContext, key: AccountKey) -> Result<()> {
+    info!("Fetch balance");
+    let bal = match &key {
+        AccountKey::Account(_) => ctx.tl.account_balance(key.account_id()).await?,
+        AccountKey::Card(_) => ctx.tl.card_balance(key.account_id()).await?,
+    };
+    ctx.summary.unknown_fields("balance", bal.results.iter());
+    if ctx.config.balance_history {
+        ctx.store
+            .put_balance_snapshot(&key, Utc::now(), bal.results.clone())
+            .await?;
+    }
+    ctx.store.put_balance(&key, bal.results).await?;
     Ok(())
 }
 
 #[instrument(skip_all)]
-async fn account_direct_debits(
-    tl: Arc<TlClient>,
-    target_dir: Arc<Path>,
-    account: AccountsResult,
-) -> Result<()> {
-    info!("Fetch direct debits");
-    let bal = tl.account_direct_debits(&account.account_id).await?;
-    let path = &target_dir
-        .join("accounts")
-        .join(account_dir_name(&account))
-        .join("standing-orders.jsons");
-    write_jsons_atomically(path, bal.results).await?;
-    Ok(())
+async fn pending(ctx: SyncContext, key: AccountKey) -> Result<()> {
+    info!("Fetch pending transactions");
+    let pending = match &key {
+        AccountKey::Account(_) => ctx.tl.account_pending(key.account_id()).await?,
+        AccountKey::Card(_) => ctx.tl.card_pending(key.account_id()).await?,
+    };
+    ctx.summary
+        .unknown_fields("pending", pending.results.iter());
+    // Written once we've seen this run's booked transactions.
+    let ready = ctx.pending.pending(&key, pending.results);
+    write_pending(&ctx, ready).await
 }
 
-#[instrument(skip_all, fields(?month))]
-async fn account_tx(
-    tl: Arc<TlClient>,
-    target_dir: Arc<Path>,
-    account: AccountsResult,
-    month: RangeInclusive<NaiveDate>,
-) -> Result<()> {
-    // TODO: split into per-month jobs.
-    let mut txes = tl
-        .account_transactions(&account.account_id, *month.start(), *month.end())
-        .await?;
+/// Writes an account's pending transactions, once they're reconciled.
+async fn write_pending(ctx: &SyncContext, ready: Option<Reconciled>) -> Result<()> {
+    if let Some((key, txes, resolved)) = ready {
+        if resolved > 0 {
+            info!(%resolved, "Pending transactions since booked");
+        }
+        ctx.summary.pending_resolved(resolved);
+        ctx.store.put_pending(&key, txes).await?;
+    }
+    Ok(())
+}
 
-    if txes.results.is_empty() {
-        info!("No results for month found");
-        return Ok(());
-    }
-
-    txes.results.reverse();
-    write_jsons_atomically(
-        &target_dir
-            .join("accounts")
-            .join(&account_dir_name(&account))
-            .join(month.start().format("%Y-%m.jsons").to_string()),
-        txes.results,
-    )
-    .await?;
+#[instrument(skip_all)]
+async fn account_standing_orders(ctx: SyncContext, key: AccountKey) -> Result<()> {
+    info!("Fetch standing orders");
+    let orders = ctx.tl.account_standing_orders(key.account_id()).await?;
+    ctx.store.put_standing_orders(&key, orders.results).await?;
     Ok(())
 }
 
 #[instrument(skip_all)]
-async fn cards(tl: Arc<TlClient>, target_dir: Arc<Path>) -> Result<Vec<CardsResult>> {
-    let cards = tl.fetch_cards().await?;
-    write_jsons_atomically(&target_dir.join("cards.jsons"), cards.results.clone()).await?;
-    for card in cards.results.iter().cloned() {
-        let path = target_dir
-            .join("cards")
-            .join(&card.account_id)
-            .join("account.jsons");
-        write_jsons_atomically(&path, vec![card]).await?;
-    }
-
-    Ok(cards.results)
+async fn account_direct_debits(ctx: SyncContext, key: AccountKey) -> Result<()> {
+    info!("Fetch direct debits");
+    let debits = ctx.tl.account_direct_debits(key.account_id()).await?;
+    ctx.store.put_direct_debits(&key, debits.results).await?;
+    Ok(())
 }
 
 #[instrument(skip_all)]
-async fn card_balance(tl: Arc<TlClient>, target_dir: Arc<Path>, account_id: String) -> Result<()> {
-    info!("Fetch balance");
-    let bal = tl.card_balance(&account_id).await?;
-    write_jsons_atomically(
-        &target_dir
-            .join("cards")
-            .join(account_id)
-            .join("balance.jsons"),
-        bal.results,
-    )
-    .await?;
+async fn account_scheduled_payments(ctx: SyncContext, key: AccountKey) -> Result<()> {
+    info!("Fetch scheduled payments");
+    let payments = match ctx.tl.account_scheduled_payments(key.account_id()).await {
+        Err(e) if e.is_unsupported() => {
+            info!("Provider does not offer scheduled payments");
+            return Ok(());
+        }
+        res => res?,
+    };
+    ctx.store
+        .put_scheduled_payments(&key, payments.results)
+        .await?;
     Ok(())
 }
 
 #[instrument(skip_all)]
-async fn card_pending(tl: Arc<TlClient>, target_dir: Arc<Path>, account_id: String) -> Result<()> {
-    info!("Fetch pending transactions");
-    let bal = tl.card_pending(&account_id).await?;
-    let path = &target_dir
-        .join("cards")
-        .join(account_id)
-        .join("pending.jsons");
-    write_jsons_atomically(path, bal.results).await?;
+async fn account_beneficiaries(ctx: SyncContext, key: AccountKey) -> Result<()> {
+    info!("Fetch beneficiaries");
+    let beneficiaries = match ctx.tl.account_beneficiaries(key.account_id()).await {
+        Err(e) if e.is_unsupported() => {
+            info!("Provider does not offer beneficiaries");
+            return Ok(());
+        }
+        res => res?,
+    };
+    ctx.store
+        .put_beneficiaries(&key, beneficiaries.results)
+        .await?;
     Ok(())
 }
 
 #[instrument(skip_all, fields(?month))]
-async fn card_tx(
-    tl: Arc<TlClient>,
-    target_dir: Arc<Path>,
-    account_id: String,
+async fn transactions(
+    ctx: SyncContext,
+    key: AccountKey,
     month: RangeInclusive<NaiveDate>,
 ) -> Result<()> {
-    let mut txes = tl
-        .card_transactions(&account_id, *month.start(), *month.end())
-        .await?;
+    let (kind, mut txes) = match &key {
+        AccountKey::Account(_) => (
+            Kind::Account,
+            ctx.tl
+                .account_transactions(key.account_id(), *month.start(), *month.end())
+                .await?,
+        ),
+        AccountKey::Card(_) => (
+            Kind::Card,
+            ctx.tl
+                .card_transactions(key.account_id(), *month.start(), *month.end())
+                .await?,
+        ),
+    };
 
+    ctx.summary
+        .unknown_fields("transactions", txes.results.iter());
+    let count = txes.results.len();
+    let ready = ctx.pending.booked(&key, &txes.results);
     if txes.results.is_empty() {
-        info!(?month, "No results for month found");
-        return Ok(());
-    }
+        info!("No results for month found");
+    } else {
+        let ids = txes
+            .results
+            .iter()
+            .filter_map(transaction_id)
+            .collect::<Vec<_>>();
+        let mut found_new = false;
+        if ctx.new_transactions.is_watching(&key) {
+            found_new = find_new(&ctx, &key, *month.start(), &txes.results).await?;
+        }
 
-    txes.results.reverse();
+        if let Some(categories) = ctx.categories.as_ref() {
+            txes.results
+                .iter_mut()
+                .for_each(|tx| categories.annotate(tx));
+        }
+        if let Some(fx) = ctx.fx.as_ref() {
+            txes.results.iter_mut().for_each(|tx| fx.annotate(tx));
+        }
+        ctx.store
+            .put_transactions(&key, *month.start(), txes.results)
+            .await?;
+        // Only once they're stored, lest a failed write leave them looking
+        // seen.
+        ctx.store.put_seen(&key, ids).await?;
+        if found_new {
+            ctx.new_transactions.save(&ctx.target_dir).await?;
+        }
+    }
 
-    write_jsons_atomically(
-        &target_dir
-            .join("cards")
-            .join(&account_id)
-            .join(month.start().format("%Y-%m.jsons").to_string()),
-        txes.results,
-    )
-    .await?;
+    write_pending(&ctx, ready).await?;
+    ctx.summary.month(count);
+    ctx.observer.on_month_fetched(&key, *month.start(), count);
+    ctx.state
+        .complete(kind, key.account_id(), *month.start())
+        .await?;
+    ctx.journal
+        .complete(kind, key.account_id(), *month.start())
+        .await?;
     Ok(())
 }
 
-fn months(period: RangeInclusive<NaiveDate>) -> impl Iterator<Item = RangeInclusive<NaiveDate>> {
-    let month_start_date = period.start().with_day(1).expect("day one");
+impl PlannedFetch {
+    fn new(key: &AccountKey, endpoint: &str, output: PathBuf) -> Self {
+        let kind = match key {
+            AccountKey::Account(_) => "accounts",
+            AccountKey::Card(_) => "cards",
+        };
+        Self {
+            endpoint: format!("/data/v1/{}/{}/{}", kind, key.account_id(), endpoint),
+            output,
+        }
+    }
 
-    let month_starts = month_start_date.iter_days().filter(|d| d.day() == 1);
-    let month_ends = month_starts.clone().skip(1).map({
-        let period = period.clone();
-        move |d| min(d.pred_opt().unwrap(), *period.end())
-    });
-    month_starts
-        .take_while(move |d| d <= period.end())
-        .zip(month_ends)
-        .map(|(a, b)| a..=b)
+    fn transactions(key: &AccountKey, month: &RangeInclusive<NaiveDate>, output: PathBuf) -> Self {
+        let endpoint = format!("transactions?from={}&to={}", month.start(), month.end());
+        Self::new(key, &endpoint, output)
+    }
 }
 
-async fn write_jsons_atomically<T: Serialize + Send + 'static>(
-    path: &Path,
-    data: Vec<T>,
-) -> Result<()> {
-    let path = path.to_owned();
-    let span = Span::current();
-    spawn_blocking(move || -> Result<()> {
-        let _guard = span.enter();
-        let dir = path.parent().unwrap_or_else(|| Path::new("."));
-        std::fs::create_dir_all(dir)?;
-        let mut tmpf = NamedTempFile::new_in(dir)?;
-        let mut buf = Vec::new();
-        for item in data {
-            serde_json::to_writer(&mut buf, &item)?;
-            assert!(!buf.contains(&b'\n'));
-            tmpf.write_all(&buf)?;
-            tmpf.write_all(b"\n")?;
-            buf.clear();
-        }
-        tmpf.as_file_mut().flush()?;
-        tmpf.persist(&path)?;
-        debug!(?path, "Stored data");
-        Ok(())
+fn fs_store(config: &ProviderConfig) -> FsStore {
+    let mut store = FsStore::new(&config.target_dir)
+        .with_merge_transactions(config.merge_months)
+        .with_transaction_format(config.transaction_format)
+        .with_compression(config.compression)
+        .with_layout(config.layout.clone())
+        .with_seen_index(config.seen_index);
+    if let Some(days) = config.pending_retention_days {
+        store = store.with_pending_retention(chrono::Duration::days(days.into()));
+    }
+    store
+}
+
+/// Where `config` says to write, telling `observer` about each file.
+fn store(config: &ProviderConfig, observer: Arc<dyn SyncObserver>) -> Arc<dyn Store> {
+    let observer: Arc<dyn SyncObserver> = if config.upload.is_empty() {
+        observer
+    } else {
+        Arc::new(UploadBacklog::new(&config.target_dir, observer))
+    };
+    let local = fs_store(config).with_observer(observer);
+    let store: Arc<dyn Store> = match config.remote.clone() {
+        Some(remote) => Arc::new(S3Store::new(local, remote)),
+        None => Arc::new(local),
+    };
+    redacted(config, store)
+}
+
+fn redacted(config: &ProviderConfig, store: Arc<dyn Store>) -> Arc<dyn Store> {
+    if config.redact {
+        Arc::new(RedactedStore(store))
+    } else {
+        store
+    }
+}
+
+/// Notes which of `fetched` we hadn't stored before: per the store's seen
+/// index, if it keeps one, so as not to read the month back.
+async fn find_new(
+    ctx: &SyncContext,
+    key: &AccountKey,
+    month: NaiveDate,
+    fetched: &[TransactionsResult],
+) -> Result<bool> {
+    let ids = fetched.iter().filter_map(transaction_id).collect();
+    if let Some(unseen) = ctx.store.get_unseen(key, ids).await? {
+        let unseen = unseen.into_iter().collect::<HashSet<_>>();
+        let new = fetched
+            .iter()
+            .filter(|tx| transaction_id(tx).map_or(false, |id| unseen.contains(&id)))
+            .cloned()
+            .collect();
+        return Ok(ctx.new_transactions.found(key, new));
+    }
+    Ok(match ctx.store.get_transactions(key, month).await? {
+        Some(stored) => ctx.new_transactions.compare(key, &stored, fetched),
+        None => false,
     })
-    .await??;
-    Ok(())
+}
+
+pub(crate) fn transaction_id(tx: &TransactionsResult) -> Option<String> {
+    tx.transaction_id
+        .as_ref()
+        .or(tx.normalised_provider_transaction_id.as_ref())
+        .or(tx.provider_transaction_id.as_ref())
+        .cloned()
 }
@@ -0,0 +1,132 @@
+//! Where spans and logs go: the console, and optionally an OpenTelemetry
+//! collector.
+
+#[cfg(not(feature = "otlp"))]
+use anyhow::anyhow;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+#[cfg(not(feature = "otlp"))]
+use crate::config::OtlpConfig;
+use crate::MainConfig;
+
+/// Keeps the exporter running; call [`Telemetry::shutdown`] before exiting
+/// to flush any buffered spans.
+#[must_use]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+/// How to format log lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per event, for log aggregators.
+    Json,
+}
+
+/// Installs the global subscriber, which also captures `log` records,
+/// logging in `format`. Must be called from within a tokio runtime if
+/// `config.otlp` is set.
+pub fn init(config: &MainConfig, format: LogFormat) -> Result<Telemetry> {
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
+        .with_thread_names(true)
+        .with_thread_ids(true);
+    let fmt = match format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().with_current_span(true).boxed(),
+    };
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt);
+
+    #[cfg(feature = "otlp")]
+    {
+        let (layer, provider) = match config.otlp.as_ref() {
+            Some(otlp) => {
+                let (layer, provider) = otlp::layer(otlp)?;
+                (Some(layer), Some(provider))
+            }
+            None => (None, None),
+        };
+        registry.with(layer).try_init()?;
+        Ok(Telemetry { provider })
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        if let Some(otlp) = config.otlp.as_ref() {
+            return Err(no_otlp_support(otlp));
+        }
+        registry.try_init()?;
+        Ok(Telemetry {})
+    }
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider {
+            for result in provider.force_flush() {
+                if let Err(error) = result {
+                    eprintln!("Failed to flush spans: {}", error);
+                }
+            }
+            if let Err(error) = provider.shutdown() {
+                eprintln!("Failed to shut down span exporter: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+fn no_otlp_support(otlp: &OtlpConfig) -> anyhow::Error {
+    anyhow!("Built without the `otlp` feature")
+        .context(format!("Exporting traces to {}", otlp.endpoint))
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use anyhow::Result;
+    use opentelemetry::{trace::TracerProvider as _, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{
+        runtime,
+        trace::{Config, TracerProvider},
+        Resource,
+    };
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    use crate::config::OtlpConfig;
+
+    pub(super) fn layer<S>(config: &OtlpConfig) -> Result<(impl Layer<S>, TracerProvider)>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let service_name = config
+            .service_name
+            .clone()
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned());
+        let provider = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(&config.endpoint)
+                    .with_headers(config.headers.clone()),
+            )
+            .with_trace_config(
+                Config::default()
+                    .with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+            )
+            .install_batch(runtime::Tokio)?;
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+    }
+}
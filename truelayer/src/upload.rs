@@ -0,0 +1,161 @@
+//! Sending the files a sync changed somewhere else once it finishes, eg: to
+//! a bucket, or via a command of the user's own such as `rclone`.
+//!
+//! Each file is noted in a backlog in the target directory as it's written,
+//! and the backlog cleared once it's uploaded; so files changed by a sync
+//! that fails are uploaded by the next that succeeds.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    error::Context,
+    observer::SyncObserver,
+    s3::{S3Client, S3Config},
+    store::slash_separated,
+    Error, Result,
+};
+
+/// Files written but not yet uploaded, relative to the target directory;
+/// one per line.
+const UPLOAD_BACKLOG_FILE: &str = ".upload-backlog";
+
+/// Where to send changed files, eg:
+/// `upload = [{ command = "rsync --relative \"$@\" backup:finance/" }]`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadConfig {
+    /// Run with `sh -c` in the target directory, with the changed files'
+    /// paths relative to it as arguments, ie: `"$@"`, and the provider's
+    /// name in `$TL_SCRAPER_PROVIDER`.
+    Command(String),
+    /// Put each changed file in an S3-compatible bucket.
+    S3(S3Config),
+}
+
+/// Notes each file the store writes in the backlog.
+#[derive(Debug)]
+pub(crate) struct UploadBacklog {
+    target_dir: PathBuf,
+    observer: Arc<dyn SyncObserver>,
+}
+
+impl UploadBacklog {
+    pub(crate) fn new(target_dir: &Path, observer: Arc<dyn SyncObserver>) -> Self {
+        Self {
+            target_dir: target_dir.to_owned(),
+            observer,
+        }
+    }
+
+    fn record(&self, path: &Path) -> io::Result<()> {
+        let mut backlog = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.target_dir.join(UPLOAD_BACKLOG_FILE))?;
+        writeln!(backlog, "{}", slash_separated(path))
+    }
+}
+
+impl SyncObserver for UploadBacklog {
+    fn on_file_written(&self, path: &Path) {
+        if let Err(error) = self.record(path) {
+            warn!(?path, %error, "Failed to note file to upload");
+        }
+        self.observer.on_file_written(path);
+    }
+}
+
+/// The files in `target_dir`'s backlog that are still there.
+pub(crate) async fn backlog(target_dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let path = target_dir.join(UPLOAD_BACKLOG_FILE);
+    let content = match tokio::fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {:?}", path)),
+    };
+    Ok(content
+        .lines()
+        .map(PathBuf::from)
+        .filter(|file| target_dir.join(file).exists())
+        .collect())
+}
+
+pub(crate) async fn clear_backlog(target_dir: &Path) -> Result<()> {
+    let path = target_dir.join(UPLOAD_BACKLOG_FILE);
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Removing {:?}", path)),
+    }
+}
+
+/// Sends `files`, relative to `target_dir`, to each of `destinations` in
+/// turn, stopping at the first that fails.
+#[instrument(skip_all, fields(%provider, files=%files.len()))]
+pub(crate) async fn upload(
+    provider: &str,
+    target_dir: &Path,
+    destinations: &[UploadConfig],
+    files: &[PathBuf],
+) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    for destination in destinations {
+        match destination {
+            UploadConfig::Command(command) => {
+                run_command(provider, target_dir, command, files).await?
+            }
+            UploadConfig::S3(config) => put_s3(target_dir, config, files).await?,
+        }
+    }
+    Ok(())
+}
+
+async fn run_command(
+    provider: &str,
+    target_dir: &Path,
+    command: &str,
+    files: &[PathBuf],
+) -> Result<()> {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .args(files)
+        .current_dir(target_dir)
+        .env("TL_SCRAPER_PROVIDER", provider)
+        .stdin(Stdio::null())
+        .status()
+        .await
+        .with_context(|| format!("Running upload command: {:?}", command))?;
+    if !status.success() {
+        return Err(Error::UploadFailed {
+            command: command.to_owned(),
+            status,
+        });
+    }
+    info!(%command, "Ran upload command");
+    Ok(())
+}
+
+async fn put_s3(target_dir: &Path, config: &S3Config, files: &[PathBuf]) -> Result<()> {
+    let client = S3Client::new(config)?;
+    for file in files {
+        let body = tokio::fs::read(target_dir.join(file))
+            .await
+            .with_context(|| format!("Reading {:?} to upload", file))?;
+        client.put(&slash_separated(file), body).await?;
+    }
+    info!(bucket=%config.bucket, prefix=%config.prefix, "Uploaded to S3");
+    Ok(())
+}
@@ -0,0 +1,2440 @@
+//! Runs the client and sync against a mock TrueLayer, serving responses
+//! recorded in `fixtures/`.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use again::RetryPolicy;
+use chrono::{Datelike, NaiveDate, Utc};
+use futures::TryStreamExt;
+use rust_decimal::Decimal;
+use scraper_sdk::{RunCancelled, RunDeadlineExceeded};
+use secrecy::ExposeSecret;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use tempfile::TempDir;
+use tl_scraper::{
+    store::{AccountKey, CardNaming, FsStore, Layout, Reader, Store},
+    verify_hashes, AccountsResult, ApiError, Backfill, CardsResult, Cassette, ClientCreds,
+    Environment, Error, HttpCache, JobPool, PingConfig, PoolConfig, ProviderConfig, ProviderStatus,
+    ProviderSync, ScraperConfig, SyncEngine, SyncObserver, SyncOptions, SyncState, TlClient,
+    TlClientBuilder, TransactionsResult, EXIT_CANCELLED, EXIT_DEADLINE_EXCEEDED,
+    EXIT_REAUTH_NEEDED,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wiremock::{
+    matchers::{
+        body_string_contains, header, header_exists, method, path, path_regex, query_param,
+        query_param_is_missing,
+    },
+    Mock, MockServer, ResponseTemplate,
+};
+
+const ACCOUNT_ID: &str = "f1234560abf9f57287637624def390871";
+
+struct Harness {
+    server: MockServer,
+    dir: TempDir,
+}
+
+impl Harness {
+    /// A mock server, and a token for it that expires in `expires_in`.
+    async fn start(expires_in: chrono::Duration) -> Harness {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().expect("tempdir");
+        let harness = Harness { server, dir };
+        let token = json!({
+            "access_token": "stored-access-token",
+            "expires_at": Utc::now() + expires_in,
+            "token_type": "Bearer",
+            "refresh_token": "stored-refresh-token",
+            "scope": null,
+            "redirect_uri": "http://localhost/callback",
+            "authed_at": Utc::now(),
+            "environment": harness.env(),
+        });
+        std::fs::write(harness.token_path(), token.to_string()).expect("write token");
+        harness
+    }
+
+    fn env(&self) -> Environment {
+        Environment::Custom {
+            api: self.server.uri(),
+            auth: self.server.uri(),
+        }
+    }
+
+    fn token_path(&self) -> PathBuf {
+        self.dir.path().join("token.json")
+    }
+
+    fn target_dir(&self) -> PathBuf {
+        self.dir.path().join("data")
+    }
+
+    fn client(&self) -> TlClient {
+        self.builder().build().expect("build client")
+    }
+
+    fn builder(&self) -> TlClientBuilder {
+        let creds: ClientCreds =
+            serde_json::from_value(json!({"id": "client-id", "secret": "client-secret"}))
+                .expect("client creds");
+        TlClient::builder(self.env(), &self.token_path(), &creds)
+            .retry_policy(RetryPolicy::fixed(Duration::ZERO).with_max_retries(0))
+    }
+
+    /// Serves `fixture` for `GET route`.
+    async fn get(&self, route: &str, fixture: &str) {
+        Mock::given(method("GET"))
+            .and(path(route))
+            .and(header("authorization", "Bearer stored-access-token"))
+            .respond_with(self.fixture(fixture))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Answers the first `times` requests for `GET route` with `response`,
+    /// ahead of anything else served for it; eg: to throttle, or stall.
+    async fn get_first(&self, route: &str, times: u64, response: ResponseTemplate) {
+        Mock::given(method("GET"))
+            .and(path(route))
+            .respond_with(response)
+            .up_to_n_times(times)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// A client that retries failed requests `retries` times, immediately.
+    fn retrying_builder(&self, retries: usize) -> TlClientBuilder {
+        self.builder()
+            .retry_policy(RetryPolicy::fixed(Duration::ZERO).with_max_retries(retries))
+    }
+
+    async fn requests_received(&self) -> usize {
+        self.server
+            .received_requests()
+            .await
+            .expect("recording requests")
+            .len()
+    }
+
+    fn fixture(&self, name: &str) -> ResponseTemplate {
+        self.fixture_with_status(200, name)
+    }
+
+    fn fixture_with_status(&self, status: u16, name: &str) -> ResponseTemplate {
+        let body = read_fixture(name).replace("{{base}}", &self.server.uri());
+        ResponseTemplate::new(status).set_body_raw(body, "application/json")
+    }
+
+    /// Syncs June 2024 from a single account, with `config` added to the
+    /// provider's configuration.
+    async fn sync_accounts(&self, config: serde_json::Value) -> Arc<ProviderSync> {
+        self.sync_accounts_with(config, SyncOptions::default())
+            .await
+    }
+
+    async fn sync_accounts_with(
+        &self,
+        config: serde_json::Value,
+        options: SyncOptions,
+    ) -> Arc<ProviderSync> {
+        self.accounts().await;
+        let sync = Arc::new(ProviderSync::new(
+            "mock",
+            Arc::new(self.client()),
+            &self.provider_config(config),
+            options,
+        ));
+
+        scraper_sdk::sync_all(
+            vec![sync.clone()],
+            date("2024-06-01")..=date("2024-06-30"),
+            JobPool::new(1),
+        )
+        .await
+        .expect("sync");
+        sync
+    }
+
+    /// A provider syncing accounts into the target directory, with `config`
+    /// added.
+    fn provider_config(&self, config: serde_json::Value) -> ProviderConfig {
+        let mut provider = json!({
+            "user_token": self.token_path(),
+            "target_dir": self.target_dir(),
+            "scrape_accounts": true,
+        });
+        provider
+            .as_object_mut()
+            .expect("object")
+            .extend(config.as_object().expect("object").clone());
+        std::fs::create_dir_all(self.target_dir()).expect("target dir");
+        serde_json::from_value(provider).expect("provider config")
+    }
+
+    /// Serves a single account, with its balance and a month of
+    /// transactions.
+    async fn accounts(&self) {
+        self.get("/data/v1/accounts", "accounts.json").await;
+        self.get(
+            &format!("/data/v1/accounts/{}/balance", ACCOUNT_ID),
+            "balance.json",
+        )
+        .await;
+        self.get(
+            &format!("/data/v1/accounts/{}/transactions/pending", ACCOUNT_ID),
+            "pending.json",
+        )
+        .await;
+        self.transactions().await;
+    }
+
+    async fn transactions(&self) {
+        let route = format!("/data/v1/accounts/{}/transactions", ACCOUNT_ID);
+        Mock::given(method("GET"))
+            .and(path(route.as_str()))
+            .and(query_param_is_missing("cursor"))
+            .respond_with(self.fixture("transactions-page-1.json"))
+            .mount(&self.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(route.as_str()))
+            .and(query_param("cursor", "2"))
+            .respond_with(self.fixture("transactions-page-2.json"))
+            .mount(&self.server)
+            .await;
+    }
+}
+
+fn read_fixture(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("Reading {:?}: {}", path, err))
+}
+
+/// The `results` of a fixture, for tests that use the store directly.
+fn fixture_results<T: DeserializeOwned>(name: &str) -> T {
+    let response: serde_json::Value = serde_json::from_str(&read_fixture(name)).expect("json");
+    serde_json::from_value(response["results"].clone())
+        .unwrap_or_else(|err| panic!("Parsing {}: {}", name, err))
+}
+
+fn date(s: &str) -> NaiveDate {
+    s.parse().expect("date")
+}
+
+fn decimal(s: &str) -> Decimal {
+    s.parse().expect("decimal")
+}
+
+#[tokio::test]
+async fn fetches_accounts() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+
+    let accounts = harness.client().fetch_accounts().await.expect("accounts");
+
+    assert_eq!(accounts.results.len(), 1);
+    assert_eq!(accounts.results[0].account_id, ACCOUNT_ID);
+    assert_eq!(accounts.results[0].display_name, "Club Lloyds");
+    assert_eq!(
+        accounts.results[0].account_number.sort_code.as_deref(),
+        Some("12-34-56")
+    );
+}
+
+/// Serves `body` to every request over plain HTTP/1.1, counting the
+/// connections made to it.
+async fn counting_server(body: String) -> (String, Arc<AtomicUsize>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let base = format!("http://{}", listener.local_addr().expect("address"));
+    let connections = Arc::new(AtomicUsize::new(0));
+    let response = format!(
+        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    tokio::spawn({
+        let connections = connections.clone();
+        async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                connections.fetch_add(1, Ordering::SeqCst);
+                let response = response.clone();
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    let mut buf = [0; 4096];
+                    while let Ok(n @ 1..) = socket.read(&mut buf).await {
+                        received.extend_from_slice(&buf[..n]);
+                        // Requests are all GETs, so end with their headers.
+                        while let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                            received.drain(..end + 4);
+                            if socket.write_all(response.as_bytes()).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    });
+    (base, connections)
+}
+
+#[tokio::test]
+async fn fetches_with_tuned_pool() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let (base, connections) = counting_server(read_fixture("accounts.json")).await;
+    let env = Environment::Custom {
+        api: base.clone(),
+        auth: base,
+    };
+    let mut token: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(harness.token_path()).expect("token"))
+            .expect("parse token");
+    token["environment"] = json!(env);
+    std::fs::write(harness.token_path(), token.to_string()).expect("write token");
+    let creds: ClientCreds =
+        serde_json::from_value(json!({"id": "client-id", "secret": "client-secret"}))
+            .expect("client creds");
+    let fetch_three = |pool: PoolConfig| {
+        let client = TlClient::builder(env.clone(), &harness.token_path(), &creds)
+            .pool(pool)
+            .build()
+            .expect("build client");
+        async move {
+            for _ in 0..3 {
+                let accounts = client.fetch_accounts().await.expect("accounts");
+                assert_eq!(accounts.results.len(), 1);
+            }
+        }
+    };
+
+    fetch_three(PoolConfig::default()).await;
+    assert_eq!(connections.swap(0, Ordering::SeqCst), 1);
+    let pool: PoolConfig = serde_json::from_value(json!({
+        "max_idle_per_host": 0,
+        "idle_timeout_s": 30,
+        "tcp_keepalive_s": 15,
+    }))
+    .expect("pool config");
+    fetch_three(pool).await;
+    // Without idle connections to reuse, each request makes its own.
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+}
+
+fn rate_limited(retry_after_s: u64) -> ResponseTemplate {
+    ResponseTemplate::new(429).insert_header("retry-after", retry_after_s.to_string().as_str())
+}
+
+#[tokio::test]
+async fn retries_when_rate_limited() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness
+        .get_first("/data/v1/accounts", 2, rate_limited(0))
+        .await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+    let client = harness.retrying_builder(2).build().expect("build client");
+
+    let accounts = client.fetch_accounts().await.expect("accounts");
+
+    assert_eq!(accounts.results.len(), 1);
+    assert_eq!(harness.requests_received().await, 3);
+}
+
+#[tokio::test]
+async fn gives_up_when_still_rate_limited() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness
+        .get_first("/data/v1/accounts", 3, rate_limited(0))
+        .await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+    let client = harness.retrying_builder(1).build().expect("build client");
+
+    let err = client.fetch_accounts().await.expect_err("rate limited");
+
+    assert!(
+        matches!(err, Error::RateLimited { retry_after: Some(delay) } if delay == Duration::ZERO),
+        "{:?}",
+        err
+    );
+    assert_eq!(harness.requests_received().await, 2);
+}
+
+#[tokio::test]
+async fn waits_out_retry_after() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness
+        .get_first("/data/v1/accounts", 1, rate_limited(1))
+        .await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+    let client = harness.retrying_builder(1).build().expect("build client");
+
+    let started = Instant::now();
+    client.fetch_accounts().await.expect("accounts");
+
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(harness.requests_received().await, 2);
+}
+
+/// Queues `GET route` as an async request, with its results at `results`.
+async fn queue_async(harness: &Harness, route: &str, results: &str) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .and(query_param("async", "true"))
+        .respond_with(ResponseTemplate::new(202).set_body_json(json!({
+            "results_uri": format!("{}{}", harness.server.uri(), results),
+            "status": "Queued",
+            "task_id": "task-1",
+        })))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+}
+
+#[tokio::test]
+async fn polls_for_async_results() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    queue_async(&harness, "/data/v1/accounts", "/data/v1/results/task-1").await;
+    harness
+        .get_first(
+            "/data/v1/results/task-1",
+            1,
+            ResponseTemplate::new(200).set_body_json(json!({"status": "Running"})),
+        )
+        .await;
+    harness
+        .get("/data/v1/results/task-1", "accounts.json")
+        .await;
+    let client = harness
+        .builder()
+        .async_data(Duration::from_secs(30))
+        .build()
+        .expect("build client");
+
+    let accounts = client.fetch_accounts().await.expect("accounts");
+
+    assert_eq!(accounts.results.len(), 1);
+    // Queued, still running, then done.
+    assert_eq!(harness.requests_received().await, 3);
+}
+
+#[tokio::test]
+async fn gives_up_on_slow_async_results() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    queue_async(&harness, "/data/v1/accounts", "/data/v1/results/task-1").await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/results/task-1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"status": "Running"})))
+        .mount(&harness.server)
+        .await;
+    let client = harness
+        .builder()
+        .async_data(Duration::from_millis(500))
+        .build()
+        .expect("build client");
+
+    let err = client.fetch_accounts().await.unwrap_err();
+
+    assert!(matches!(err, Error::AsyncTimedOut { .. }), "{:?}", err);
+    assert_eq!(harness.requests_received().await, 2);
+}
+
+#[tokio::test]
+async fn records_rate_limit_quota() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let limited = harness
+        .fixture("accounts.json")
+        .insert_header("x-ratelimit-limit", "100")
+        .insert_header("x-ratelimit-remaining", "42")
+        .insert_header("x-ratelimit-reset", "60");
+    harness.get_first("/data/v1/accounts", 1, limited).await;
+    let client = harness.client();
+
+    client.fetch_accounts().await.expect("accounts");
+
+    let quota = client.rate_limit_quota().expect("quota");
+    assert_eq!(quota.limit, Some(100));
+    assert_eq!(quota.remaining, 42);
+    assert_eq!(
+        quota.resets_at,
+        Some(quota.observed_at + chrono::Duration::seconds(60))
+    );
+}
+
+#[tokio::test]
+async fn pauses_at_rate_limit_floor() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let exhausted = harness
+        .fixture("accounts.json")
+        .insert_header("x-ratelimit-remaining", "1")
+        .insert_header("x-ratelimit-reset", "1");
+    harness.get_first("/data/v1/accounts", 1, exhausted).await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+    let client = harness
+        .builder()
+        .rate_limit_floor(1)
+        .build()
+        .expect("build client");
+
+    client.fetch_accounts().await.expect("accounts");
+    let started = Instant::now();
+    client.fetch_accounts().await.expect("accounts");
+
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn counts_token_requests_towards_rate_limit() {
+    let harness = Harness::start(-chrono::Duration::hours(1)).await;
+    let exhausted = harness
+        .fixture("token.json")
+        .insert_header("x-ratelimit-remaining", "1")
+        .insert_header("x-ratelimit-reset", "1");
+    Mock::given(method("POST"))
+        .and(path("/connect/token"))
+        .respond_with(exhausted)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .and(header("authorization", "Bearer new-access-token"))
+        .respond_with(harness.fixture("accounts.json"))
+        .mount(&harness.server)
+        .await;
+    let client = harness
+        .builder()
+        .rate_limit_floor(1)
+        .build()
+        .expect("build client");
+
+    let started = Instant::now();
+    client.fetch_accounts().await.expect("accounts");
+
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn spaces_requests_at_configured_rate() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+    let client = harness
+        .builder()
+        .rate_limit(2.0)
+        .build()
+        .expect("build client");
+
+    let started = Instant::now();
+    for _ in 0..4 {
+        client.fetch_accounts().await.expect("accounts");
+    }
+
+    // Two may go at once, then one every half second.
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn retries_slow_responses() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let stalled = harness
+        .fixture("accounts.json")
+        .set_delay(Duration::from_secs(5));
+    harness.get_first("/data/v1/accounts", 1, stalled).await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+    let client = harness
+        .retrying_builder(1)
+        .request_timeout(Duration::from_millis(200))
+        .build()
+        .expect("build client");
+
+    let accounts = client.fetch_accounts().await.expect("accounts");
+
+    assert_eq!(accounts.results.len(), 1);
+    assert_eq!(harness.requests_received().await, 2);
+}
+
+#[tokio::test]
+async fn reuses_cached_response_when_not_modified() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .and(header("if-none-match", "\"v1\""))
+        .respond_with(ResponseTemplate::new(304))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .respond_with(
+            harness
+                .fixture("accounts.json")
+                .insert_header("etag", "\"v1\""),
+        )
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    let client = harness
+        .builder()
+        .http_cache(HttpCache::new(harness.dir.path().join("cache")))
+        .build()
+        .expect("build client");
+
+    let fetched = client.fetch_accounts().await.expect("accounts");
+    let cached = client.fetch_accounts().await.expect("cached accounts");
+
+    assert_eq!(cached.results.len(), 1);
+    assert_eq!(cached.results[0].account_id, fetched.results[0].account_id);
+}
+
+#[tokio::test]
+async fn fails_when_not_modified_without_cached_response() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .respond_with(ResponseTemplate::new(304))
+        .mount(&harness.server)
+        .await;
+    let client = harness
+        .builder()
+        .http_cache(HttpCache::new(harness.dir.path().join("cache")))
+        .build()
+        .expect("build client");
+
+    let err = client.fetch_accounts().await.expect_err("nothing cached");
+
+    assert!(matches!(err, Error::CacheMiss { .. }), "{:?}", err);
+}
+
+#[tokio::test]
+async fn does_not_cache_responses_when_redacting() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+
+    let cached = harness.provider_config(json!({"http_cache": true}));
+    let redacted = harness.provider_config(json!({"http_cache": true, "redact": true}));
+
+    assert!(cached.http_cache().is_some());
+    assert!(redacted.http_cache().is_none());
+}
+
+#[tokio::test]
+async fn replays_recorded_responses() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+    let cassette = harness.dir.path().join("cassette");
+
+    let recorded = harness
+        .builder()
+        .cassette(Cassette::Record(cassette.clone()))
+        .build()
+        .expect("build client")
+        .fetch_accounts()
+        .await
+        .expect("record accounts");
+    harness.server.reset().await;
+    let replayed = harness
+        .builder()
+        .cassette(Cassette::Replay(cassette))
+        .build()
+        .expect("build client")
+        .fetch_accounts()
+        .await
+        .expect("replay accounts");
+
+    assert_eq!(replayed.results.len(), recorded.results.len());
+    assert_eq!(replayed.results[0].account_id, ACCOUNT_ID);
+}
+
+#[tokio::test]
+async fn follows_transaction_pages() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.transactions().await;
+
+    let txes = harness
+        .client()
+        .account_transactions(ACCOUNT_ID, date("2024-06-01"), date("2024-06-30"))
+        .await
+        .expect("transactions");
+
+    let descriptions = txes
+        .results
+        .iter()
+        .map(|tx| tx.description.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(descriptions, ["TESCO STORES 3185", "ACME LTD SALARY"]);
+    assert_eq!(
+        txes.results[0].meta.provider_category.as_deref(),
+        Some("DEB")
+    );
+    assert_eq!(
+        txes.results[0].meta.other["provider_id"],
+        "0ec5e1a5-63f6-42b4-9a4e-7b3a5d1a2d2f"
+    );
+}
+
+#[tokio::test]
+async fn streams_transactions_month_by_month() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.transactions().await;
+    let client = harness.client();
+
+    let txes = client
+        .stream_account_transactions(ACCOUNT_ID, date("2024-05-15")..=date("2024-06-30"))
+        .try_collect::<Vec<_>>()
+        .await
+        .expect("transactions");
+
+    // Both pages, for each of May and June.
+    assert_eq!(txes.len(), 4);
+    let months = harness
+        .server
+        .received_requests()
+        .await
+        .expect("requests")
+        .iter()
+        .filter(|req| !req.url.query_pairs().any(|(name, _)| name == "cursor"))
+        .filter_map(|req| {
+            req.url
+                .query_pairs()
+                .find(|(name, _)| name == "from")
+                .map(|(_, from)| from.into_owned())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(months, ["2024-05-15", "2024-06-01"]);
+}
+
+#[tokio::test]
+async fn rejected_token_needs_reauthentication() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .respond_with(harness.fixture_with_status(401, "invalid-token.json"))
+        .mount(&harness.server)
+        .await;
+
+    let err = harness.client().fetch_accounts().await.unwrap_err();
+
+    assert!(err.needs_reauthentication(), "{:?}", err);
+}
+
+#[tokio::test]
+async fn refused_refresh_names_provider_to_reauthenticate() {
+    let harness = Harness::start(-chrono::Duration::hours(1)).await;
+    Mock::given(method("POST"))
+        .and(path("/connect/token"))
+        .respond_with(harness.fixture_with_status(400, "invalid-grant.json"))
+        .mount(&harness.server)
+        .await;
+    let config: ProviderConfig = serde_json::from_value(json!({
+        "user_token": harness.token_path(),
+        "target_dir": harness.target_dir(),
+        "scrape_accounts": true,
+    }))
+    .expect("provider config");
+    std::fs::create_dir_all(harness.target_dir()).expect("target dir");
+    let sync = Arc::new(ProviderSync::new(
+        "mock",
+        Arc::new(harness.client()),
+        &config,
+        SyncOptions::default(),
+    ));
+
+    let err = scraper_sdk::sync_all(
+        vec![sync],
+        date("2024-06-01")..=date("2024-06-30"),
+        JobPool::new(1),
+    )
+    .await
+    .unwrap_err();
+
+    assert!(err.needs_reauthentication(), "{:?}", err);
+    assert_eq!(
+        err.reauthentication_required()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        ["mock"]
+    );
+}
+
+#[tokio::test]
+async fn refreshes_shared_token_once() {
+    let harness = Harness::start(-chrono::Duration::hours(1)).await;
+    Mock::given(method("POST"))
+        .and(path("/connect/token"))
+        .respond_with(harness.fixture("token.json"))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .and(header("authorization", "Bearer new-access-token"))
+        .respond_with(harness.fixture("accounts.json"))
+        .expect(2)
+        .mount(&harness.server)
+        .await;
+
+    // As if from two processes syncing the same provider.
+    let (first, second) = (harness.client(), harness.client());
+    let (a, b) = tokio::join!(first.fetch_accounts(), second.fetch_accounts());
+
+    a.expect("first");
+    b.expect("second");
+}
+
+#[tokio::test]
+async fn refreshes_expired_token() {
+    let harness = Harness::start(-chrono::Duration::hours(1)).await;
+    Mock::given(method("POST"))
+        .and(path("/connect/token"))
+        .respond_with(harness.fixture("token.json"))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .and(header("authorization", "Bearer new-access-token"))
+        .respond_with(harness.fixture("accounts.json"))
+        .mount(&harness.server)
+        .await;
+
+    let accounts = harness.client().fetch_accounts().await.expect("accounts");
+
+    assert_eq!(accounts.results.len(), 1);
+    let stored = std::fs::read_to_string(harness.token_path()).expect("token");
+    assert!(stored.contains("new-refresh-token"), "{}", stored);
+}
+
+#[tokio::test]
+async fn refreshes_token_about_to_expire() {
+    let harness = Harness::start(chrono::Duration::seconds(30)).await;
+    Mock::given(method("POST"))
+        .and(path("/connect/token"))
+        .respond_with(harness.fixture("token.json"))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/data/v1/accounts"))
+        .and(header("authorization", "Bearer new-access-token"))
+        .respond_with(harness.fixture("accounts.json"))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    harness.get("/data/v1/accounts", "accounts.json").await;
+
+    let without_margin = harness
+        .builder()
+        .expiry_margin(chrono::Duration::zero())
+        .build()
+        .expect("build client");
+    without_margin
+        .fetch_accounts()
+        .await
+        .expect("accounts with stored token");
+    harness
+        .client()
+        .fetch_accounts()
+        .await
+        .expect("accounts with refreshed token");
+}
+
+#[tokio::test]
+async fn skips_endpoints_without_granted_scopes() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let mut token: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(harness.token_path()).expect("token"))
+            .expect("parse token");
+    token["scope"] = json!("accounts balance offline_access");
+    std::fs::write(harness.token_path(), token.to_string()).expect("write token");
+
+    harness.sync_accounts(json!({})).await;
+
+    let account_dir = harness.target_dir().join("accounts/12-34-56 12345678");
+    assert!(account_dir.join("balance.jsons").exists());
+    assert!(!account_dir.join("2024-06.jsons").exists());
+    assert!(!account_dir.join("pending.jsons").exists());
+}
+
+#[tokio::test]
+async fn syncs_accounts_to_target_dir() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+
+    let sync = harness.sync_accounts(json!({})).await;
+
+    let account_dir = harness.target_dir().join("accounts/12-34-56 12345678");
+    for file in ["account.jsons", "balance.jsons", "2024-06.jsons"] {
+        assert!(
+            account_dir.join(file).exists(),
+            "{} missing from {:?}",
+            file,
+            account_dir
+        );
+    }
+    let month = std::fs::read_to_string(account_dir.join("2024-06.jsons")).expect("month");
+    assert_eq!(month.lines().count(), 2, "{}", month);
+    let unknown = sync.summary().unknown_fields;
+    assert_eq!(
+        unknown
+            .get("transactions")
+            .map(|fields| fields.iter().map(String::as_str).collect::<Vec<_>>()),
+        Some(vec!["payment_scheme"]),
+        "{:?}",
+        unknown
+    );
+    for endpoint in ["accounts", "balance"] {
+        assert_eq!(
+            unknown
+                .get(endpoint)
+                .map(|fields| fields.iter().map(String::as_str).collect::<Vec<_>>()),
+            Some(vec!["update_timestamp"]),
+            "{}: {:?}",
+            endpoint,
+            unknown
+        );
+    }
+}
+
+#[tokio::test]
+async fn runs_sync_engine() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+
+    let report = SyncEngine::builder(
+        "mock",
+        Arc::new(harness.client()),
+        &harness.provider_config(json!({})),
+    )
+    .period(date("2024-06-01")..=date("2024-06-30"))
+    .build()
+    .expect("engine")
+    .run()
+    .await;
+
+    let summary = report.into_result().expect("sync");
+    let provider = &summary.providers["mock"];
+    assert_eq!(provider.accounts, 1);
+    assert_eq!(provider.transactions_written, 2);
+    assert!(harness
+        .target_dir()
+        .join("accounts/12-34-56 12345678/2024-06.jsons")
+        .exists());
+}
+
+#[derive(Debug, Default)]
+struct RecordingObserver {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+impl SyncObserver for RecordingObserver {
+    fn on_account_discovered(&self, key: &AccountKey) {
+        self.record(format!("account {}", key.account_id()));
+    }
+
+    fn on_month_fetched(&self, _: &AccountKey, month: NaiveDate, transactions: usize) {
+        self.record(format!("month {} {}", month, transactions));
+    }
+
+    fn on_file_written(&self, path: &Path) {
+        self.record(format!("wrote {}", path.display()));
+    }
+}
+
+impl RecordingObserver {
+    fn record(&self, event: String) {
+        self.events.lock().expect("lock").push(event);
+    }
+}
+
+#[tokio::test]
+async fn tells_observer_about_progress() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+    let observer = Arc::new(RecordingObserver::default());
+
+    SyncEngine::builder(
+        "mock",
+        Arc::new(harness.client()),
+        &harness.provider_config(json!({})),
+    )
+    .period(date("2024-06-01")..=date("2024-06-30"))
+    .observer(observer.clone())
+    .build()
+    .expect("engine")
+    .run()
+    .await
+    .into_result()
+    .expect("sync");
+
+    let events = observer.events.lock().expect("lock").clone();
+    for event in [
+        format!("account {}", ACCOUNT_ID),
+        "month 2024-06-01 2".to_owned(),
+        "wrote accounts/12-34-56 12345678/2024-06.jsons".to_owned(),
+    ] {
+        assert!(events.contains(&event), "{} not in {:?}", event, events);
+    }
+}
+
+#[tokio::test]
+async fn keeps_custom_store_when_observed() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+    let elsewhere = tempfile::tempdir().expect("tempdir");
+    let observer = Arc::new(RecordingObserver::default());
+
+    let sync = ProviderSync::new(
+        "mock",
+        Arc::new(harness.client()),
+        &harness.provider_config(json!({})),
+        SyncOptions::default(),
+    )
+    .with_store(Arc::new(FsStore::new(elsewhere.path())))
+    .with_observer(observer.clone());
+    scraper_sdk::sync_all(
+        vec![Arc::new(sync)],
+        date("2024-06-01")..=date("2024-06-30"),
+        JobPool::new(1),
+    )
+    .await
+    .expect("sync");
+
+    assert!(elsewhere
+        .path()
+        .join("accounts/12-34-56 12345678/2024-06.jsons")
+        .exists());
+    let events = observer.events.lock().expect("lock").clone();
+    assert!(events.contains(&format!("account {}", ACCOUNT_ID)));
+}
+
+#[tokio::test]
+async fn keeps_sync_state_in_custom_store() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+    let elsewhere = tempfile::tempdir().expect("tempdir");
+
+    let sync = ProviderSync::new(
+        "mock",
+        Arc::new(harness.client()),
+        &harness.provider_config(json!({})),
+        SyncOptions::default(),
+    )
+    .with_store(Arc::new(FsStore::new(elsewhere.path())));
+    scraper_sdk::sync_all(
+        vec![Arc::new(sync)],
+        date("2024-06-01")..=date("2024-06-30"),
+        JobPool::new(1),
+    )
+    .await
+    .expect("sync");
+
+    let state = SyncState::load(elsewhere.path()).await.expect("state");
+    assert_eq!(
+        state.accounts[ACCOUNT_ID].last_synced_month,
+        Some(date("2024-06-01"))
+    );
+    assert!(!harness.target_dir().join("state.json").exists());
+}
+
+#[tokio::test]
+async fn reconciles_running_balances() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.sync_accounts(json!({})).await;
+
+    let report = tl_scraper::reconcile(
+        &harness.target_dir(),
+        &Layout::default(),
+        date("2024-05-01")..=date("2024-06-30"),
+    )
+    .await
+    .expect("reconcile");
+
+    let account = &report.accounts["accounts/12-34-56 12345678"];
+    assert_eq!(account.transactions, 2);
+    assert_eq!(account.missing_months, [date("2024-05-01")]);
+    // The fixtures' running balances don't add up.
+    let gaps = account
+        .balance_gaps
+        .iter()
+        .map(|gap| (gap.transaction_id.as_deref(), gap.expected))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        gaps,
+        [(Some("36f1b4c1f8e8a4b6b1c41d6e8f3d91a2"), decimal("3261.2"))]
+    );
+    let mismatch = account.current_balance.as_ref().expect("mismatch");
+    assert_eq!(mismatch.current, decimal("1161.2"));
+    assert!(!report.is_ok());
+}
+
+#[tokio::test]
+async fn reconciles_configured_layout() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let config = json!({
+        "layout": {"account_dir": "{account_id}", "month_file": "{year}/{month}"},
+    });
+    harness.sync_accounts(config.clone()).await;
+
+    let layout = harness.provider_config(config).layout;
+    let report = tl_scraper::reconcile(
+        &harness.target_dir(),
+        &layout,
+        date("2024-06-01")..=date("2024-06-30"),
+    )
+    .await
+    .expect("reconcile");
+
+    let account = &report.accounts[ACCOUNT_ID];
+    assert_eq!(account.transactions, 2);
+    assert!(account.missing_months.is_empty());
+}
+
+#[tokio::test]
+async fn stores_categories_from_rules() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let rules = harness.dir.path().join("categories.toml");
+    std::fs::write(
+        &rules,
+        r#"
+[[rule]]
+description = "(?i)^tesco"
+category = "Groceries"
+tags = ["food"]
+
+[[rule]]
+description = "SALARY"
+merchant = "^ACME"
+category = "Never matches, as there's no merchant"
+"#,
+    )
+    .expect("rules");
+
+    harness
+        .sync_accounts(json!({"category_rules": rules, "store_categories": true}))
+        .await;
+
+    let month = std::fs::read_to_string(
+        harness
+            .target_dir()
+            .join("accounts/12-34-56 12345678/2024-06.jsons"),
+    )
+    .expect("month");
+    let categories = month
+        .lines()
+        .map(|line| {
+            let tx: serde_json::Value = serde_json::from_str(line).expect("transaction");
+            let description = tx["description"].as_str().expect("description").to_owned();
+            (description, (tx["category"].clone(), tx["tags"].clone()))
+        })
+        .collect::<std::collections::BTreeMap<_, _>>();
+    assert_eq!(
+        categories["TESCO STORES 3185"],
+        (json!("Groceries"), json!(["food"]))
+    );
+    assert_eq!(
+        categories["ACME LTD SALARY"],
+        (serde_json::Value::Null, serde_json::Value::Null)
+    );
+}
+
+#[tokio::test]
+async fn skips_endpoints_the_provider_lacks() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness
+        .get(
+            &format!("/data/v1/accounts/{}/beneficiaries", ACCOUNT_ID),
+            "beneficiaries.json",
+        )
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!(
+            "/data/v1/accounts/{}/scheduled_payments",
+            ACCOUNT_ID
+        )))
+        .respond_with(harness.fixture_with_status(501, "endpoint-not-supported.json"))
+        .mount(&harness.server)
+        .await;
+
+    harness
+        .sync_accounts(json!({
+            "scrape_scheduled_payments": true,
+            "scrape_beneficiaries": true,
+        }))
+        .await;
+
+    let account_dir = harness.target_dir().join("accounts/12-34-56 12345678");
+    let beneficiaries =
+        std::fs::read_to_string(account_dir.join("beneficiaries.jsons")).expect("beneficiaries");
+    assert!(beneficiaries.contains("b7c1e4d2"), "{}", beneficiaries);
+    assert!(!account_dir.join("scheduled-payments.jsons").exists());
+}
+
+#[tokio::test]
+async fn reads_client_secret_from_command() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        r#"
+[main]
+environment = "sandbox"
+secret_cmd = "printf 'hunter2\\nlogin: me\\n'"
+client_id = "client-id"
+
+[providers]
+"#,
+    )
+    .expect("write config");
+    let config = ScraperConfig::load(&config_path).expect("config");
+
+    let creds = config.credentials().await.expect("credentials");
+
+    assert_eq!(creds.id(), "client-id");
+    assert_eq!(creds.secret().expose_secret(), "hunter2");
+}
+
+#[tokio::test]
+async fn reads_client_credentials_from_env() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        r#"
+[main]
+environment = "sandbox"
+credentials_env = { id = "TL_SCRAPER_TEST_CLIENT_ID", secret = "TL_SCRAPER_TEST_CLIENT_SECRET" }
+
+[providers]
+"#,
+    )
+    .expect("write config");
+    let config = ScraperConfig::load(&config_path).expect("config");
+
+    std::env::remove_var("TL_SCRAPER_TEST_CLIENT_SECRET");
+    std::env::set_var("TL_SCRAPER_TEST_CLIENT_ID", "env-client-id");
+    let err = config.credentials().await.expect_err("secret unset");
+    assert!(
+        format!("{:#}", err).contains("$TL_SCRAPER_TEST_CLIENT_SECRET"),
+        "{:#}",
+        err
+    );
+
+    std::env::set_var("TL_SCRAPER_TEST_CLIENT_SECRET", "env-secret");
+    let creds = config.credentials().await.expect("credentials");
+
+    assert_eq!(creds.id(), "env-client-id");
+    assert_eq!(creds.secret().expose_secret(), "env-secret");
+}
+
+fn refused_refresh(provider: &str) -> Error {
+    Error::ReauthenticationRequired {
+        provider: Some(provider.to_owned()),
+        reason: ApiError {
+            error: "invalid_grant".to_owned(),
+            error_description: None,
+            error_details: None,
+        },
+    }
+}
+
+fn failed_providers(failed: Vec<(&str, Error)>) -> BTreeMap<String, Error> {
+    failed
+        .into_iter()
+        .map(|(name, error)| (name.to_owned(), error))
+        .collect()
+}
+
+#[test]
+fn exits_distinctly_however_many_providers_failed() {
+    let other = || Error::Config("boom".to_owned());
+    let cancelled = || {
+        Error::Cancelled(RunCancelled {
+            skipped: 1,
+            aborted: 0,
+        })
+    };
+    let overran = || {
+        Error::DeadlineExceeded(RunDeadlineExceeded {
+            skipped: 1,
+            aborted: 0,
+        })
+    };
+
+    assert_eq!(other().exit_status(), None);
+    assert_eq!(cancelled().exit_status(), Some(EXIT_CANCELLED));
+    assert_eq!(
+        Error::ProvidersFailed(failed_providers(vec![("a", other()), ("b", overran())]))
+            .exit_status(),
+        Some(EXIT_DEADLINE_EXCEEDED)
+    );
+    assert_eq!(
+        Error::ProvidersFailed(failed_providers(vec![
+            ("a", refused_refresh("a")),
+            ("b", other())
+        ]))
+        .exit_status(),
+        Some(EXIT_REAUTH_NEEDED)
+    );
+    assert_eq!(
+        Error::ProvidersFailed(failed_providers(vec![
+            ("a", refused_refresh("a")),
+            ("b", cancelled())
+        ]))
+        .exit_status(),
+        Some(EXIT_CANCELLED)
+    );
+    assert_eq!(
+        Error::ProvidersFailed(failed_providers(vec![("a", other()), ("b", other())]))
+            .exit_status(),
+        None
+    );
+}
+
+#[test]
+fn fails_run_past_failed_provider_threshold() {
+    let other = || Error::Config("boom".to_owned());
+
+    assert!(Error::providers_failed(BTreeMap::new(), 0).is_ok());
+    assert!(Error::providers_failed(failed_providers(vec![("a", other())]), 1).is_ok());
+    let err = Error::providers_failed(failed_providers(vec![("a", other())]), 0)
+        .expect_err("over threshold");
+    assert!(matches!(err, Error::Config(_)), "{:?}", err);
+    let err = Error::providers_failed(failed_providers(vec![("a", other()), ("b", other())]), 1)
+        .expect_err("over threshold");
+    assert!(
+        matches!(&err, Error::ProvidersFailed(failed) if failed.len() == 2),
+        "{:?}",
+        err
+    );
+    let err = Error::providers_failed(failed_providers(vec![("a", refused_refresh("a"))]), 1)
+        .expect_err("needs re-authentication");
+    assert_eq!(err.exit_status(), Some(EXIT_REAUTH_NEEDED));
+    let cancelled = Error::Cancelled(RunCancelled {
+        skipped: 1,
+        aborted: 0,
+    });
+    let err = Error::providers_failed(failed_providers(vec![("a", cancelled), ("b", other())]), 2)
+        .expect_err("cancelled");
+    assert_eq!(err.exit_status(), Some(EXIT_CANCELLED));
+    let overran = Error::DeadlineExceeded(RunDeadlineExceeded {
+        skipped: 1,
+        aborted: 0,
+    });
+    let err =
+        Error::providers_failed(failed_providers(vec![("a", overran)]), 1).expect_err("overran");
+    assert_eq!(err.exit_status(), Some(EXIT_DEADLINE_EXCEEDED));
+}
+
+#[test]
+fn expands_target_dir_templates() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+[main]
+client_credentials = "creds.json"
+environment = "sandbox"
+
+[providers.lloyds]
+user_token = "lloyds-token.json"
+target_dir = "{}/{{provider}}/{{env}}"
+"#,
+            dir.path().display()
+        ),
+    )
+    .expect("write config");
+
+    let config = ScraperConfig::load(&config_path).expect("config");
+
+    assert_eq!(
+        config.provider("lloyds").expect("provider").target_dir,
+        dir.path().join("lloyds/sandbox")
+    );
+}
+
+#[tokio::test]
+async fn backfills_until_provider_refuses() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+    let today = Utc::now().date_naive();
+    let last_year = today.year() - 1;
+    let refused = NaiveDate::from_ymd_opt(last_year, 12, 1).expect("date");
+    Mock::given(method("GET"))
+        .and(path(format!(
+            "/data/v1/accounts/{}/transactions",
+            ACCOUNT_ID
+        )))
+        .and(query_param("from", refused.to_string()))
+        .respond_with(harness.fixture_with_status(403, "sca-exceeded.json"))
+        .with_priority(1)
+        .expect(2)
+        .mount(&harness.server)
+        .await;
+    let config = harness.provider_config(json!({}));
+    let client = Arc::new(harness.client());
+
+    let report = Backfill::new("mock", client.clone(), &config, last_year)
+        .run()
+        .await
+        .expect("backfill");
+
+    assert_eq!(report.months_synced, today.month() as usize);
+    assert_eq!(report.refused, Some(refused));
+    assert_eq!(report.earliest, NaiveDate::from_ymd_opt(today.year(), 1, 1));
+
+    // Picks up where it left off, only refetching the current month.
+    let report = Backfill::new("mock", client, &config, last_year)
+        .run()
+        .await
+        .expect("backfill");
+    assert_eq!(report.months_synced, 1);
+    assert_eq!(report.months_skipped, today.month() as usize - 1);
+    assert_eq!(report.refused, Some(refused));
+
+    // Having authenticated since, asks again.
+    let mut token: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(harness.token_path()).expect("token"))
+            .expect("token json");
+    token["authed_at"] = json!(Utc::now());
+    std::fs::write(harness.token_path(), token.to_string()).expect("write token");
+    let report = Backfill::new("mock", Arc::new(harness.client()), &config, last_year)
+        .run()
+        .await
+        .expect("backfill");
+    assert_eq!(report.refused, Some(refused));
+}
+
+#[tokio::test]
+async fn writes_transactions_in_canonical_order() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.sync_accounts(json!({})).await;
+
+    let month = std::fs::read_to_string(
+        harness
+            .target_dir()
+            .join("accounts/12-34-56 12345678/2024-06.jsons"),
+    )
+    .expect("month");
+    let lines = month.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("TESCO STORES 3185"), "{}", lines[0]);
+    assert!(lines[1].contains("ACME LTD SALARY"), "{}", lines[1]);
+    for line in lines {
+        let keys = [
+            "\"amount\"",
+            "\"currency\"",
+            "\"description\"",
+            "\"timestamp\"",
+        ]
+        .map(|key| line.find(key).expect(key));
+        assert!(keys.windows(2).all(|w| w[0] < w[1]), "{}", line);
+    }
+}
+
+#[tokio::test]
+async fn reads_back_compressed_files() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let account_dir = harness.target_dir().join("accounts/12-34-56 12345678");
+    harness.sync_accounts(json!({})).await;
+    assert!(account_dir.join("2024-06.jsons").exists());
+
+    harness.sync_accounts(json!({"compression": "gzip"})).await;
+
+    assert!(account_dir.join("2024-06.jsons.gz").exists());
+    assert!(!account_dir.join("2024-06.jsons").exists());
+    let report = tl_scraper::reconcile(
+        &harness.target_dir(),
+        &Layout::default(),
+        date("2024-06-01")..=date("2024-06-30"),
+    )
+    .await
+    .expect("reconcile");
+    let account = &report.accounts["accounts/12-34-56 12345678"];
+    assert_eq!(account.transactions, 2);
+    assert!(account.missing_months.is_empty());
+    let verified = tl_scraper::verify_hashes(&harness.target_dir())
+        .await
+        .expect("verify");
+    assert!(verified.is_ok(), "{:?}", verified);
+}
+
+#[tokio::test]
+async fn reads_stored_data() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.sync_accounts(json!({"compression": "gzip"})).await;
+    let reader = Reader::new(&harness.target_dir());
+
+    let accounts = reader.accounts().expect("accounts");
+    let names = accounts.iter().map(|a| a.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["accounts/12-34-56 12345678"]);
+    let account = &accounts[0];
+    assert_eq!(
+        reader.months(account).expect("months"),
+        [date("2024-06-01")]
+    );
+    let txes = reader
+        .transactions(account, date("2024-06-05")..=date("2024-06-30"))
+        .expect("transactions");
+    let descriptions = txes
+        .iter()
+        .map(|tx| tx.description.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(descriptions, ["ACME LTD SALARY"]);
+    assert!(reader
+        .month_transactions(account, date("2024-05-01"))
+        .expect("month")
+        .is_none());
+    let balance = reader.balance(account).expect("balance");
+    assert_eq!(balance[0].current, decimal("1161.2"));
+}
+
+#[tokio::test]
+async fn reports_provider_status() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let config = harness.provider_config(json!({}));
+    let before = ProviderStatus::check(&harness.client(), &config)
+        .await
+        .expect("status");
+    assert!(before.token_expires_at.expect("expiry") > Utc::now());
+    assert!(before.refresh_token_age(Utc::now()).expect("age") < chrono::Duration::minutes(1));
+    assert_eq!(before.accounts, 0);
+    assert!(before.last_synced_at.is_none());
+
+    harness.sync_accounts(json!({})).await;
+    let after = ProviderStatus::check(&harness.client(), &config)
+        .await
+        .expect("status");
+    assert_eq!(after.accounts, 1);
+    assert_eq!(after.latest_month, Some(date("2024-06-01")));
+    assert!(after.last_synced_at.is_some());
+
+    std::fs::remove_file(harness.token_path()).expect("remove token");
+    let logged_out = ProviderStatus::check(&harness.client(), &config)
+        .await
+        .expect("status");
+    assert!(logged_out.token_expires_at.is_none());
+    assert_eq!(logged_out.accounts, 1);
+}
+
+#[tokio::test]
+async fn converts_amounts_to_base_currency() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let rates = harness.dir.path().join("eurofxref-hist.csv");
+    std::fs::write(
+        &rates,
+        "Date,USD,JPY,GBP,\n2024-06-14,1.0713,N/A,0.8434,\n2024-05-31,1.0800,N/A,0.8500,\n",
+    )
+    .expect("rates");
+
+    harness
+        .sync_accounts(json!({
+            "fx": {"base_currency": "USD", "rates": {"ecb": {"path": rates}}},
+        }))
+        .await;
+
+    let month = std::fs::read_to_string(
+        harness
+            .target_dir()
+            .join("accounts/12-34-56 12345678/2024-06.jsons"),
+    )
+    .expect("month");
+    let converted = month
+        .lines()
+        .map(|line| {
+            let tx: serde_json::Value = serde_json::from_str(line).expect("transaction");
+            assert_eq!(tx["base_currency"], "USD", "{}", tx);
+            let amount = tx["base_amount"].as_str().expect("base_amount");
+            (
+                tx["description"].as_str().expect("description").to_owned(),
+                decimal(amount),
+            )
+        })
+        .collect::<std::collections::BTreeMap<_, _>>();
+    // Both use the rates from the 31st, as the latest published by then.
+    assert_eq!(converted["TESCO STORES 3185"], decimal("-30.71"));
+    assert_eq!(converted["ACME LTD SALARY"], decimal("2668.24"));
+}
+
+#[tokio::test]
+async fn verifies_stored_hashes() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.sync_accounts(json!({})).await;
+
+    let report = tl_scraper::verify_hashes(&harness.target_dir())
+        .await
+        .expect("verify");
+    assert!(report.is_ok(), "{:?}", report);
+    assert!(report.checked > 0, "{:?}", report);
+
+    let account_dir = Path::new("accounts/12-34-56 12345678");
+    let month = account_dir.join("2024-06.jsons");
+    std::fs::write(harness.target_dir().join(&month), "{}\n").expect("tamper");
+    let balance = account_dir.join("balance.jsons");
+    std::fs::remove_file(harness.target_dir().join(&balance)).expect("remove");
+
+    let report = tl_scraper::verify_hashes(&harness.target_dir())
+        .await
+        .expect("verify");
+    assert_eq!(report.modified, vec![month]);
+    assert_eq!(report.missing, vec![balance]);
+}
+
+#[tokio::test]
+async fn resumes_from_journal() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    std::fs::create_dir_all(harness.target_dir()).expect("target dir");
+    let journal = json!({
+        "from_date": "2024-06-01",
+        "to_date": "2024-06-30",
+        "accounts": {ACCOUNT_ID: ["2024-06-01"]},
+    });
+    let journal_path = harness.target_dir().join(".journal.json");
+    std::fs::write(&journal_path, journal.to_string()).expect("write journal");
+
+    harness
+        .sync_accounts_with(
+            json!({}),
+            SyncOptions {
+                resume: true,
+                ..SyncOptions::default()
+            },
+        )
+        .await;
+
+    let account_dir = harness.target_dir().join("accounts/12-34-56 12345678");
+    assert!(account_dir.join("balance.jsons").exists());
+    assert!(!account_dir.join("2024-06.jsons").exists());
+    assert!(!journal_path.exists());
+}
+
+#[tokio::test]
+async fn syncs_into_configured_layout() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+
+    harness
+        .sync_accounts(json!({
+            "layout": {"account_dir": "{account_id}", "month_file": "{year}/{month}"},
+        }))
+        .await;
+
+    let month = harness.target_dir().join(ACCOUNT_ID).join("2024/06.jsons");
+    assert!(month.exists(), "{:?} missing", month);
+}
+
+#[tokio::test]
+async fn moves_cards_to_named_directories() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let cards: Vec<CardsResult> = fixture_results("cards.json");
+
+    FsStore::new(dir.path())
+        .put_cards(cards.clone())
+        .await
+        .expect("store by id");
+    let layout = Layout {
+        card_naming: CardNaming::DisplayName,
+        ..Layout::default()
+    };
+    FsStore::new(dir.path())
+        .with_layout(layout)
+        .put_cards(cards)
+        .await
+        .expect("store by name");
+
+    let named = dir.path().join("cards/Platinum Cashback 1234");
+    assert!(named.join("account.jsons").exists());
+    let by_id = dir.path().join("cards/9ab3c6f0e4d2b1a0c8f7e6d5c4b3a291");
+    assert!(by_id.join("account.jsons").exists());
+    #[cfg(unix)]
+    assert!(std::fs::symlink_metadata(&by_id)
+        .expect("old path")
+        .file_type()
+        .is_symlink());
+    assert!(verify_hashes(dir.path()).await.expect("verify").is_ok());
+    let stored = Reader::new(dir.path()).accounts().expect("accounts");
+    let names = stored.iter().map(|a| a.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["cards/Platinum Cashback 1234"]);
+}
+
+#[tokio::test]
+async fn names_clashing_cards_by_id() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let mut cards: Vec<CardsResult> = fixture_results("cards.json");
+    let mut replacement = cards[0].clone();
+    replacement.account_id = "0f1e2d3c4b5a69788796a5b4c3d2e1f0".to_owned();
+    cards.push(replacement);
+    let layout = Layout {
+        card_naming: CardNaming::DisplayName,
+        ..Layout::default()
+    };
+
+    FsStore::new(dir.path())
+        .with_layout(layout)
+        .put_cards(cards.clone())
+        .await
+        .expect("store");
+
+    assert!(!dir.path().join("cards/Platinum Cashback 1234").exists());
+    for card in cards.iter() {
+        let by_id = dir.path().join("cards").join(&card.account_id);
+        let stored = std::fs::read_to_string(by_id.join("account.jsons")).expect("card");
+        assert!(stored.contains(&card.account_id), "{}", stored);
+    }
+}
+
+#[tokio::test]
+async fn sanitises_card_directory_names() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let mut cards: Vec<CardsResult> = fixture_results("cards.json");
+    cards[0].display_name = "Joint/Shared".to_owned();
+    let mut dots = cards[0].clone();
+    dots.account_id = "0f1e2d3c4b5a69788796a5b4c3d2e1f0".to_owned();
+    dots.display_name = "..".to_owned();
+    dots.partial_card_number = String::new();
+    cards.push(dots.clone());
+    let layout = Layout {
+        card_naming: CardNaming::DisplayName,
+        ..Layout::default()
+    };
+
+    FsStore::new(dir.path())
+        .with_layout(layout)
+        .put_cards(cards)
+        .await
+        .expect("store");
+
+    let named = dir.path().join("cards/Joint-Shared 1234");
+    assert!(named.join("account.jsons").exists(), "{:?} missing", named);
+    let by_id = dir.path().join("cards").join(&dots.account_id);
+    assert!(by_id.join("account.jsons").exists(), "{:?} missing", by_id);
+}
+
+#[tokio::test]
+async fn writes_pending_transactions_when_sync_fails() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    Mock::given(method("GET"))
+        .and(path(format!("/data/v1/accounts/{}/balance", ACCOUNT_ID)))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount(&harness.server)
+        .await;
+    harness.accounts().await;
+    let sync = Arc::new(ProviderSync::new(
+        "mock",
+        Arc::new(harness.client()),
+        &harness.provider_config(json!({})),
+        SyncOptions::default(),
+    ));
+
+    scraper_sdk::sync_all(
+        vec![sync],
+        date("2024-06-01")..=date("2024-06-30"),
+        JobPool::new(1),
+    )
+    .await
+    .expect_err("balance fails");
+
+    let pending = harness
+        .target_dir()
+        .join("accounts/12-34-56 12345678/pending.jsons");
+    assert!(pending.exists(), "{:?} missing", pending);
+}
+
+#[cfg(feature = "parquet")]
+#[tokio::test]
+async fn merges_parquet_months() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let account: Vec<AccountsResult> = fixture_results("accounts.json");
+    let key = AccountKey::Account(account[0].clone());
+    let mut txes: Vec<TransactionsResult> = fixture_results("transactions-page-1.json");
+    let newest = txes.clone();
+    txes.extend(fixture_results::<Vec<TransactionsResult>>(
+        "transactions-page-2.json",
+    ));
+    let month = date("2024-06-01");
+    let store = FsStore::new(dir.path())
+        .with_transaction_format(tl_scraper::store::TransactionFormat::Parquet)
+        .with_merge_transactions(true);
+
+    store
+        .put_transactions(&key, month, txes.clone())
+        .await
+        .expect("month");
+    store
+        .put_transactions(&key, month, newest)
+        .await
+        .expect("part of month");
+
+    let stored = store
+        .get_transactions(&key, month)
+        .await
+        .expect("read month")
+        .expect("readable");
+    let ids = |txes: &[TransactionsResult]| {
+        let mut ids = txes
+            .iter()
+            .map(|tx| tx.transaction_id.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+    assert_eq!(ids(&stored), ids(&txes));
+    assert_eq!(store.stats().files_unchanged, 1);
+}
+
+#[tokio::test]
+async fn merges_transactions_without_ids() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let account: Vec<AccountsResult> = fixture_results("accounts.json");
+    let key = AccountKey::Account(account[0].clone());
+    let newest: Vec<TransactionsResult> = fixture_results("transactions-page-1.json");
+    let mut without_id: Vec<TransactionsResult> = fixture_results("transactions-page-2.json");
+    for tx in without_id.iter_mut() {
+        tx.transaction_id = None;
+        tx.normalised_provider_transaction_id = None;
+        tx.provider_transaction_id = None;
+    }
+    let all = newest
+        .iter()
+        .chain(&without_id)
+        .cloned()
+        .collect::<Vec<_>>();
+    let month = date("2024-06-01");
+    let store = FsStore::new(dir.path()).with_merge_transactions(true);
+
+    store
+        .put_transactions(&key, month, all.clone())
+        .await
+        .expect("month");
+    store
+        .put_transactions(&key, month, newest)
+        .await
+        .expect("part of month");
+    store
+        .put_transactions(&key, month, all.clone())
+        .await
+        .expect("month again");
+
+    let stored = store
+        .get_transactions(&key, month)
+        .await
+        .expect("read month")
+        .expect("readable");
+    assert_eq!(stored.len(), all.len());
+    assert!(stored.iter().any(|tx| tx.transaction_id.is_none()));
+}
+
+#[tokio::test]
+async fn keeps_replaced_pending_transactions() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let account: Vec<AccountsResult> = fixture_results("accounts.json");
+    let key = AccountKey::Account(account[0].clone());
+    let pending: Vec<TransactionsResult> = fixture_results("transactions-page-1.json");
+    let history = dir
+        .path()
+        .join("accounts/12-34-56 12345678/pending-history");
+    std::fs::create_dir_all(&history).expect("history");
+    std::fs::write(history.join("2020-01-01T000000Z.jsons"), "").expect("expired snapshot");
+    let store = FsStore::new(dir.path()).with_pending_retention(chrono::Duration::days(7));
+
+    store
+        .put_pending(&key, pending.clone())
+        .await
+        .expect("pending");
+    store
+        .put_pending(&key, pending.clone())
+        .await
+        .expect("same pending");
+    store
+        .put_pending(&key, Vec::new())
+        .await
+        .expect("none pending");
+
+    let snapshots = std::fs::read_dir(&history)
+        .expect("history")
+        .map(|entry| entry.expect("entry").path())
+        .collect::<Vec<_>>();
+    assert_eq!(snapshots.len(), 1, "{:?}", snapshots);
+    let archived = std::fs::read_to_string(&snapshots[0]).expect("snapshot");
+    assert_eq!(archived.lines().count(), pending.len());
+}
+
+#[tokio::test]
+async fn skips_excluded_accounts() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+
+    let sync = harness
+        .sync_accounts(json!({
+            "exclude_accounts": [{"name": "^Club"}],
+        }))
+        .await;
+
+    assert_eq!(sync.summary().accounts, 0);
+    assert!(!harness.target_dir().join("accounts").exists());
+}
+
+#[tokio::test]
+async fn redacts_written_accounts() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+
+    harness.sync_accounts(json!({"redact": true})).await;
+
+    let account = std::fs::read_to_string(
+        harness
+            .target_dir()
+            .join("accounts")
+            .join(ACCOUNT_ID)
+            .join("account.jsons"),
+    )
+    .expect("account");
+    assert!(!account.contains("12345678"), "{}", account);
+    assert!(account.contains("****5678"), "{}", account);
+}
+
+#[tokio::test]
+async fn redacts_payees() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness
+        .get(
+            &format!("/data/v1/accounts/{}/beneficiaries", ACCOUNT_ID),
+            "beneficiaries.json",
+        )
+        .await;
+    harness
+        .get(
+            &format!("/data/v1/accounts/{}/scheduled_payments", ACCOUNT_ID),
+            "scheduled-payments.json",
+        )
+        .await;
+
+    harness
+        .sync_accounts(json!({
+            "redact": true,
+            "scrape_scheduled_payments": true,
+            "scrape_beneficiaries": true,
+        }))
+        .await;
+
+    let account_dir = harness.target_dir().join("accounts").join(ACCOUNT_ID);
+    let beneficiaries =
+        std::fs::read_to_string(account_dir.join("beneficiaries.jsons")).expect("beneficiaries");
+    for exposed in ["87654321", "65-43-21", "J SMITH"] {
+        assert!(!beneficiaries.contains(exposed), "{}", beneficiaries);
+    }
+    assert!(beneficiaries.contains("****4321"), "{}", beneficiaries);
+    assert!(beneficiaries.contains("b7c1e4d2"), "{}", beneficiaries);
+    let payments = std::fs::read_to_string(account_dir.join("scheduled-payments.jsons"))
+        .expect("scheduled payments");
+    for exposed in ["11223344", "01-02-03", "A LANDLORD"] {
+        assert!(!payments.contains(exposed), "{}", payments);
+    }
+    assert!(payments.contains("****3344"), "{}", payments);
+    assert!(payments.contains("REDACTED"), "{}", payments);
+}
+
+#[tokio::test]
+async fn redacts_counterparties() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness
+        .get(
+            &format!("/data/v1/accounts/{}/standing_orders", ACCOUNT_ID),
+            "standing-orders.json",
+        )
+        .await;
+    harness
+        .get(
+            &format!("/data/v1/accounts/{}/direct_debits", ACCOUNT_ID),
+            "direct-debits.json",
+        )
+        .await;
+
+    harness
+        .sync_accounts(json!({
+            "redact": true,
+            "scrape_standing_orders": true,
+            "scrape_direct_debits": true,
+        }))
+        .await;
+
+    let account_dir = harness.target_dir().join("accounts").join(ACCOUNT_ID);
+    let orders = std::fs::read_to_string(account_dir.join("standing-orders.jsons"))
+        .expect("standing orders");
+    for exposed in ["A LANDLORD", "FLAT 2 RENT"] {
+        assert!(!orders.contains(exposed), "{}", orders);
+    }
+    let debits =
+        std::fs::read_to_string(account_dir.join("direct-debits.jsons")).expect("direct debits");
+    assert!(!debits.contains("BRIGHT ENERGY"), "{}", debits);
+    assert!(debits.contains("dd-91c2"), "{}", debits);
+    let transactions =
+        std::fs::read_to_string(account_dir.join("2024-06.jsons")).expect("transactions");
+    for exposed in ["ACME HOLDINGS PLC", "J BLOGGS"] {
+        assert!(!transactions.contains(exposed), "{}", transactions);
+    }
+    assert!(transactions.contains("ACME LTD SALARY"), "{}", transactions);
+}
+
+#[tokio::test]
+async fn notifies_of_new_transactions() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.sync_accounts(json!({})).await;
+    // Forget the most recent transaction.
+    let month = harness
+        .target_dir()
+        .join("accounts/12-34-56 12345678/2024-06.jsons");
+    let stored = std::fs::read_to_string(&month).expect("month");
+    let older = stored.lines().skip(1).collect::<Vec<_>>().join("\n");
+    std::fs::write(&month, older).expect("write month");
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("ACME LTD SALARY"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+
+    harness
+        .sync_accounts(json!({
+            "notify": {"webhook": {"url": format!("{}/hook", harness.server.uri())}},
+        }))
+        .await;
+}
+
+#[tokio::test]
+async fn finds_new_transactions_with_seen_index() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.sync_accounts(json!({"seen_index": true})).await;
+    // Were the month read back, this would look new.
+    let month = harness
+        .target_dir()
+        .join("accounts/12-34-56 12345678/2024-06.jsons");
+    let stored = std::fs::read_to_string(&month).expect("month");
+    let older = stored.lines().skip(1).collect::<Vec<_>>().join("\n");
+    std::fs::write(&month, older).expect("write month");
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&harness.server)
+        .await;
+
+    harness
+        .sync_accounts(json!({
+            "seen_index": true,
+            "notify": {"webhook": {"url": format!("{}/hook", harness.server.uri())}},
+        }))
+        .await;
+}
+
+#[tokio::test]
+async fn resends_unsent_notifications() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.sync_accounts(json!({})).await;
+    let month = harness
+        .target_dir()
+        .join("accounts/12-34-56 12345678/2024-06.jsons");
+    let stored = std::fs::read_to_string(&month).expect("month");
+    let older = stored.lines().skip(1).collect::<Vec<_>>().join("\n");
+    std::fs::write(&month, older).expect("write month");
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("ACME LTD SALARY"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    let notify = json!({
+        "notify": {"webhook": {"url": format!("{}/hook", harness.server.uri())}},
+    });
+
+    harness.sync_accounts(notify.clone()).await;
+    // By now the transaction is stored, so only the unsent notification
+    // can tell us about it.
+    harness.sync_accounts(notify).await;
+}
+
+#[cfg(feature = "git")]
+#[tokio::test]
+async fn commits_synced_changes() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let repo = git2::Repository::init(harness.target_dir()).expect("init");
+    let cache = harness.target_dir().join(".cache");
+    std::fs::create_dir_all(&cache).expect("cache dir");
+    std::fs::write(cache.join("response.json"), "{}").expect("cached response");
+    harness.sync_accounts(json!({"git_commit": true})).await;
+
+    let head = repo.head().expect("head").peel_to_commit().expect("commit");
+    let message = head.message().expect("message");
+    assert!(
+        message.starts_with("Sync mock: 2024-06-01 to 2024-06-30"),
+        "{}",
+        message
+    );
+    let tree = head.tree().expect("tree");
+    assert!(tree.get_path("accounts".as_ref()).is_ok());
+    assert!(tree.get_path(".hashes".as_ref()).is_ok());
+    assert!(tree.get_path(".sync.lock".as_ref()).is_err());
+    assert!(tree.get_path(".cache".as_ref()).is_err());
+}
+
+#[cfg(feature = "git")]
+#[tokio::test]
+async fn commits_progress_when_deadline_passes() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let repo = git2::Repository::init(harness.target_dir()).expect("init");
+    let stalled = harness
+        .fixture("transactions-page-1.json")
+        .set_delay(Duration::from_secs(5));
+    harness
+        .get_first(
+            &format!("/data/v1/accounts/{}/transactions", ACCOUNT_ID),
+            1,
+            stalled,
+        )
+        .await;
+    harness.accounts().await;
+
+    let report = SyncEngine::builder(
+        "mock",
+        Arc::new(harness.client()),
+        &harness.provider_config(json!({"git_commit": true})),
+    )
+    .period(date("2024-06-01")..=date("2024-06-30"))
+    .deadline(Duration::from_millis(500))
+    .build()
+    .expect("engine")
+    .run()
+    .await;
+
+    let err = report.into_result().unwrap_err();
+    assert_eq!(err.exit_status(), Some(EXIT_DEADLINE_EXCEEDED), "{:?}", err);
+    let head = repo.head().expect("head").peel_to_commit().expect("commit");
+    let message = head.message().expect("message");
+    assert!(
+        message.starts_with("Sync mock (stopped early)"),
+        "{}",
+        message
+    );
+    let tree = head.tree().expect("tree");
+    assert!(tree
+        .get_path("accounts/12-34-56 12345678/balance.jsons".as_ref())
+        .is_ok());
+}
+
+#[tokio::test]
+async fn uploads_changed_files() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    std::env::set_var("UPLOADS_CHANGED_FILES_KEY_ID", "key-id");
+    std::env::set_var("UPLOADS_CHANGED_FILES_SECRET", "secret");
+    Mock::given(method("PUT"))
+        .and(path_regex("^/bucket/backup/accounts/.+"))
+        .and(header_exists("authorization"))
+        .and(header_exists("x-amz-content-sha256"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1..)
+        .mount(&harness.server)
+        .await;
+    let uploaded = harness.dir.path().join("uploaded");
+
+    harness
+        .sync_accounts(json!({
+            "upload": [
+                {"command": format!("printf '%s\\n' \"$@\" > '{}'", uploaded.display())},
+                {"s3": {
+                    "bucket": "bucket",
+                    "prefix": "backup",
+                    "endpoint": harness.server.uri(),
+                    "access_key_id_env": "UPLOADS_CHANGED_FILES_KEY_ID",
+                    "secret_access_key_env": "UPLOADS_CHANGED_FILES_SECRET",
+                }},
+            ],
+        }))
+        .await;
+
+    let uploaded = std::fs::read_to_string(uploaded).expect("uploaded");
+    assert!(
+        uploaded
+            .lines()
+            .any(|file| file == "accounts/12-34-56 12345678/2024-06.jsons"),
+        "{}",
+        uploaded
+    );
+}
+
+#[tokio::test]
+async fn uploads_files_from_failed_syncs() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    let failing = Mock::given(method("GET"))
+        .and(path(format!("/data/v1/accounts/{}/balance", ACCOUNT_ID)))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount_as_scoped(&harness.server)
+        .await;
+    harness.accounts().await;
+    let uploaded = harness.dir.path().join("uploaded");
+    let provider = harness.provider_config(json!({
+        "upload": [{"command": format!("printf '%s\\n' \"$@\" > '{}'", uploaded.display())}],
+    }));
+    let sync = |provider: &ProviderConfig| {
+        let sync = Arc::new(ProviderSync::new(
+            "mock",
+            Arc::new(harness.client()),
+            provider,
+            SyncOptions::default(),
+        ));
+        scraper_sdk::sync_all(
+            vec![sync],
+            date("2024-06-01")..=date("2024-06-30"),
+            JobPool::new(1),
+        )
+    };
+
+    sync(&provider).await.expect_err("balance fails");
+    let backlog = std::fs::read_to_string(harness.target_dir().join(".upload-backlog"))
+        .expect("upload backlog");
+    assert!(!uploaded.exists());
+    drop(failing);
+    sync(&provider).await.expect("sync");
+
+    let uploaded = std::fs::read_to_string(uploaded).expect("uploaded");
+    assert!(!backlog.is_empty());
+    for file in backlog.lines() {
+        assert!(
+            uploaded.lines().any(|f| f == file),
+            "{}: {}",
+            file,
+            uploaded
+        );
+    }
+    assert!(!harness.target_dir().join(".upload-backlog").exists());
+}
+
+/// A config syncing `mock` to a bucket on the mock server, caching in the
+/// target directory, with `extra` added to the provider's config.
+fn bucket_config(harness: &Harness, extra: &str) -> ScraperConfig {
+    std::env::set_var("SYNCS_TO_BUCKET_KEY_ID", "key-id");
+    std::env::set_var("SYNCS_TO_BUCKET_SECRET", "secret");
+    let config_path = harness.dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+[main]
+environment = "sandbox"
+
+[providers.mock]
+user_token = "{}"
+target_dir = "s3://bucket/finance/{{provider}}"
+cache_dir = "{}/{{provider}}"
+scrape_accounts = true
+s3 = {{ endpoint = "{}", access_key_id_env = "SYNCS_TO_BUCKET_KEY_ID", secret_access_key_env = "SYNCS_TO_BUCKET_SECRET" }}
+{}
+"#,
+            harness.token_path().display(),
+            harness.target_dir().display(),
+            harness.server.uri(),
+            extra,
+        ),
+    )
+    .expect("write config");
+    ScraperConfig::load(&config_path).expect("config")
+}
+
+/// Accepts uploads to the bucket, expecting at least one.
+async fn accept_uploads(harness: &Harness) {
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/bucket/finance/mock/.+\.tmp$"))
+        .and(header_exists("authorization"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1..)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex("^/bucket/finance/mock/"))
+        .and(header_exists("x-amz-copy-source"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1..)
+        .mount(&harness.server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path_regex(r"^/bucket/finance/mock/.+\.tmp$"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1..)
+        .mount(&harness.server)
+        .await;
+}
+
+async fn sync_provider(provider: &ProviderConfig, client: TlClient) {
+    let sync = Arc::new(ProviderSync::new(
+        "mock",
+        Arc::new(client),
+        provider,
+        SyncOptions::default(),
+    ));
+    scraper_sdk::sync_all(
+        vec![sync],
+        date("2024-06-01")..=date("2024-06-30"),
+        JobPool::new(1),
+    )
+    .await
+    .expect("sync");
+}
+
+#[tokio::test]
+async fn syncs_to_bucket() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+    accept_uploads(&harness).await;
+    let config = bucket_config(&harness, "");
+    let provider = config.provider("mock").expect("provider");
+    assert_eq!(provider.target_dir, harness.target_dir().join("mock"));
+
+    sync_provider(provider, harness.client()).await;
+
+    assert!(harness
+        .target_dir()
+        .join("mock/accounts/12-34-56 12345678/2024-06.jsons")
+        .exists());
+    let requests = harness.server.received_requests().await.expect("requests");
+    assert!(requests
+        .iter()
+        .any(|request| request.method.as_str() == "PUT"
+            && request.url.path()
+                == "/bucket/finance/mock/accounts/12-34-56%2012345678/2024-06.jsons"
+            && request.headers.contains_key("x-amz-copy-source")));
+}
+
+#[tokio::test]
+async fn retries_failed_uploads() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+    accept_uploads(&harness).await;
+    let config = bucket_config(&harness, "");
+    let provider = config.provider("mock").expect("provider");
+    let failing = Mock::given(method("PUT"))
+        .and(path_regex(r"/2024-06\.jsons\..+\.tmp$"))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount_as_scoped(&harness.server)
+        .await;
+    let sync = Arc::new(ProviderSync::new(
+        "mock",
+        Arc::new(harness.client()),
+        provider,
+        SyncOptions::default(),
+    ));
+    scraper_sdk::sync_all(
+        vec![sync],
+        date("2024-06-01")..=date("2024-06-30"),
+        JobPool::new(1),
+    )
+    .await
+    .expect_err("upload fails");
+    drop(failing);
+    let before = harness.requests_received().await;
+
+    sync_provider(provider, harness.client()).await;
+
+    let requests = harness.server.received_requests().await.expect("requests");
+    assert!(requests[before..]
+        .iter()
+        .any(|request| request.method.as_str() == "PUT"
+            && request.url.path()
+                == "/bucket/finance/mock/accounts/12-34-56%2012345678/2024-06.jsons"));
+    let pending = std::fs::read_to_string(harness.target_dir().join("mock/.pending-uploads.json"))
+        .expect("pending uploads");
+    assert_eq!(
+        serde_json::from_str::<Vec<PathBuf>>(&pending).expect("pending uploads"),
+        Vec::<PathBuf>::new()
+    );
+}
+
+#[tokio::test]
+async fn merges_months_already_in_bucket() {
+    let harness = Harness::start(chrono::Duration::hours(1)).await;
+    harness.accounts().await;
+    accept_uploads(&harness).await;
+    let stored = json!({
+        "transaction_id": "0b7e5c1d9f3a4b2e8c6d1a0f9e8d7c6b",
+        "timestamp": "2024-06-02T00:00:00+00:00",
+        "description": "CORNER SHOP",
+        "amount": -3.5,
+        "currency": "GBP",
+        "transaction_type": "DEBIT",
+        "transaction_category": "PURCHASE",
+        "transaction_classification": [],
+        "merchant_name": null,
+        "running_balance": null,
+    });
+    Mock::given(method("GET"))
+        .and(path(
+            "/bucket/finance/mock/accounts/12-34-56%2012345678/2024-06.jsons",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_string(format!("{}\n", stored)))
+        .expect(1)
+        .mount(&harness.server)
+        .await;
+    let config = bucket_config(&harness, "merge_months = true");
+
+    sync_provider(config.provider("mock").expect("provider"), harness.client()).await;
+
+    let month = std::fs::read_to_string(
+        harness
+            .target_dir()
+            .join("mock/accounts/12-34-56 12345678/2024-06.jsons"),
+    )
+    .expect("month");
+    assert!(month.contains("CORNER SHOP"), "{}", month);
+    assert!(month.contains("TESCO STORES 3185"), "{}", month);
+    let requests = harness.server.received_requests().await.expect("requests");
+    assert!(requests
+        .iter()
+        .any(|request| request.method.as_str() == "PUT"
+            && request.url.path().contains("2024-06.jsons.")
+            && String::from_utf8_lossy(&request.body).contains("CORNER SHOP")));
+}
+
+#[tokio::test]
+async fn pings_start_and_setup_failure() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/ping/start"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/ping/fail"))
+        .and(body_string_contains("Reading client credentials"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let config: PingConfig = serde_json::from_value(json!({
+        "success_url": format!("{}/ping", server.uri()),
+    }))
+    .expect("ping config");
+
+    tl_scraper::ping_start("mock", &config).await;
+    let error = anyhow::anyhow!("Reading client credentials");
+    tl_scraper::ping_failure("mock", &config, &error).await;
+}